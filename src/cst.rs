@@ -0,0 +1,397 @@
+use crate::jit::*;
+use crate::operators::*;
+
+// The concrete syntax tree keeps every character of the source. Whitespace and comments are
+// kept as trivia in front of the token that follows them, and brackets group the tokens between
+// them, so tools can change a program without losing its layout. The tokens that the parser
+// turns into the expression tree are taken from it
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,
+    BlockComment
+}
+
+#[derive(Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub pos: SourcePos
+}
+
+// A token and the trivia in front of it. token is None for text that is not part of the language
+#[derive(Debug, Clone)]
+pub struct CstToken {
+    pub leading: Vec<Trivia>,
+    pub text: String,
+    pub token: Option<Token>,
+    pub pos: SourcePos
+}
+
+#[derive(Debug, Clone)]
+pub enum CstNode {
+    Token(CstToken),
+    // ( ), [ ] or { } and what is between them, close is None if the bracket is never closed
+    Group { open: CstToken, children: Vec<CstNode>, close: Option<CstToken> }
+}
+
+pub struct Cst {
+    pub nodes: Vec<CstNode>,
+    pub trailing: Vec<Trivia> // The trivia after the last token
+}
+
+// A piece of the source, either a word that becomes a token or trivia
+enum Piece {
+    Word(String),
+    Trivia(TriviaKind, String)
+}
+
+// Split the source into words and trivia. Words are separated by whitespace and the delimiters
+// [ ] ( ) { } , ; : . which are also words by themselves. A . between digits is kept as the
+// decimal point of a number. A | is also a word by itself, unless it is part of |=, and so are
+// < and > right after a name, like in pair<i32>
+fn split_words(my_str: &str) -> Vec<(Piece, SourcePos)> {
+    let mut pieces: Vec<(Piece, SourcePos)> = vec!();
+    let chars: Vec<char> = my_str.chars().collect();
+    let mut pos = SourcePos { line: 1, col: 1 };
+    let mut index = 0;
+    while index < chars.len() {
+        let start = index;
+        let piece_pos = pos;
+        let mut kind: Option<TriviaKind> = None;
+        if let Some((end, closed)) = comment_end(&chars, index) {
+            let text: String = chars[index..end].iter().collect();
+            // Doc comments mean something, and a /* without an end has to be reported, so they are words
+            if is_doc_comment(&text).is_none() && closed {
+                kind = Some(if text.starts_with("//") { TriviaKind::LineComment } else { TriviaKind::BlockComment });
+            }
+            index = end;
+        }
+        else if chars[index].is_whitespace() {
+            while index < chars.len() && chars[index].is_whitespace() {
+                index += 1;
+            }
+            kind = Some(TriviaKind::Whitespace);
+        }
+        else if chars[index] == ':' && chars.get(index + 1) == Some(&':') {
+            // :: is one word
+            index += 2;
+        }
+        else if let Some(end) = char_literal_end(&chars, index) {
+            // A character literal is one word, even when the character is a space or a delimiter
+            index = end;
+        }
+        else if is_word_separator(&chars, index, "") {
+            index += 1;
+        }
+        else {
+            let mut word = String::new();
+            while index < chars.len() && !chars[index].is_whitespace() && comment_end(&chars, index).is_none() && !is_word_separator(&chars, index, &word) {
+                word.push(chars[index]);
+                index += 1;
+            }
+        }
+
+        let text: String = chars[start..index].iter().collect();
+        for c in text.chars() {
+            if c == '\n' {
+                pos = SourcePos { line: pos.line + 1, col: 1 };
+            }
+            else {
+                pos.col += 1;
+            }
+        }
+        match kind {
+            Some(kind) => pieces.push((Piece::Trivia(kind, text), piece_pos)),
+            None => pieces.push((Piece::Word(text), piece_pos))
+        }
+    }
+    return pieces;
+}
+
+// If a character literal like 'a' or '\n' starts at chars[start], the index just after it
+fn char_literal_end(chars: &[char], start: usize) -> Option<usize> {
+    if chars[start] != '\'' {
+        return None;
+    }
+    let mut index = start + 1;
+    while index < chars.len() && chars[index] != '\n' {
+        match chars[index] {
+            '\\' => {
+                index += 2;
+            }
+            '\'' => {
+                return Some(index + 1);
+            }
+            _ => {
+                index += 1;
+            }
+        }
+    }
+    return None;
+}
+
+fn is_prefix_char(c: char) -> bool {
+    let mut symbol = [0; 4];
+    let symbol: &str = c.encode_utf8(&mut symbol);
+    return symbol == "&" || symbol == "*" || OPERATORS.iter().any(|op| op.arity == Arity::Prefix && op.symbol == symbol);
+}
+
+// Whether the character at index is a word of its own, curr_word is the word before it
+fn is_word_separator(chars: &[char], index: usize, curr_word: &str) -> bool {
+    let i = chars[index];
+    let is_decimal_point = i == '.'
+        && is_decimal_digits(curr_word.strip_prefix('-').unwrap_or(curr_word))
+        && chars.get(index + 1).is_some_and(|c| c.is_ascii_digit());
+    // &, * and the prefix operators in front of something are split off, so that &x, *x and ~x
+    // work without a space
+    let is_prefix = is_prefix_char(i)
+        && curr_word.is_empty()
+        && chars.get(index + 1).is_some_and(|c| c.is_alphanumeric() || "_([".contains(*c) || is_prefix_char(*c));
+    // The | around the parameters of a closure are split off, so that |x| works without spaces
+    let is_pipe = i == '|' && chars.get(index + 1) != Some(&'=');
+    // The < and > around type arguments are split off, so that pair<pair<i32>> and max::<f64>
+    // work without spaces, while x<<2 and x>>2 are still shifts
+    let is_angle_bracket = is_type_args_open(chars, index) || (i == '>' && !"-=".contains(chars[index - 1]) && in_type_args(chars, index));
+    return is_prefix || is_pipe || is_angle_bracket || ("[](){},;:.".contains(i) && !is_decimal_point);
+}
+
+// Whether chars[index] is a < right after a name, that starts type arguments
+fn is_type_args_open(chars: &[char], index: usize) -> bool {
+    return chars[index] == '<'
+        && index > 0 && (chars[index - 1].is_alphanumeric() || "_:".contains(chars[index - 1]))
+        && chars.get(index + 1).is_some_and(|c| !"<=".contains(*c));
+}
+
+// Whether chars[index] is inside type arguments that are not closed yet, looking back no further
+// than the start of the line or statement
+fn in_type_args(chars: &[char], index: usize) -> bool {
+    let mut depth = 0;
+    for back in (0..index).rev() {
+        match chars[back] {
+            '>' if back > 0 && "-=".contains(chars[back - 1]) => {}
+            '>' => {
+                depth += 1;
+            }
+            '<' if is_type_args_open(chars, back) => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            ';' | '{' | '}' | '=' | '\n' => {
+                return false;
+            }
+            _ => {}
+        }
+    }
+    return false;
+}
+
+// If a comment starts at chars[start], the index just after it and whether it was closed.
+// // comments go to the end of the line, /* */ comments can be nested
+pub fn comment_end(chars: &[char], start: usize) -> Option<(usize, bool)> {
+    if chars.get(start) != Some(&'/') {
+        return None;
+    }
+    match chars.get(start + 1) {
+        Some('/') => {
+            let end = chars[start..].iter().position(|c| *c == '\n').map_or(chars.len(), |offset| start + offset);
+            return Some((end, true));
+        }
+        Some('*') => {
+            let mut depth = 1;
+            let mut index = start + 2;
+            while index < chars.len() {
+                match (chars[index], chars.get(index + 1)) {
+                    ('/', Some('*')) => {
+                        depth += 1;
+                        index += 2;
+                    }
+                    ('*', Some('/')) => {
+                        depth -= 1;
+                        index += 2;
+                        if depth == 0 {
+                            return Some((index, true));
+                        }
+                    }
+                    _ => {
+                        index += 1;
+                    }
+                }
+            }
+            return Some((chars.len(), false));
+        }
+        _ => {
+            return None;
+        }
+    }
+}
+
+// The text of a /// doc comment, //// is an ordinary comment like it is in Rust
+pub fn is_doc_comment(my_str: &str) -> Option<String> {
+    let text = my_str.strip_prefix("///")?;
+    if text.starts_with('/') {
+        return None;
+    }
+    return Some(text.strip_prefix(' ').unwrap_or(text).to_string());
+}
+
+fn closing_bracket(token: &Option<Token>) -> Option<Token> {
+    match token {
+        Some(Token::LParen) => Some(Token::RParen),
+        Some(Token::LBracket) => Some(Token::RBracket),
+        Some(Token::LCurly) => Some(Token::RCurly),
+        _ => None
+    }
+}
+
+fn same_token(a: &Option<Token>, b: &Token) -> bool {
+    return a.as_ref().is_some_and(|a| std::mem::discriminant(a) == std::mem::discriminant(b));
+}
+
+impl CstNode {
+    fn write(&self, out: &mut String) {
+        match self {
+            CstNode::Token(token) => {
+                token.write(out);
+            }
+            CstNode::Group { open, children, close } => {
+                open.write(out);
+                for child in children.iter() {
+                    child.write(out);
+                }
+                if let Some(close) = close {
+                    close.write(out);
+                }
+            }
+        }
+    }
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a CstToken>) {
+        match self {
+            CstNode::Token(token) => {
+                tokens.push(token);
+            }
+            CstNode::Group { open, children, close } => {
+                tokens.push(open);
+                for child in children.iter() {
+                    child.collect_tokens(tokens);
+                }
+                if let Some(close) = close {
+                    tokens.push(close);
+                }
+            }
+        }
+    }
+}
+
+impl CstToken {
+    fn write(&self, out: &mut String) {
+        for trivia in self.leading.iter() {
+            *out += &trivia.text;
+        }
+        *out += &self.text;
+    }
+}
+
+impl Cst {
+    pub fn parse(source: &str) -> Cst {
+        // Each level is an open bracket and the nodes after it, the first level is the whole file
+        let mut levels: Vec<(Option<CstToken>, Vec<CstNode>)> = vec!((None, vec!()));
+        let mut leading: Vec<Trivia> = vec!();
+        for (piece, pos) in split_words(source) {
+            let text = match piece {
+                Piece::Trivia(kind, text) => {
+                    leading.push(Trivia { kind, text, pos });
+                    continue;
+                }
+                Piece::Word(text) => text
+            };
+            let token = if text.starts_with("/*") { None } else { classify_word(&text) };
+            let cst_token = CstToken { leading: std::mem::take(&mut leading), text, token, pos };
+
+            let closes_level = match &levels.last().unwrap().0 {
+                Some(open) => closing_bracket(&open.token).is_some_and(|close| same_token(&cst_token.token, &close)),
+                None => false
+            };
+            if closes_level {
+                let (open, children) = levels.pop().unwrap();
+                let group = CstNode::Group { open: open.unwrap(), children, close: Some(cst_token) };
+                levels.last_mut().unwrap().1.push(group);
+            }
+            else if closing_bracket(&cst_token.token).is_some() {
+                levels.push((Some(cst_token), vec!()));
+            }
+            else {
+                levels.last_mut().unwrap().1.push(CstNode::Token(cst_token));
+            }
+        }
+        // Brackets that are never closed still make a group
+        while levels.len() > 1 {
+            let (open, children) = levels.pop().unwrap();
+            let group = CstNode::Group { open: open.unwrap(), children, close: None };
+            levels.last_mut().unwrap().1.push(group);
+        }
+        return Cst { nodes: levels.pop().unwrap().1, trailing: leading };
+    }
+    // The source the tree was made from, exactly
+    pub fn to_string(&self) -> String {
+        let mut out = String::new();
+        for node in self.nodes.iter() {
+            node.write(&mut out);
+        }
+        for trivia in self.trailing.iter() {
+            out += &trivia.text;
+        }
+        return out;
+    }
+    // Every token in the order they are in the source
+    pub fn tokens(&self) -> Vec<&CstToken> {
+        let mut tokens: Vec<&CstToken> = vec!();
+        for node in self.nodes.iter() {
+            node.collect_tokens(&mut tokens);
+        }
+        return tokens;
+    }
+    // Every comment, and whether there was a token before it on its line
+    pub fn comments(&self) -> Vec<(&Trivia, bool)> {
+        let mut comments: Vec<(&Trivia, bool)> = vec!();
+        let mut last_token_line = 0;
+        let tokens = self.tokens();
+        let trivia = tokens.iter().map(|token| (token.leading.as_slice(), Some(token.pos.line))).chain(std::iter::once((self.trailing.as_slice(), None)));
+        for (leading, token_line) in trivia {
+            for trivia in leading.iter().filter(|trivia| trivia.kind != TriviaKind::Whitespace) {
+                comments.push((trivia, trivia.pos.line == last_token_line));
+            }
+            if let Some(token_line) = token_line {
+                last_token_line = token_line;
+            }
+        }
+        return comments;
+    }
+    // The tokens the parser works on, text that is not part of the language is an error
+    pub fn to_token_list(&self) -> TokenList {
+        let mut token_list = TokenList::new();
+        for cst_token in self.tokens() {
+            match &cst_token.token {
+                Some(token) => {
+                    debug_print(&format!("{}: {}", token.to_string(), cst_token.text));
+                    token_list.push(token.clone(), cst_token.pos);
+                }
+                None if cst_token.text.starts_with("/*") => {
+                    token_list.push_error(format!("unterminated block comment starting at {}", cst_token.pos.to_string()), cst_token.pos);
+                }
+                None => {
+                    let err = match (parse_float_literal(&cst_token.text), parse_int_literal(&cst_token.text)) {
+                        // A number that is out of range, or has something in it that does not belong there
+                        (Some(Err(err)), _) | (None, Some(Err(err))) => err,
+                        _ => format!("unexpected '{}'", cst_token.text)
+                    };
+                    token_list.push_error(format!("{} at {}", err, cst_token.pos.to_string()), cst_token.pos);
+                }
+            }
+        }
+        return token_list;
+    }
+}
@@ -0,0 +1,268 @@
+use crate::jit::*;
+use core::marker::PhantomData;
+use core::mem;
+
+// The way to use the language from a Rust program. Scripts are compiled with an Engine, and
+// the functions in them are then called like Rust functions:
+//
+//     extern "C" fn square(x: f64) -> f64 { x * x }
+//
+//     let mut engine = Engine::new();
+//     engine.register_fn("square", square as extern "C" fn(f64) -> f64)?;
+//     let mut program = engine.compile("fn area(r: f64) -> f64 { 3.14 * square(r) }")?;
+//     let area: f64 = program.call("area", (2.0,))?;
+//
+// Nothing is written to stdout, what scripts print is kept in the program, see take_output
+
+// A type that can be passed between Rust and scripts
+pub trait ScriptValue: Copy {
+    fn value_type() -> ValueType;
+}
+
+impl ScriptValue for i32 {
+    fn value_type() -> ValueType {
+        return ValueType::I32;
+    }
+}
+
+impl ScriptValue for f64 {
+    fn value_type() -> ValueType {
+        return ValueType::F64;
+    }
+}
+
+// A function or closure that a script passes to a host function, like the f of
+//
+//     extern "C" fn apply_twice(f: Callable<(i32,), i32>, x: i32) -> i32 { unsafe { f.call((f.call((x,)),)) } }
+//
+// It points to the function value the script passed, which only lives until the host function returns
+#[repr(transparent)]
+pub struct Callable<Args, R> {
+    value: *const [*const u8; 2], // The address of the code, and the env of a closure or null
+    phantom: PhantomData<fn(Args) -> R>
+}
+
+impl<Args, R> Clone for Callable<Args, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Args, R> Copy for Callable<Args, R> {}
+
+impl<Args: ScriptArgs, R: ScriptReturn> ScriptValue for Callable<Args, R> {
+    fn value_type() -> ValueType {
+        return ValueType::Fn(Args::value_types(), R::return_type().map(Box::new));
+    }
+}
+
+impl<Args: ScriptArgs, R: ScriptReturn> Callable<Args, R> {
+    /// # Safety
+    /// Only while the host function that was given the callable is running
+    pub unsafe fn call(&self, args: Args) -> R {
+        let [code_ptr, env] = *self.value;
+        if env.is_null() {
+            return args.call::<R>(code_ptr);
+        }
+        return args.call_with_env::<R>(code_ptr, env);
+    }
+}
+
+// What a function can return, () is a function that does not return anything. Function values
+// are passed in memory, so host functions cannot return them
+pub trait ScriptReturn {
+    fn return_type() -> Option<ValueType>;
+}
+
+impl ScriptReturn for i32 {
+    fn return_type() -> Option<ValueType> {
+        return Some(ValueType::I32);
+    }
+}
+
+impl ScriptReturn for f64 {
+    fn return_type() -> Option<ValueType> {
+        return Some(ValueType::F64);
+    }
+}
+
+impl ScriptReturn for () {
+    fn return_type() -> Option<ValueType> {
+        return None;
+    }
+}
+
+// The arguments of a call into a script, as a tuple
+pub trait ScriptArgs {
+    fn value_types() -> Vec<ValueType>;
+    /// # Safety
+    /// code_ptr has to be a function that takes these arguments and returns R
+    unsafe fn call<R: ScriptReturn>(self, code_ptr: *const u8) -> R;
+    /// # Safety
+    /// code_ptr has to be a closure that takes env and then these arguments, and returns R
+    unsafe fn call_with_env<R: ScriptReturn>(self, code_ptr: *const u8, env: *const u8) -> R;
+}
+
+// A Rust function that scripts can call, it has to use the C calling convention
+pub trait HostFunction {
+    fn signature() -> (Vec<ValueType>, Option<ValueType>);
+    fn addr(self) -> *const u8;
+}
+
+macro_rules! script_functions {
+    ($($arg:ident),*) => {
+        impl<$($arg: ScriptValue),*> ScriptArgs for ($($arg,)*) {
+            fn value_types() -> Vec<ValueType> {
+                return vec!($($arg::value_type()),*);
+            }
+            #[allow(non_snake_case)]
+            unsafe fn call<R: ScriptReturn>(self, code_ptr: *const u8) -> R {
+                let ($($arg,)*) = self;
+                let code_fn = mem::transmute::<*const u8, extern "C" fn($($arg),*) -> R>(code_ptr);
+                return code_fn($($arg),*);
+            }
+            #[allow(non_snake_case)]
+            unsafe fn call_with_env<R: ScriptReturn>(self, code_ptr: *const u8, env: *const u8) -> R {
+                let ($($arg,)*) = self;
+                let code_fn = mem::transmute::<*const u8, extern "C" fn(*const u8, $($arg),*) -> R>(code_ptr);
+                return code_fn(env, $($arg),*);
+            }
+        }
+
+        impl<$($arg: ScriptValue,)* R: ScriptReturn> HostFunction for extern "C" fn($($arg),*) -> R {
+            fn signature() -> (Vec<ValueType>, Option<ValueType>) {
+                return (vec!($($arg::value_type()),*), R::return_type());
+            }
+            fn addr(self) -> *const u8 {
+                return self as *const u8;
+            }
+        }
+    };
+}
+
+script_functions!();
+script_functions!(A);
+script_functions!(A, B);
+script_functions!(A, B, C);
+script_functions!(A, B, C, D);
+
+// An error the compiler found in a script, and where it is if the compiler said so
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub pos: Option<SourcePos>
+}
+
+// Everything that stopped a script from compiling. The compiler stops at the first error it
+// finds, so for now there is only ever one
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>
+}
+
+impl Diagnostics {
+    fn from_error(err: CompileError) -> Diagnostics {
+        return Diagnostics { errors: vec!(Diagnostic { message: err.message, pos: err.pos }) };
+    }
+    pub fn to_string(&self) -> String {
+        let messages: Vec<String> = self.errors.iter().map(|err| err.message.clone()).collect();
+        return messages.join("\n");
+    }
+}
+
+struct RegisteredFunction {
+    name: String,
+    params: Vec<ValueType>,
+    return_type: Option<ValueType>,
+    addr: *const u8
+}
+
+pub struct Engine {
+    host_functions: Vec<RegisteredFunction>,
+    overflow_checks: bool
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        return Engine { host_functions: vec!(), overflow_checks: false };
+    }
+
+    // Let the scripts compiled after this call f by name. The built in functions like print
+    // cannot be replaced, scripts would still call the built in one
+    pub fn register_fn<F: HostFunction>(&mut self, name: &str, f: F) -> Result<(), String> {
+        if is_builtin_function(name) {
+            return Err(format!("function {} is built in and cannot be registered", name));
+        }
+        let (params, return_type) = F::signature();
+        self.host_functions.retain(|function| function.name != name);
+        self.host_functions.push(RegisteredFunction { name: name.to_string(), params, return_type, addr: f.addr() });
+        return Ok(());
+    }
+
+    // Make i32 arithmetic that overflows a runtime error in the scripts compiled after this call,
+    // see JIT::set_overflow_checks
+    pub fn set_overflow_checks(&mut self, overflow_checks: bool) {
+        self.overflow_checks = overflow_checks;
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Program, Diagnostics> {
+        let mut jit = JIT::default();
+        jit.set_overflow_checks(self.overflow_checks);
+        for function in self.host_functions.iter() {
+            jit.register_host_function(&function.name, function.params.clone(), function.return_type.clone(), function.addr);
+        }
+        // Const fns run while compiling, what they print is thrown away with the debug output
+        let (result, _) = capture_output(|| without_debug_output(|| {
+            let mut token_list = tokenize(source.to_string());
+            let tree = generate_tree(&mut token_list)?;
+            return jit.update(&tree);
+        }));
+        let main = result.map_err(Diagnostics::from_error)?;
+        return Ok(Program { jit, main, output: String::new() });
+    }
+}
+
+// A compiled script. Closures that outlive the function that made them have their env on the
+// heap, and it is only freed once the call or run that made it returns, so a single call that
+// makes such closures in a long loop holds on to the memory of every one of them until then
+pub struct Program {
+    jit: JIT,
+    main: cranelift_module::FuncId,
+    output: String
+}
+
+impl Program {
+    // Call a function of the script, the argument and return types have to match how it is declared
+    pub fn call<Args: ScriptArgs, R: ScriptReturn>(&mut self, name: &str, args: Args) -> Result<R, String> {
+        let (code_ptr, params, return_type) = match self.jit.get_function(name) {
+            Some(function) => function,
+            None => {
+                return Err(format!("the program has no function {}", name));
+            }
+        };
+        if params != Args::value_types().as_slice() || return_type != R::return_type().as_ref() {
+            let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+            let return_type = return_type.map_or("nothing".to_string(), |return_type| return_type.to_string());
+            return Err(format!("function {} takes ({}) and returns {}, it was called with other types", name, params.join(", "), return_type));
+        }
+        let (result, output) = capture_output(|| {
+            // The types were checked against the signature of the function above
+            let result = release_heap_after(|| unsafe { args.call::<R>(code_ptr) });
+            return take_runtime_error().map_or(Ok(result), |err| Err(err.to_string()));
+        });
+        self.output += &output;
+        return result;
+    }
+
+    // Run the statements of the script that are outside of any function, returns what the program returns
+    pub fn run(&mut self) -> Result<i32, String> {
+        let (result, output) = capture_output(|| execute_program(self.main, &self.jit));
+        self.output += &output;
+        return result.map_err(|err| err.to_string());
+    }
+
+    // What the script has printed since the last time this was called
+    pub fn take_output(&mut self) -> String {
+        return mem::take(&mut self.output);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::jit::*;
+use crate::operators::*;
+use crate::interpreter::{run_differential, with_interpreter_stack};
+use crate::cst::Cst;
+use std::cell::RefCell;
+use std::panic;
+
+// A small random number generator (splitmix64), so that every fuzz case can be repeated from
+// its seed without any outside dependency
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        return Rng { state: seed };
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+    // A number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        return (self.next_u64() % n as u64) as usize;
+    }
+    // True one time out of n
+    pub fn one_in(&mut self, n: usize) -> bool {
+        return self.below(n) == 0;
+    }
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        return &items[self.below(items.len())];
+    }
+}
+
+// The parts of the compiler that can be fuzzed, each one is run on the source text it is given
+#[derive(Clone, Copy, PartialEq)]
+pub enum FuzzTarget {
+    Tokenize,
+    GenerateTree,
+    Pipeline, // Tokenize, parse and compile, but not run since the input might never finish
+    Differential // Run a generated program with both the JIT and the interpreter
+}
+
+impl FuzzTarget {
+    pub fn to_string(&self) -> String {
+        match self {
+            FuzzTarget::Tokenize => {
+                return "tokenize".to_string();
+            }
+            FuzzTarget::GenerateTree => {
+                return "generate_tree".to_string();
+            }
+            FuzzTarget::Pipeline => {
+                return "pipeline".to_string();
+            }
+            FuzzTarget::Differential => {
+                return "differential".to_string();
+            }
+        }
+    }
+    pub fn all() -> Vec<FuzzTarget> {
+        return vec!(FuzzTarget::Tokenize, FuzzTarget::GenerateTree, FuzzTarget::Pipeline, FuzzTarget::Differential);
+    }
+}
+
+// A fuzz case that crashed the compiler, or that the JIT and the interpreter did not agree on
+pub struct FuzzFailure {
+    pub target: FuzzTarget,
+    pub seed: u64,
+    pub message: String,
+    pub input: String
+}
+
+impl FuzzFailure {
+    pub fn to_string(&self) -> String {
+        return format!("fuzz target {} failed with seed {}: {}\n-- input:\n{}", self.target.to_string(), self.seed, self.message, self.input);
+    }
+}
+
+thread_local! {
+    // The message of the last panic, the panic hook stores it here instead of printing it
+    static PANIC_MESSAGE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Run a fuzz target on one input, returns the panic message if it panicked, or the error if
+// the target does not allow any
+pub fn run_target(target: FuzzTarget, input: &str) -> Result<(), String> {
+    let input = input.to_string();
+    let result = panic::catch_unwind(move || {
+        match target {
+            FuzzTarget::Tokenize => {
+                if Cst::parse(&input).to_string() != input {
+                    return Err("the concrete syntax tree does not give back the source".to_string());
+                }
+                tokenize(input);
+            }
+            FuzzTarget::GenerateTree => {
+                let _ = generate_tree(&mut tokenize(input));
+            }
+            FuzzTarget::Pipeline => {
+                if let Ok(tree) = generate_tree(&mut tokenize(input)) {
+                    let _ = cranelift_treverse_tree(&tree);
+                }
+            }
+            FuzzTarget::Differential => {
+                // Generated programs are well typed, so any error is a bug
+                return run_differential(input).map(|_| ());
+            }
+        }
+        return Ok(());
+    });
+    match result {
+        Ok(result) => {
+            return result;
+        }
+        Err(_) => {
+            let message = PANIC_MESSAGE.with(|message| message.borrow_mut().take());
+            return Err(format!("panicked: {}", message.unwrap_or_default()));
+        }
+    }
+}
+
+// The operators from the table that work on a number type, every infix one also has a compound assignment
+fn operators_for(value_type: &ValueType, arity: Arity) -> Vec<&'static str> {
+    return OPERATORS.iter()
+        .filter(|op| op.arity == arity && (*value_type == ValueType::I32 || matches!(op.operands, Operands::Numbers(_))))
+        .map(|op| op.symbol)
+        .collect();
+}
+
+// The most times a generated function calls itself, it is counted down by its first parameter
+const RECURSION_LIMIT: usize = 300;
+
+// A function the generator has written, that the code after it can call
+#[derive(Clone)]
+struct GeneratedFunction {
+    name: String,
+    params: Vec<ValueType>, // Without the count of a recursive function
+    return_type: ValueType,
+    is_const: bool,
+    is_recursive: bool, // The first parameter is an extra i32 that counts down to 0
+    recurses: bool // It calls itself, or calls a function that recurses
+}
+
+// Generates programs that compile, so that the JIT and the interpreter can be compared on them.
+// Only features that both of them support are used. A function only calls itself with a count
+// that goes down to 0, and the functions that recurse do not call other functions that recurse,
+// so every program finishes soon enough
+pub struct ProgramGenerator<'a> {
+    rng: &'a mut Rng,
+    source: String,
+    variables: Vec<(String, ValueType)>,
+    functions: Vec<GeneratedFunction>,
+    in_const_fn: bool,
+    in_recursive_fn: bool,
+    calls_recursion: bool, // Whether the function being generated calls a function that recurses
+    name_count: usize
+}
+
+impl<'a> ProgramGenerator<'a> {
+    pub fn new(rng: &'a mut Rng) -> ProgramGenerator<'a> {
+        return ProgramGenerator {
+            rng, source: String::new(), variables: vec!(), functions: vec!(), in_const_fn: false, in_recursive_fn: false, calls_recursion: false, name_count: 0
+        };
+    }
+    // Identifiers cannot contain every digit, so names are made of letters only
+    fn new_name(&mut self, prefix: &str) -> String {
+        let mut name = prefix.to_string();
+        let mut count = self.name_count;
+        self.name_count += 1;
+        loop {
+            name.push((b'a' + (count % 26) as u8) as char);
+            count /= 26;
+            if count == 0 {
+                break;
+            }
+        }
+        // Names like "fn" would be read as a keyword
+        if matches!(name.as_str(), "fn" | "if") {
+            return self.new_name(prefix);
+        }
+        return name;
+    }
+    fn gen_type(&mut self) -> ValueType {
+        match self.rng.below(6) {
+            0 => {
+                return ValueType::F64;
+            }
+            // Now and then an array that makes a stack frame bigger than a page
+            1 if self.rng.one_in(8) => {
+                return ValueType::Array(Box::new(ValueType::I32), 1000 + self.rng.below(1000));
+            }
+            1 => {
+                return ValueType::Array(Box::new(ValueType::I32), 1 + self.rng.below(4));
+            }
+            2 => {
+                return ValueType::Struct("pair".to_string());
+            }
+            3 => {
+                return ValueType::Enum("opt".to_string());
+            }
+            _ => {
+                return ValueType::I32;
+            }
+        }
+    }
+    fn gen_i32_literal(&mut self) -> String {
+        match self.rng.below(8) {
+            0 => {
+                return format!("{}", i32::MAX - self.rng.below(3) as i32);
+            }
+            1 => {
+                return format!("(0 - {})", self.rng.below(1000));
+            }
+            2 => {
+                // The same kind of number written in another way
+                let value = self.rng.below(100_000);
+                let literals = [format!("{:#x}", value), format!("{:#o}", value), format!("{:#b}", value), format!("{}_{:03}", value / 1000, value % 1000)];
+                return self.rng.pick(&literals).clone();
+            }
+            3 => {
+                let chars = ['a', 'Z', '0', ' ', ';', '(', '\\', '\'', 'é'];
+                return format!("{:?}", self.rng.pick(&chars));
+            }
+            _ => {
+                return format!("{}", self.rng.below(100));
+            }
+        }
+    }
+    fn gen_f64_literal(&mut self) -> String {
+        return format!("{}.{}", self.rng.below(100), self.rng.below(100));
+    }
+    // An expression of the given type, depth limits how deep it nests
+    fn gen_expr(&mut self, value_type: &ValueType, depth: usize) -> String {
+        let candidates: Vec<String> = self.variables.iter()
+            .filter(|(_, var_type)| var_type == value_type)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !candidates.is_empty() && self.rng.one_in(3) {
+            return self.rng.pick(&candidates).clone();
+        }
+        match value_type {
+            ValueType::I32 | ValueType::F64 => {
+                return self.gen_number_expr(value_type, depth);
+            }
+            ValueType::Array(elem_type, len) => {
+                if *len > 8 || self.rng.one_in(2) {
+                    let value = self.gen_expr(elem_type, depth + 1);
+                    return format!("[{}; {}]", value, len);
+                }
+                let elements: Vec<String> = (0..*len).map(|_| self.gen_expr(elem_type, depth + 1)).collect();
+                return format!("[{}]", elements.join(", "));
+            }
+            ValueType::Struct(_) => {
+                let a = self.gen_expr(&ValueType::I32, depth + 1);
+                let b = self.gen_expr(&ValueType::F64, depth + 1);
+                return format!("pair {{ a: {}, b: {} }}", a, b);
+            }
+            _ => {
+                if self.rng.one_in(3) {
+                    return "opt::none".to_string();
+                }
+                let value = self.gen_expr(&ValueType::I32, depth + 1);
+                return format!("opt::some({})", value);
+            }
+        }
+    }
+    fn gen_number_expr(&mut self, value_type: &ValueType, depth: usize) -> String {
+        let choice = if depth > 3 { 0 } else { self.rng.below(6) };
+        match choice {
+            1 | 2 => {
+                let operator = *self.rng.pick(&operators_for(value_type, Arity::Infix));
+                let lhs = self.gen_number_expr(value_type, depth + 1);
+                let rhs = self.gen_number_expr(value_type, depth + 1);
+                // Dividing by zero is a compile error when a const fn does it, | 1 keeps the divisor from being 0
+                let rhs = if (operator == "/" || operator == "%") && *value_type == ValueType::I32 { format!("({} | 1)", rhs) } else { rhs };
+                let overflow = find_operator(operator).and_then(|op| op.overflow.as_ref());
+                if let (Some(overflow), ValueType::I32) = (overflow, value_type) {
+                    if self.rng.one_in(4) {
+                        let mode = if self.rng.one_in(2) { "wrapping" } else { "saturating" };
+                        return format!("{}_{}({}, {})", mode, overflow.name, lhs, rhs);
+                    }
+                }
+                if self.rng.one_in(2) {
+                    return format!("({} {} {})", lhs, operator, rhs);
+                }
+                let prefix_operators = operators_for(value_type, Arity::Prefix);
+                if !prefix_operators.is_empty() && self.rng.one_in(4) {
+                    return format!("{}({} {} {})", self.rng.pick(&prefix_operators), lhs, operator, rhs);
+                }
+                return format!("{} {} {}", lhs, operator, rhs);
+            }
+            3 => {
+                // A field of a struct, or an element of an array that might be out of bounds
+                let places: Vec<String> = self.variables.iter()
+                    .filter_map(|(name, var_type)| match (var_type, value_type) {
+                        (ValueType::Struct(_), ValueType::I32) => Some(format!("{}.a", name)),
+                        (ValueType::Struct(_), ValueType::F64) => Some(format!("{}.b", name)),
+                        // An index out of bounds in a const fn is a compile error, so leave them out there
+                        (ValueType::Array(_, len), ValueType::I32) if !self.in_const_fn => Some(format!("{}[{}]", name, len + 1)),
+                        _ => None
+                    })
+                    .collect();
+                if places.is_empty() {
+                    return self.gen_number_expr(value_type, depth + 1);
+                }
+                let place = self.rng.pick(&places).clone();
+                if let Some(array) = place.strip_suffix(']') {
+                    // The number after [ is one more than the last index, so it is sometimes out of bounds
+                    let (array, len) = array.split_once('[').unwrap();
+                    let index = self.rng.below(len.parse::<usize>().unwrap());
+                    return format!("{}[{}]", array, index);
+                }
+                return place;
+            }
+            4 => {
+                let functions: Vec<GeneratedFunction> = self.functions.iter()
+                    .filter(|function| function.return_type == *value_type && (function.is_const || !self.in_const_fn) && !(function.recurses && self.in_recursive_fn))
+                    .cloned()
+                    .collect();
+                if functions.is_empty() {
+                    return self.gen_number_expr(value_type, depth + 1);
+                }
+                let function = self.rng.pick(&functions).clone();
+                self.calls_recursion |= function.recurses;
+                let mut args: Vec<String> = function.params.iter().map(|param| self.gen_expr(param, depth + 1)).collect();
+                if function.is_recursive {
+                    args.insert(0, self.rng.below(RECURSION_LIMIT).to_string());
+                }
+                let name = function.name;
+                // Sometimes through a function pointer, taken out of an array or out of parentheses
+                match self.rng.below(4) {
+                    0 => {
+                        return format!("[{}][0]({})", name, args.join(", "));
+                    }
+                    1 => {
+                        return format!("({})({})", name, args.join(", "));
+                    }
+                    _ => {
+                        return format!("{}({})", name, args.join(", "));
+                    }
+                }
+            }
+            _ => {
+                let candidates: Vec<String> = self.variables.iter()
+                    .filter(|(_, var_type)| var_type == value_type)
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                if !candidates.is_empty() && self.rng.one_in(2) {
+                    return self.rng.pick(&candidates).clone();
+                }
+                if *value_type == ValueType::F64 {
+                    return self.gen_f64_literal();
+                }
+                return self.gen_i32_literal();
+            }
+        }
+    }
+    fn gen_statement(&mut self, indent: &str) -> String {
+        let numbers: Vec<(String, ValueType)> = self.variables.iter()
+            .filter(|(_, var_type)| *var_type == ValueType::I32 || *var_type == ValueType::F64)
+            .cloned()
+            .collect();
+        match self.rng.below(6) {
+            0 if !numbers.is_empty() => {
+                let (name, var_type) = self.rng.pick(&numbers).clone();
+                let operators = operators_for(&var_type, Arity::Infix);
+                let operator = if self.rng.one_in(3) { "=".to_string() } else { format!("{}=", self.rng.pick(&operators)) };
+                let value = self.gen_expr(&var_type, 0);
+                return format!("{}{} {} {};\n", indent, name, operator, value);
+            }
+            1 if !numbers.is_empty() => {
+                let (_, var_type) = self.rng.pick(&numbers).clone();
+                let value = self.gen_expr(&var_type, 0);
+                return format!("{}print({});\n", indent, value);
+            }
+            2 => {
+                let enums: Vec<String> = self.variables.iter()
+                    .filter(|(_, var_type)| *var_type == ValueType::Enum("opt".to_string()))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                if enums.is_empty() {
+                    return self.gen_let(indent);
+                }
+                let name = self.rng.pick(&enums).clone();
+                let binding = self.new_name("m");
+                self.variables.push((binding.clone(), ValueType::I32));
+                let some_value = self.gen_expr(&ValueType::I32, 1);
+                self.variables.pop();
+                let none_value = self.gen_expr(&ValueType::I32, 1);
+                return format!("{}match {} {{\n{}    opt::some({}) => {{ print({}); }}\n{}    _ => {{ print({}); }}\n{}}}\n", indent, name, indent, binding, some_value, indent, none_value, indent);
+            }
+            _ => {
+                return self.gen_let(indent);
+            }
+        }
+    }
+    fn gen_let(&mut self, indent: &str) -> String {
+        let value_type = self.gen_type();
+        let value = self.gen_expr(&value_type, 0);
+        let name = self.new_name("v");
+        self.variables.push((name.clone(), value_type));
+        return format!("{}let {} = {};\n", indent, name, value);
+    }
+    fn gen_function(&mut self) {
+        let name = self.new_name("f");
+        let is_const = self.rng.one_in(4);
+        let is_recursive = self.rng.one_in(3);
+        let params: Vec<ValueType> = (0..self.rng.below(3)).map(|_| self.gen_type()).collect();
+        let return_type = if self.rng.one_in(3) { ValueType::F64 } else { ValueType::I32 };
+
+        let outer_variables = std::mem::take(&mut self.variables);
+        self.in_const_fn = is_const;
+        self.in_recursive_fn = is_recursive;
+        self.calls_recursion = false;
+        let mut param_list: Vec<String> = vec!();
+        // The count is not one of the variables, so nothing else changes it
+        let count = self.new_name("n");
+        if is_recursive {
+            param_list.push(format!("{}: i32", count));
+        }
+        for param in params.iter() {
+            let param_name = self.new_name("p");
+            param_list.push(format!("{}: {}", param_name, param.to_string()));
+            self.variables.push((param_name, param.clone()));
+        }
+        let mut body = String::new();
+        for _ in 0..self.rng.below(4) {
+            // A const fn cannot print, so it only gets lets
+            let statement = if is_const { self.gen_let("    ") } else { self.gen_statement("    ") };
+            body += &statement;
+        }
+        if is_recursive {
+            let args: Vec<String> = params.iter().map(|param| self.gen_expr(param, 1)).collect();
+            let call = format!("{}({})", name, std::iter::once(format!("{} - 1", count)).chain(args).collect::<Vec<String>>().join(", "));
+            let operator = *self.rng.pick(&operators_for(&return_type, Arity::Infix));
+            let operator = if operator == "/" || operator == "%" { "+" } else { operator };
+            let value = self.gen_expr(&return_type, 2);
+            body += &format!("    if {} {{\n        return {} {} {};\n    }}\n", count, call, operator, value);
+        }
+        let value = self.gen_expr(&return_type, 0);
+        body += &format!("    {}\n", value);
+        self.variables = outer_variables;
+        self.in_const_fn = false;
+        self.in_recursive_fn = false;
+
+        let keyword = if is_const { "const fn" } else { "fn" };
+        self.source += &format!("{} {}({}) -> {} {{\n{}}}\n", keyword, name, param_list.join(", "), return_type.to_string(), body);
+        let recurses = is_recursive || self.calls_recursion;
+        self.functions.push(GeneratedFunction { name, params, return_type, is_const, is_recursive, recurses });
+    }
+    pub fn generate(mut self) -> String {
+        self.source += "struct pair { a: i32, b: f64 }\n";
+        self.source += "enum opt { some(i32), none }\n";
+        for _ in 0..self.rng.below(4) {
+            self.gen_function();
+        }
+        for _ in 0..1 + self.rng.below(10) {
+            let statement = self.gen_statement("");
+            self.source += &statement;
+        }
+        let result = self.gen_expr(&ValueType::I32, 0);
+        self.source += &format!("return {};\n", result);
+        return self.source;
+    }
+}
+
+// Change a few characters of a program, so that the input is mostly valid but broken somewhere
+fn mutate(rng: &mut Rng, source: &str) -> String {
+    const PIECES: &[&str] = &[
+        "(", ")", "[", "]", "{", "}", ",", ";", ":", "::", "=>", ".", "=", "+=", "-=", "+", "-", "*", "/",
+        "&", "&mut ", "let ", "fn ", "const ", "struct ", "enum ", "match ", "_", "x", "0", "-", "99999999999", "1.5", " ", "\n", "é", "\t"
+    ];
+    let mut chars: Vec<char> = source.chars().collect();
+    for _ in 0..1 + rng.below(4) {
+        let at = rng.below(chars.len() + 1);
+        match rng.below(3) {
+            0 if at < chars.len() => {
+                let end = (at + 1 + rng.below(8)).min(chars.len());
+                chars.drain(at..end);
+            }
+            1 => {
+                let piece: Vec<char> = rng.pick(PIECES).chars().collect();
+                chars.splice(at..at, piece);
+            }
+            _ => {
+                let piece: Vec<char> = (0..1 + rng.below(4)).map(|_| (32 + rng.below(95) as u8) as char).collect();
+                chars.splice(at..at, piece);
+            }
+        }
+    }
+    return chars.into_iter().collect();
+}
+
+// Make the input for one fuzz case from its seed
+pub fn generate_input(target: FuzzTarget, seed: u64) -> String {
+    let mut rng = Rng::new(seed);
+    let program = ProgramGenerator::new(&mut rng).generate();
+    if target == FuzzTarget::Differential {
+        return program;
+    }
+    return mutate(&mut rng, &program);
+}
+
+// Run iterations cases on every fuzz target, starting from seed. Returns the failures found,
+// each of them can be run again on its own with its seed
+pub fn fuzz(iterations: u64, seed: u64) -> Vec<FuzzFailure> {
+    // Generated programs recurse with large arrays in their stack frames, so the JIT needs as
+    // big a stack as the interpreter does
+    return with_interpreter_stack(|| fuzz_cases(iterations, seed));
+}
+
+fn fuzz_cases(iterations: u64, seed: u64) -> Vec<FuzzFailure> {
+    set_debug_output(false);
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(|info| {
+        PANIC_MESSAGE.with(|message| *message.borrow_mut() = Some(info.to_string()));
+    }));
+
+    let mut failures: Vec<FuzzFailure> = vec!();
+    for iteration in 0..iterations {
+        let case_seed = seed.wrapping_add(iteration);
+        for target in FuzzTarget::all() {
+            let input = generate_input(target, case_seed);
+            let result = capture_output(|| run_target(target, &input)).0;
+            if let Err(message) = result {
+                failures.push(FuzzFailure { target, seed: case_seed, message, input });
+            }
+        }
+    }
+
+    panic::set_hook(previous_hook);
+    set_debug_output(true);
+    return failures;
+}
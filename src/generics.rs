@@ -0,0 +1,394 @@
+use crate::jit::*;
+use std::collections::HashMap;
+
+// Generic functions and structs are never compiled themselves. For every list of type arguments
+// a generic is used with, a copy of it is made with the type parameters replaced by those types,
+// and the copy is compiled like any other function or struct. A copy is named after the type
+// arguments, like max<i32> or pair<f64>, so the names are the same every time the same
+// program is compiled
+
+// Type arguments nested deeper than this most likely come from a generic that uses itself with
+// bigger and bigger types, which would never stop making copies
+pub const INSTANCE_DEPTH_LIMIT: usize = 16;
+
+// The generic functions and structs of a program, by name
+#[derive(Default)]
+pub struct Generics {
+    pub functions: HashMap<String, Function>,
+    pub structs: HashMap<String, StructDecl>
+}
+
+// A generic function or struct together with the type arguments of one copy of it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instance {
+    Function(String, Vec<ValueType>),
+    Struct(String, Vec<ValueType>)
+}
+
+impl Instance {
+    pub fn to_string(&self) -> String {
+        match self {
+            Instance::Function(name, type_args) | Instance::Struct(name, type_args) => {
+                return ValueType::Generic(name.clone(), type_args.clone()).to_string();
+            }
+        }
+    }
+}
+
+// Replace the type parameters in value_type by the type arguments in the same place
+pub fn substitute_type(value_type: &ValueType, type_params: &[String], type_args: &[ValueType]) -> ValueType {
+    match value_type {
+        ValueType::Struct(name) | ValueType::Enum(name) => {
+            match type_params.iter().position(|type_param| type_param == name) {
+                Some(index) => {
+                    return type_args[index].clone();
+                }
+                None => {
+                    return value_type.clone();
+                }
+            }
+        }
+        ValueType::Array(elem_type, len) => {
+            return ValueType::Array(Box::new(substitute_type(elem_type, type_params, type_args)), *len);
+        }
+        ValueType::Ref(inner_type, mutable) => {
+            return ValueType::Ref(Box::new(substitute_type(inner_type, type_params, type_args)), *mutable);
+        }
+        ValueType::Fn(params, return_type) => {
+            let params: Vec<ValueType> = params.iter().map(|param| substitute_type(param, type_params, type_args)).collect();
+            let return_type = return_type.as_ref().map(|return_type| Box::new(substitute_type(return_type, type_params, type_args)));
+            return ValueType::Fn(params, return_type);
+        }
+        ValueType::Generic(name, inner_args) => {
+            let inner_args: Vec<ValueType> = inner_args.iter().map(|inner_arg| substitute_type(inner_arg, type_params, type_args)).collect();
+            return ValueType::Generic(name.clone(), inner_args);
+        }
+        ValueType::I32 | ValueType::F64 | ValueType::Infer => {
+            return value_type.clone();
+        }
+    }
+}
+
+// The copy of a generic function for these type arguments, every type written down in it has
+// the type parameters replaced
+pub fn instantiate_function(generic: &Function, type_args: &[ValueType]) -> Function {
+    let type_params = &generic.type_params;
+    let mut body = generic.body.clone();
+    for expr in body.iter_mut() {
+        walk_expr_mut(expr, &mut |expr| {
+            match expr {
+                Expr::Let(token) => {
+                    if let Some(value_type) = &token.value_type {
+                        token.value_type = Some(substitute_type(value_type, type_params, type_args));
+                    }
+                }
+                Expr::Closure(token) => {
+                    for (_, value_type) in token.params.iter_mut() {
+                        *value_type = substitute_type(value_type, type_params, type_args);
+                    }
+                    if let Some(return_type) = &token.return_type {
+                        token.return_type = Some(substitute_type(return_type, type_params, type_args));
+                    }
+                }
+                Expr::Call(token) => {
+                    for type_arg in token.type_args.iter_mut() {
+                        *type_arg = substitute_type(type_arg, type_params, type_args);
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
+    return Function {
+        name: ValueType::Generic(generic.name.clone(), type_args.to_vec()).to_string(),
+        type_params: vec!(),
+        is_const: generic.is_const,
+        params: generic.params.iter().map(|(name, value_type)| (name.clone(), substitute_type(value_type, type_params, type_args))).collect(),
+        return_type: generic.return_type.as_ref().map(|return_type| substitute_type(return_type, type_params, type_args)),
+        body,
+        doc: generic.doc.clone(),
+        pos: generic.pos
+    };
+}
+
+// The copy of a generic struct for these type arguments
+pub fn instantiate_struct(generic: &StructDecl, type_args: &[ValueType]) -> StructDecl {
+    return StructDecl {
+        name: ValueType::Generic(generic.name.clone(), type_args.to_vec()).to_string(),
+        type_params: vec!(),
+        fields: generic.fields.iter().map(|(name, value_type)| (name.clone(), substitute_type(value_type, &generic.type_params, type_args))).collect(),
+        doc: generic.doc.clone(),
+        pos: generic.pos
+    };
+}
+
+// The fields of a struct type and their types, in the order they are declared. The interpreter
+// makes instances of generic structs as it goes, those have no layout and are worked out from
+// the generic instead
+pub fn struct_fields(struct_type: &ValueType, types: &TypeTable) -> Vec<(String, ValueType)> {
+    if let Some(layout) = struct_type.struct_name().and_then(|name| types.structs.get(&name)) {
+        return layout.fields.iter().map(|field| (field.name.clone(), field.value_type.clone())).collect();
+    }
+    match struct_type {
+        ValueType::Generic(name, type_args) => {
+            return instantiate_struct(&types.generics.structs[name], type_args).fields;
+        }
+        _ => {
+            return vec!();
+        }
+    }
+}
+
+// Work out the type arguments from the types of the values given for param_types, like the
+// arguments of a call. A value whose type is not known yet is None. A type parameter that no
+// value says anything about stays None
+pub fn infer_type_args(type_params: &[String], param_types: &[ValueType], arg_types: &[Option<ValueType>]) -> Vec<Option<ValueType>> {
+    let mut type_args: Vec<Option<ValueType>> = vec![None; type_params.len()];
+    for (param_type, arg_type) in param_types.iter().zip(arg_types.iter()) {
+        if let Some(arg_type) = arg_type {
+            unify(param_type, arg_type, type_params, &mut type_args);
+        }
+    }
+    return type_args;
+}
+
+// Bind the type parameters in param_type to the parts of arg_type in the same place. The first
+// binding wins, a value that does not fit it is reported when the arguments are checked
+fn unify(param_type: &ValueType, arg_type: &ValueType, type_params: &[String], type_args: &mut Vec<Option<ValueType>>) {
+    match (param_type, arg_type) {
+        (ValueType::Struct(name) | ValueType::Enum(name), _) if type_params.contains(name) => {
+            let index = type_params.iter().position(|type_param| type_param == name).unwrap();
+            if type_args[index].is_none() && !contains_infer(arg_type) {
+                type_args[index] = Some(arg_type.clone());
+            }
+        }
+        (ValueType::Array(param_elem, _), ValueType::Array(arg_elem, _)) | (ValueType::Ref(param_elem, _), ValueType::Ref(arg_elem, _)) => {
+            unify(param_elem, arg_elem, type_params, type_args);
+        }
+        (ValueType::Fn(param_params, param_return), ValueType::Fn(arg_params, arg_return)) => {
+            for (param, arg) in param_params.iter().zip(arg_params.iter()) {
+                unify(param, arg, type_params, type_args);
+            }
+            if let (Some(param_return), Some(arg_return)) = (param_return, arg_return) {
+                unify(param_return, arg_return, type_params, type_args);
+            }
+        }
+        (ValueType::Generic(param_name, param_args), ValueType::Generic(arg_name, arg_args)) if param_name == arg_name => {
+            for (param, arg) in param_args.iter().zip(arg_args.iter()) {
+                unify(param, arg, type_params, type_args);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Whether a part of value_type is _, the type of a closure parameter that is not known yet
+pub fn contains_infer(value_type: &ValueType) -> bool {
+    match value_type {
+        ValueType::Infer => {
+            return true;
+        }
+        ValueType::Array(inner_type, _) | ValueType::Ref(inner_type, _) => {
+            return contains_infer(inner_type);
+        }
+        ValueType::Fn(params, return_type) => {
+            return params.iter().chain(return_type.as_deref()).any(contains_infer);
+        }
+        ValueType::Generic(_, type_args) => {
+            return type_args.iter().any(contains_infer);
+        }
+        ValueType::I32 | ValueType::F64 | ValueType::Struct(..) | ValueType::Enum(..) => {
+            return false;
+        }
+    }
+}
+
+// How deep the types inside value_type are nested
+fn type_depth(value_type: &ValueType) -> usize {
+    match value_type {
+        ValueType::Array(inner_type, _) | ValueType::Ref(inner_type, _) => {
+            return 1 + type_depth(inner_type);
+        }
+        ValueType::Fn(params, return_type) => {
+            return 1 + params.iter().chain(return_type.as_deref()).map(type_depth).max().unwrap_or(0);
+        }
+        ValueType::Generic(_, type_args) => {
+            return 1 + type_args.iter().map(type_depth).max().unwrap_or(0);
+        }
+        ValueType::I32 | ValueType::F64 | ValueType::Struct(..) | ValueType::Enum(..) | ValueType::Infer => {
+            return 0;
+        }
+    }
+}
+
+// Add every instance of a generic struct that value_type names
+fn collect_type_instances(value_type: &ValueType, pos: SourcePos, found: &mut Vec<(Instance, SourcePos)>) {
+    match value_type {
+        ValueType::Array(inner_type, _) | ValueType::Ref(inner_type, _) => {
+            collect_type_instances(inner_type, pos, found);
+        }
+        ValueType::Fn(params, return_type) => {
+            for value_type in params.iter().chain(return_type.as_deref()) {
+                collect_type_instances(value_type, pos, found);
+            }
+        }
+        ValueType::Generic(name, type_args) => {
+            for type_arg in type_args.iter() {
+                collect_type_instances(type_arg, pos, found);
+            }
+            found.push((Instance::Struct(name.clone(), type_args.clone()), pos));
+        }
+        ValueType::I32 | ValueType::F64 | ValueType::Struct(..) | ValueType::Enum(..) | ValueType::Infer => {}
+    }
+}
+
+// Add every instance that the types written down in expr name, and the calls that give their
+// type arguments like max::<i32>(a, b)
+fn collect_instances(expr: &Expr, found: &mut Vec<(Instance, SourcePos)>) {
+    walk_expr(expr, &mut |expr| {
+        match expr {
+            Expr::Function(token) => {
+                for value_type in token.params.iter().map(|(_, value_type)| value_type).chain(token.return_type.iter()) {
+                    collect_type_instances(value_type, token.pos, found);
+                }
+            }
+            Expr::StructDecl(token) => {
+                for (_, value_type) in token.fields.iter() {
+                    collect_type_instances(value_type, token.pos, found);
+                }
+            }
+            Expr::EnumDecl(token) => {
+                for value_type in token.variants.iter().flat_map(|(_, payload)| payload.iter()) {
+                    collect_type_instances(value_type, token.pos, found);
+                }
+            }
+            Expr::ConstDecl(token) => {
+                collect_type_instances(&token.value_type, token.pos, found);
+            }
+            Expr::Let(token) => {
+                if let Some(value_type) = &token.value_type {
+                    collect_type_instances(value_type, token.pos, found);
+                }
+            }
+            Expr::Closure(token) => {
+                for value_type in token.params.iter().map(|(_, value_type)| value_type).chain(token.return_type.iter()) {
+                    collect_type_instances(value_type, token.pos, found);
+                }
+            }
+            Expr::Call(token) if !token.type_args.is_empty() => {
+                for type_arg in token.type_args.iter() {
+                    collect_type_instances(type_arg, token.pos, found);
+                }
+                found.push((Instance::Function(token.name.clone(), token.type_args.clone()), token.pos));
+            }
+            _ => {}
+        }
+    });
+}
+
+// Take the generic functions and structs out of the program, and add a copy of them for every
+// instance the program uses. The instances that the types written down in the program name are
+// found here, those that are only known once the types of expressions are worked out, like
+// max(a, b), are found while compiling and passed in as instances. A generic that is never used
+// is not checked beyond being parsed
+pub fn instantiate_generics(expr_tree: &[Expr], instances: &[Instance]) -> Result<(Vec<Expr>, Generics), CompileError> {
+    let mut generics = Generics::default();
+    let mut tree: Vec<Expr> = vec!();
+    for expr in expr_tree {
+        match expr {
+            Expr::Function(token) if !token.type_params.is_empty() => {
+                if is_builtin_function(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("function {} is built in and cannot be declared again, at {}", token.name, token.pos.to_string())));
+                }
+                if generics.functions.insert(token.name.clone(), token.clone()).is_some() {
+                    return Err(CompileError::at(token.pos, format!("function {} is declared twice at {}", token.name, token.pos.to_string())));
+                }
+            }
+            Expr::StructDecl(token) if !token.type_params.is_empty() => {
+                if generics.structs.insert(token.name.clone(), token.clone()).is_some() {
+                    return Err(CompileError::at(token.pos, format!("type {} is declared twice at {}", token.name, token.pos.to_string())));
+                }
+            }
+            _ => {
+                tree.push(expr.clone());
+            }
+        }
+    }
+    for expr in tree.iter() {
+        let (name, pos, is_type) = match expr {
+            Expr::Function(token) => (&token.name, token.pos, false),
+            Expr::StructDecl(token) => (&token.name, token.pos, true),
+            Expr::EnumDecl(token) => (&token.name, token.pos, true),
+            _ => {
+                continue;
+            }
+        };
+        if !is_type && generics.functions.contains_key(name) {
+            return Err(CompileError::at(pos, format!("function {} is declared twice at {}", name, pos.to_string())));
+        }
+        if is_type && generics.structs.contains_key(name) {
+            return Err(CompileError::at(pos, format!("type {} is declared twice at {}", name, pos.to_string())));
+        }
+    }
+
+    // Instances are copied in the order they are found, so the program comes out the same
+    // every time
+    let mut found: Vec<(Instance, SourcePos)> = vec!();
+    for expr in tree.iter() {
+        collect_instances(expr, &mut found);
+    }
+    for instance in instances.iter() {
+        let pos = match instance {
+            Instance::Function(name, _) => generics.functions.get(name).map(|generic| generic.pos),
+            Instance::Struct(name, _) => generics.structs.get(name).map(|generic| generic.pos)
+        };
+        found.push((instance.clone(), pos.unwrap_or(SourcePos { line: 1, col: 1 })));
+    }
+    let mut copied: Vec<Instance> = vec!();
+    let mut next = 0;
+    while next < found.len() {
+        let (instance, pos) = found[next].clone();
+        next += 1;
+        if copied.contains(&instance) {
+            continue;
+        }
+        let (name, type_args, type_params, kind) = match &instance {
+            Instance::Function(name, type_args) => {
+                match generics.functions.get(name) {
+                    Some(generic) => (name, type_args, &generic.type_params, "function"),
+                    None => {
+                        return Err(CompileError::at(pos, format!("function {} has no type parameters, but is given {} at {}", name, instance.to_string(), pos.to_string())));
+                    }
+                }
+            }
+            Instance::Struct(name, type_args) => {
+                match generics.structs.get(name) {
+                    Some(generic) => (name, type_args, &generic.type_params, "struct"),
+                    None if tree.iter().any(|expr| matches!(expr, Expr::StructDecl(StructDecl { name: other, .. }) | Expr::EnumDecl(EnumDecl { name: other, .. }) if other == name)) => {
+                        return Err(CompileError::at(pos, format!("type {} has no type parameters, but is used as {} at {}", name, instance.to_string(), pos.to_string())));
+                    }
+                    None => {
+                        return Err(CompileError::at(pos, format!("unknown type {} at {}", instance.to_string(), pos.to_string())));
+                    }
+                }
+            }
+        };
+        if type_params.len() != type_args.len() {
+            return Err(CompileError::at(pos, format!("{} {} takes {} type parameters but {} were given at {}", kind, name, type_params.len(), type_args.len(), pos.to_string())));
+        }
+        if type_args.iter().any(contains_infer) {
+            return Err(CompileError::at(pos, format!("the type arguments of {} have to be written out in full, at {}", instance.to_string(), pos.to_string())));
+        }
+        if type_args.iter().map(type_depth).max().unwrap_or(0) >= INSTANCE_DEPTH_LIMIT {
+            return Err(CompileError::at(pos, format!("{} {} is used with type arguments nested more than {} levels deep at {}, it most likely uses itself with bigger and bigger types", kind, name, INSTANCE_DEPTH_LIMIT, pos.to_string())));
+        }
+        let copy = match &instance {
+            Instance::Function(..) => Expr::Function(instantiate_function(&generics.functions[name], type_args)),
+            Instance::Struct(..) => Expr::StructDecl(instantiate_struct(&generics.structs[name], type_args))
+        };
+        collect_instances(&copy, &mut found);
+        tree.push(copy);
+        copied.push(instance);
+    }
+    return Ok((tree, generics));
+}
@@ -0,0 +1,379 @@
+use crate::jit::*;
+use crate::operators::*;
+
+// The grammar of the language, written as a PEG over the tokens from tokenize. It gives the
+// expression tree that the compiler and the interpreter work on. positions has where in the
+// source each token starts, so the tree can point back at the source
+peg::parser!{ grammar vm_grammar(positions: &[SourcePos]) for [Token] {
+    // Where the token at p starts, or where the last token starts at the end of the tokens
+    rule pos() -> SourcePos = p:position!() { token_pos(positions, p) }
+
+    rule semicolon() = quiet!{[Token::EndExpr]} / expected!("';'")
+    rule comma() = quiet!{[Token::Comma]} / expected!("','")
+    rule colon() = quiet!{[Token::Colon]} / expected!("':'")
+    rule dot() = quiet!{[Token::Dot]}
+    rule arrow() = quiet!{[Token::Arrow]}
+    rule fat_arrow() = quiet!{[Token::FatArrow]} / expected!("'=>'")
+    rule path_sep() = quiet!{[Token::PathSep]} / expected!("'::'")
+    rule langle() = quiet!{[Token::LAngle]}
+    rule rangle() = quiet!{[Token::RAngle]} / expected!("'>'")
+    rule ampersand() = quiet!{symbol("&")}
+    rule lparen() = quiet!{[Token::LParen]} / expected!("'('")
+    rule rparen() = quiet!{[Token::RParen]} / expected!("')'")
+    rule lbracket() = quiet!{[Token::LBracket]} / expected!("'['")
+    rule rbracket() = quiet!{[Token::RBracket]} / expected!("']'")
+    rule lcurly() = quiet!{[Token::LCurly]} / expected!("'{'")
+    rule rcurly() = quiet!{[Token::RCurly]} / expected!("'}'")
+    rule star() = quiet!{symbol("*")}
+    rule assign() = quiet!{[Token::OperatorToken(OperatorToken { op_type: OperatorType::Assign(None) })]} / expected!("'='")
+
+    // An operator from the table in operators.rs, & and * are also used for references
+    rule symbol(symbol: &'static str)
+        = t:$([Token::OperatorToken(_)]) {? match &t[0] {
+            Token::OperatorToken(OperatorToken { op_type: OperatorType::Operator(op) }) if op.symbol == symbol => Ok(()),
+            _ => Err(symbol)
+        } }
+
+    // An infix operator or assignment, the operators are tried quietly so that a missing ; is not
+    // reported as a missing operator
+    rule operator() -> OperatorToken
+        = quiet!{t:$([Token::OperatorToken(_)]) {? match &t[0] {
+            Token::OperatorToken(OperatorToken { op_type: OperatorType::Operator(op) }) if op.arity == Arity::Prefix => Err("operator"),
+            Token::OperatorToken(token) => Ok(token.clone()),
+            _ => Err("operator")
+        } }}
+
+    rule prefix_operator() -> &'static Operator
+        = quiet!{t:$([Token::OperatorToken(_)]) {? match &t[0] {
+            Token::OperatorToken(OperatorToken { op_type: OperatorType::Operator(op) }) if op.arity == Arity::Prefix => Ok(*op),
+            _ => Err("operator")
+        } }}
+
+
+    rule identifier() -> String
+        = t:$(quiet!{[Token::IdentifierToken(_)]} / expected!("identifier")) {? match &t[0] {
+            Token::IdentifierToken(token) => Ok(token.text.clone()),
+            _ => Err("identifier")
+        } }
+
+    // One of the words in KEYWORDS
+    rule keyword(word: &'static str)
+        = quiet!{t:$([Token::Keyword(_)]) {? match &t[0] {
+            Token::Keyword(keyword) if *keyword == word => Ok(()),
+            _ => Err(word)
+        } }}
+    rule any_keyword() = quiet!{[Token::Keyword(_)]}
+
+    // A name with a meaning in some places, like the types i32 and f64, that is not reserved
+    rule builtin_name(word: &'static str)
+        = quiet!{t:$([Token::IdentifierToken(_)]) {? match &t[0] {
+            Token::IdentifierToken(token) if token.text == word => Ok(()),
+            _ => Err(word)
+        } }}
+
+    rule number() -> NumberToken
+        = quiet!{t:$([Token::NumberToken(_)]) {? match &t[0] {
+            Token::NumberToken(token) => Ok(token.clone()),
+            _ => Err("number")
+        } }}
+    rule float() -> f64
+        = quiet!{t:$([Token::FloatToken(_)]) {? match &t[0] {
+            Token::FloatToken(token) => Ok(token.num),
+            _ => Err("number")
+        } }}
+    rule length() -> usize
+        = quiet!{n:number() {? if n.num >= 0 { Ok(n.num as usize) } else { Err("array length") } }}
+        / expected!("array length")
+
+    // <T, U> after the name of a generic declaration
+    rule type_params() -> Vec<String>
+        = langle() names:(identifier() ++ comma()) rangle() { names }
+
+    // ::<i32, f64> after the name of a generic function that is called
+    rule type_args() -> Vec<ValueType>
+        = quiet!{path_sep() langle()} type_args:(value_type() ++ comma()) rangle() { type_args }
+
+    // i32, f64, [i32; 4], point, pair<i32>, &mut point or fn(i32) -> i32. Every name is a struct
+    // type until resolve_enum_types has seen the enums
+    rule value_type() -> ValueType
+        = builtin_name("i32") { ValueType::I32 }
+        / builtin_name("f64") { ValueType::F64 }
+        / ampersand() mutable:mutable() inner:value_type() { ValueType::Ref(Box::new(inner), mutable) }
+        / keyword("fn") lparen() params:(value_type() ** comma()) rparen() return_type:(arrow() t:value_type() { t })? {
+            ValueType::Fn(params, return_type.map(Box::new))
+        }
+        / lbracket() inner:value_type() semicolon() len:length() rbracket() { ValueType::Array(Box::new(inner), len) }
+        / name:identifier() langle() type_args:(value_type() ++ comma()) rangle() {
+            ValueType::Generic(name, type_args)
+        }
+        / name:identifier() { ValueType::Struct(name) }
+        / expected!("type")
+
+    rule mutable() -> bool = keyword("mut") { true } / { false }
+
+    // An expression. A struct literal is only allowed when structs is true, so that the { after
+    // the value of a match starts its arms
+    rule expr(structs: bool) -> Expr
+        = first:operand(structs) rest:(p:pos() op:operator() e:operand(structs) { (p, op, e) })* {
+            group_operations(first, &mut rest.into_iter().peekable(), ASSIGNMENT_PRECEDENCE + 1)
+        }
+
+    // A single operand with the indexing, field access and calls that follow it. &, * and ~ apply
+    // to the whole operand, so &a[i] is the address of a[i]
+    rule operand(structs: bool) -> Expr
+        = p:pos() ampersand() mutable:mutable() e:operand(structs) { Expr::AddressOf(AddressOf { expr: Box::new(e), mutable, pos: p }) }
+        / p:pos() star() e:operand(structs) { Expr::Deref(Deref { expr: Box::new(e), pos: p }) }
+        / p:pos() operator:prefix_operator() e:operand(structs) { Expr::Unary(Unary { operator, expr: Box::new(e), pos: p }) }
+        / p:pos() e:primary(structs) steps:postfix()* {
+            let mut operand = e;
+            for step in steps {
+                operand = match step {
+                    Postfix::Index(index) => Expr::Index(Index { array: Box::new(operand), index: Box::new(index), pos: p }),
+                    Postfix::Field(field) => Expr::Field(Field { expr: Box::new(operand), field, pos: p }),
+                    Postfix::Call(args) => Expr::IndirectCall(IndirectCall { callee: Box::new(operand), args, pos: p })
+                };
+            }
+            operand
+        }
+
+    rule postfix() -> Postfix
+        = quiet!{lbracket()} index:expr(true) rbracket() { Postfix::Index(index) }
+        / dot() field:identifier() { Postfix::Field(field) }
+        / quiet!{lparen()} args:call_args() { Postfix::Call(args) }
+
+    rule primary(structs: bool) -> Expr
+        = p:pos() enum_name:quiet!{identifier()} quiet!{path_sep()} variant:identifier() args:(quiet!{lparen()} a:call_args() { a })? {
+            Expr::EnumLiteral(EnumLiteral { enum_name, variant, args: args.unwrap_or_default(), pos: p })
+        }
+        / p:pos() name:quiet!{identifier()} type_args:type_args()? quiet!{lparen()} args:call_args() {
+            Expr::Call(Call { name, type_args: type_args.unwrap_or_default(), args, pos: p })
+        }
+        / start:quiet!{p:pos() name:identifier() {? if structs { Ok((p, name)) } else { Err("struct literal") } }} quiet!{lcurly()} fields:struct_fields() {
+            Expr::StructLiteral(StructLiteral { name: start.1, fields, pos: start.0 })
+        }
+        / p:pos() name:quiet!{identifier()} { Expr::IdentifierToken(Identifier { text: name, pos: p }) }
+        / token:number() { Expr::NumberToken(token) }
+        / num:float() { Expr::FloatToken(FloatToken { num }) }
+        / quiet!{lbracket()} e:array_literal() { e }
+        / quiet!{lparen()} e:expr(true) rparen() { e }
+        / p:pos() quiet!{symbol("|")} params:(closure_param() ** comma()) (quiet!{symbol("|")} / expected!("'|'")) body:closure_body(structs) {
+            let (return_type, body, is_block) = body;
+            Expr::Closure(Closure { params, return_type, body, is_block, pos: p })
+        }
+        / expected!("expression")
+
+    // A parameter of a closure, its type can be left out
+    rule closure_param() -> (String, ValueType)
+        = name:identifier() value_type:(colon() t:value_type() { t })? { (name, value_type.unwrap_or(ValueType::Infer)) }
+
+    // -> type { body }, { body } or a single expression whose value is returned
+    rule closure_body(structs: bool) -> (Option<ValueType>, Vec<Expr>, bool)
+        = arrow() return_type:value_type() lcurly() body:block() { (Some(return_type), body, true) }
+        / quiet!{lcurly()} body:block() { (None, body, true) }
+        / p:pos() value:expr(structs) {
+            (Some(ValueType::Infer), vec!(Expr::Return(Return { value: Some(Box::new(value)), is_tail: true, pos: p })), false)
+        }
+
+    // The arguments of a call, the opening parenthesis is already matched
+    rule call_args() -> Vec<Expr> = args:(expr(true) ** comma()) rparen() { args }
+
+    // name { field: value, ... }, the opening curly bracket is already matched
+    rule struct_fields() -> Vec<(String, Expr)>
+        = fields:((name:identifier() colon() value:expr(true) { (name, value) }) ** comma()) comma()? rcurly() { fields }
+
+    // [a, b, c] or [value; count], the opening bracket is already matched
+    rule array_literal() -> Expr
+        = rbracket() { Expr::ArrayLiteral(ArrayLiteral { elements: vec!() }) }
+        / first:expr(true) e:(
+            semicolon() count:length() rbracket() { ArrayTail::Repeat(count) }
+            / rest:(comma() e:expr(true) { e })* comma()? rbracket() { ArrayTail::List(rest) }
+        ) {
+            match e {
+                ArrayTail::Repeat(count) => Expr::ArrayRepeat(ArrayRepeat { value: Box::new(first), count }),
+                ArrayTail::List(rest) => Expr::ArrayLiteral(ArrayLiteral { elements: std::iter::once(first).chain(rest).collect() })
+            }
+        }
+
+    // let name: type = value, or let name: type without a value
+    rule let_statement() -> Expr
+        = p:pos() keyword("let") name:identifier() value_type:(colon() t:value_type() { t })? assign() value:expr(true) {
+            Expr::Let(Let { name, value_type, value: Some(Box::new(value)), pos: p })
+        }
+        / p:pos() keyword("let") name:identifier() colon() value_type:value_type() &statement_end() {
+            Expr::Let(Let { name, value_type: Some(value_type), value: None, pos: p })
+        }
+
+    // const name: type = value
+    rule const_decl() -> Expr
+        = p:pos() keyword("const") name:identifier() colon() value_type:value_type() assign() value:expr(true) {
+            Expr::ConstDecl(ConstDecl { name, value_type, value: Box::new(value), doc: None, pos: p })
+        }
+
+    // struct name { field: type, ... }, or struct name<T, ...> { ... }
+    rule struct_decl() -> Expr
+        = p:pos() keyword("struct") name:identifier() type_params:type_params()? lcurly()
+          fields:((field:identifier() colon() t:value_type() { (field, t) }) ** comma()) comma()? rcurly() {
+            Expr::StructDecl(StructDecl { name, type_params: type_params.unwrap_or_default(), fields, doc: None, pos: p })
+        }
+
+    // enum name { variant, variant(type, ...), ... }
+    rule enum_decl() -> Expr
+        = p:pos() keyword("enum") name:identifier() lcurly() variants:(enum_variant() ** comma()) comma()? rcurly() {
+            Expr::EnumDecl(EnumDecl { name, variants, doc: None, pos: p })
+        }
+    rule enum_variant() -> (String, Vec<ValueType>)
+        = name:identifier() payload:(quiet!{lparen()} t:(value_type() ++ comma()) rparen() { t })? { (name, payload.unwrap_or_default()) }
+
+    // fn name(param: type, ...) -> type { body }, or the same with const in front. A generic
+    // function has its type parameters after its name, fn name<T, ...>(...)
+    rule function() -> Expr
+        = p:pos() is_const:(keyword("const") { true } / { false }) keyword("fn") name:identifier() type_params:type_params()? lparen()
+          params:((param:identifier() colon() t:value_type() { (param, t) }) ** comma()) rparen()
+          return_type:(arrow() t:value_type() { t })? lcurly() body:block() {
+            Expr::Function(Function { name, type_params: type_params.unwrap_or_default(), is_const, params, return_type, body, doc: None, pos: p })
+        }
+
+    // match value { pattern => { body } ... }, an arm can also be a single expression ended by a ,
+    rule match_statement() -> Expr
+        = p:pos() keyword("match") value:expr(false) lcurly() comma()* arms:(match_arm() ** (comma()*)) comma()* rcurly() {
+            Expr::Match(Match { value: Box::new(value), arms, pos: p })
+        }
+    rule match_arm() -> MatchArm
+        = p:pos() pattern:match_pattern() fat_arrow() body:(
+            quiet!{lcurly()} body:block() { body }
+            / ep:pos() e:expr(true) arm_end() { vec!(Expr::Tail(Tail { value: Box::new(e), pos: ep })) }
+        ) { MatchArm { pattern, body, pos: p } }
+    rule arm_end() = &[Token::Comma | Token::RCurly] / expected!("','") / expected!("'}'")
+    rule match_pattern() -> MatchPattern
+        = quiet!{name:identifier() {? if name == "_" { Ok(()) } else { Err("_") } }} { MatchPattern::Wildcard }
+        / enum_name:identifier() path_sep() variant:identifier() bindings:(quiet!{lparen()} b:(identifier() ++ comma()) rparen() { b })? {
+            MatchPattern::Variant(enum_name, variant, bindings.unwrap_or_default())
+        }
+
+    // if condition { body }, with else { body } or else if ... after it. Like the value of a match,
+    // the condition cannot be a struct literal
+    rule if_statement() -> Expr
+        = p:pos() keyword("if") condition:expr(false) lcurly() then_body:block()
+          else_body:(keyword("else") body:(e:if_statement() { vec!(e) } / lcurly() b:block() { b }) { body })? {
+            Expr::If(If { condition: Box::new(condition), then_body, else_body, pos: p })
+        }
+
+    // while condition { body }
+    rule while_statement() -> Expr
+        = p:pos() keyword("while") condition:expr(false) lcurly() body:block() {
+            Expr::While(While { condition: Box::new(condition), body, pos: p })
+        }
+
+    // The /// comments in front of a declaration
+    rule doc() -> String
+        = lines:(t:$(quiet!{[Token::DocComment(_)]}) { t[0].clone() })+
+          (&(keyword("fn") / keyword("struct") / keyword("enum") / keyword("const")) / expected!("a fn, struct, enum or const after the doc comment")) {
+            lines.into_iter().filter_map(|token| if let Token::DocComment(text) = token { Some(text) } else { None }).collect::<Vec<String>>().join("\n")
+        }
+
+    // return value, or return on its own
+    rule return_statement() -> Expr
+        = p:pos() keyword("return") value:expr(true)? {
+            Expr::Return(Return { value: value.map(Box::new), is_tail: false, pos: p })
+        }
+
+    // Declarations end with a } so they do not need a ;, every other statement is ended by a ;
+    // or the end of the file or block. An expression that is ended by the end of the block is
+    // the value of the block
+    rule statement() -> Option<Expr>
+        = semicolon() { None }
+        / doc:doc()? e:(struct_decl() / enum_decl() / function()) { Some(with_doc(e, doc)) }
+        / doc:doc()? e:const_decl() statement_end() { Some(with_doc(e, doc)) }
+        / e:match_statement() { Some(e) }
+        / e:if_statement() { Some(e) }
+        / e:while_statement() { Some(e) }
+        / e:let_statement() statement_end() { Some(e) }
+        / e:return_statement() statement_end() { Some(e) }
+        / !any_keyword() p:pos() e:expr(true) is_tail:(semicolon() { false } / block_end() { true }) {
+            if is_tail { Some(Expr::Tail(Tail { value: Box::new(e), pos: p })) } else { Some(e) }
+        }
+    rule statement_end() = semicolon() / block_end()
+    rule block_end() = &rcurly() / ![_]
+
+    // The statements of a block up to its closing }, the { is already matched
+    rule block() -> Vec<Expr> = s:statement()* rcurly() { s.into_iter().flatten().collect() }
+
+    pub rule program() -> Vec<Expr>
+        = s:statement()* ![_] { s.into_iter().flatten().collect() }
+}}
+
+// What can follow an operand
+enum Postfix {
+    Index(Expr),
+    Field(String),
+    Call(Vec<Expr>)
+}
+
+// What follows the first element of an array literal
+enum ArrayTail {
+    Repeat(usize),
+    List(Vec<Expr>)
+}
+
+fn token_pos(positions: &[SourcePos], index: usize) -> SourcePos {
+    return positions.get(index).or_else(|| positions.last()).copied().unwrap_or(SourcePos { line: 1, col: 1 });
+}
+
+type Operations = std::iter::Peekable<std::vec::IntoIter<(SourcePos, OperatorToken, Expr)>>;
+
+// Build the tree for lhs and the operators and operands after it, taking the operators with a
+// precedence below limit. The precedences come from the operator table, a * b + c is (a * b) + c
+// and a = b = c is a = (b = c)
+fn group_operations(mut lhs: Expr, rest: &mut Operations, limit: usize) -> Expr {
+    loop {
+        match rest.peek() {
+            Some((_, op, _)) if op.op_type.precedence() < limit => {}
+            _ => {
+                return lhs;
+            }
+        }
+        let (pos, op, rhs) = rest.next().unwrap();
+        let precedence = op.op_type.precedence();
+        // The operators that bind tighter than op go to its right operand, and so do the ones
+        // with the same precedence when they group from the right
+        let rhs_limit = if op.op_type.is_right_associative() { precedence + 1 } else { precedence };
+        let rhs = group_operations(rhs, rest, rhs_limit);
+        lhs = operation(lhs, op, rhs, pos);
+    }
+}
+
+fn operation(expr1: Expr, operator: OperatorToken, expr2: Expr, pos: SourcePos) -> Expr {
+    return Expr::Operation(Operation { expr1: Box::new(expr1), operator, expr2: Box::new(expr2), pos });
+}
+
+fn with_doc(mut expr: Expr, doc: Option<String>) -> Expr {
+    match &mut expr {
+        Expr::Function(Function { doc: expr_doc, .. }) | Expr::StructDecl(StructDecl { doc: expr_doc, .. })
+        | Expr::EnumDecl(EnumDecl { doc: expr_doc, .. }) | Expr::ConstDecl(ConstDecl { doc: expr_doc, .. }) => {
+            *expr_doc = doc;
+        }
+        _ => {}
+    }
+    return expr;
+}
+
+// Parse the tokens of a whole program. A syntax error lists what could have come at the furthest
+// token the grammar got to, like "expected ',' or ']' at line 2, col 14"
+pub fn parse_program(tokens: &[Token], positions: &[SourcePos]) -> Result<Vec<Expr>, CompileError> {
+    match vm_grammar::program(tokens, positions) {
+        Ok(tree) => {
+            return Ok(tree);
+        }
+        Err(err) => {
+            let pos = token_pos(positions, err.location);
+            let mut expected: Vec<&str> = err.expected.tokens().collect();
+            expected.sort();
+            let expected = match expected.split_last() {
+                None => "something else".to_string(),
+                Some((last, [])) => last.to_string(),
+                Some((last, rest)) => format!("{} or {}", rest.join(", "), last)
+            };
+            return Err(CompileError::at(pos, format!("expected {} at {}", expected, pos.to_string())));
+        }
+    }
+}
@@ -0,0 +1,1416 @@
+use crate::jit::*;
+use crate::operators::*;
+use crate::generics::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// Const evaluation gives up after this many steps, the program is most likely never going to
+// finish
+pub const CONST_EVAL_STEP_LIMIT: usize = 1_000_000;
+
+// Calls nested deeper than this are most likely endless recursion
+pub const CALL_DEPTH_LIMIT: usize = 10_000;
+
+// The interpreter recurses for every call and every expression inside another, so it runs on a
+// thread with a stack that is big enough for CALL_DEPTH_LIMIT calls
+const INTERPRETER_STACK_SIZE: usize = 1 << 30;
+
+// Whether a runtime error is the interpreter giving up at CALL_DEPTH_LIMIT
+fn is_call_depth_error(err: &str) -> bool {
+    return err.contains(&format!("the interpreter went more than {} calls deep", CALL_DEPTH_LIMIT));
+}
+
+pub(crate) fn with_interpreter_stack<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    return std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(INTERPRETER_STACK_SIZE).spawn_scoped(scope, f).unwrap();
+        return thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    });
+}
+
+#[derive(Clone)]
+pub enum Value {
+    I32(i32),
+    F64(f64),
+    Array(ValueType, Vec<Value>), // The type is the type of the elements
+    Struct(ValueType, Vec<(String, Value)>), // The fields are in the order they are declared
+    Enum(String, usize, Vec<Value>), // The index of the variant, and its payload
+    Ref(Reference),
+    Function(String, ValueType), // A pointer to the function with this name, and its type
+    Closure(Rc<ClosureValue>)
+}
+
+// A closure and the values of the variables it captured when it was created
+pub struct ClosureValue {
+    function: Function,
+    captured: Vec<(String, Value)>
+}
+
+// The memory of a variable or of an allocation, references point into it
+pub type Cell = Rc<RefCell<Value>>;
+
+// A step from a value to a part of it
+#[derive(Clone, PartialEq)]
+pub enum PathStep {
+    Index(usize),
+    Field(String)
+}
+
+#[derive(Clone)]
+pub struct Reference {
+    pub target: Option<(Cell, Vec<PathStep>)>, // None for a reference that does not point anywhere yet
+    pub ref_type: ValueType
+}
+
+fn get_path<'v>(value: &'v Value, path: &[PathStep]) -> &'v Value {
+    let mut value = value;
+    for step in path {
+        value = match (step, value) {
+            (PathStep::Index(index), Value::Array(_, elements)) => &elements[*index],
+            (PathStep::Field(field), Value::Struct(_, fields)) => &fields.iter().find(|(name, _)| name == field).unwrap().1,
+            _ => unreachable!()
+        };
+    }
+    return value;
+}
+
+fn get_path_mut<'v>(value: &'v mut Value, path: &[PathStep]) -> &'v mut Value {
+    let mut value = value;
+    for step in path {
+        value = match (step, value) {
+            (PathStep::Index(index), Value::Array(_, elements)) => &mut elements[*index],
+            (PathStep::Field(field), Value::Struct(_, fields)) => &mut fields.iter_mut().find(|(name, _)| name == field).unwrap().1,
+            _ => unreachable!()
+        };
+    }
+    return value;
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::I32(..) => {
+                return ValueType::I32;
+            }
+            Value::F64(..) => {
+                return ValueType::F64;
+            }
+            Value::Array(elem_type, elements) => {
+                return ValueType::Array(Box::new(elem_type.clone()), elements.len());
+            }
+            Value::Struct(struct_type, _) => {
+                return struct_type.clone();
+            }
+            Value::Enum(name, _, _) => {
+                return ValueType::Enum(name.clone());
+            }
+            Value::Ref(reference) => {
+                return reference.ref_type.clone();
+            }
+            Value::Function(_, fn_type) => {
+                return fn_type.clone();
+            }
+            // The types that the closure does not give are _, which any type can be used as
+            Value::Closure(closure) => {
+                return closure.function.fn_type();
+            }
+        }
+    }
+    // The value of memory that is all zeros
+    pub fn zero(value_type: &ValueType, types: &TypeTable) -> Result<Value, CompileError> {
+        match value_type {
+            ValueType::I32 => {
+                return Ok(Value::I32(0));
+            }
+            ValueType::F64 => {
+                return Ok(Value::F64(0.0));
+            }
+            ValueType::Array(elem_type, len) => {
+                let elem = Value::zero(elem_type, types)?;
+                return Ok(Value::Array(elem_type.as_ref().clone(), vec![elem; *len]));
+            }
+            ValueType::Struct(..) | ValueType::Generic(..) => {
+                let mut fields: Vec<(String, Value)> = vec!();
+                for (name, field_type) in struct_fields(value_type, types) {
+                    let value = Value::zero(&field_type, types)?;
+                    fields.push((name, value));
+                }
+                return Ok(Value::Struct(value_type.clone(), fields));
+            }
+            ValueType::Enum(name) => {
+                let variant = match types.enums[name].variants.first() {
+                    Some(variant) => variant,
+                    None => {
+                        return Err(CompileError::new(format!("enum {} has no variants", name)));
+                    }
+                };
+                let mut payload: Vec<Value> = vec!();
+                for payload_type in variant.payload.iter() {
+                    payload.push(Value::zero(payload_type, types)?);
+                }
+                return Ok(Value::Enum(name.clone(), 0, payload));
+            }
+            ValueType::Ref(..) => {
+                return Ok(Value::Ref(Reference { target: None, ref_type: value_type.clone() }));
+            }
+            ValueType::Fn(..) | ValueType::Infer => {
+                return Err(CompileError::new(format!("there is no zero value for a {}", value_type.to_string())));
+            }
+        }
+    }
+    // An expression that creates this value
+    pub fn to_expr(&self, types: &TypeTable, pos: SourcePos) -> Result<Expr, CompileError> {
+        match self {
+            Value::I32(num) => {
+                return Ok(Expr::NumberToken(NumberToken { num: *num, text: None }));
+            }
+            Value::F64(num) => {
+                return Ok(Expr::FloatToken(FloatToken { num: *num }));
+            }
+            Value::Array(elem_type, elements) if elements.is_empty() => {
+                // An empty array literal has no type, so repeat a value zero times instead
+                let value = Value::zero(elem_type, types)?.to_expr(types, pos)?;
+                return Ok(Expr::ArrayRepeat(ArrayRepeat { value: Box::new(value), count: 0 }));
+            }
+            Value::Array(_, elements) => {
+                let mut exprs: Vec<Expr> = vec!();
+                for element in elements.iter() {
+                    exprs.push(element.to_expr(types, pos)?);
+                }
+                return Ok(Expr::ArrayLiteral(ArrayLiteral { elements: exprs }));
+            }
+            Value::Struct(struct_type, fields) => {
+                let mut exprs: Vec<(String, Expr)> = vec!();
+                for (field, value) in fields.iter() {
+                    exprs.push((field.clone(), value.to_expr(types, pos)?));
+                }
+                // The type arguments of a generic struct are worked out again from the fields
+                let name = match struct_type {
+                    ValueType::Generic(name, _) => name.clone(),
+                    _ => struct_type.to_string()
+                };
+                return Ok(Expr::StructLiteral(StructLiteral { name, fields: exprs, pos }));
+            }
+            Value::Enum(name, variant, payload) => {
+                let mut args: Vec<Expr> = vec!();
+                for value in payload.iter() {
+                    args.push(value.to_expr(types, pos)?);
+                }
+                let variant = types.enums[name].variants[*variant].name.clone();
+                return Ok(Expr::EnumLiteral(EnumLiteral { enum_name: name.clone(), variant, args, pos }));
+            }
+            Value::Ref(..) => {
+                return Err(CompileError::at(pos, format!("a reference cannot be worked out while compiling, at {}", pos.to_string())));
+            }
+            Value::Function(name, _) => {
+                return Ok(Expr::IdentifierToken(Identifier { text: name.clone(), pos }));
+            }
+            Value::Closure(..) => {
+                return Err(CompileError::at(pos, format!("a closure cannot be worked out while compiling, at {}", pos.to_string())));
+            }
+        }
+    }
+}
+
+// The variables of a function call, the innermost scope is last
+pub struct Frame {
+    scopes: Vec<HashMap<String, Cell>>
+}
+
+impl Frame {
+    pub fn new() -> Frame {
+        return Frame { scopes: vec!(HashMap::new()) };
+    }
+    pub fn get(&self, name: &str) -> Option<Cell> {
+        return self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned();
+    }
+    // Declare a variable in the innermost scope, a variable with the same name is shadowed
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), Rc::new(RefCell::new(value)));
+    }
+}
+
+// What running a statement did, either the function goes on or it returns
+pub enum Flow {
+    Next,
+    Return(Option<Value>)
+}
+
+// Runs the same expression trees that the JIT compiles, directly. When const_only is set it
+// only runs what a const fn is allowed to do
+pub struct Interpreter<'a> {
+    types: &'a TypeTable,
+    functions: HashMap<String, &'a Function>,
+    instances: HashMap<String, Rc<Function>>, // Instances of generic functions made while running, see instance
+    consts: HashMap<String, Value>,
+    heap: Vec<Cell>, // Every allocation made by alloc that has not been freed yet
+    freed: Vec<Cell>, // The allocations that were freed, references to them cannot be used any more
+    const_only: bool,
+    pub overflow_checks: bool, // See JIT::set_overflow_checks
+    steps: usize,
+    depth: usize,
+    pos: SourcePos // The last position seen, for errors in expressions that have no position
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(types: &'a TypeTable, expr_tree: &'a [Expr], const_only: bool) -> Interpreter<'a> {
+        let mut functions: HashMap<String, &'a Function> = HashMap::new();
+        for expr in expr_tree {
+            if let Expr::Function(token) = expr {
+                functions.insert(token.name.clone(), token);
+            }
+        }
+        return Interpreter {
+            types,
+            functions,
+            instances: HashMap::new(),
+            consts: HashMap::new(),
+            heap: vec!(),
+            freed: vec!(),
+            const_only,
+            overflow_checks: false,
+            steps: 0,
+            depth: 0,
+            pos: SourcePos { line: 1, col: 1 }
+        };
+    }
+    fn step(&mut self) -> Result<(), CompileError> {
+        self.steps += 1;
+        if self.const_only && self.steps > CONST_EVAL_STEP_LIMIT {
+            return Err(CompileError::at(self.pos, format!("const evaluation did not finish within {} steps, at {}, it might never finish", CONST_EVAL_STEP_LIMIT, self.pos.to_string())));
+        }
+        return Ok(());
+    }
+    // Work out the value of an expression that is run while compiling
+    pub fn eval_const(&mut self, expr: &Expr) -> Result<Value, CompileError> {
+        self.steps = 0;
+        return self.eval_expr(expr, &mut Frame::new());
+    }
+    // Work out every const, consts can use the consts declared before them
+    pub fn eval_consts(&mut self, expr_tree: &[Expr]) -> Result<(), CompileError> {
+        for expr in expr_tree {
+            if let Expr::ConstDecl(token) = expr {
+                self.pos = token.pos;
+                if self.consts.contains_key(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("const {} is declared twice at {}", token.name, token.pos.to_string())));
+                }
+                check_type(&token.value_type, self.types).map_err(|err| CompileError::at(token.pos, format!("{} at {}", err.message, token.pos.to_string())))?;
+
+                // Consts are always worked out like they are while compiling
+                let const_only = self.const_only;
+                self.const_only = true;
+                let value = self.eval_const(&token.value);
+                self.const_only = const_only;
+                let value = value?;
+                if !value.value_type().can_be_used_as(&token.value_type) {
+                    return Err(CompileError::at(token.pos, format!("mismatched types for const {} at {}: expected {}, found {}", token.name, token.pos.to_string(), token.value_type.to_string(), value.value_type().to_string())));
+                }
+                self.consts.insert(token.name.clone(), value);
+            }
+        }
+        return Ok(());
+    }
+    // Run the statements of the program that are not declarations, and return what they
+    // return, or 0 if they get to the end
+    pub fn run_main(&mut self, expr_tree: &[Expr]) -> Result<i32, CompileError> {
+        self.eval_consts(expr_tree)?;
+        let mut frame = Frame::new();
+        for expr in expr_tree {
+            if matches!(expr, Expr::Function(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..)) {
+                continue;
+            }
+            match self.exec_statement(expr, &mut frame)? {
+                Flow::Next => {}
+                Flow::Return(Some(Value::I32(result))) => {
+                    return Ok(result);
+                }
+                Flow::Return(Some(other)) => {
+                    return Err(CompileError::at(self.pos, format!("mismatched types in return at {}: expected i32, found {}", self.pos.to_string(), other.value_type().to_string())));
+                }
+                Flow::Return(None) => {
+                    return Err(CompileError::at(self.pos, format!("return without a value at {}, the function returns i32", self.pos.to_string())));
+                }
+            }
+        }
+        return Ok(0);
+    }
+    // Find the memory that a place like a, a[i].x or *r refers to. Other expressions are put
+    // in memory of their own
+    fn eval_place(&mut self, expr: &Expr, frame: &mut Frame) -> Result<(Cell, Vec<PathStep>), CompileError> {
+        match expr {
+            Expr::IdentifierToken(token) if frame.get(&token.text).is_some() => {
+                return Ok((frame.get(&token.text).unwrap(), vec!()));
+            }
+            Expr::Index(token) => {
+                let (cell, mut path) = self.eval_deref_place(&token.array, token.pos, frame)?;
+                let index = match self.eval_expr(&token.index, frame)? {
+                    Value::I32(index) => index,
+                    other => {
+                        return Err(CompileError::at(token.pos, format!("array index must be i32, found {} at {}", other.value_type().to_string(), token.pos.to_string())));
+                    }
+                };
+                self.pos = token.pos;
+                let len = match get_path(&cell.borrow(), &path) {
+                    Value::Array(_, elements) => elements.len(),
+                    other => {
+                        return Err(CompileError::at(token.pos, format!("cannot index into a value of type {} at {}", other.value_type().to_string(), token.pos.to_string())));
+                    }
+                };
+                check_bounds(index, len, token.pos)?;
+                path.push(PathStep::Index(index as usize));
+                return Ok((cell, path));
+            }
+            Expr::Field(token) => {
+                let (cell, mut path) = self.eval_deref_place(&token.expr, token.pos, frame)?;
+                self.pos = token.pos;
+                let has_field = match get_path(&cell.borrow(), &path) {
+                    Value::Struct(_, fields) => fields.iter().any(|(name, _)| *name == token.field),
+                    _ => false
+                };
+                if !has_field {
+                    let value_type = get_path(&cell.borrow(), &path).value_type();
+                    return Err(CompileError::at(token.pos, format!("a value of type {} has no field {}, at {}", value_type.to_string(), token.field, token.pos.to_string())));
+                }
+                path.push(PathStep::Field(token.field.clone()));
+                return Ok((cell, path));
+            }
+            Expr::Deref(token) => {
+                self.pos = token.pos;
+                match self.eval_expr(&token.expr, frame)? {
+                    Value::Ref(reference) => {
+                        let target = reference.target.ok_or(CompileError::at(token.pos, format!("dereference of a reference that does not point anywhere, at {}", token.pos.to_string())))?;
+                        self.check_not_freed(&target.0, token.pos)?;
+                        return Ok(target);
+                    }
+                    other => {
+                        return Err(CompileError::at(token.pos, format!("cannot dereference a value of type {} at {}", other.value_type().to_string(), token.pos.to_string())));
+                    }
+                }
+            }
+            _ => {
+                let value = self.eval_expr(expr, frame)?;
+                return Ok((Rc::new(RefCell::new(value)), vec!()));
+            }
+        }
+    }
+    // Using a reference to memory that was freed is a runtime error, like in generated code
+    fn check_not_freed(&self, cell: &Cell, pos: SourcePos) -> Result<(), CompileError> {
+        if self.freed.iter().any(|allocation| Rc::ptr_eq(allocation, cell)) {
+            return Err(RuntimeError { message: "use of memory after it was freed".to_string(), pos }.to_compile_error());
+        }
+        return Ok(());
+    }
+    // Like eval_place, but follows references, since indexing and field access look through them
+    fn eval_deref_place(&mut self, expr: &Expr, pos: SourcePos, frame: &mut Frame) -> Result<(Cell, Vec<PathStep>), CompileError> {
+        let (mut cell, mut path) = self.eval_place(expr, frame)?;
+        loop {
+            let target = match get_path(&cell.borrow(), &path) {
+                Value::Ref(reference) => reference.target.clone(),
+                _ => {
+                    break;
+                }
+            };
+            match target {
+                Some(target) => {
+                    self.check_not_freed(&target.0, pos)?;
+                    (cell, path) = target;
+                }
+                None => {
+                    return Err(CompileError::at(self.pos, format!("dereference of a reference that does not point anywhere, at {}", self.pos.to_string())));
+                }
+            }
+        }
+        return Ok((cell, path));
+    }
+    pub fn eval_expr(&mut self, expr: &Expr, frame: &mut Frame) -> Result<Value, CompileError> {
+        self.step()?;
+        match expr {
+            Expr::IdentifierToken(token) => {
+                if let Some(cell) = frame.get(&token.text) {
+                    return Ok(cell.borrow().clone());
+                }
+                if let Some(value) = self.consts.get(&token.text) {
+                    return Ok(value.clone());
+                }
+                // The name of a function on its own is a pointer to it
+                if let Some(function) = self.functions.get(&token.text) {
+                    return Ok(Value::Function(token.text.clone(), function.fn_type()));
+                }
+                if is_builtin_function(&token.text) {
+                    return Err(CompileError::at(token.pos, format!("function {} is built in and cannot be used as a value", token.text)));
+                }
+                if self.types.generics.functions.contains_key(&token.text) {
+                    return Err(CompileError::at(token.pos, format!("generic function {} cannot be used as a value, only called", token.text)));
+                }
+                return Err(CompileError::at(token.pos, format!("unknown variable {} at {}", token.text, token.pos.to_string())));
+            }
+            Expr::NumberToken(token) => {
+                return Ok(Value::I32(token.num));
+            }
+            Expr::FloatToken(token) => {
+                return Ok(Value::F64(token.num));
+            }
+            Expr::Operation(token) => {
+                let op = match token.operator.op_type {
+                    OperatorType::Operator(op) => op,
+                    OperatorType::Assign(..) => {
+                        return Err(CompileError::new("Cannot parse assign operation inside expression!".to_string()));
+                    }
+                };
+                let val1 = self.eval_expr(&token.expr1, frame)?;
+                let val2 = self.eval_expr(&token.expr2, frame)?;
+                return eval_operation(op, &[val1, val2], token.pos, self.overflow_checks);
+            }
+            Expr::ArrayLiteral(token) => {
+                let mut elements: Vec<Value> = vec!();
+                for element in token.elements.iter() {
+                    elements.push(self.eval_expr(element, frame)?);
+                }
+                // The compiler has worked out the type of [] from where it is used, any element type will do
+                let elem_type = match elements.first() {
+                    Some(element) => element.value_type(),
+                    None => ValueType::Infer
+                };
+                for element in elements.iter() {
+                    if element.value_type() != elem_type {
+                        return Err(CompileError::new(format!("mismatched types in array: expected {}, found {}", elem_type.to_string(), element.value_type().to_string())));
+                    }
+                }
+                return Ok(Value::Array(elem_type, elements));
+            }
+            Expr::ArrayRepeat(token) => {
+                let value = self.eval_expr(&token.value, frame)?;
+                return Ok(Value::Array(value.value_type(), vec![value; token.count]));
+            }
+            Expr::Index(..) | Expr::Field(..) => {
+                let (cell, path) = self.eval_place(expr, frame)?;
+                let value = get_path(&cell.borrow(), &path).clone();
+                return Ok(value);
+            }
+            Expr::StructLiteral(token) => {
+                self.pos = token.pos;
+                if !self.types.structs.contains_key(&token.name) && !self.types.generics.structs.contains_key(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("unknown struct {} at {}", token.name, token.pos.to_string())));
+                }
+                // The values are worked out in the order they are written, and then put in
+                // the order the fields are declared
+                let mut values: Vec<(String, Value)> = vec!();
+                for (name, value) in token.fields.iter() {
+                    values.push((name.clone(), self.eval_expr(value, frame)?));
+                }
+                let struct_type = match self.types.generics.structs.get(&token.name) {
+                    Some(generic) => {
+                        let mut param_types: Vec<ValueType> = vec!();
+                        let mut value_types: Vec<Option<ValueType>> = vec!();
+                        for (name, value) in values.iter() {
+                            if let Some((_, field_type)) = generic.fields.iter().find(|(field, _)| field == name) {
+                                param_types.push(field_type.clone());
+                                value_types.push(Some(value.value_type()));
+                            }
+                        }
+                        let type_args = infer_type_args(&generic.type_params, &param_types, &value_types);
+                        ValueType::Generic(token.name.clone(), Interpreter::known_type_args(type_args))
+                    }
+                    None => ValueType::Struct(token.name.clone())
+                };
+                let mut fields: Vec<(String, Value)> = vec!();
+                for (field_name, _) in struct_fields(&struct_type, self.types) {
+                    match values.iter().position(|(name, _)| *name == field_name) {
+                        Some(index) => {
+                            fields.push(values.swap_remove(index));
+                        }
+                        None => {
+                            return Err(CompileError::at(token.pos, format!("missing field {} in {} at {}", field_name, struct_type.to_string(), token.pos.to_string())));
+                        }
+                    }
+                }
+                if let Some((name, _)) = values.first() {
+                    return Err(CompileError::at(token.pos, format!("struct {} has no field {} at {}", token.name, name, token.pos.to_string())));
+                }
+                return Ok(Value::Struct(struct_type, fields));
+            }
+            Expr::Call(..) | Expr::IndirectCall(..) => {
+                if let Some(value) = self.eval_call(expr, frame)? {
+                    return Ok(value);
+                }
+                let (name, pos) = match expr {
+                    Expr::IndirectCall(token) => (token.callee.to_string(), token.pos),
+                    Expr::Call(token) => (token.name.clone(), token.pos),
+                    _ => unreachable!()
+                };
+                return Err(CompileError::at(pos, format!("function {} does not return a value, at {}", name, pos.to_string())));
+            }
+            Expr::EnumLiteral(token) => {
+                self.pos = token.pos;
+                let types = self.types;
+                let (variant, _) = match types.enums.get(&token.enum_name).and_then(|layout| layout.get_variant(&token.variant)) {
+                    Some(variant) => variant,
+                    None => {
+                        return Err(CompileError::at(token.pos, format!("unknown variant {}::{} at {}", token.enum_name, token.variant, token.pos.to_string())));
+                    }
+                };
+                let mut payload: Vec<Value> = vec!();
+                for arg in token.args.iter() {
+                    payload.push(self.eval_expr(arg, frame)?);
+                }
+                return Ok(Value::Enum(token.enum_name.clone(), variant, payload));
+            }
+            Expr::AddressOf(AddressOf { pos, .. }) | Expr::Deref(Deref { pos, .. }) if self.const_only => {
+                return Err(CompileError::at(*pos, format!("references cannot be used in const evaluation, at {}", pos.to_string())));
+            }
+            Expr::AddressOf(token) => {
+                let (cell, path) = self.eval_place(&token.expr, frame)?;
+                self.pos = token.pos;
+                let value_type = get_path(&cell.borrow(), &path).value_type();
+                let ref_type = ValueType::Ref(Box::new(value_type), token.mutable);
+                return Ok(Value::Ref(Reference { target: Some((cell, path)), ref_type }));
+            }
+            Expr::Deref(..) => {
+                let (cell, path) = self.eval_place(expr, frame)?;
+                let value = get_path(&cell.borrow(), &path).clone();
+                return Ok(value);
+            }
+            Expr::Unary(token) => {
+                let value = self.eval_expr(&token.expr, frame)?;
+                return eval_operation(token.operator, &[value], token.pos, self.overflow_checks);
+            }
+            Expr::Closure(token) => {
+                let names = closure_captures(token, &|name| frame.get(name).is_some());
+                let captured: Vec<(String, Value)> = names.into_iter()
+                    .map(|name| {
+                        let value = frame.get(&name).unwrap().borrow().clone();
+                        return (name, value);
+                    })
+                    .collect();
+                let function = Function { name: "closure".to_string(), type_params: vec!(), is_const: false, params: token.params.clone(), return_type: token.return_type.clone(), body: token.body.clone(), doc: None, pos: token.pos };
+                return Ok(Value::Closure(Rc::new(ClosureValue { function, captured })));
+            }
+            Expr::Let(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Function(..) | Expr::Match(..)
+            | Expr::If(..) | Expr::While(..) | Expr::Return(..) | Expr::Tail(..) | Expr::Empty => {
+                return Err(CompileError::new("Could not parse expression".to_string()));
+            }
+        }
+    }
+    pub fn exec_statement(&mut self, expr: &Expr, frame: &mut Frame) -> Result<Flow, CompileError> {
+        self.step()?;
+        match expr {
+            Expr::Return(token) => {
+                self.pos = token.pos;
+                match token.value.as_deref() {
+                    Some(value @ (Expr::Call(..) | Expr::IndirectCall(..))) => {
+                        // Whether a call gives a value is only known once it is made
+                        let result = self.eval_call(value, frame)?;
+                        self.pos = token.pos;
+                        match result {
+                            Some(value) => {
+                                return Ok(Flow::Return(Some(value)));
+                            }
+                            None if token.is_tail => {
+                                return Ok(Flow::Next);
+                            }
+                            None => {
+                                return Ok(Flow::Return(None));
+                            }
+                        }
+                    }
+                    // Calls are taken care of above
+                    Some(value) if expr_has_value(value, &|_| true) => {
+                        let value = self.eval_expr(value, frame)?;
+                        self.pos = token.pos;
+                        return Ok(Flow::Return(Some(value)));
+                    }
+                    Some(value) => {
+                        self.exec_statement(value, frame)?;
+                        // A statement at the end of a function without a ; after it does not return
+                        if token.is_tail {
+                            return Ok(Flow::Next);
+                        }
+                        return Ok(Flow::Return(None));
+                    }
+                    None => {
+                        return Ok(Flow::Return(None));
+                    }
+                }
+            }
+            Expr::Tail(token) => {
+                let is_unused_value = match token.value.as_ref() {
+                    Expr::Call(..) | Expr::IndirectCall(..) => self.eval_call(&token.value, frame)?.is_some(),
+                    value if expr_has_value(value, &|_| true) => true,
+                    value => {
+                        return self.exec_statement(value, frame);
+                    }
+                };
+                if is_unused_value {
+                    return Err(CompileError::at(token.pos, format!("the value of {} at {} is not used, only the last expression of a function is returned", token.value.to_string(), token.pos.to_string())));
+                }
+            }
+            Expr::Let(token) => {
+                let value = match &token.value {
+                    Some(value) => self.eval_expr(value, frame)?,
+                    // Nothing reads the variable before it is assigned, so any value of its type will do
+                    None => match token.value_type.clone().unwrap_or(ValueType::Infer) {
+                        value_type @ ValueType::Fn(..) => Value::Function(String::new(), value_type),
+                        value_type => Value::zero(&value_type, self.types)?
+                    }
+                };
+                if let Some(declared_type) = &token.value_type {
+                    if !value.value_type().can_be_used_as(declared_type) {
+                        return Err(CompileError::at(token.pos, format!("mismatched types for {}: expected {}, found {}", token.name, declared_type.to_string(), value.value_type().to_string())));
+                    }
+                }
+                frame.define(&token.name, value);
+            }
+            Expr::Operation(token) if matches!(token.operator.op_type, OperatorType::Assign(..)) => {
+                if let OperatorType::Assign(op) = token.operator.op_type {
+                    self.exec_assignment(op, &token.expr1, &token.expr2, token.pos, frame)?;
+                }
+            }
+            Expr::Call(..) | Expr::IndirectCall(..) => {
+                // The function might not return anything
+                self.eval_call(expr, frame)?;
+            }
+            Expr::Match(token) => {
+                return self.exec_match(token, frame);
+            }
+            Expr::If(token) => {
+                let body = match self.eval_condition(&token.condition, "if", token.pos, frame)? {
+                    true => Some(&token.then_body),
+                    false => token.else_body.as_ref()
+                };
+                if let Some(body) = body {
+                    return self.exec_body(body, frame);
+                }
+            }
+            Expr::While(token) => {
+                while self.eval_condition(&token.condition, "while", token.pos, frame)? {
+                    if let Flow::Return(value) = self.exec_body(&token.body, frame)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            Expr::StructDecl(StructDecl { pos, .. }) | Expr::EnumDecl(EnumDecl { pos, .. }) | Expr::ConstDecl(ConstDecl { pos, .. }) | Expr::Function(Function { pos, .. }) => {
+                return Err(CompileError::at(*pos, format!("declarations are only allowed at the top level, at {}", pos.to_string())));
+            }
+            Expr::Empty => {}
+            _ => {
+                self.eval_expr(expr, frame)?;
+            }
+        }
+        return Ok(Flow::Next);
+    }
+    // Apply = or a compound assignment like += to a variable, or to a place in memory like a[i] or *r
+    fn exec_assignment(&mut self, op: Option<&Operator>, place: &Expr, value: &Expr, pos: SourcePos, frame: &mut Frame) -> Result<(), CompileError> {
+        let value = self.eval_expr(value, frame)?;
+
+        let mut root = place;
+        while let Expr::Index(Index { array: inner, .. }) | Expr::Field(Field { expr: inner, .. }) = root {
+            root = inner;
+        }
+        if let Expr::IdentifierToken(token) = root {
+            if frame.get(&token.text).is_none() {
+                if self.consts.contains_key(&token.text) {
+                    return Err(CompileError::at(token.pos, format!("cannot assign to const {}", token.text)));
+                }
+                // Assigning to a variable that does not exist yet declares it
+                if let (None, Expr::IdentifierToken(..)) = (op, place) {
+                    frame.define(&token.text, value);
+                    return Ok(());
+                }
+                return Err(CompileError::at(token.pos, format!("unknown variable {} at {}", token.text, token.pos.to_string())));
+            }
+        }
+
+        let overflow_checks = self.overflow_checks;
+        let (cell, path) = self.eval_place(place, frame)?;
+        let mut memory = cell.borrow_mut();
+        let target = get_path_mut(&mut memory, &path);
+        if !value.value_type().can_be_used_as(&target.value_type()) {
+            return Err(CompileError::at(pos, format!("mismatched types when assigning to {}: expected {}, found {}", place.to_string(), target.value_type().to_string(), value.value_type().to_string())));
+        }
+        *target = match op {
+            None => value,
+            Some(op) => eval_operation(op, &[target.clone(), value], pos, overflow_checks)?
+        };
+        return Ok(());
+    }
+    // The condition of an if or a while is an i32, anything but 0 is true
+    fn eval_condition(&mut self, condition: &Expr, statement: &str, pos: SourcePos, frame: &mut Frame) -> Result<bool, CompileError> {
+        self.step()?;
+        match self.eval_expr(condition, frame)? {
+            Value::I32(value) => {
+                return Ok(value != 0);
+            }
+            other => {
+                return Err(CompileError::at(pos, format!("mismatched types in the condition of the {} at {}: expected i32, found {}", statement, pos.to_string(), other.value_type().to_string())));
+            }
+        }
+    }
+    // Run the statements of a body until one of them returns, the variables declared in it are
+    // gone after it
+    fn exec_body(&mut self, body: &[Expr], frame: &mut Frame) -> Result<Flow, CompileError> {
+        frame.scopes.push(HashMap::new());
+        let mut flow = Flow::Next;
+        for expr in body.iter() {
+            flow = self.exec_statement(expr, frame)?;
+            if let Flow::Return(..) = flow {
+                break;
+            }
+        }
+        frame.scopes.pop();
+        return Ok(flow);
+    }
+    fn exec_match(&mut self, token: &Match, frame: &mut Frame) -> Result<Flow, CompileError> {
+        // Matching looks through references like indexing does
+        let (cell, path) = self.eval_deref_place(&token.value, token.pos, frame)?;
+        let value = get_path(&cell.borrow(), &path).clone();
+        self.pos = token.pos;
+        let (enum_name, variant, payload) = match value {
+            Value::Enum(enum_name, variant, payload) => (enum_name, variant, payload),
+            other => {
+                return Err(CompileError::at(token.pos, format!("cannot match on a value of type {} at {}", other.value_type().to_string(), token.pos.to_string())));
+            }
+        };
+        let variant_name = &self.types.enums[&enum_name].variants[variant].name;
+        for arm in token.arms.iter() {
+            let bindings = match &arm.pattern {
+                MatchPattern::Variant(_, name, bindings) if name == variant_name => bindings.as_slice(),
+                MatchPattern::Wildcard => &[],
+                _ => {
+                    continue;
+                }
+            };
+
+            // Variables declared in the arm, like the bindings, are gone after it
+            frame.scopes.push(HashMap::new());
+            for (binding, value) in bindings.iter().zip(payload.iter()) {
+                if binding != "_" {
+                    frame.define(binding, value.clone());
+                }
+            }
+            let mut flow = Flow::Next;
+            for expr in arm.body.iter() {
+                flow = self.exec_statement(expr, frame)?;
+                if let Flow::Return(..) = flow {
+                    break;
+                }
+            }
+            frame.scopes.pop();
+            return Ok(flow);
+        }
+        return Err(CompileError::at(token.pos, format!("no arm of the match at {} matches {}::{}", token.pos.to_string(), enum_name, variant_name)));
+    }
+    // Make a call, it goes through a function value when it is to a variable or to the value
+    // of an expression. Returns None if the function does not return anything
+    fn eval_call(&mut self, expr: &Expr, frame: &mut Frame) -> Result<Option<Value>, CompileError> {
+        let (callee, arg_exprs, pos) = match expr {
+            Expr::Call(token) if is_builtin_function(&token.name) || (frame.get(&token.name).is_none() && !self.consts.contains_key(&token.name)) => {
+                (None, &token.args, token.pos)
+            }
+            Expr::Call(token) => {
+                let callee = Expr::IdentifierToken(Identifier { text: token.name.clone(), pos: token.pos });
+                (Some(self.eval_callee(&callee, token.pos, frame)?), &token.args, token.pos)
+            }
+            Expr::IndirectCall(token) => {
+                (Some(self.eval_callee(&token.callee, token.pos, frame)?), &token.args, token.pos)
+            }
+            _ => unreachable!()
+        };
+        let mut args: Vec<Value> = vec!();
+        for arg in arg_exprs.iter() {
+            args.push(self.eval_expr(arg, frame)?);
+        }
+        self.pos = pos;
+        match (callee, expr) {
+            (Some(Value::Closure(closure)), _) => {
+                return self.call_closure(&closure, args, pos);
+            }
+            (Some(Value::Function(name, _)), _) => {
+                return self.call_function(&name, args, pos);
+            }
+            (_, Expr::Call(token)) if self.types.generics.functions.contains_key(&token.name) => {
+                let function = self.instance(token, &args)?;
+                if self.const_only && !function.is_const {
+                    return Err(CompileError::at(pos, format!("cannot call {} in const evaluation, it is not a const fn, at {}", function.name, pos.to_string())));
+                }
+                return self.run_function(&function, Frame::new(), args, pos);
+            }
+            (_, Expr::Call(token)) => {
+                return self.call_function(&token.name, args, pos);
+            }
+            _ => unreachable!()
+        }
+    }
+    // The instance of a generic function that a call goes to, the type arguments are either
+    // given or worked out from the values of the arguments. Instances that the program does
+    // not have already are made the first time they are called
+    fn instance(&mut self, token: &Call, args: &[Value]) -> Result<Rc<Function>, CompileError> {
+        let generic = &self.types.generics.functions[&token.name];
+        let type_args = if token.type_args.is_empty() {
+            let param_types: Vec<ValueType> = generic.params.iter().map(|(_, value_type)| value_type.clone()).collect();
+            let arg_types: Vec<Option<ValueType>> = args.iter().map(|arg| Some(arg.value_type())).collect();
+            let type_args = infer_type_args(&generic.type_params, &param_types, &arg_types);
+            Interpreter::known_type_args(type_args)
+        }
+        else {
+            token.type_args.clone()
+        };
+        let name = Instance::Function(token.name.clone(), type_args.clone()).to_string();
+        if let Some(function) = self.instances.get(&name) {
+            return Ok(function.clone());
+        }
+        let function = match self.functions.get(&name) {
+            Some(function) => Rc::new((*function).clone()),
+            None => Rc::new(instantiate_function(generic, &type_args))
+        };
+        self.instances.insert(name, function.clone());
+        return Ok(function);
+    }
+    // The type arguments that were worked out. One that the values say nothing about, like the
+    // return type of a closure, is _, which takes any value
+    fn known_type_args(type_args: Vec<Option<ValueType>>) -> Vec<ValueType> {
+        return type_args.into_iter().map(|type_arg| type_arg.unwrap_or(ValueType::Infer)).collect();
+    }
+    // The function or closure that callee is
+    fn eval_callee(&mut self, callee: &Expr, pos: SourcePos, frame: &mut Frame) -> Result<Value, CompileError> {
+        match self.eval_expr(callee, frame)? {
+            value @ (Value::Function(..) | Value::Closure(..)) => {
+                return Ok(value);
+            }
+            other => {
+                return Err(CompileError::at(pos, format!("cannot call {} at {}, a value of type {} is not a function", callee.to_string(), pos.to_string(), other.value_type().to_string())));
+            }
+        }
+    }
+    // Call a function, returns None if the function does not return anything
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>, pos: SourcePos) -> Result<Option<Value>, CompileError> {
+        let function = match self.functions.get(name) {
+            Some(function) => *function,
+            None if is_builtin_function(name) => {
+                return self.call_builtin(name, args, pos);
+            }
+            None => {
+                return Err(CompileError::at(pos, format!("unknown function {} at {}", name, pos.to_string())));
+            }
+        };
+        if self.const_only && !function.is_const {
+            return Err(CompileError::at(pos, format!("cannot call {} in const evaluation, it is not a const fn, at {}", name, pos.to_string())));
+        }
+        return self.run_function(function, Frame::new(), args, pos);
+    }
+    // A closure starts out with the variables it captured, the parameters without a type take
+    // whatever they are given
+    fn call_closure(&mut self, closure: &ClosureValue, args: Vec<Value>, pos: SourcePos) -> Result<Option<Value>, CompileError> {
+        let mut frame = Frame::new();
+        for (name, value) in closure.captured.iter() {
+            frame.define(name, value.clone());
+        }
+        return self.run_function(&closure.function, frame, args, pos);
+    }
+    fn run_function(&mut self, function: &Function, mut frame: Frame, args: Vec<Value>, pos: SourcePos) -> Result<Option<Value>, CompileError> {
+        let name = &function.name;
+        if function.params.len() != args.len() {
+            return Err(CompileError::at(pos, format!("function {} takes {} arguments but {} were given at {}", name, function.params.len(), args.len(), pos.to_string())));
+        }
+        if self.depth >= CALL_DEPTH_LIMIT {
+            return Err(CompileError::at(pos, format!("the interpreter went more than {} calls deep at {}, the recursion might never end", CALL_DEPTH_LIMIT, pos.to_string())));
+        }
+
+        for ((param, param_type), arg) in function.params.iter().zip(args) {
+            if !arg.value_type().can_be_used_as(param_type) {
+                return Err(CompileError::at(pos, format!("mismatched types in call to {} at {}: expected {}, found {}", name, pos.to_string(), param_type.to_string(), arg.value_type().to_string())));
+            }
+            frame.define(param, arg);
+        }
+
+        self.depth += 1;
+        let mut result = Ok(Flow::Next);
+        for expr in function.body.iter() {
+            result = self.exec_statement(expr, &mut frame);
+            if !matches!(result, Ok(Flow::Next)) {
+                break;
+            }
+        }
+        self.depth -= 1;
+
+        match (result?, &function.return_type) {
+            (Flow::Return(Some(value)), Some(return_type)) => {
+                if !value.value_type().can_be_used_as(return_type) {
+                    return Err(CompileError::at(self.pos, format!("mismatched types in return at {}: expected {}, found {}", self.pos.to_string(), return_type.to_string(), value.value_type().to_string())));
+                }
+                return Ok(Some(value));
+            }
+            // The value of |x| print(x) is nothing
+            (_, Some(ValueType::Infer)) => {
+                return Ok(None);
+            }
+            (Flow::Return(None), Some(return_type)) => {
+                return Err(CompileError::at(self.pos, format!("return without a value at {}, the function returns {}", self.pos.to_string(), return_type.to_string())));
+            }
+            (Flow::Next, Some(_)) => {
+                return Err(CompileError::at(function.pos, format!("function {} can reach the end of its body without returning a value, at {}", name, function.pos.to_string())));
+            }
+            (Flow::Return(Some(value)), None) => {
+                return Err(CompileError::at(self.pos, format!("cannot return a {} at {}, the function does not return anything", value.value_type().to_string(), self.pos.to_string())));
+            }
+            (_, None) => {
+                return Ok(None);
+            }
+        }
+    }
+    fn call_builtin(&mut self, name: &str, mut args: Vec<Value>, pos: SourcePos) -> Result<Option<Value>, CompileError> {
+        // The intrinsics only work on their arguments, so const fns can use them
+        if let Some((mode, op)) = find_intrinsic(name) {
+            return Ok(Some(self.call_intrinsic(name, mode, op, &args, pos)?));
+        }
+        if self.const_only {
+            return Err(CompileError::at(pos, format!("{} cannot be used in const evaluation, at {}", name, pos.to_string())));
+        }
+        if args.len() != 1 {
+            return Err(CompileError::at(pos, format!("function {} takes 1 arguments but {} were given at {}", name, args.len(), pos.to_string())));
+        }
+        let arg = args.pop().unwrap();
+        match (name, arg) {
+            ("alloc", value) => {
+                let ref_type = ValueType::Ref(Box::new(value.value_type()), true);
+                let cell = Rc::new(RefCell::new(value));
+                self.heap.push(cell.clone());
+                return Ok(Some(Value::Ref(Reference { target: Some((cell, vec!())), ref_type })));
+            }
+            ("free", Value::Ref(reference)) => {
+                if let Some((cell, path)) = &reference.target {
+                    if let Some(index) = self.heap.iter().position(|allocation| Rc::ptr_eq(allocation, cell)) {
+                        if path.is_empty() {
+                            self.freed.push(self.heap.swap_remove(index));
+                            return Ok(None);
+                        }
+                    }
+                }
+                let message = "free of memory that was not allocated with alloc, or that was already freed".to_string();
+                return Err(RuntimeError { message, pos }.to_compile_error());
+            }
+            ("print", Value::I32(value)) => {
+                write_output(&format!("{}\n", value));
+                return Ok(None);
+            }
+            ("print", Value::F64(value)) => {
+                write_output(&format!("{}\n", value));
+                return Ok(None);
+            }
+            (_, value) => {
+                return Err(CompileError::at(pos, format!("{} cannot be given a value of type {}, at {}", name, value.value_type().to_string(), pos.to_string())));
+            }
+        }
+    }
+    // wrapping_add(a, b), checked_add(a, b), saturating_add(a, b) and the same for the other
+    // operators that can overflow
+    fn call_intrinsic(&self, name: &str, mode: ArithmeticMode, op: &Operator, args: &[Value], pos: SourcePos) -> Result<Value, CompileError> {
+        let ints = match args {
+            [Value::I32(a), Value::I32(b)] => [*a, *b],
+            _ => {
+                return Err(CompileError::at(pos, format!("{} expects two i32 arguments, at {}", name, pos.to_string())));
+            }
+        };
+        let overflow = op.overflow.as_ref().unwrap();
+        let runtime_error = |message: &str| RuntimeError { message: message.to_string(), pos }.to_compile_error();
+        match mode {
+            ArithmeticMode::Wrapping => {
+                return (op.eval_i32)(&ints).map(Value::I32).map_err(runtime_error);
+            }
+            ArithmeticMode::Checked => {
+                let option = &self.types.enums[OPTION_TYPE];
+                match eval_exact(overflow, &ints).ok().and_then(|result| i32::try_from(result).ok()) {
+                    Some(result) => {
+                        let (tag, _) = option.get_variant("some").unwrap();
+                        return Ok(Value::Enum(OPTION_TYPE.to_string(), tag, vec!(Value::I32(result))));
+                    }
+                    None => {
+                        let (tag, _) = option.get_variant("none").unwrap();
+                        return Ok(Value::Enum(OPTION_TYPE.to_string(), tag, vec!()));
+                    }
+                }
+            }
+            ArithmeticMode::Saturating => {
+                // Shifting by more than 31 shifts by 31, and by less than 0 does not shift
+                let ints = if overflow.shifts { [ints[0], ints[1].clamp(0, 31)] } else { ints };
+                let result = eval_exact(overflow, &ints).map_err(runtime_error)?;
+                return Ok(Value::I32(result.clamp(i32::MIN as i64, i32::MAX as i64) as i32));
+            }
+        }
+    }
+}
+
+// Report the same runtime error as the code the JIT generates
+fn check_bounds(index: i32, len: usize, pos: SourcePos) -> Result<(), CompileError> {
+    if index < 0 || index as usize >= len {
+        let message = format!("index out of bounds: the len is {} but the index is {}", len, index);
+        return Err(RuntimeError { message, pos }.to_compile_error());
+    }
+    return Ok(());
+}
+
+// Work out an operator from the table in operators.rs on the values of its operands
+fn eval_operation(op: &Operator, args: &[Value], pos: SourcePos, overflow_checks: bool) -> Result<Value, CompileError> {
+    let arg_types: Vec<ValueType> = args.iter().map(|arg| arg.value_type()).collect();
+    operation_type(op, &arg_types, pos)?;
+    let ints: Vec<i32> = args.iter().filter_map(|arg| if let Value::I32(a) = arg { Some(*a) } else { None }).collect();
+    let floats: Vec<f64> = args.iter().filter_map(|arg| if let Value::F64(a) = arg { Some(*a) } else { None }).collect();
+    match (&op.overflow, op.operands) {
+        (Some(overflow), _) if overflow_checks && ints.len() == args.len() => {
+            let message = match eval_exact(overflow, &ints).map(i32::try_from) {
+                Ok(Ok(result)) => {
+                    return Ok(Value::I32(result));
+                }
+                Ok(Err(_)) => overflow.message,
+                Err(message) => message
+            };
+            return Err(RuntimeError { message: message.to_string(), pos }.to_compile_error());
+        }
+        _ if ints.len() == args.len() => {
+            return (op.eval_i32)(&ints).map(Value::I32).map_err(|message| RuntimeError { message: message.to_string(), pos }.to_compile_error());
+        }
+        (_, Operands::Numbers(eval_f64)) if floats.len() == args.len() => {
+            return Ok(Value::F64(eval_f64(&floats)));
+        }
+        _ => {
+            return Err(CompileError::at(pos, format!("cannot use {} on these operands at {}", op.symbol, pos.to_string())));
+        }
+    }
+}
+
+// Whether an expression only depends on literals, so that it can be worked out while compiling
+fn is_constant_expr(expr: &Expr) -> bool {
+    match expr {
+        Expr::NumberToken(..) | Expr::FloatToken(..) => {
+            return true;
+        }
+        Expr::Operation(token) => {
+            return is_constant_expr(&token.expr1) && is_constant_expr(&token.expr2);
+        }
+        Expr::Unary(token) => {
+            return is_constant_expr(&token.expr);
+        }
+        Expr::ArrayLiteral(token) => {
+            return token.elements.iter().all(is_constant_expr);
+        }
+        Expr::ArrayRepeat(token) => {
+            return is_constant_expr(&token.value);
+        }
+        Expr::StructLiteral(token) => {
+            return token.fields.iter().all(|(_, value)| is_constant_expr(value));
+        }
+        Expr::EnumLiteral(token) => {
+            return token.args.iter().all(is_constant_expr);
+        }
+        _ => {
+            return false;
+        }
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    fn check_not_const(&self, name: &str) -> Result<(), CompileError> {
+        if self.consts.contains_key(name) {
+            return Err(CompileError::at(self.pos, format!("{} is a const and cannot also be a variable, at {}", name, self.pos.to_string())));
+        }
+        return Ok(());
+    }
+    fn fold_statement(&mut self, expr: &Expr) -> Result<Expr, CompileError> {
+        match expr {
+            Expr::Let(token) => {
+                self.check_not_const(&token.name)?;
+                let value = match &token.value {
+                    Some(value) => Some(Box::new(self.fold_expr(value)?)),
+                    None => None
+                };
+                return Ok(Expr::Let(Let { value, ..token.clone() }));
+            }
+            Expr::Operation(token) if matches!(token.operator.op_type, OperatorType::Assign(..)) => {
+                // The place assigned to is left alone, apart from the indices in it
+                let mut root = token.expr1.as_ref();
+                while let Expr::Index(Index { array: inner, .. }) | Expr::Field(Field { expr: inner, .. }) = root {
+                    root = inner;
+                }
+                if let Expr::IdentifierToken(ident) = root {
+                    if self.consts.contains_key(&ident.text) {
+                        return Err(CompileError::at(ident.pos, format!("cannot assign to const {}", ident.text)));
+                    }
+                }
+                let place = self.fold_place(&token.expr1)?;
+                let value = self.fold_expr(&token.expr2)?;
+                return Ok(Expr::Operation(Operation { expr1: Box::new(place), operator: token.operator.clone(), expr2: Box::new(value), pos: token.pos }));
+            }
+            Expr::Match(token) => {
+                self.pos = token.pos;
+                let value = self.fold_expr(&token.value)?;
+                let mut arms: Vec<MatchArm> = vec!();
+                for arm in token.arms.iter() {
+                    self.pos = arm.pos;
+                    if let MatchPattern::Variant(_, _, bindings) = &arm.pattern {
+                        for binding in bindings.iter() {
+                            self.check_not_const(binding)?;
+                        }
+                    }
+                    let mut body: Vec<Expr> = vec!();
+                    for expr in arm.body.iter() {
+                        body.push(self.fold_statement(expr)?);
+                    }
+                    arms.push(MatchArm { pattern: arm.pattern.clone(), body, pos: arm.pos });
+                }
+                return Ok(Expr::Match(Match { value: Box::new(value), arms, pos: token.pos }));
+            }
+            Expr::If(token) => {
+                self.pos = token.pos;
+                let condition = self.fold_expr(&token.condition)?;
+                let then_body = self.fold_body(&token.then_body)?;
+                let else_body = match &token.else_body {
+                    Some(else_body) => Some(self.fold_body(else_body)?),
+                    None => None
+                };
+                return Ok(Expr::If(If { condition: Box::new(condition), then_body, else_body, pos: token.pos }));
+            }
+            Expr::While(token) => {
+                self.pos = token.pos;
+                let condition = self.fold_expr(&token.condition)?;
+                let body = self.fold_body(&token.body)?;
+                return Ok(Expr::While(While { condition: Box::new(condition), body, pos: token.pos }));
+            }
+            Expr::Return(token) => {
+                self.pos = token.pos;
+                let value = match &token.value {
+                    Some(value) => Some(Box::new(self.fold_statement(value)?)),
+                    None => None
+                };
+                return Ok(Expr::Return(Return { value, ..token.clone() }));
+            }
+            Expr::Tail(token) => {
+                self.pos = token.pos;
+                let value = self.fold_statement(&token.value)?;
+                return Ok(Expr::Tail(Tail { value: Box::new(value), pos: token.pos }));
+            }
+            _ => {
+                return self.fold_expr(expr);
+            }
+        }
+    }
+    fn fold_body(&mut self, body: &[Expr]) -> Result<Vec<Expr>, CompileError> {
+        let mut folded: Vec<Expr> = vec!();
+        for expr in body.iter() {
+            folded.push(self.fold_statement(expr)?);
+        }
+        return Ok(folded);
+    }
+    fn fold_place(&mut self, expr: &Expr) -> Result<Expr, CompileError> {
+        match expr {
+            Expr::Index(token) => {
+                let array = self.fold_place(&token.array)?;
+                let index = self.fold_expr(&token.index)?;
+                return Ok(Expr::Index(Index { array: Box::new(array), index: Box::new(index), pos: token.pos }));
+            }
+            Expr::Field(token) => {
+                let inner = self.fold_place(&token.expr)?;
+                return Ok(Expr::Field(Field { expr: Box::new(inner), ..token.clone() }));
+            }
+            _ => {
+                return self.fold_expr(expr);
+            }
+        }
+    }
+    fn fold_exprs(&mut self, exprs: &[Expr]) -> Result<Vec<Expr>, CompileError> {
+        let mut folded: Vec<Expr> = vec!();
+        for expr in exprs {
+            folded.push(self.fold_expr(expr)?);
+        }
+        return Ok(folded);
+    }
+    // Put the values of consts in place of their names, and the results of calls to const fns
+    // in place of the calls when every argument is known
+    fn fold_expr(&mut self, expr: &Expr) -> Result<Expr, CompileError> {
+        match expr {
+            Expr::IdentifierToken(token) => {
+                if let Some(value) = self.consts.get(&token.text) {
+                    return value.to_expr(self.types, self.pos);
+                }
+                return Ok(expr.clone());
+            }
+            Expr::Operation(token) => {
+                let expr1 = self.fold_expr(&token.expr1)?;
+                let expr2 = self.fold_expr(&token.expr2)?;
+                return Ok(Expr::Operation(Operation { expr1: Box::new(expr1), operator: token.operator.clone(), expr2: Box::new(expr2), pos: token.pos }));
+            }
+            Expr::ArrayLiteral(token) => {
+                return Ok(Expr::ArrayLiteral(ArrayLiteral { elements: self.fold_exprs(&token.elements)? }));
+            }
+            Expr::ArrayRepeat(token) => {
+                let value = self.fold_expr(&token.value)?;
+                return Ok(Expr::ArrayRepeat(ArrayRepeat { value: Box::new(value), count: token.count }));
+            }
+            Expr::Index(token) => {
+                self.pos = token.pos;
+                let array = self.fold_expr(&token.array)?;
+                let index = self.fold_expr(&token.index)?;
+                return Ok(Expr::Index(Index { array: Box::new(array), index: Box::new(index), pos: token.pos }));
+            }
+            Expr::StructLiteral(token) => {
+                self.pos = token.pos;
+                let mut fields: Vec<(String, Expr)> = vec!();
+                for (name, value) in token.fields.iter() {
+                    fields.push((name.clone(), self.fold_expr(value)?));
+                }
+                return Ok(Expr::StructLiteral(StructLiteral { name: token.name.clone(), fields, pos: token.pos }));
+            }
+            Expr::Field(token) => {
+                self.pos = token.pos;
+                let inner = self.fold_expr(&token.expr)?;
+                return Ok(Expr::Field(Field { expr: Box::new(inner), ..token.clone() }));
+            }
+            Expr::Call(token) => {
+                self.pos = token.pos;
+                // A call to a const that holds a function pointer is a call to that function
+                let name = match self.consts.get(&token.name) {
+                    Some(Value::Function(name, _)) => name.clone(),
+                    _ => token.name.clone()
+                };
+                let args = self.fold_exprs(&token.args)?;
+                let is_const_fn = self.functions.get(&name).is_some_and(|function| function.is_const);
+                if is_const_fn && args.iter().all(is_constant_expr) {
+                    self.steps = 0;
+                    let mut values: Vec<Value> = vec!();
+                    for arg in args.iter() {
+                        values.push(self.eval_expr(arg, &mut Frame::new())?);
+                    }
+                    if let Some(value) = self.call_function(&name, values, token.pos)? {
+                        return value.to_expr(self.types, token.pos);
+                    }
+                }
+                return Ok(Expr::Call(Call { name, type_args: token.type_args.clone(), args, pos: token.pos }));
+            }
+            Expr::IndirectCall(token) => {
+                self.pos = token.pos;
+                let callee = self.fold_expr(&token.callee)?;
+                let args = self.fold_exprs(&token.args)?;
+                return Ok(Expr::IndirectCall(IndirectCall { callee: Box::new(callee), args, pos: token.pos }));
+            }
+            Expr::AddressOf(token) => {
+                self.pos = token.pos;
+                let inner = self.fold_expr(&token.expr)?;
+                return Ok(Expr::AddressOf(AddressOf { expr: Box::new(inner), ..token.clone() }));
+            }
+            Expr::Deref(token) => {
+                self.pos = token.pos;
+                let inner = self.fold_expr(&token.expr)?;
+                return Ok(Expr::Deref(Deref { expr: Box::new(inner), pos: token.pos }));
+            }
+            Expr::Unary(token) => {
+                let inner = self.fold_expr(&token.expr)?;
+                return Ok(Expr::Unary(Unary { expr: Box::new(inner), ..token.clone() }));
+            }
+            Expr::EnumLiteral(token) => {
+                self.pos = token.pos;
+                let args = self.fold_exprs(&token.args)?;
+                return Ok(Expr::EnumLiteral(EnumLiteral { args, ..token.clone() }));
+            }
+            Expr::Closure(token) => {
+                self.pos = token.pos;
+                for (param, _) in token.params.iter() {
+                    self.check_not_const(param)?;
+                }
+                let mut body: Vec<Expr> = vec!();
+                for expr in token.body.iter() {
+                    body.push(self.fold_statement(expr)?);
+                }
+                return Ok(Expr::Closure(Closure { body, ..token.clone() }));
+            }
+            _ => {
+                return Ok(expr.clone());
+            }
+        }
+    }
+}
+
+// Work out every const while compiling, and every call to a const fn where all arguments are
+// known. Their results are put in the tree as literals, and the const declarations are removed
+pub fn fold_constants(expr_tree: &[Expr], types: &TypeTable, overflow_checks: bool) -> Result<Vec<Expr>, CompileError> {
+    // Without any consts there is nothing to work out, and no need for a thread to do it on
+    let mut has_consts = false;
+    for expr in expr_tree {
+        walk_expr(expr, &mut |expr| has_consts |= matches!(expr, Expr::ConstDecl(..)) || matches!(expr, Expr::Function(token) if token.is_const));
+    }
+    if !has_consts {
+        return Ok(expr_tree.to_vec());
+    }
+    return with_interpreter_stack(|| {
+        let mut interpreter = Interpreter::new(types, expr_tree, true);
+        interpreter.overflow_checks = overflow_checks;
+        interpreter.eval_consts(expr_tree)?;
+
+        let mut folded: Vec<Expr> = vec!();
+        for expr in expr_tree {
+            match expr {
+                Expr::ConstDecl(..) => {}
+                Expr::Function(token) => {
+                    interpreter.pos = token.pos;
+                    for (param, _) in token.params.iter() {
+                        interpreter.check_not_const(param)?;
+                    }
+                    let mut body: Vec<Expr> = vec!();
+                    for expr in token.body.iter() {
+                        body.push(interpreter.fold_statement(expr)?);
+                    }
+                    folded.push(Expr::Function(Function { body, ..token.clone() }));
+                }
+                _ => {
+                    folded.push(interpreter.fold_statement(expr)?);
+                }
+            }
+        }
+        return Ok(folded);
+    });
+}
+
+// What running a program gave, the value it returned or the runtime error it stopped with,
+// and everything it printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramOutcome {
+    pub result: Result<i32, String>,
+    pub output: String
+}
+
+impl ProgramOutcome {
+    pub fn to_string(&self) -> String {
+        let result = match &self.result {
+            Ok(value) => format!("return: {}", value),
+            Err(err) => err.clone()
+        };
+        return format!("{}\n{}", self.output, result);
+    }
+}
+
+// Run a program with the interpreter instead of the JIT
+pub fn interpret_program(expr_tree: &[Expr]) -> Result<ProgramOutcome, CompileError> {
+    let (expr_tree, generics) = instantiate_generics(expr_tree, &[])?;
+    let mut types = compute_type_layouts(&expr_tree)?;
+    types.generics = generics;
+    semantic_pass(&expr_tree, &types)?;
+    let (result, output) = with_interpreter_stack(|| capture_output(|| Interpreter::new(&types, &expr_tree, false).run_main(&expr_tree)));
+    return Ok(ProgramOutcome { result: result.map_err(|err| err.to_string()), output });
+}
+
+// Run a program with both the JIT and the interpreter, it is an error if they do not agree
+// on what the program returns or prints
+pub fn run_differential(my_str: String) -> Result<ProgramOutcome, String> {
+    let mut token_list = tokenize(my_str.clone());
+    let tree = generate_tree(&mut token_list).map_err(|err| err.to_string())?;
+    let jit_outcome = compile_code(my_str).map_err(|err| err.to_string())?;
+
+    let interpreter_outcome = interpret_program(&tree).map_err(|err| err.to_string())?;
+    // The JIT has no limit on how deep calls go, so when the interpreter gives up on deep
+    // recursion there is nothing to compare but what it printed before that
+    if interpreter_outcome.result.as_ref().is_err_and(|err| is_call_depth_error(err)) && jit_outcome.output.starts_with(&interpreter_outcome.output) {
+        return Ok(jit_outcome);
+    }
+    if jit_outcome != interpreter_outcome {
+        return Err(format!("the JIT and the interpreter do not agree\n-- jit:\n{}\n-- interpreter:\n{}", jit_outcome.to_string(), interpreter_outcome.to_string()));
+    }
+    return Ok(jit_outcome);
+}
@@ -1,15 +1,52 @@
 use cranelift::codegen::CodegenError;
 use cranelift::codegen::verifier::{VerifierErrors};
+use cranelift::codegen::isa;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
 use cranelift_module::{DataContext, FuncId, Linkage, Module, ModuleError};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::alloc::Layout;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use target_lexicon::Triple;
+use unicode_xid::UnicodeXID;
+use crate::interpreter::{fold_constants, ProgramOutcome};
+use crate::printer::print_program;
+use crate::cst::{Cst, is_doc_comment};
+use crate::grammar::parse_program;
+use crate::operators::*;
+use crate::generics::*;
 use core::mem;
 
+#[derive(Clone)]
 pub enum Expr {
     Operation(Operation),
-    IdentifierToken(IdentifierToken),
+    IdentifierToken(Identifier),
     NumberToken(NumberToken),
+    FloatToken(FloatToken),
+    ArrayLiteral(ArrayLiteral),
+    ArrayRepeat(ArrayRepeat),
+    Index(Index),
+    StructLiteral(StructLiteral),
+    Field(Field),
+    Call(Call),
+    IndirectCall(IndirectCall),
+    AddressOf(AddressOf),
+    Deref(Deref),
+    Unary(Unary),
+    EnumLiteral(EnumLiteral),
+    Closure(Closure),
+    Let(Let),
+    StructDecl(StructDecl),
+    EnumDecl(EnumDecl),
+    ConstDecl(ConstDecl),
+    Function(Function),
+    Match(Match),
+    If(If),
+    While(While),
+    Return(Return),
+    Tail(Tail),
     Empty
 }
 
@@ -20,398 +57,3780 @@ impl Expr {
                 return token.text.clone();
             }
             Expr::NumberToken(token) => {
-                return token.num.to_string();
+                return token.text.clone().unwrap_or_else(|| token.num.to_string());
+            }
+            Expr::FloatToken(token) => {
+                return format!("{:?}", token.num);
             }
             Expr::Operation(token) => {
-                let arg1 = token.expr1.to_string();
-                let arg2 = token.expr2.to_string();
-                let operator = "Operator".to_string();
-                let mut return_string = "(".to_string();
-                return_string += arg1.as_str();
-                return_string += " ";
-                return_string += operator.as_str();
-                return_string += " ";
-                return_string += arg2.as_str();
-                return_string += ")";
-
+                let arg1 = operand_to_string(&token.expr1, &token.operator.op_type, false);
+                let arg2 = operand_to_string(&token.expr2, &token.operator.op_type, true);
+                return format!("{} {} {}", arg1, token.operator.to_string(), arg2);
+            }
+            Expr::ArrayLiteral(token) => {
+                let elements: Vec<String> = token.elements.iter().map(|e| e.to_string()).collect();
+                return "[".to_string() + elements.join(", ").as_str() + "]";
+            }
+            Expr::ArrayRepeat(token) => {
+                return format!("[{}; {}]", token.value.to_string(), token.count);
+            }
+            Expr::Index(token) => {
+                return format!("{}[{}]", token.array.to_string(), token.index.to_string());
+            }
+            Expr::StructLiteral(token) => {
+                let fields: Vec<String> = token.fields.iter()
+                    .map(|(name, value)| format!("{}: {}", name, value.to_string()))
+                    .collect();
+                return format!("{} {{ {} }}", token.name, fields.join(", "));
+            }
+            Expr::Field(token) => {
+                return format!("{}.{}", token.expr.to_string(), token.field);
+            }
+            Expr::Call(token) => {
+                let args: Vec<String> = token.args.iter().map(|e| e.to_string()).collect();
+                if !token.type_args.is_empty() {
+                    let type_args: Vec<String> = token.type_args.iter().map(|value_type| value_type.to_string()).collect();
+                    return format!("{}::<{}>({})", token.name, type_args.join(", "), args.join(", "));
+                }
+                return format!("{}({})", token.name, args.join(", "));
+            }
+            Expr::IndirectCall(token) => {
+                let args: Vec<String> = token.args.iter().map(|e| e.to_string()).collect();
+                match token.callee.as_ref() {
+                    Expr::Index(..) | Expr::Field(..) | Expr::Call(..) | Expr::IndirectCall(..) => {
+                        return format!("{}({})", token.callee.to_string(), args.join(", "));
+                    }
+                    _ => {
+                        return format!("({})({})", token.callee.to_string(), args.join(", "));
+                    }
+                }
+            }
+            Expr::AddressOf(token) => {
+                if token.mutable {
+                    return format!("&mut {}", token.expr.to_string());
+                }
+                return format!("&{}", token.expr.to_string());
+            }
+            Expr::Deref(token) => {
+                return format!("*{}", token.expr.to_string());
+            }
+            Expr::Unary(token) => {
+                if let Expr::Operation(..) = token.expr.as_ref() {
+                    return format!("{}({})", token.operator.symbol, token.expr.to_string());
+                }
+                return format!("{}{}", token.operator.symbol, token.expr.to_string());
+            }
+            Expr::EnumLiteral(token) => {
+                if token.args.is_empty() {
+                    return format!("{}::{}", token.enum_name, token.variant);
+                }
+                let args: Vec<String> = token.args.iter().map(|e| e.to_string()).collect();
+                return format!("{}::{}({})", token.enum_name, token.variant, args.join(", "));
+            }
+            Expr::Closure(token) => {
+                let params: Vec<String> = token.params.iter()
+                    .map(|(name, value_type)| {
+                        if *value_type == ValueType::Infer {
+                            return name.clone();
+                        }
+                        return format!("{}: {}", name, value_type.to_string());
+                    })
+                    .collect();
+                let params = format!("|{}|", params.join(", "));
+                if !token.is_block {
+                    return format!("{} {}", params, token.body[0].to_string());
+                }
+                let body: Vec<String> = token.body.iter().map(|e| e.to_string()).collect();
+                match &token.return_type {
+                    Some(return_type) => {
+                        return format!("{} -> {} {{ {} }}", params, return_type.to_string(), body.join("; "));
+                    }
+                    None => {
+                        return format!("{} {{ {} }}", params, body.join("; "));
+                    }
+                }
+            }
+            Expr::Let(token) => {
+                let mut return_string = "let ".to_string() + token.name.as_str();
+                if let Some(value_type) = &token.value_type {
+                    return_string += ": ";
+                    return_string += value_type.to_string().as_str();
+                }
+                if let Some(value) = &token.value {
+                    return_string += " = ";
+                    return_string += value.to_string().as_str();
+                }
+                return return_string;
+            }
+            Expr::StructDecl(token) => {
+                let fields: Vec<String> = token.fields.iter()
+                    .map(|(name, value_type)| format!("{}: {}", name, value_type.to_string()))
+                    .collect();
+                return format!("struct {}{} {{ {} }}", token.name, type_params_to_string(&token.type_params), fields.join(", "));
+            }
+            Expr::EnumDecl(token) => {
+                let variants: Vec<String> = token.variants.iter()
+                    .map(|(name, payload)| {
+                        if payload.is_empty() {
+                            return name.clone();
+                        }
+                        let payload: Vec<String> = payload.iter().map(|value_type| value_type.to_string()).collect();
+                        return format!("{}({})", name, payload.join(", "));
+                    })
+                    .collect();
+                return format!("enum {} {{ {} }}", token.name, variants.join(", "));
+            }
+            Expr::ConstDecl(token) => {
+                return format!("const {}: {} = {}", token.name, token.value_type.to_string(), token.value.to_string());
+            }
+            Expr::Function(token) => {
+                let params: Vec<String> = token.params.iter()
+                    .map(|(name, value_type)| format!("{}: {}", name, value_type.to_string()))
+                    .collect();
+                let mut return_string = format!("fn {}{}({})", token.name, type_params_to_string(&token.type_params), params.join(", "));
+                if token.is_const {
+                    return_string = "const ".to_string() + return_string.as_str();
+                }
+                if let Some(return_type) = &token.return_type {
+                    return_string += " -> ";
+                    return_string += return_type.to_string().as_str();
+                }
+                let body: Vec<String> = token.body.iter().map(|e| e.to_string()).collect();
+                return_string += " { ";
+                return_string += body.join("; ").as_str();
+                return_string += " }";
+                return return_string;
+            }
+            Expr::Match(token) => {
+                let arms: Vec<String> = token.arms.iter()
+                    .map(|arm| {
+                        let body: Vec<String> = arm.body.iter().map(|e| e.to_string()).collect();
+                        return format!("{} => {{ {} }}", arm.pattern.to_string(), body.join("; "));
+                    })
+                    .collect();
+                return format!("match {} {{ {} }}", token.value.to_string(), arms.join(" "));
+            }
+            Expr::If(token) => {
+                let then_body: Vec<String> = token.then_body.iter().map(|e| e.to_string()).collect();
+                let mut return_string = format!("if {} {{ {} }}", token.condition.to_string(), then_body.join("; "));
+                match token.else_body.as_deref() {
+                    Some([else_if @ Expr::If(..)]) => {
+                        return_string += &format!(" else {}", else_if.to_string());
+                    }
+                    Some(else_body) => {
+                        let else_body: Vec<String> = else_body.iter().map(|e| e.to_string()).collect();
+                        return_string += &format!(" else {{ {} }}", else_body.join("; "));
+                    }
+                    None => {}
+                }
                 return return_string;
             }
+            Expr::While(token) => {
+                let body: Vec<String> = token.body.iter().map(|e| e.to_string()).collect();
+                return format!("while {} {{ {} }}", token.condition.to_string(), body.join("; "));
+            }
+            Expr::Return(token) => {
+                match &token.value {
+                    Some(value) if token.is_tail => {
+                        return value.to_string();
+                    }
+                    Some(value) => {
+                        return format!("return {}", value.to_string());
+                    }
+                    None => {
+                        return "return".to_string();
+                    }
+                }
+            }
+            Expr::Tail(token) => {
+                return token.value.to_string();
+            }
             Expr::Empty => {
                 return "Empty".to_string();
             }
         }
     }
 }
+// <T, U> after the name of a generic declaration, nothing after other declarations
+pub fn type_params_to_string(type_params: &[String]) -> String {
+    if type_params.is_empty() {
+        return String::new();
+    }
+    return format!("<{}>", type_params.join(", "));
+}
+
+// Print an operand of an operator, with parentheses only if they are needed to keep the same tree
+fn operand_to_string(expr: &Expr, parent: &OperatorType, is_right: bool) -> String {
+    if let Expr::Operation(token) = expr {
+        let (inner, outer) = (token.operator.op_type.precedence(), parent.precedence());
+        if inner > outer || (inner == outer && is_right != parent.is_right_associative()) {
+            return format!("({})", expr.to_string());
+        }
+    }
+    return expr.to_string();
+}
+
+// A variable used in an expression
+#[derive(Clone)]
+pub struct Identifier {
+    pub text: String,
+    pub pos: SourcePos
+}
+
+// expr1 operator expr2, pos is where the operator is
+#[derive(Clone)]
 pub struct Operation {
-    expr1: Box<Expr>,
-    operator: OperatorToken,
-    expr2: Box<Expr>
+    pub expr1: Box<Expr>,
+    pub operator: OperatorToken,
+    pub expr2: Box<Expr>,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub enum Token {
-    IdentifierToken(IdentifierToken),
-    NumberToken(NumberToken),
-    OperatorToken(OperatorToken),
-    EndExpr,
+// [a, b, c]
+#[derive(Clone)]
+pub struct ArrayLiteral {
+    pub elements: Vec<Expr>
 }
 
-impl Token {
-    pub fn to_string(&self) -> String {
-        match self {
-            Token::IdentifierToken(_token) => {
-                return "Identifier".to_string();
-            }
-            Token::NumberToken(_token) => {
-                return "Number".to_string();
-            }
-            Token::OperatorToken(_token) => {
-                return "Operator".to_string();
-            }
-            Token::EndExpr => {
-                return "EndExpr".to_string();
-            }
-        }
-    }
+// [value; count]
+#[derive(Clone)]
+pub struct ArrayRepeat {
+    pub value: Box<Expr>,
+    pub count: usize
 }
 
-#[derive(Debug, Clone)]
-pub struct IdentifierToken {
-    pub text: String
+// array[index], pos is where the indexing happens so that a failed bounds check can report it
+#[derive(Clone)]
+pub struct Index {
+    pub array: Box<Expr>,
+    pub index: Box<Expr>,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub struct NumberToken {
-    pub num: i32
+// name { field: value, ... }
+#[derive(Clone)]
+pub struct StructLiteral {
+    pub name: String,
+    pub fields: Vec<(String, Expr)>,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub enum OpType0 {
-    Div,
-    Mul,
+// expr.field
+#[derive(Clone)]
+pub struct Field {
+    pub expr: Box<Expr>,
+    pub field: String,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub enum OpType1 {
-    Add,
-    Sub,
+// name(args), when name is a variable the call goes to the function it points to. A call to a
+// generic function can give its type arguments like name::<i32>(args), see generics.rs
+#[derive(Clone)]
+pub struct Call {
+    pub name: String,
+    pub type_args: Vec<ValueType>,
+    pub args: Vec<Expr>,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub enum OpType2 {
-    Eq,
-    AddEq,
-    SubEq
+// callee(args), a call through a function pointer like table[i](x) or s.callback(x)
+#[derive(Clone)]
+pub struct IndirectCall {
+    pub callee: Box<Expr>,
+    pub args: Vec<Expr>,
+    pub pos: SourcePos
 }
 
+// &expr or &mut expr
+#[derive(Clone)]
+pub struct AddressOf {
+    pub expr: Box<Expr>,
+    pub mutable: bool,
+    pub pos: SourcePos
+}
 
-#[derive(Debug, Clone)]
-pub enum OperatorType {
-    OpType0(OpType0),
-    OpType1(OpType1),
-    OpType2(OpType2)
+// *expr
+#[derive(Clone)]
+pub struct Deref {
+    pub expr: Box<Expr>,
+    pub pos: SourcePos
 }
 
-impl OperatorType {
-    pub fn type_number(&self) -> usize {
-        match self {
-            OperatorType::OpType0(..) => {
-                return 0;
-            }
-            OperatorType::OpType1(..) => {
-                return 1;
-            }
-            OperatorType::OpType2(..) => {
-                return 2;
-            }
-        }
-    }
+// A prefix operator and its operand, like ~expr
+#[derive(Clone)]
+pub struct Unary {
+    pub operator: &'static Operator,
+    pub expr: Box<Expr>,
+    pub pos: SourcePos
 }
 
-#[derive(Debug, Clone)]
-pub struct OperatorToken {
-    pub op_type: OperatorType
+// |param, param: value_type| value, or |params| -> return_type { body }. A parameter without a
+// type is ValueType::Infer until the type is worked out from where the closure is used, and so
+// is the return type of |params| value, which is the type of the value. The body of that form is
+// a single tail Return. Closures copy the variables they use when they are created
+#[derive(Clone)]
+pub struct Closure {
+    pub params: Vec<(String, ValueType)>,
+    pub return_type: Option<ValueType>,
+    pub body: Vec<Expr>,
+    pub is_block: bool,
+    pub pos: SourcePos
 }
 
-pub fn is_end_of_expression(my_str: String) -> Option<Token> {
-    if my_str.len() == 0 {
-        return None;
-    }
-    match my_str.as_str() {
-        ";" => {
-            return Some(Token::EndExpr);
-        }
-        _ => {
-            return None;
-        }
-    }
-    
+// let name: value_type = value, or let name: value_type without a value. The variable has to be
+// assigned before it is read then, see check_definite_assignment
+#[derive(Clone)]
+pub struct Let {
+    pub name: String,
+    pub value_type: Option<ValueType>,
+    pub value: Option<Box<Expr>>,
+    pub pos: SourcePos
 }
 
-pub fn is_number(my_str: String) -> Option<NumberToken> {
-    if my_str.len() == 0 {
-        return None;
-    }
-    let mut iter_count = 0;
-    for i in my_str.chars().into_iter() {
-        if iter_count == 0 && i == '-' {
-            continue;
-        }
-        match i {
-            '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
+// struct name { field: value_type, ... }, or struct name<T, ...> { ... } for a generic struct
+#[derive(Clone)]
+pub struct StructDecl {
+    pub name: String,
+    pub type_params: Vec<String>,
+    pub fields: Vec<(String, ValueType)>,
+    pub doc: Option<String>,
+    pub pos: SourcePos
+}
+
+// enum name { Variant, Variant(value_type, ...), ... }
+#[derive(Clone)]
+pub struct EnumDecl {
+    pub name: String,
+    pub variants: Vec<(String, Vec<ValueType>)>,
+    pub doc: Option<String>,
+    pub pos: SourcePos
+}
+
+// enum_name::variant(args), the arguments are left out for variants without a payload
+#[derive(Clone)]
+pub struct EnumLiteral {
+    pub enum_name: String,
+    pub variant: String,
+    pub args: Vec<Expr>,
+    pub pos: SourcePos
+}
+
+// match value { pattern => { body } ... }
+#[derive(Clone)]
+pub struct Match {
+    pub value: Box<Expr>,
+    pub arms: Vec<MatchArm>,
+    pub pos: SourcePos
+}
+
+#[derive(Clone)]
+pub struct MatchArm {
+    pub pattern: MatchPattern,
+    pub body: Vec<Expr>,
+    pub pos: SourcePos
+}
+
+#[derive(Clone)]
+pub enum MatchPattern {
+    // enum_name::variant(a, b), a binding that is _ is ignored
+    Variant(String, String, Vec<String>),
+    // _ matches everything
+    Wildcard
+}
+
+impl MatchPattern {
+    pub fn to_string(&self) -> String {
+        match self {
+            MatchPattern::Variant(enum_name, variant, bindings) if bindings.is_empty() => {
+                return format!("{}::{}", enum_name, variant);
             }
-            _ => {
-                return None;
+            MatchPattern::Variant(enum_name, variant, bindings) => {
+                return format!("{}::{}({})", enum_name, variant, bindings.join(", "));
+            }
+            MatchPattern::Wildcard => {
+                return "_".to_string();
             }
         }
-        iter_count += 1;
     }
-    return Some(NumberToken{num: my_str.parse::<i32>().unwrap()});
 }
 
-pub fn is_identifier(my_str: String) -> Option<IdentifierToken> {
-    if my_str.len() == 0 {
-        return None;
-    }
-    let mut iter_count = 0;
-    for i in my_str.chars().into_iter() {
-        if "123456789".find(i).is_some() {
-            if iter_count == 0 {
-                return None;
-            }
-        }
-        else if "abcdefghijklmnopqrstuvwxyz_".find(i).is_some() {
-        }
-        else {
-            return None;
-        }
-        iter_count += 1;
-    }
-    return Some(IdentifierToken{text: my_str});
+// if condition { then_body } else { else_body }. The condition is an i32 and anything but 0 is
+// true. else if is an else body that is just another If
+#[derive(Clone)]
+pub struct If {
+    pub condition: Box<Expr>,
+    pub then_body: Vec<Expr>,
+    pub else_body: Option<Vec<Expr>>,
+    pub pos: SourcePos
 }
 
-pub fn is_operator(my_str: String) -> Option<OperatorToken> {
-    let op_type: OperatorType;
-    match my_str.as_str() {
-        "+=" => { op_type = OperatorType::OpType2(OpType2::AddEq); }
-        "-=" => { op_type = OperatorType::OpType2(OpType2::AddEq); }
-        "=" => { op_type = OperatorType::OpType2(OpType2::Eq); }
-        "+" => { op_type = OperatorType::OpType1(OpType1::Add); }
-        "-" => { op_type = OperatorType::OpType1(OpType1::Sub); }
-        "*" => { op_type = OperatorType::OpType0(OpType0::Mul); }
-        "/" => { op_type = OperatorType::OpType0(OpType0::Div); }
-        _ => { return None; }
-    }
-    return Some(OperatorToken{op_type: op_type});
+// while condition { body }, the body runs again for as long as the condition is not 0
+#[derive(Clone)]
+pub struct While {
+    pub condition: Box<Expr>,
+    pub body: Vec<Expr>,
+    pub pos: SourcePos
 }
 
-/// The basic JIT class.
-pub struct JIT {
-    /// The function builder context, which is reused across multiple
-    /// FunctionBuilder instances.
-    builder_context: FunctionBuilderContext,
+// const name: value_type = value, the value is worked out while compiling
+#[derive(Clone)]
+pub struct ConstDecl {
+    pub name: String,
+    pub value_type: ValueType,
+    pub value: Box<Expr>,
+    pub doc: Option<String>,
+    pub pos: SourcePos
+}
 
-    /// The main Cranelift context, which holds the state for codegen. Cranelift
-    /// separates this from `Module` to allow for parallel compilation, with a
-    /// context per thread, though this isn't in the simple demo here.
-    ctx: codegen::Context,
+// The text of the /// comments in front of a declaration, one line each, is kept in its doc
 
-    /// The data context, which is to data objects what `ctx` is to functions.
-    _data_ctx: DataContext,
+// fn name(param: value_type, ...) -> return_type { body }
+// The function returns with return statements, or with the expression at the end of its body
+// that has no ; after it, just like the program itself. A const fn can also be run while compiling.
+// A generic function, fn name<T, ...>(...), is compiled once for every list of types it is used with
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub type_params: Vec<String>,
+    pub is_const: bool,
+    pub params: Vec<(String, ValueType)>,
+    pub return_type: Option<ValueType>,
+    pub body: Vec<Expr>,
+    pub doc: Option<String>,
+    pub pos: SourcePos
+}
 
-    /// The module, with the jit backend, which manages the JIT'd
-    /// functions.
-    module: JITModule,
+impl Function {
+    // The type of a pointer to the function
+    pub fn fn_type(&self) -> ValueType {
+        let params: Vec<ValueType> = self.params.iter().map(|(_, value_type)| value_type.clone()).collect();
+        return ValueType::Fn(params, self.return_type.clone().map(Box::new));
+    }
 }
 
-impl Default for JIT {
-    fn default() -> Self {
-        let mut builder = JITBuilder::new(cranelift_module::default_libcall_names());
+// return value, or just return in a function that does not return anything. is_tail is set
+// for the expression at the end of a function, see lower_tail_expressions
+#[derive(Clone)]
+pub struct Return {
+    pub value: Option<Box<Expr>>,
+    pub is_tail: bool,
+    pub pos: SourcePos
+}
 
-        // Register hello world print function.
-        let print_addr = print_hello_world as *const u8;
-        builder.symbol("print_hello_world", print_addr);
+// An expression at the end of a block with no ; after it, the value of the block. Tails that
+// are not at the end of a function are left as a Tail, their value is not used
+#[derive(Clone)]
+pub struct Tail {
+    pub value: Box<Expr>,
+    pub pos: SourcePos
+}
 
-        let module = JITModule::new(builder);
-        Self {
-            builder_context: FunctionBuilderContext::new(),
-            ctx: module.make_context(),
-            _data_ctx: DataContext::new(),
-            module,
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourcePos {
+    pub line: u32,
+    pub col: u32
 }
 
-pub fn print_hello_world() -> () {
-    println!("{}", "hello world!");
+impl SourcePos {
+    pub fn to_string(&self) -> String {
+        return format!("line {}, col {}", self.line, self.col);
+    }
 }
 
-pub struct TokenList {
-    tokens: Vec<Token>,
-    curr: usize
+// An error that stops a program from compiling, and where it is when the compiler knows. The
+// message is what is shown to the user, so it says where the error is as well
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    pub pos: Option<SourcePos>
 }
 
-impl TokenList {
-    pub fn new() -> TokenList {
-        TokenList { tokens: vec!(), curr: 0 }
-    }
-    pub fn get_curr(&self) -> Option<Token> {
-        if self.is_end() {
-            return None;
-        }
-        return Some(self.tokens[self.curr].clone());
-    }
-    // Get the current token and go to next token
-    pub fn get_curr_inc(&mut self) -> Option<Token> { // Get the current and icrement pointer
-        if self.is_end() {
-            return None;
-        }
-        let curr_token = Some(self.tokens[self.curr].clone());
-        self.curr += 1;
-        return curr_token;
-    }
-    pub fn inc_curr(&mut self) {
-        self.curr += 1;
+impl CompileError {
+    pub fn new(message: String) -> CompileError {
+        return CompileError { message, pos: None };
     }
-    pub fn is_end(&self) -> bool {
-        if self.curr >= self.tokens.len() {
-            return true;
-        }
-        else {
-            return false;
-        }
+    pub fn at(pos: SourcePos, message: String) -> CompileError {
+        return CompileError { message, pos: Some(pos) };
     }
-    pub fn get(&mut self, i: usize) -> Option<Token> {
-        if i >= self.tokens.len() {
-            return None;
-        }
-        else {
-            return Some(self.tokens[i].clone());
-        }
+    // Give the error a position if it does not have one yet
+    pub fn or_at(self, pos: SourcePos) -> CompileError {
+        return CompileError { pos: Some(self.pos.unwrap_or(pos)), ..self };
     }
     pub fn to_string(&self) -> String {
-        let mut return_str = "{".to_string();
-        for i in 0..self.tokens.len() {
-            if i != 0 {
-                return_str += ", ";
-            }
-            return_str += self.tokens[i].to_string().as_str();
-        }
-        return_str += "}";
-        return return_str;
+        return self.message.clone();
     }
 }
 
-pub fn recursive_generate_tree(tokens: &mut TokenList, arg1_input: Option<Expr>) -> Result<Expr, String> {
-    let arg1: Expr;
-    if arg1_input.is_some() {
-        arg1 = arg1_input.unwrap()
-    }
-    else {
-        match tokens.get_curr_inc() {
-            Some(Token::IdentifierToken(token)) => {
-                arg1 = Expr::IdentifierToken(token);
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueType {
+    I32,
+    F64,
+    Array(Box<ValueType>, usize),
+    Struct(String),
+    Enum(String),
+    Ref(Box<ValueType>, bool), // The bool is true for &mut
+    // A function or closure with these parameters and return type. It is stored as the address
+    // of its code and the address of the variables a closure captured, which is null for functions
+    Fn(Vec<ValueType>, Option<Box<ValueType>>),
+    Generic(String, Vec<ValueType>), // An instance of a generic struct, like pair<i32>
+    Infer // A type that is not written down, see Closure
+}
+
+impl ValueType {
+    pub fn to_string(&self) -> String {
+        match self {
+            ValueType::I32 => {
+                return "i32".to_string();
+            }
+            ValueType::F64 => {
+                return "f64".to_string();
+            }
+            ValueType::Array(elem_type, len) => {
+                return format!("[{}; {}]", elem_type.to_string(), len);
             }
-            Some(Token::NumberToken(token)) => {
-                arg1 = Expr::NumberToken(token);
+            ValueType::Struct(name) | ValueType::Enum(name) => {
+                return name.clone();
             }
-            Some(Token::EndExpr) | None => {
-                tokens.curr += 1;
-                if arg1_input.is_some() {
-                    return Ok(arg1_input.unwrap())
+            ValueType::Generic(name, type_args) => {
+                let type_args: Vec<String> = type_args.iter().map(|type_arg| type_arg.to_string()).collect();
+                return format!("{}<{}>", name, type_args.join(", "));
+            }
+            ValueType::Ref(inner_type, true) => {
+                return format!("&mut {}", inner_type.to_string());
+            }
+            ValueType::Ref(inner_type, false) => {
+                return format!("&{}", inner_type.to_string());
+            }
+            ValueType::Fn(params, return_type) => {
+                let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                match return_type {
+                    Some(return_type) => {
+                        return format!("fn({}) -> {}", params.join(", "), return_type.to_string());
+                    }
+                    None => {
+                        return format!("fn({})", params.join(", "));
+                    }
                 }
-                return Ok(Expr::Empty);
             }
-            _ => {
-                return Err("invalid type".to_string());
+            ValueType::Infer => {
+                return "_".to_string();
             }
         }
     }
-    let operator: OperatorToken;
-    match tokens.get_curr_inc() {
-        Some(Token::OperatorToken(token)) => {
-            operator = token;
-        }
-        Some(Token::EndExpr) | None => {
-            return Ok(arg1);
-        }
-        _ => {
-            return Err("invalid operator".to_string());
-        }
-    }
-
-    let arg2: Expr;
-    match tokens.get_curr_inc() {
-        Some(Token::IdentifierToken(token)) => {
-            arg2 = Expr::IdentifierToken(token);
-        }
-        Some(Token::NumberToken(token)) => {
-            arg2 = Expr::NumberToken(token);
-        }
-        Some(Token::EndExpr) | None => {
-            return Err("expected identifier after operator".to_string());
-        }
-        _ => {
-            return Err("invalid identifier".to_string());
+    // Size in bytes when stored in memory
+    pub fn size(&self, types: &TypeTable) -> u32 {
+        match self {
+            ValueType::I32 => {
+                return 4;
+            }
+            ValueType::F64 => {
+                return 8;
+            }
+            ValueType::Array(elem_type, len) => {
+                return elem_type.size(types) * (*len as u32);
+            }
+            ValueType::Struct(..) | ValueType::Generic(..) => {
+                return types.structs[&self.struct_name().unwrap()].size;
+            }
+            ValueType::Enum(name) => {
+                return types.enums[name].size;
+            }
+            ValueType::Ref(..) => {
+                // Code only ever runs on the host, so its pointers are the ones we store
+                return mem::size_of::<usize>() as u32;
+            }
+            ValueType::Fn(..) => {
+                return 2 * mem::size_of::<usize>() as u32;
+            }
+            ValueType::Infer => {
+                unreachable!("the types of closures are worked out before they are laid out");
+            }
         }
     }
-
-    // To see if the next operator should execute first
-    match tokens.get(tokens.curr) {
-        Some(Token::OperatorToken(token)) => {
-            // The next operator should execute first
-            if token.op_type.type_number() < operator.op_type.type_number() {
-                let arg2 = recursive_generate_tree(tokens, Some(arg2));
-                if arg2.is_err() {
-                    return arg2;
-                }
-                return Ok(Expr::Operation(Operation{expr1: Box::new(arg1), expr2: Box::new(arg2.unwrap()), operator: operator}));
+    pub fn align(&self, types: &TypeTable) -> u8 {
+        match self {
+            ValueType::I32 => {
+                return 4;
             }
-            else if token.op_type.type_number() >= operator.op_type.type_number() {
-                let this_expr = Expr::Operation(Operation{expr1: Box::new(arg1), expr2: Box::new(arg2), operator: operator});
-                let result = recursive_generate_tree(tokens, Some(this_expr));
-                return result;
+            ValueType::F64 => {
+                return 8;
             }
+            ValueType::Array(elem_type, _) => {
+                return elem_type.align(types);
+            }
+            ValueType::Struct(..) | ValueType::Generic(..) => {
+                return types.structs[&self.struct_name().unwrap()].align;
+            }
+            ValueType::Enum(name) => {
+                return types.enums[name].align;
+            }
+            ValueType::Ref(..) | ValueType::Fn(..) => {
+                return mem::align_of::<usize>() as u8;
+            }
+            ValueType::Infer => {
+                unreachable!("the types of closures are worked out before they are laid out");
+            }
+        }
+    }
+    // Whether >> copies the sign bit into the bits it shifts in
+    pub fn is_signed(&self) -> bool {
+        return matches!(self, ValueType::I32);
+    }
+    // Aggregates live in memory, and their cranelift value is the address of that memory
+    pub fn is_aggregate(&self) -> bool {
+        match self {
+            ValueType::I32 | ValueType::F64 | ValueType::Ref(..) | ValueType::Infer => {
+                return false;
+            }
+            ValueType::Array(..) | ValueType::Struct(..) | ValueType::Enum(..) | ValueType::Fn(..) | ValueType::Generic(..) => {
+                return true;
+            }
+        }
+    }
+    // The name of the layout of a struct type, the layout of an instance of a generic struct is
+    // named like pair<i32>
+    pub fn struct_name(&self) -> Option<String> {
+        match self {
+            ValueType::Struct(name) => {
+                return Some(name.clone());
+            }
+            ValueType::Generic(..) => {
+                return Some(self.to_string());
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    // A &mut can be used where a & is expected, otherwise the types have to be the same
+    pub fn can_be_used_as(&self, expected: &ValueType) -> bool {
+        match (self, expected) {
+            (ValueType::Ref(inner_type, mutable), ValueType::Ref(expected_inner, expected_mutable)) => {
+                return inner_type.is_same_as(expected_inner) && (*mutable || !*expected_mutable);
+            }
+            _ => {
+                return self.is_same_as(expected);
+            }
+        }
+    }
+    // Like ==, except that Infer is the same as every type. The compiler works out every Infer,
+    // only the interpreter has closures whose types are not known
+    pub fn is_same_as(&self, other: &ValueType) -> bool {
+        match (self, other) {
+            (ValueType::Infer, _) | (_, ValueType::Infer) => {
+                return true;
+            }
+            (ValueType::Array(elem_type, len), ValueType::Array(other_elem, other_len)) => {
+                return len == other_len && elem_type.is_same_as(other_elem);
+            }
+            (ValueType::Ref(inner_type, mutable), ValueType::Ref(other_inner, other_mutable)) => {
+                return mutable == other_mutable && inner_type.is_same_as(other_inner);
+            }
+            (ValueType::Fn(params, return_type), ValueType::Fn(other_params, other_return)) => {
+                let same_returns = match (return_type, other_return) {
+                    (Some(return_type), Some(other_return)) => return_type.is_same_as(other_return),
+                    (None, None) => true,
+                    // A return type that is still to be inferred might turn out to be nothing
+                    (Some(return_type), None) | (None, Some(return_type)) => **return_type == ValueType::Infer
+                };
+                return same_returns && params.len() == other_params.len() && params.iter().zip(other_params.iter()).all(|(a, b)| a.is_same_as(b));
+            }
+            (ValueType::Generic(name, type_args), ValueType::Generic(other_name, other_args)) => {
+                return name == other_name && type_args.len() == other_args.len() && type_args.iter().zip(other_args.iter()).all(|(a, b)| a.is_same_as(b));
+            }
+            _ => {
+                return self == other;
+            }
+        }
+    }
+    pub fn cranelift_type(&self, pointer_type: types::Type) -> types::Type {
+        match self {
+            ValueType::F64 => {
+                return types::F64;
+            }
+            ValueType::Ref(..) => {
+                return pointer_type;
+            }
+            _ if self.is_aggregate() => {
+                return pointer_type;
+            }
+            _ => {
+                return types::I32;
+            }
+        }
+    }
+}
+
+pub struct StructField {
+    pub name: String,
+    pub value_type: ValueType,
+    pub offset: u32
+}
+
+// Where every field of a struct ends up in memory
+pub struct StructLayout {
+    pub fields: Vec<StructField>,
+    pub size: u32,
+    pub align: u8
+}
+
+impl StructLayout {
+    pub fn get_field(&self, name: &str) -> Option<&StructField> {
+        return self.fields.iter().find(|field| field.name == name);
+    }
+}
+
+// One variant of an enum, the offsets of the payload are from the start of the enum
+pub struct EnumVariant {
+    pub name: String,
+    pub payload: Vec<ValueType>,
+    pub offsets: Vec<u32>
+}
+
+// Enums are stored as an i32 tag, the index of the variant, followed by the payload of the
+// variant. The payloads of all variants share the same memory after the tag
+pub struct EnumLayout {
+    pub variants: Vec<EnumVariant>,
+    pub size: u32,
+    pub align: u8
+}
+
+impl EnumLayout {
+    pub fn get_variant(&self, name: &str) -> Option<(usize, &EnumVariant)> {
+        return self.variants.iter().enumerate().find(|(_, variant)| variant.name == name);
+    }
+}
+
+// The layouts of every struct and enum in the program. Every instance of a generic struct has a
+// layout of its own, under a name like pair<i32>
+pub struct TypeTable {
+    pub structs: HashMap<String, StructLayout>,
+    pub enums: HashMap<String, EnumLayout>,
+    pub generics: Generics
+}
+
+// Lay out fields one after the other starting at offset, each one padded to its alignment.
+// Returns the offsets of the fields, the end of the last field and the largest alignment
+fn layout_fields(field_types: &[&ValueType], mut offset: u32, types: &TypeTable) -> (Vec<u32>, u32, u8) {
+    let mut offsets: Vec<u32> = vec!();
+    let mut align: u8 = 1;
+    for value_type in field_types {
+        let field_align = value_type.align(types);
+        offset = align_to(offset, field_align);
+        offsets.push(offset);
+        offset += value_type.size(types);
+        align = align.max(field_align);
+    }
+    return (offsets, offset, align);
+}
+
+// Fields are laid out in the order they are declared, each one padded to its alignment,
+// and the type is padded at the end so that arrays of it keep every element aligned
+fn compute_type_layout(name: &str, decls: &HashMap<String, &Expr>, types: &mut TypeTable, visiting: &mut Vec<String>) -> Result<(), CompileError> {
+    if types.structs.contains_key(name) || types.enums.contains_key(name) {
+        return Ok(());
+    }
+    let (field_types, pos): (Vec<&ValueType>, SourcePos) = match decls[name] {
+        Expr::StructDecl(decl) => (decl.fields.iter().map(|(_, value_type)| value_type).collect(), decl.pos),
+        Expr::EnumDecl(decl) => (decl.variants.iter().flat_map(|(_, payload)| payload.iter()).collect(), decl.pos),
+        _ => unreachable!()
+    };
+    if visiting.iter().any(|visiting_name| visiting_name == name) {
+        return Err(CompileError::at(pos, format!("type {} contains itself at {}", name, pos.to_string())));
+    }
+    visiting.push(name.to_string());
+
+    // Make sure every type the fields depend on has a layout first
+    for value_type in field_types.iter() {
+        let mut inner_type = *value_type;
+        while let ValueType::Array(elem_type, _) = inner_type {
+            inner_type = elem_type;
+        }
+        let inner_name = match inner_type {
+            ValueType::Struct(inner_name) | ValueType::Enum(inner_name) => inner_name.clone(),
+            ValueType::Generic(..) => inner_type.to_string(),
+            _ => {
+                continue;
+            }
+        };
+        if !decls.contains_key(&inner_name) {
+            return Err(CompileError::at(pos, format!("unknown type {} in {} at {}", inner_name, name, pos.to_string())));
+        }
+        compute_type_layout(&inner_name, decls, types, visiting)?;
+    }
+
+    match decls[name] {
+        Expr::StructDecl(decl) => {
+            let mut fields: Vec<StructField> = vec!();
+            for (field_name, value_type) in decl.fields.iter() {
+                if fields.iter().any(|field| field.name == *field_name) {
+                    return Err(CompileError::at(decl.pos, format!("field {} is declared twice in struct {} at {}", field_name, name, decl.pos.to_string())));
+                }
+                fields.push(StructField { name: field_name.clone(), value_type: value_type.clone(), offset: 0 });
+            }
+            let (offsets, end, align) = layout_fields(&field_types, 0, types);
+            for (field, offset) in fields.iter_mut().zip(offsets) {
+                field.offset = offset;
+            }
+            types.structs.insert(name.to_string(), StructLayout { fields, size: align_to(end, align), align });
+        }
+        Expr::EnumDecl(decl) => {
+            // The payloads start after the tag, at an offset that suits every variant
+            let payload_align = field_types.iter().map(|value_type| value_type.align(types)).max().unwrap_or(1);
+            let payload_start = align_to(4, payload_align);
+            let align = payload_align.max(4);
+            let mut end = payload_start;
+            let mut variants: Vec<EnumVariant> = vec!();
+            for (variant_name, payload) in decl.variants.iter() {
+                if variants.iter().any(|variant| variant.name == *variant_name) {
+                    return Err(CompileError::at(decl.pos, format!("variant {} is declared twice in enum {} at {}", variant_name, name, decl.pos.to_string())));
+                }
+                let payload_types: Vec<&ValueType> = payload.iter().collect();
+                let (offsets, variant_end, _) = layout_fields(&payload_types, payload_start, types);
+                end = end.max(variant_end);
+                variants.push(EnumVariant { name: variant_name.clone(), payload: payload.clone(), offsets });
+            }
+            types.enums.insert(name.to_string(), EnumLayout { variants, size: align_to(end, align), align });
+        }
+        _ => unreachable!()
+    }
+
+    visiting.pop();
+    return Ok(());
+}
+
+fn align_to(offset: u32, align: u8) -> u32 {
+    let align = align as u32;
+    return offset.div_ceil(align) * align;
+}
+
+// The types that are part of the language, checked_add and the other checked intrinsics give
+// an option::some(result), or option::none when the result does not fit
+pub const OPTION_TYPE: &str = "option";
+
+fn builtin_types() -> Vec<Expr> {
+    return vec!(Expr::EnumDecl(EnumDecl {
+        name: OPTION_TYPE.to_string(),
+        variants: vec!(("some".to_string(), vec!(ValueType::I32)), ("none".to_string(), vec!())),
+        doc: None,
+        pos: SourcePos { line: 1, col: 1 }
+    }));
+}
+
+pub fn compute_type_layouts(expr_tree: &[Expr]) -> Result<TypeTable, CompileError> {
+    let builtins = builtin_types();
+    let mut decls: HashMap<String, &Expr> = HashMap::new();
+    let mut order: Vec<&String> = vec!(); // Go through the types in the order they are declared, so errors are always the same
+    for expr in builtins.iter().chain(expr_tree.iter()) {
+        let (name, pos) = match expr {
+            Expr::StructDecl(decl) => (&decl.name, decl.pos),
+            Expr::EnumDecl(decl) => (&decl.name, decl.pos),
+            _ => {
+                continue;
+            }
+        };
+        if decls.insert(name.clone(), expr).is_some() {
+            if name == OPTION_TYPE {
+                return Err(CompileError::at(pos, format!("type {} is built in and cannot be declared again, at {}", name, pos.to_string())));
+            }
+            return Err(CompileError::at(pos, format!("type {} is declared twice at {}", name, pos.to_string())));
+        }
+        order.push(name);
+    }
+    let mut types = TypeTable { structs: HashMap::new(), enums: HashMap::new(), generics: Generics::default() };
+    for name in order.iter() {
+        compute_type_layout(name, &decls, &mut types, &mut vec!())?;
+    }
+
+    // References do not need a layout of what they point to, but it still has to exist
+    for expr in order.iter().map(|name| decls[*name]) {
+        let (name, field_types, pos): (&String, Vec<&ValueType>, SourcePos) = match expr {
+            Expr::StructDecl(decl) => (&decl.name, decl.fields.iter().map(|(_, value_type)| value_type).collect(), decl.pos),
+            Expr::EnumDecl(decl) => (&decl.name, decl.variants.iter().flat_map(|(_, payload)| payload.iter()).collect(), decl.pos),
+            _ => unreachable!()
+        };
+        for value_type in field_types {
+            check_type(value_type, &types).map_err(|err| CompileError::at(pos, format!("{} in {} at {}", err.message, name, pos.to_string())))?;
+        }
+    }
+    return Ok(types);
+}
+
+#[derive(Debug, Clone)]
+pub enum Token {
+    IdentifierToken(IdentifierToken),
+    Keyword(&'static str), // One of KEYWORDS
+    NumberToken(NumberToken),
+    FloatToken(FloatToken),
+    OperatorToken(OperatorToken),
+    EndExpr,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LCurly,
+    RCurly,
+    Comma,
+    Colon,
+    Dot,
+    Arrow,
+    PathSep,
+    FatArrow,
+    LAngle, // The < and > around type parameters and type arguments
+    RAngle,
+    DocComment(String), // The text of a /// comment, without the ///
+}
+
+impl Token {
+    pub fn to_string(&self) -> String {
+        match self {
+            Token::IdentifierToken(_token) => {
+                return "Identifier".to_string();
+            }
+            Token::Keyword(keyword) => {
+                return format!("Keyword({})", keyword);
+            }
+            Token::NumberToken(_token) => {
+                return "Number".to_string();
+            }
+            Token::FloatToken(_token) => {
+                return "Float".to_string();
+            }
+            Token::OperatorToken(_token) => {
+                return "Operator".to_string();
+            }
+            Token::EndExpr => {
+                return "EndExpr".to_string();
+            }
+            Token::LBracket => {
+                return "LBracket".to_string();
+            }
+            Token::RBracket => {
+                return "RBracket".to_string();
+            }
+            Token::Comma => {
+                return "Comma".to_string();
+            }
+            Token::LParen => {
+                return "LParen".to_string();
+            }
+            Token::RParen => {
+                return "RParen".to_string();
+            }
+            Token::LCurly => {
+                return "LCurly".to_string();
+            }
+            Token::RCurly => {
+                return "RCurly".to_string();
+            }
+            Token::Colon => {
+                return "Colon".to_string();
+            }
+            Token::Dot => {
+                return "Dot".to_string();
+            }
+            Token::Arrow => {
+                return "Arrow".to_string();
+            }
+            Token::PathSep => {
+                return "PathSep".to_string();
+            }
+            Token::FatArrow => {
+                return "FatArrow".to_string();
+            }
+            Token::LAngle => {
+                return "LAngle".to_string();
+            }
+            Token::RAngle => {
+                return "RAngle".to_string();
+            }
+            Token::DocComment(_text) => {
+                return "DocComment".to_string();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdentifierToken {
+    pub text: String
+}
+
+#[derive(Debug, Clone)]
+pub struct NumberToken {
+    pub num: i32,
+    pub text: Option<String> // How the literal was written, so that 0xff and 'a' are printed back the same way
+}
+
+#[derive(Debug, Clone)]
+pub struct FloatToken {
+    pub num: f64
+}
+
+// An operator from the table in operators.rs, or an assignment
+#[derive(Debug, Clone)]
+pub enum OperatorType {
+    Operator(&'static Operator),
+    // = on its own, or a compound assignment like += with the operator it applies
+    Assign(Option<&'static Operator>)
+}
+
+impl OperatorType {
+    // The operators with the lowest precedence are applied first
+    pub fn precedence(&self) -> usize {
+        match self {
+            OperatorType::Operator(op) => {
+                return op.precedence;
+            }
+            OperatorType::Assign(..) => {
+                return ASSIGNMENT_PRECEDENCE;
+            }
+        }
+    }
+    pub fn is_right_associative(&self) -> bool {
+        match self {
+            OperatorType::Operator(op) => {
+                return op.associativity == Associativity::Right;
+            }
+            OperatorType::Assign(..) => {
+                return true;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OperatorToken {
+    pub op_type: OperatorType
+}
+
+impl OperatorToken {
+    pub fn to_string(&self) -> String {
+        match &self.op_type {
+            OperatorType::Operator(op) => {
+                return op.symbol.to_string();
+            }
+            OperatorType::Assign(None) => {
+                return "=".to_string();
+            }
+            OperatorType::Assign(Some(op)) => {
+                return format!("{}=", op.symbol);
+            }
+        }
+    }
+}
+
+pub fn is_end_of_expression(my_str: String) -> Option<Token> {
+    if my_str.len() == 0 {
+        return None;
+    }
+    match my_str.as_str() {
+        ";" => {
+            return Some(Token::EndExpr);
+        }
+        _ => {
+            return None;
+        }
+    }
+    
+}
+
+pub fn is_delimiter(my_str: String) -> Option<Token> {
+    match my_str.as_str() {
+        "[" => {
+            return Some(Token::LBracket);
+        }
+        "]" => {
+            return Some(Token::RBracket);
+        }
+        "," => {
+            return Some(Token::Comma);
+        }
+        ":" => {
+            return Some(Token::Colon);
+        }
+        "(" => {
+            return Some(Token::LParen);
+        }
+        ")" => {
+            return Some(Token::RParen);
+        }
+        "{" => {
+            return Some(Token::LCurly);
+        }
+        "}" => {
+            return Some(Token::RCurly);
+        }
+        "." => {
+            return Some(Token::Dot);
+        }
+        "<" => {
+            return Some(Token::LAngle);
+        }
+        ">" => {
+            return Some(Token::RAngle);
+        }
+        "->" => {
+            return Some(Token::Arrow);
+        }
+        "::" => {
+            return Some(Token::PathSep);
+        }
+        "=>" => {
+            return Some(Token::FatArrow);
+        }
+        _ => {
+            return None;
+        }
+    }
+}
+
+pub fn is_number(my_str: String) -> Option<NumberToken> {
+    let num = parse_int_literal(&my_str)?.ok()?;
+    return Some(NumberToken{num, text: Some(my_str)});
+}
+
+// An integer literal: 12, -7, 1_000, 0xff, 0o17, 0b101, or a character like 'a' or '\n', which
+// is its code point. None if the word does not look like one, the error says what is wrong with it
+pub fn parse_int_literal(word: &str) -> Option<Result<i32, String>> {
+    if word.starts_with('\'') {
+        return Some(parse_char_literal(word));
+    }
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word)
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let (radix, base_name, digits) = match digits.get(..2) {
+        Some("0x") => (16, "hexadecimal", &digits[2..]),
+        Some("0o") => (8, "octal", &digits[2..]),
+        Some("0b") => (2, "binary", &digits[2..]),
+        _ => (10, "decimal", digits)
+    };
+    // The value saturates far outside of the i32 range, so a literal that is too big is still out of range
+    let mut value: u64 = 0;
+    let mut has_digits = false;
+    for (index, c) in digits.char_indices() {
+        if c == '_' {
+            continue;
+        }
+        match c.to_digit(radix) {
+            Some(digit) => {
+                value = value.saturating_mul(radix as u64).saturating_add(digit as u64);
+                has_digits = true;
+            }
+            None if c.is_ascii_digit() => {
+                return Some(Err(format!("invalid digit '{}' in {} literal {}", c, base_name, word)));
+            }
+            None => {
+                return Some(Err(format!("invalid suffix '{}' for number literal {}", &digits[index..], word)));
+            }
+        }
+    }
+    if !has_digits {
+        return Some(Err(format!("no valid digits found for number literal {}", word)));
+    }
+    let value = if negative { -(value as i128) } else { value as i128 };
+    if value < i32::MIN as i128 || value > i32::MAX as i128 {
+        return Some(Err(format!("literal {} is out of range for i32, which goes from {} to {}", word, i32::MIN, i32::MAX)));
+    }
+    return Some(Ok(value as i32));
+}
+
+// 'a', or one of the escapes '\n', '\r', '\t', '\0', '\\', '\'', '\"' and '\u{1f600}'
+fn parse_char_literal(word: &str) -> Result<i32, String> {
+    let inner = match word.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        Some(inner) => inner,
+        None => {
+            return Err(format!("unterminated character literal {}", word));
+        }
+    };
+    let mut chars = inner.chars();
+    let c = match chars.next() {
+        Some('\\') => {
+            let escape: String = chars.by_ref().collect();
+            match escape.as_str() {
+                "n" => '\n',
+                "r" => '\r',
+                "t" => '\t',
+                "0" => '\0',
+                "\\" => '\\',
+                "'" => '\'',
+                "\"" => '"',
+                _ => {
+                    let code = escape.strip_prefix("u{").and_then(|rest| rest.strip_suffix('}'))
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32);
+                    match code {
+                        Some(c) => c,
+                        None => {
+                            return Err(format!("unknown character escape in {}", word));
+                        }
+                    }
+                }
+            }
+        }
+        Some(c) => c,
+        None => {
+            return Err(format!("empty character literal {}", word));
+        }
+    };
+    if chars.next().is_some() {
+        return Err(format!("character literal {} may only contain one character", word));
+    }
+    return Ok(c as i32);
+}
+
+// Numbers with a decimal point, like 1.5, -0.25 or 1_000.5
+pub fn is_float(my_str: String) -> Option<FloatToken> {
+    let num = parse_float_literal(&my_str)?.ok()?;
+    return Some(FloatToken{num});
+}
+
+// A float literal: 1.5, -0.25, 1_000.0. None if the word does not look like one, the error says
+// what is wrong with it
+pub fn parse_float_literal(word: &str) -> Option<Result<f64, String>> {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    let (whole, fraction) = digits.split_once('.')?;
+    if !is_decimal_digits(whole) || !is_decimal_digits(fraction) {
+        return None;
+    }
+    // So many digits that the value does not fit parses as infinity
+    let num = word.replace('_', "").parse::<f64>().unwrap();
+    if !num.is_finite() {
+        return Some(Err(format!("literal {} is out of range for f64", word)));
+    }
+    return Some(Ok(num));
+}
+
+// Digits with _ between them to group them, like 1_000
+pub fn is_decimal_digits(my_str: &str) -> bool {
+    return my_str.starts_with(|c: char| c.is_ascii_digit()) && my_str.chars().all(|c| c.is_ascii_digit() || c == '_');
+}
+
+// Words that cannot be used as names. Some of them are not used by the language yet, they are
+// kept free for it
+pub const KEYWORDS: [&str; 27] = [
+    "as", "break", "const", "continue", "else", "enum", "false", "fn", "for", "if", "impl", "in", "let", "loop",
+    "match", "mod", "mut", "pub", "return", "self", "struct", "trait", "true", "type", "use", "where", "while"
+];
+
+pub fn find_keyword(word: &str) -> Option<&'static str> {
+    return KEYWORDS.iter().find(|keyword| **keyword == word).copied();
+}
+
+// Names start with a letter or _ and go on with letters, digits and _, where letters are the
+// Unicode XID_Start and XID_Continue characters like in Rust. A raw identifier like r#fn is a
+// name that is written like a keyword
+pub fn is_identifier(my_str: String) -> Option<IdentifierToken> {
+    let (is_raw, name) = match my_str.strip_prefix("r#") {
+        Some(name) => (true, name),
+        None => (false, my_str.as_str())
+    };
+    let mut chars = name.chars();
+    let first = chars.next()?;
+    if !(first == '_' || first.is_xid_start()) || !chars.all(|c| c.is_xid_continue()) {
+        return None;
+    }
+    if name == "_" && is_raw {
+        return None;
+    }
+    match (find_keyword(name), is_raw) {
+        (Some(_), false) => {
+            return None;
+        }
+        (Some(_), true) => {
+            // The name keeps its r#, so that it is printed back the way it can be read again
+            return Some(IdentifierToken{text: my_str});
+        }
+        (None, _) => {
+            // r#x is the same name as x
+            return Some(IdentifierToken{text: name.to_string()});
+        }
+    }
+}
+
+// The operators are all in the table in operators.rs, and every infix operator has a compound
+// assignment
+pub fn is_operator(my_str: String) -> Option<OperatorToken> {
+    if my_str == "=" {
+        return Some(OperatorToken{op_type: OperatorType::Assign(None)});
+    }
+    if let Some(op) = find_operator(&my_str) {
+        return Some(OperatorToken{op_type: OperatorType::Operator(op)});
+    }
+    let op = find_operator(my_str.strip_suffix('=')?).filter(|op| op.arity == Arity::Infix)?;
+    return Some(OperatorToken{op_type: OperatorType::Assign(Some(op))});
+}
+
+/// The basic JIT class.
+pub struct JIT {
+    /// The function builder context, which is reused across multiple
+    /// FunctionBuilder instances.
+    builder_context: FunctionBuilderContext,
+
+    /// The main Cranelift context, which holds the state for codegen. Cranelift
+    /// separates this from `Module` to allow for parallel compilation, with a
+    /// context per thread, though this isn't in the simple demo here.
+    ctx: codegen::Context,
+
+    /// The data context, which is to data objects what `ctx` is to functions.
+    _data_ctx: DataContext,
+
+    /// The module, with the jit backend, which manages the JIT'd
+    /// functions.
+    module: JITModule,
+
+    /// The indirection table. Calls load the address of the function from its slot, so that
+    /// a function can be recompiled and swapped in without recompiling its callers.
+    slots: HashMap<String, Box<usize>>,
+
+    /// The functions that are currently in use, see update.
+    compiled: HashMap<String, CompiledFunction>,
+
+    /// Counts the updates, recompiled functions get it in their name to keep names unique.
+    generation: usize,
+
+    /// The functions that the last update had to compile.
+    pub recompiled: Vec<String>,
+
+    /// Functions of the host program that scripts can call, see register_host_function.
+    host_functions: FunctionTable,
+
+    /// Whether i32 arithmetic that overflows is a runtime error, see set_overflow_checks.
+    overflow_checks: bool,
+
+    /// Instances of generics that the functions being built use but the program does not
+    /// have yet, see update.
+    missing_instances: RefCell<Vec<Instance>>,
+
+    /// The instances of generics that the function being built uses, see build_program.
+    used_instances: RefCell<Vec<Instance>>,
+
+    /// Only check programs for errors, without making machine code, see check_program.
+    check_only: bool,
+}
+
+// A function in the jit, and the fingerprint of the code it was compiled from
+#[derive(Clone)]
+struct CompiledFunction {
+    fingerprint: u64,
+    func_id: FuncId,
+    params: Vec<ValueType>,
+    return_type: Option<ValueType>,
+    instances: Vec<Instance> // The instances of generics it uses, see build_program
+}
+
+// The top level statements are compiled into a function with this name, it cannot clash with
+// the functions of the program since it is not an identifier
+const PROGRAM_NAME: &str = "<program>";
+
+// Same as JITBuilder::new, except that the host isa is used without probing the cpu for
+// optional features, the probing in cranelift_native panics on newer cpus
+pub fn new_jit_builder() -> JITBuilder {
+    let mut flag_builder = settings::builder();
+    flag_builder.set("use_colocated_libcalls", "false").unwrap();
+    flag_builder.set("is_pic", "true").unwrap();
+    // Frames of 4 KiB or more would call a stack probe, and there is no probestack symbol to call
+    flag_builder.set("enable_probestack", "false").unwrap();
+    let isa_builder = isa::lookup(Triple::host()).unwrap_or_else(|msg| {
+        panic!("host machine is not supported: {}", msg);
+    });
+    let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+    return JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+}
+
+// The machine code of every update is freed with the jit. Nothing can be running it by then,
+// host functions only get pointers into it for as long as they are called
+impl Drop for JIT {
+    fn drop(&mut self) {
+        unsafe {
+            self.module.free_memory();
+        }
+    }
+}
+
+impl Default for JIT {
+    fn default() -> Self {
+        let mut builder = new_jit_builder();
+
+        // Register hello world print function.
+        let print_addr = print_hello_world as *const u8;
+        builder.symbol("print_hello_world", print_addr);
+
+        // Register the functions that generated code calls when it hits a runtime error
+        builder.symbol("index_out_of_bounds", index_out_of_bounds as *const u8);
+        builder.symbol("divide_by_zero", divide_by_zero as *const u8);
+        builder.symbol("use_after_free", use_after_free as *const u8);
+        builder.symbol("arithmetic_overflow", arithmetic_overflow as *const u8);
+        builder.symbol("has_runtime_error", has_runtime_error as *const u8);
+
+        // Register the heap functions behind alloc and free
+        builder.symbol("vm_alloc", vm_alloc as *const u8);
+        builder.symbol("vm_free", vm_free as *const u8);
+        builder.symbol("vm_alloc_env", vm_alloc_env as *const u8);
+        builder.symbol("vm_is_live", vm_is_live as *const u8);
+
+        // Register the functions behind print
+        builder.symbol("vm_print_i32", vm_print_i32 as *const u8);
+        builder.symbol("vm_print_f64", vm_print_f64 as *const u8);
+
+        let module = JITModule::new(builder);
+        Self {
+            builder_context: FunctionBuilderContext::new(),
+            ctx: module.make_context(),
+            _data_ctx: DataContext::new(),
+            module,
+            slots: HashMap::new(),
+            compiled: HashMap::new(),
+            generation: 0,
+            recompiled: vec!(),
+            host_functions: FunctionTable::new(),
+            overflow_checks: false,
+            missing_instances: RefCell::new(vec!()),
+            used_instances: RefCell::new(vec!()),
+            check_only: false,
+        }
+    }
+}
+
+pub fn print_hello_world() -> () {
+    println!("hello world!");
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub pos: SourcePos
+}
+
+impl RuntimeError {
+    pub fn to_string(&self) -> String {
+        return format!("runtime error: {} at {}", self.message, self.pos.to_string());
+    }
+    // Const evaluation runs while compiling, so what stops it there is a compile error
+    pub fn to_compile_error(&self) -> CompileError {
+        return CompileError::at(self.pos, self.to_string());
+    }
+}
+
+thread_local! {
+    // Set by the runtime error functions called from generated code, the generated code then
+    // returns straight away and whoever called it picks the error up with take_runtime_error
+    static RUNTIME_ERROR: RefCell<Option<RuntimeError>> = const { RefCell::new(None) };
+}
+
+pub fn set_runtime_error(message: String, pos: SourcePos) {
+    RUNTIME_ERROR.with(|err| {
+        // Keep the first error, that is the one that caused the program to stop
+        if err.borrow().is_none() {
+            *err.borrow_mut() = Some(RuntimeError { message, pos });
+        }
+    });
+}
+
+pub fn take_runtime_error() -> Option<RuntimeError> {
+    return RUNTIME_ERROR.with(|err| err.borrow_mut().take());
+}
+
+pub extern "C" fn has_runtime_error() -> i32 {
+    return RUNTIME_ERROR.with(|err| err.borrow().is_some() as i32);
+}
+
+pub extern "C" fn index_out_of_bounds(line: i32, col: i32, index: i32, len: i32) {
+    let message = format!("index out of bounds: the len is {} but the index is {}", len, index);
+    set_runtime_error(message, SourcePos { line: line as u32, col: col as u32 });
+}
+
+pub extern "C" fn divide_by_zero(line: i32, col: i32) {
+    set_runtime_error("attempt to divide by zero".to_string(), SourcePos { line: line as u32, col: col as u32 });
+}
+
+pub extern "C" fn use_after_free(line: i32, col: i32) {
+    set_runtime_error("use of memory after it was freed".to_string(), SourcePos { line: line as u32, col: col as u32 });
+}
+
+// Only called when overflow checks are on, op is the index of the operator in OPERATORS
+pub extern "C" fn arithmetic_overflow(line: i32, col: i32, op: i32) {
+    let message = OPERATORS[op as usize].overflow.as_ref().unwrap().message;
+    set_runtime_error(message.to_string(), SourcePos { line: line as u32, col: col as u32 });
+}
+
+thread_local! {
+    // Every allocation made by alloc that has not been freed yet, so that free can report
+    // pointers that did not come from alloc or that were already freed
+    static HEAP_ALLOCATIONS: RefCell<HashMap<usize, Layout>> = RefCell::new(HashMap::new());
+}
+
+fn heap_layout(size: i64, align: i64) -> Layout {
+    // Zero sized allocations are not allowed, so empty arrays still take up a byte
+    return Layout::from_size_align((size as usize).max(1), align as usize).unwrap();
+}
+
+pub extern "C" fn vm_alloc(size: i64, align: i64) -> *mut u8 {
+    let layout = heap_layout(size, align);
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    HEAP_ALLOCATIONS.with(|allocations| allocations.borrow_mut().insert(ptr as usize, layout));
+    return ptr;
+}
+
+pub extern "C" fn vm_free(line: i32, col: i32, ptr: usize, size: i64, align: i64) {
+    let layout = heap_layout(size, align);
+    let allocated = HEAP_ALLOCATIONS.with(|allocations| allocations.borrow_mut().remove(&ptr));
+    match allocated {
+        Some(allocated_layout) if allocated_layout == layout => {
+            FREED_ALLOCATIONS.with(|freed| freed.borrow_mut().insert(ptr, layout));
+            FREE_ORDER.with(|order| order.borrow_mut().push_back(ptr));
+            FREE_COUNT.with(|count| count.set(count.get() + 1));
+            FREED_BYTES.with(|bytes| bytes.set(bytes.get() + layout.size()));
+            // Keep the quarantine bounded, the memory that was freed the longest ago is given back first
+            while FREED_BYTES.with(|bytes| bytes.get()) > FREED_BYTES_LIMIT && FREE_ORDER.with(|order| order.borrow().len()) > 1 {
+                let oldest = FREE_ORDER.with(|order| order.borrow_mut().pop_front()).unwrap();
+                give_back_freed(oldest);
+            }
+        }
+        _ => {
+            let message = "free of memory that was not allocated with alloc, or that was already freed".to_string();
+            set_runtime_error(message, SourcePos { line: line as u32, col: col as u32 });
+        }
+    }
+}
+
+// How many bytes of freed memory are kept back before the oldest of it is given back
+const FREED_BYTES_LIMIT: usize = 1 << 20;
+
+thread_local! {
+    // Memory that free was called on. It is kept back for a while so that nothing else is put
+    // there while references to it can still be used, and using them is a runtime error, see
+    // vm_is_live. Once there is more than FREED_BYTES_LIMIT of it, or the host's call into the
+    // program returns, it is given back, oldest first
+    static FREED_ALLOCATIONS: RefCell<BTreeMap<usize, Layout>> = const { RefCell::new(BTreeMap::new()) };
+    static FREE_ORDER: RefCell<VecDeque<usize>> = const { RefCell::new(VecDeque::new()) };
+    static FREE_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) }; // How many frees there have been
+    static FREED_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn give_back_freed(ptr: usize) {
+    let layout = FREED_ALLOCATIONS.with(|freed| freed.borrow_mut().remove(&ptr)).unwrap();
+    FREED_BYTES.with(|bytes| bytes.set(bytes.get() - layout.size()));
+    unsafe { std::alloc::dealloc(ptr as *mut u8, layout) };
+}
+
+// How many bytes of freed memory are kept back right now
+pub fn freed_bytes() -> usize {
+    return FREED_BYTES.with(|bytes| bytes.get());
+}
+
+// 0 if ptr points into memory that was freed, generated code checks every reference it uses
+// that can point to the heap
+pub extern "C" fn vm_is_live(ptr: usize) -> i32 {
+    return FREED_ALLOCATIONS.with(|freed| {
+        match freed.borrow().range(..=ptr).next_back() {
+            Some((start, layout)) if ptr < start + layout.size() => 0,
+            _ => 1
+        }
+    });
+}
+
+thread_local! {
+    // The envs of closures that are on the heap, see cranelift_closure
+    static CLOSURE_ENVS: RefCell<Vec<(usize, Layout)>> = const { RefCell::new(vec!()) };
+}
+
+pub extern "C" fn vm_alloc_env(size: i64, align: i64) -> *mut u8 {
+    let layout = heap_layout(size, align);
+    let ptr = unsafe { std::alloc::alloc(layout) };
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    CLOSURE_ENVS.with(|envs| envs.borrow_mut().push((ptr as usize, layout)));
+    return ptr;
+}
+
+// How many closure envs are on the heap right now
+pub fn closure_env_count() -> usize {
+    return CLOSURE_ENVS.with(|envs| envs.borrow().len());
+}
+
+// Run generated code from the host, and give back the closure envs it put on the heap and the
+// memory it freed once it returns. Only numbers are given back to the host, and host functions
+// only get to use a closure while they are called, so nothing can point there after that
+pub fn release_heap_after<R>(f: impl FnOnce() -> R) -> R {
+    let envs_mark = CLOSURE_ENVS.with(|envs| envs.borrow().len());
+    let free_mark = FREE_COUNT.with(|count| count.get());
+    let result = f();
+    for (ptr, layout) in CLOSURE_ENVS.with(|envs| envs.borrow_mut().split_off(envs_mark)) {
+        unsafe { std::alloc::dealloc(ptr as *mut u8, layout) };
+    }
+    // The newest frees are at the back, some of them might have been given back already
+    let frees = FREE_COUNT.with(|count| count.get()) - free_mark;
+    for _ in 0..frees {
+        match FREE_ORDER.with(|order| order.borrow_mut().pop_back()) {
+            Some(ptr) => give_back_freed(ptr),
+            None => break
+        }
+    }
+    return result;
+}
+
+thread_local! {
+    // Whether the compiler prints the tokens and trees it makes, tools like the fuzzer turn it off
+    static DEBUG_OUTPUT: std::cell::Cell<bool> = const { std::cell::Cell::new(true) };
+}
+
+pub fn set_debug_output(enabled: bool) {
+    DEBUG_OUTPUT.with(|debug_output| debug_output.set(enabled));
+}
+
+// Run f without any debug output, and put the setting back afterwards
+pub fn without_debug_output<R>(f: impl FnOnce() -> R) -> R {
+    let previous = DEBUG_OUTPUT.with(|debug_output| debug_output.replace(false));
+    let result = f();
+    set_debug_output(previous);
+    return result;
+}
+
+pub fn debug_print(text: &str) {
+    if DEBUG_OUTPUT.with(|debug_output| debug_output.get()) {
+        println!("{}", text);
+    }
+}
+
+thread_local! {
+    // Where print writes to while capture_output is running, otherwise it goes to stdout
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn write_output(text: &str) {
+    let captured = OUTPUT.with(|output| {
+        if let Some(output) = output.borrow_mut().as_mut() {
+            output.push_str(text);
+            return true;
+        }
+        return false;
+    });
+    if !captured {
+        print!("{}", text);
+    }
+}
+
+// Run f and return everything the program printed while it ran
+pub fn capture_output<R>(f: impl FnOnce() -> R) -> (R, String) {
+    let previous = OUTPUT.with(|output| output.borrow_mut().replace(String::new()));
+    let result = f();
+    let captured = OUTPUT.with(|output| mem::replace(&mut *output.borrow_mut(), previous));
+    return (result, captured.unwrap_or_default());
+}
+
+// The type the compiler gave a variable, pos is where the statement that defined it starts
+#[derive(Clone)]
+pub struct VariableType {
+    pub name: String,
+    pub value_type: ValueType,
+    pub pos: SourcePos
+}
+
+thread_local! {
+    // The variables the compiler defines while capture_variable_types is running, tools like the
+    // language server use them to show the types that were worked out
+    static VARIABLE_TYPES: RefCell<Option<Vec<VariableType>>> = const { RefCell::new(None) };
+}
+
+fn record_variable_type(name: &str, value_type: &ValueType, pos: SourcePos) {
+    VARIABLE_TYPES.with(|variable_types| {
+        if let Some(variable_types) = variable_types.borrow_mut().as_mut() {
+            variable_types.push(VariableType { name: name.to_string(), value_type: value_type.clone(), pos });
+        }
+    });
+}
+
+// Run f and return the type of every variable the compiler defined while it ran
+pub fn capture_variable_types<R>(f: impl FnOnce() -> R) -> (R, Vec<VariableType>) {
+    let previous = VARIABLE_TYPES.with(|variable_types| variable_types.borrow_mut().replace(vec!()));
+    let result = f();
+    let captured = VARIABLE_TYPES.with(|variable_types| mem::replace(&mut *variable_types.borrow_mut(), previous));
+    return (result, captured.unwrap_or_default());
+}
+
+pub extern "C" fn vm_print_i32(value: i32) {
+    write_output(&format!("{}\n", value));
+}
+
+pub extern "C" fn vm_print_f64(value: f64) {
+    write_output(&format!("{}\n", value));
+}
+
+pub struct TokenList {
+    tokens: Vec<Token>,
+    positions: Vec<SourcePos>, // Where in the source each token starts
+    errors: Vec<CompileError> // Errors found while tokenizing, generate_tree reports the first one
+}
+
+impl TokenList {
+    pub fn new() -> TokenList {
+        TokenList { tokens: vec!(), positions: vec!(), errors: vec!() }
+    }
+    pub fn push(&mut self, token: Token, pos: SourcePos) {
+        self.tokens.push(token);
+        self.positions.push(pos);
+    }
+    pub fn push_error(&mut self, err: String, pos: SourcePos) {
+        self.errors.push(CompileError::at(pos, err));
+    }
+    pub fn to_string(&self) -> String {
+        let mut return_str = "{".to_string();
+        for i in 0..self.tokens.len() {
+            if i != 0 {
+                return_str += ", ";
+            }
+            return_str += self.tokens[i].to_string().as_str();
+        }
+        return_str += "}";
+        return return_str;
+    }
+}
+
+// Types are parsed as struct types whenever they are a name, turn the ones that name an enum
+// into enum types
+fn resolve_enum_type(value_type: &mut ValueType, enums: &HashSet<String>) {
+    match value_type {
+        ValueType::Struct(name) if enums.contains(name) => {
+            *value_type = ValueType::Enum(name.clone());
+        }
+        ValueType::Array(inner_type, _) | ValueType::Ref(inner_type, _) => {
+            resolve_enum_type(inner_type, enums);
+        }
+        ValueType::Fn(params, return_type) => {
+            for value_type in params.iter_mut().chain(return_type.iter_mut().map(|return_type| return_type.as_mut())) {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        ValueType::Generic(_, type_args) => {
+            for value_type in type_args.iter_mut() {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Resolve the types written in expr itself, generate_tree walks over the whole tree with it
+fn resolve_enum_types(expr: &mut Expr, enums: &HashSet<String>) {
+    match expr {
+        Expr::Let(token) => {
+            if let Some(value_type) = &mut token.value_type {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        Expr::StructDecl(token) => {
+            for (_, value_type) in token.fields.iter_mut() {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        Expr::ConstDecl(token) => {
+            resolve_enum_type(&mut token.value_type, enums);
+        }
+        Expr::Call(token) => {
+            for value_type in token.type_args.iter_mut() {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        Expr::EnumDecl(token) => {
+            for (_, payload) in token.variants.iter_mut() {
+                for value_type in payload.iter_mut() {
+                    resolve_enum_type(value_type, enums);
+                }
+            }
+        }
+        Expr::Function(Function { params, return_type, .. }) | Expr::Closure(Closure { params, return_type, .. }) => {
+            for (_, value_type) in params.iter_mut() {
+                resolve_enum_type(value_type, enums);
+            }
+            if let Some(value_type) = return_type {
+                resolve_enum_type(value_type, enums);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The last expression of a block that has no ; after it is the value of the block. At the end
+// of a function that is what the function returns, and the same goes for the arms of a match
+// or the bodies of an if with an else that the function ends with
+fn lower_tail_expressions(body: &mut [Expr]) {
+    match body.last_mut() {
+        Some(last @ Expr::Tail(..)) => {
+            if let Expr::Tail(tail) = last.clone() {
+                *last = Expr::Return(Return { value: Some(tail.value), is_tail: true, pos: tail.pos });
+            }
+        }
+        Some(Expr::Match(token)) => {
+            for arm in token.arms.iter_mut() {
+                lower_tail_expressions(&mut arm.body);
+            }
+        }
+        Some(Expr::If(token)) if token.else_body.is_some() => {
+            lower_tail_expressions(&mut token.then_body);
+            lower_tail_expressions(token.else_body.as_mut().unwrap());
+        }
+        _ => {}
+    }
+}
+
+pub fn generate_tree(tokens: &mut TokenList) -> Result<Vec<Expr>, CompileError> {
+    if let Some(err) = tokens.errors.first() {
+        return Err(err.clone());
+    }
+    let mut return_vec = parse_program(&tokens.tokens, &tokens.positions)?;
+    let mut enums: HashSet<String> = return_vec.iter()
+        .filter_map(|expr| if let Expr::EnumDecl(decl) = expr { Some(decl.name.clone()) } else { None })
+        .collect();
+    enums.insert(OPTION_TYPE.to_string());
+    for expr in return_vec.iter_mut() {
+        walk_expr_mut(expr, &mut |expr| {
+            resolve_enum_types(expr, &enums);
+            match expr {
+                Expr::Function(token) => {
+                    lower_tail_expressions(&mut token.body);
+                }
+                Expr::Closure(token) if token.is_block => {
+                    lower_tail_expressions(&mut token.body);
+                }
+                _ => {}
+            }
+        });
+    }
+    // The program is the body of a function too, the declarations are not part of it
+    let program_end = return_vec.iter().rposition(|expr| !matches!(expr, Expr::Function(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..)));
+    if let Some(program_end) = program_end {
+        lower_tail_expressions(&mut return_vec[..=program_end]);
+    }
+    debug_print(&print_program(&return_vec));
+    return Ok(return_vec)
+}
+
+// Check that every struct named in a type has been declared
+pub fn check_type(value_type: &ValueType, types: &TypeTable) -> Result<(), CompileError> {
+    match value_type {
+        ValueType::Array(elem_type, _) | ValueType::Ref(elem_type, _) => {
+            return check_type(elem_type, types);
+        }
+        ValueType::Fn(params, return_type) => {
+            for value_type in params.iter().chain(return_type.as_deref()) {
+                check_type(value_type, types)?;
+            }
+            return Ok(());
+        }
+        ValueType::Struct(name) if types.generics.structs.contains_key(name) => {
+            return Err(CompileError::new(format!("generic struct {} is used without type arguments", name)));
+        }
+        ValueType::Struct(name) if !types.structs.contains_key(name) => {
+            return Err(CompileError::new(format!("unknown type {}", name)));
+        }
+        ValueType::Generic(_, type_args) => {
+            for type_arg in type_args.iter() {
+                check_type(type_arg, types)?;
+            }
+            if !types.structs.contains_key(&value_type.to_string()) {
+                return Err(CompileError::new(format!("unknown type {}", value_type.to_string())));
+            }
+            return Ok(());
+        }
+        ValueType::Enum(name) if !types.enums.contains_key(name) => {
+            return Err(CompileError::new(format!("unknown type {}", name)));
+        }
+        _ => {
+            return Ok(());
+        }
+    }
+}
+
+// Checks of the program that do not depend on the types of expressions, done before any code
+// is generated
+pub fn semantic_pass(expr_tree: &[Expr], types: &TypeTable) -> Result<(), CompileError> {
+    for expr in expr_tree {
+        semantic_check_statement(expr, types)?;
+    }
+    check_definite_assignment(expr_tree)?;
+    check_local_pointers(expr_tree)?;
+    return Ok(());
+}
+
+// Which variables are sure to have been given a value at some point in a function
+#[derive(Clone)]
+struct Assignments {
+    declared: HashMap<String, SourcePos>, // Where each variable that can be seen is declared
+    assigned: HashSet<String>,
+    returned: bool // Whether every way to get here has returned, nothing after it is run
+}
+
+impl Assignments {
+    fn new() -> Assignments {
+        return Assignments { declared: HashMap::new(), assigned: HashSet::new(), returned: false };
+    }
+    fn declare(&mut self, name: &str, pos: SourcePos, assigned: bool) {
+        self.declared.insert(name.to_string(), pos);
+        if assigned {
+            self.assigned.insert(name.to_string());
+        }
+        else {
+            self.assigned.remove(name);
+        }
+    }
+    // Reading a variable the compiler does not know about is reported by the compiler instead
+    fn check_read(&self, name: &str, pos: SourcePos) -> Result<(), CompileError> {
+        match self.declared.get(name) {
+            Some(declared) if !self.assigned.contains(name) && !self.returned => {
+                return Err(CompileError::at(pos, format!("use of possibly uninitialised variable {} at {}, it is declared at {}", name, pos.to_string(), declared.to_string())));
+            }
+            _ => {
+                return Ok(());
+            }
+        }
+    }
+    // Check the variables that an expression reads. A closure reads the variables it captures
+    // when it is created, its own body is checked like the body of a function
+    fn check_reads(&self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::IdentifierToken(token) => {
+                return self.check_read(&token.text, token.pos);
+            }
+            Expr::Call(token) => {
+                self.check_read(&token.name, token.pos)?;
+                for arg in token.args.iter() {
+                    self.check_reads(arg)?;
+                }
+            }
+            Expr::Closure(token) => {
+                let captures = closure_captures(token, &|name| self.declared.contains_key(name));
+                for name in captures.iter() {
+                    self.check_read(name, token.pos)?;
+                }
+                let mut assignments = Assignments::new();
+                for name in captures.iter() {
+                    assignments.declare(name, token.pos, true);
+                }
+                for (name, _) in token.params.iter() {
+                    assignments.declare(name, token.pos, true);
+                }
+                assignments.check_block(&token.body)?;
+            }
+            Expr::Operation(token) => {
+                self.check_reads(&token.expr1)?;
+                self.check_reads(&token.expr2)?;
+            }
+            Expr::ArrayLiteral(ArrayLiteral { elements: exprs }) | Expr::EnumLiteral(EnumLiteral { args: exprs, .. }) => {
+                for expr in exprs.iter() {
+                    self.check_reads(expr)?;
+                }
+            }
+            Expr::Index(token) => {
+                self.check_reads(&token.array)?;
+                self.check_reads(&token.index)?;
+            }
+            Expr::StructLiteral(token) => {
+                for (_, value) in token.fields.iter() {
+                    self.check_reads(value)?;
+                }
+            }
+            Expr::IndirectCall(token) => {
+                self.check_reads(&token.callee)?;
+                for arg in token.args.iter() {
+                    self.check_reads(arg)?;
+                }
+            }
+            // Taking the address of a variable counts as reading it, like in Rust
+            Expr::ArrayRepeat(ArrayRepeat { value: expr, .. }) | Expr::Field(Field { expr, .. }) | Expr::AddressOf(AddressOf { expr, .. })
+            | Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) => {
+                self.check_reads(expr)?;
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+    fn check_block(&mut self, body: &[Expr]) -> Result<(), CompileError> {
+        for expr in body.iter() {
+            self.check_statement(expr)?;
+        }
+        return Ok(());
+    }
+    // The assignments after a block that is run in a scope of its own, like the arm of a match.
+    // The variables it declares are gone after it, and so are the ones it hides
+    fn leave_scope(&self, inner: Assignments) -> HashSet<String> {
+        return self.declared.iter()
+            .filter(|(name, pos)| match inner.declared.get(*name) {
+                Some(inner_pos) if inner_pos == *pos => inner.assigned.contains(*name),
+                _ => self.assigned.contains(*name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+    }
+    // After code that can go different ways, a variable is assigned if it is assigned on every
+    // way that does not return
+    fn merge(&mut self, branches: Vec<Assignments>) {
+        let mut after: Option<HashSet<String>> = None;
+        for branch in branches.into_iter().filter(|branch| !branch.returned) {
+            let assigned = self.leave_scope(branch);
+            after = Some(match after {
+                Some(after) => after.intersection(&assigned).cloned().collect(),
+                None => assigned
+            });
+        }
+        match after {
+            Some(after) => {
+                self.assigned = after;
+            }
+            None => {
+                self.returned = true;
+            }
+        }
+    }
+    fn check_statement(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Let(token) => {
+                if let Some(value) = &token.value {
+                    self.check_reads(value)?;
+                }
+                self.declare(&token.name, token.pos, token.value.is_some());
+            }
+            Expr::Operation(token) if matches!(token.operator.op_type, OperatorType::Assign(..)) => {
+                self.check_reads(&token.expr2)?;
+                match (token.expr1.as_ref(), &token.operator.op_type) {
+                    (Expr::IdentifierToken(ident), OperatorType::Assign(None)) => {
+                        self.assigned.insert(ident.text.clone());
+                    }
+                    // Assigning to part of a variable, or adding to it, reads it first
+                    (place, _) => {
+                        self.check_reads(place)?;
+                    }
+                }
+            }
+            Expr::Match(token) => {
+                self.check_reads(&token.value)?;
+                let mut arms: Vec<Assignments> = vec!();
+                for arm in token.arms.iter() {
+                    let mut arm_assignments = self.clone();
+                    if let MatchPattern::Variant(_, _, bindings) = &arm.pattern {
+                        for binding in bindings.iter() {
+                            arm_assignments.declare(binding, arm.pos, true);
+                        }
+                    }
+                    arm_assignments.check_block(&arm.body)?;
+                    arms.push(arm_assignments);
+                }
+                self.merge(arms);
+            }
+            Expr::If(token) => {
+                self.check_reads(&token.condition)?;
+                let mut then_assignments = self.clone();
+                then_assignments.check_block(&token.then_body)?;
+                let mut else_assignments = self.clone();
+                if let Some(else_body) = &token.else_body {
+                    else_assignments.check_block(else_body)?;
+                }
+                self.merge(vec!(then_assignments, else_assignments));
+            }
+            // The body might not run at all, so what it assigns does not count after the loop.
+            // The first time it runs it only sees what was assigned before the loop
+            Expr::While(token) => {
+                self.check_reads(&token.condition)?;
+                self.clone().check_block(&token.body)?;
+            }
+            Expr::Return(token) => {
+                if let Some(value) = &token.value {
+                    self.check_reads(value)?;
+                }
+                self.returned = true;
+            }
+            Expr::Tail(token) => {
+                self.check_reads(&token.value)?;
+            }
+            Expr::Function(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) => {}
+            other => {
+                self.check_reads(other)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+// Make sure no variable is read before it has been given a value, on every way the program
+// can go to get to the read. Matches and ifs go different ways, and a loop can run any number
+// of times
+fn check_definite_assignment(expr_tree: &[Expr]) -> Result<(), CompileError> {
+    for expr in expr_tree {
+        if let Expr::Function(token) = expr {
+            let mut assignments = Assignments::new();
+            for (name, _) in token.params.iter() {
+                assignments.declare(name, token.pos, true);
+            }
+            assignments.check_block(&token.body)?;
+        }
+    }
+    return Assignments::new().check_block(expr_tree);
+}
+
+// Make sure no function returns a pointer to its own variables, and that no closure captures one
+fn check_local_pointers(expr_tree: &[Expr]) -> Result<(), CompileError> {
+    let returns_reference: HashSet<String> = expr_tree.iter()
+        .filter_map(|expr| match expr {
+            Expr::Function(token) if matches!(token.return_type, Some(ValueType::Ref(..) | ValueType::Fn(..))) => Some(token.name.clone()),
+            _ => None
+        })
+        .collect();
+    for expr in expr_tree {
+        if let Expr::Function(token) = expr {
+            let mut pointers = LocalPointers::new(true, &returns_reference);
+            for (name, value_type) in token.params.iter() {
+                pointers.declare(name, matches!(value_type, ValueType::Ref(..)));
+            }
+            pointers.check_block(&token.body)?;
+        }
+    }
+    return LocalPointers::new(false, &returns_reference).check_block(expr_tree);
+}
+
+// The variables of a function that point to its own variables. Such a pointer is no good once
+// the function has returned, so it cannot be returned or captured by a closure, which might be
+// called after that. Pointers are followed the same way on every branch, a variable that points
+// to a local on one of them is taken to point to it on all of them
+struct LocalPointers<'a> {
+    declared: HashSet<String>,
+    locals: HashSet<String>, // Variables that are gone when the function returns
+    references: HashSet<String>, // Variables that hold a reference rather than a value
+    to_locals: HashMap<String, String>, // Variables that may point to a local, and the local
+    in_function: bool, // Variables outside of functions are there until the program ends
+    returns_reference: &'a HashSet<String> // Functions that return a reference or a function
+}
+
+impl<'a> LocalPointers<'a> {
+    fn new(in_function: bool, returns_reference: &'a HashSet<String>) -> LocalPointers<'a> {
+        return LocalPointers {
+            declared: HashSet::new(),
+            locals: HashSet::new(),
+            references: HashSet::new(),
+            to_locals: HashMap::new(),
+            in_function,
+            returns_reference
+        };
+    }
+    fn declare(&mut self, name: &str, is_reference: bool) {
+        self.declared.insert(name.to_string());
+        if self.in_function {
+            self.locals.insert(name.to_string());
+        }
+        else {
+            self.locals.remove(name);
+        }
+        if is_reference {
+            self.references.insert(name.to_string());
+        }
+        else {
+            self.references.remove(name);
+        }
+        self.to_locals.remove(name);
+    }
+    // Whether a value is a reference, for the values that can be told without their types
+    fn is_reference(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::AddressOf(..) => {
+                return true;
+            }
+            Expr::Call(token) => {
+                return token.name == "alloc" || self.returns_reference.contains(&token.name);
+            }
+            Expr::IdentifierToken(token) => {
+                return self.references.contains(&token.text);
+            }
+            _ => {
+                return false;
+            }
+        }
+    }
+    // The local that the value of expr might point to
+    fn points_to_local(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::AddressOf(token) => {
+                return self.place_local(&token.expr);
+            }
+            Expr::IdentifierToken(token) => {
+                return self.to_locals.get(&token.text).cloned();
+            }
+            Expr::Field(Field { expr, .. }) | Expr::Index(Index { array: expr, .. }) | Expr::ArrayRepeat(ArrayRepeat { value: expr, .. }) => {
+                return self.points_to_local(expr);
+            }
+            Expr::ArrayLiteral(ArrayLiteral { elements: exprs }) | Expr::EnumLiteral(EnumLiteral { args: exprs, .. }) => {
+                return exprs.iter().find_map(|expr| self.points_to_local(expr));
+            }
+            Expr::StructLiteral(token) => {
+                return token.fields.iter().find_map(|(_, value)| self.points_to_local(value));
+            }
+            // A function that returns a reference might return one of its arguments
+            Expr::Call(token) if self.returns_reference.contains(&token.name) => {
+                return token.args.iter().find_map(|arg| self.points_to_local(arg));
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    // The local that &place might point to. The fields and elements of a reference are found
+    // through it, so they are where the reference points
+    fn place_local(&self, place: &Expr) -> Option<String> {
+        if let Expr::IdentifierToken(token) = place {
+            return self.locals.get(&token.text).cloned();
+        }
+        let mut root = place;
+        loop {
+            match root {
+                Expr::Field(Field { expr, .. }) | Expr::Index(Index { array: expr, .. }) => {
+                    root = expr;
+                }
+                Expr::Deref(token) => {
+                    return self.points_to_local(&token.expr);
+                }
+                _ => {
+                    break;
+                }
+            }
+        }
+        match root {
+            Expr::IdentifierToken(token) if self.references.contains(&token.text) => {
+                return self.to_locals.get(&token.text).cloned();
+            }
+            Expr::IdentifierToken(token) if self.locals.contains(&token.text) => {
+                return Some(token.text.clone());
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    // Check the closures in an expression
+    fn check_expr(&self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Closure(token) => {
+                let captures = closure_captures(token, &|name| self.declared.contains(name));
+                let mut body = LocalPointers::new(true, self.returns_reference);
+                for name in captures.iter() {
+                    if let Some(local) = self.to_locals.get(name) {
+                        return Err(CompileError::at(token.pos, format!("closure at {} captures {}, which points to the local variable {}", token.pos.to_string(), name, local)));
+                    }
+                    body.declare(name, self.references.contains(name));
+                }
+                for (name, value_type) in token.params.iter() {
+                    body.declare(name, matches!(value_type, ValueType::Ref(..)));
+                }
+                body.check_block(&token.body)?;
+            }
+            Expr::Call(Call { args: exprs, .. }) | Expr::ArrayLiteral(ArrayLiteral { elements: exprs })
+            | Expr::EnumLiteral(EnumLiteral { args: exprs, .. }) => {
+                for expr in exprs.iter() {
+                    self.check_expr(expr)?;
+                }
+            }
+            Expr::Operation(token) => {
+                self.check_expr(&token.expr1)?;
+                self.check_expr(&token.expr2)?;
+            }
+            Expr::Index(token) => {
+                self.check_expr(&token.array)?;
+                self.check_expr(&token.index)?;
+            }
+            Expr::StructLiteral(token) => {
+                for (_, value) in token.fields.iter() {
+                    self.check_expr(value)?;
+                }
+            }
+            Expr::IndirectCall(token) => {
+                self.check_expr(&token.callee)?;
+                for arg in token.args.iter() {
+                    self.check_expr(arg)?;
+                }
+            }
+            Expr::ArrayRepeat(ArrayRepeat { value: expr, .. }) | Expr::Field(Field { expr, .. }) | Expr::AddressOf(AddressOf { expr, .. })
+            | Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) => {
+                self.check_expr(expr)?;
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+    fn check_block(&mut self, body: &[Expr]) -> Result<(), CompileError> {
+        for expr in body.iter() {
+            self.check_statement(expr)?;
+        }
+        return Ok(());
+    }
+    fn check_statement(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Let(token) => {
+                let mut is_reference = matches!(token.value_type, Some(ValueType::Ref(..)));
+                let mut local = None;
+                if let Some(value) = &token.value {
+                    self.check_expr(value)?;
+                    is_reference |= self.is_reference(value);
+                    local = self.points_to_local(value);
+                }
+                self.declare(&token.name, is_reference);
+                if let Some(local) = local {
+                    self.to_locals.insert(token.name.clone(), local);
+                }
+            }
+            Expr::Operation(token) if matches!(token.operator.op_type, OperatorType::Assign(..)) => {
+                self.check_expr(&token.expr2)?;
+                let mut root = token.expr1.as_ref();
+                while let Expr::Field(Field { expr, .. }) | Expr::Index(Index { array: expr, .. }) = root {
+                    root = expr;
+                }
+                // Writing through a reference stores the value wherever the reference points,
+                // which outlives the function unless it is one of the function's own locals
+                let through = match root {
+                    Expr::Deref(deref) => Some(deref.expr.as_ref()),
+                    // A field or an element of a reference is found through it
+                    Expr::IdentifierToken(ident) if self.references.contains(&ident.text) && !matches!(token.expr1.as_ref(), Expr::IdentifierToken(..)) => Some(root),
+                    _ => None
+                };
+                if let (true, Some(through), Some(local)) = (self.in_function, through, self.points_to_local(&token.expr2)) {
+                    if self.points_to_local(through).is_none() {
+                        return Err(CompileError::at(token.pos, format!("storing a reference to the local variable {} in {} at {}, it is gone after the function returns", local, token.expr1.to_string(), token.pos.to_string())));
+                    }
+                }
+                if let (Expr::IdentifierToken(ident), Some(local)) = (root, self.points_to_local(&token.expr2)) {
+                    self.to_locals.insert(ident.text.clone(), local);
+                }
+            }
+            Expr::Match(token) => {
+                self.check_expr(&token.value)?;
+                for arm in token.arms.iter() {
+                    if let MatchPattern::Variant(_, _, bindings) = &arm.pattern {
+                        for binding in bindings.iter() {
+                            self.declare(binding, false);
+                        }
+                    }
+                    self.check_block(&arm.body)?;
+                }
+            }
+            Expr::If(token) => {
+                self.check_expr(&token.condition)?;
+                self.check_block(&token.then_body)?;
+                if let Some(else_body) = &token.else_body {
+                    self.check_block(else_body)?;
+                }
+            }
+            Expr::While(token) => {
+                self.check_expr(&token.condition)?;
+                self.check_block(&token.body)?;
+            }
+            Expr::Return(Return { value: Some(value), pos, .. }) => {
+                self.check_expr(value)?;
+                if let (true, Some(local)) = (self.in_function, self.points_to_local(value)) {
+                    return Err(CompileError::at(*pos, format!("returning a reference to the local variable {} at {}, it is gone after the function returns", local, pos.to_string())));
+                }
+            }
+            Expr::Tail(token) => {
+                self.check_expr(&token.value)?;
+            }
+            Expr::Function(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Return(..) => {}
+            other => {
+                self.check_expr(other)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn semantic_check_statement(expr: &Expr, types: &TypeTable) -> Result<(), CompileError> {
+    match expr {
+        Expr::Function(token) => {
+            for expr in token.body.iter() {
+                semantic_check_statement(expr, types)?;
+            }
+        }
+        Expr::Match(token) => {
+            check_match_arms(token, types)?;
+            for arm in token.arms.iter() {
+                for expr in arm.body.iter() {
+                    semantic_check_statement(expr, types)?;
+                }
+            }
+        }
+        Expr::If(token) => {
+            for expr in token.then_body.iter().chain(token.else_body.iter().flatten()) {
+                semantic_check_statement(expr, types)?;
+            }
+        }
+        Expr::While(token) => {
+            for expr in token.body.iter() {
+                semantic_check_statement(expr, types)?;
+            }
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+// Make sure the patterns of a match name real variants of one enum, and that every variant
+// is matched by some arm
+fn check_match_arms(token: &Match, types: &TypeTable) -> Result<(), CompileError> {
+    let mut enum_name: Option<&String> = None;
+    let mut covered: Vec<&String> = vec!();
+    let mut has_wildcard = false;
+    for arm in token.arms.iter() {
+        if has_wildcard {
+            return Err(CompileError::at(arm.pos, format!("unreachable match arm at {}, the _ arm before it matches everything", arm.pos.to_string())));
+        }
+        match &arm.pattern {
+            MatchPattern::Wildcard => {
+                has_wildcard = true;
+            }
+            MatchPattern::Variant(name, variant_name, bindings) => {
+                if let Some(enum_name) = enum_name {
+                    if enum_name != name {
+                        return Err(CompileError::at(arm.pos, format!("mismatched types in match at {}: expected {}, found {}", arm.pos.to_string(), enum_name, name)));
+                    }
+                }
+                enum_name = Some(name);
+                let layout = match types.enums.get(name) {
+                    Some(layout) => layout,
+                    None => {
+                        return Err(CompileError::at(arm.pos, format!("unknown enum {} at {}", name, arm.pos.to_string())));
+                    }
+                };
+                let variant = match layout.get_variant(variant_name) {
+                    Some((_, variant)) => variant,
+                    None => {
+                        return Err(CompileError::at(arm.pos, format!("enum {} has no variant {} at {}", name, variant_name, arm.pos.to_string())));
+                    }
+                };
+                if bindings.len() != variant.payload.len() {
+                    return Err(CompileError::at(arm.pos, format!("pattern {} has {} values but the variant has {} at {}", arm.pattern.to_string(), bindings.len(), variant.payload.len(), arm.pos.to_string())));
+                }
+                if covered.contains(&variant_name) {
+                    return Err(CompileError::at(arm.pos, format!("variant {}::{} is matched twice at {}", name, variant_name, arm.pos.to_string())));
+                }
+                covered.push(variant_name);
+            }
+        }
+    }
+    if has_wildcard {
+        return Ok(());
+    }
+    let enum_name = match enum_name {
+        Some(enum_name) => enum_name,
+        None => {
+            return Err(CompileError::at(token.pos, format!("match at {} has no arms", token.pos.to_string())));
+        }
+    };
+    let missing: Vec<String> = types.enums[enum_name].variants.iter()
+        .filter(|variant| !covered.contains(&&variant.name))
+        .map(|variant| format!("{}::{}", enum_name, variant.name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(CompileError::at(token.pos, format!("non-exhaustive match at {}: {} not covered", token.pos.to_string(), missing.join(", "))));
+    }
+    return Ok(());
+}
+
+// What a function looks like to the code calling it
+#[derive(Clone)]
+pub struct FunctionSignature {
+    pub params: Vec<ValueType>,
+    pub return_type: Option<ValueType>,
+    pub slot: *const usize // Where the address of the function is, see JIT::slots
+}
+
+impl FunctionSignature {
+    // The type of a pointer to the function
+    pub fn fn_type(&self) -> ValueType {
+        return ValueType::Fn(self.params.clone(), self.return_type.clone().map(Box::new));
+    }
+}
+
+pub type FunctionTable = HashMap<String, FunctionSignature>;
+
+// Aggregates are passed as a pointer to them, and are returned by writing them to memory
+// that the caller passes as a hidden first parameter
+pub fn make_cranelift_signature(module: &JITModule, params: &[ValueType], return_type: Option<&ValueType>) -> Signature {
+    let pointer_type = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    match return_type {
+        Some(return_type) if return_type.is_aggregate() => {
+            sig.params.push(AbiParam::new(pointer_type));
+        }
+        Some(return_type) => {
+            sig.returns.push(AbiParam::new(return_type.cranelift_type(pointer_type)));
+        }
+        None => {}
+    }
+    for param in params {
+        sig.params.push(AbiParam::new(param.cranelift_type(pointer_type)));
+    }
+    return sig;
+}
+
+// A closure that captures variables gets the address of them as a hidden parameter before
+// the others, see cranelift_closure
+pub fn make_closure_signature(module: &JITModule, params: &[ValueType], return_type: Option<&ValueType>, has_env: bool) -> Signature {
+    let mut sig = make_cranelift_signature(module, params, return_type);
+    if has_env {
+        let pointer_type = module.target_config().pointer_type();
+        sig.params.insert(0, AbiParam::new(pointer_type));
+    }
+    return sig;
+}
+
+// Work out the type of an expression without generating any code for it
+pub fn get_expr_type(expr: &Expr, trans: &FunctionTranslator) -> Result<ValueType, CompileError> {
+    match expr {
+        Expr::IdentifierToken(token) => {
+            if let Some(value_type) = trans.variable_type(&token.text) {
+                return Ok(value_type);
+            }
+            // The name of a function on its own is a pointer to it
+            if let Some(signature) = trans.functions.get(&token.text) {
+                return Ok(signature.fn_type());
+            }
+            if is_builtin_function(&token.text) {
+                return Err(CompileError::at(token.pos, format!("function {} is built in and cannot be used as a value", token.text)));
+            }
+            if trans.types.generics.functions.contains_key(&token.text) {
+                return Err(CompileError::at(token.pos, format!("generic function {} cannot be used as a value, only called", token.text)));
+            }
+            return Err(CompileError::at(token.pos, format!("unknown variable {} at {}", token.text, token.pos.to_string())));
+        }
+        Expr::NumberToken(..) => {
+            return Ok(ValueType::I32);
+        }
+        Expr::FloatToken(..) => {
+            return Ok(ValueType::F64);
+        }
+        Expr::Operation(token) => {
+            let op = match token.operator.op_type {
+                OperatorType::Operator(op) => op,
+                OperatorType::Assign(..) => {
+                    return Err(CompileError::new("Cannot parse assign operation inside expression!".to_string()));
+                }
+            };
+            let type1 = get_expr_type(&token.expr1, trans)?;
+            let type2 = get_expr_type(&token.expr2, trans)?;
+            return operation_type(op, &[type1, type2], token.pos);
+        }
+        Expr::Unary(token) => {
+            let value_type = get_expr_type(&token.expr, trans)?;
+            return operation_type(token.operator, &[value_type], token.pos);
+        }
+        Expr::ArrayLiteral(token) => {
+            if token.elements.is_empty() {
+                return Err(CompileError::new("cannot infer the type of an empty array".to_string()));
+            }
+            let elem_type = get_expr_type(&token.elements[0], trans)?;
+            for element in token.elements.iter().skip(1) {
+                let other_type = get_expr_type(element, trans)?;
+                if other_type != elem_type {
+                    return Err(CompileError::new(format!("mismatched types in array: expected {}, found {}", elem_type.to_string(), other_type.to_string())));
+                }
+            }
+            return Ok(ValueType::Array(Box::new(elem_type), token.elements.len()));
+        }
+        Expr::ArrayRepeat(token) => {
+            let elem_type = get_expr_type(&token.value, trans)?;
+            return Ok(ValueType::Array(Box::new(elem_type), token.count));
+        }
+        Expr::Index(token) => {
+            let index_type = get_expr_type(&token.index, trans)?;
+            if index_type != ValueType::I32 {
+                return Err(CompileError::at(token.pos, format!("array index must be i32, found {} at {}", index_type.to_string(), token.pos.to_string())));
+            }
+            match strip_refs(get_expr_type(&token.array, trans)?) {
+                ValueType::Array(elem_type, _) => {
+                    return Ok(*elem_type);
+                }
+                other => {
+                    return Err(CompileError::at(token.pos, format!("cannot index into a value of type {} at {}", other.to_string(), token.pos.to_string())));
+                }
+            }
+        }
+        Expr::StructLiteral(token) => {
+            let struct_type = struct_literal_type(token, trans)?;
+            let struct_name = struct_type.struct_name().unwrap();
+            let layout = match trans.types.structs.get(&struct_name) {
+                Some(layout) => layout,
+                None => {
+                    return Err(CompileError::at(token.pos, format!("unknown struct {} at {}", token.name, token.pos.to_string())));
+                }
+            };
+            for (name, value) in token.fields.iter() {
+                let field = match layout.get_field(name) {
+                    Some(field) => field,
+                    None => {
+                        return Err(CompileError::at(token.pos, format!("struct {} has no field {} at {}", token.name, name, token.pos.to_string())));
+                    }
+                };
+                let value_type = get_expr_type_as(value, &field.value_type, trans)?;
+                if !value_type.can_be_used_as(&field.value_type) {
+                    return Err(CompileError::at(token.pos, format!("mismatched types for field {} at {}: expected {}, found {}", name, token.pos.to_string(), field.value_type.to_string(), value_type.to_string())));
+                }
+                if token.fields.iter().filter(|(other, _)| other == name).count() > 1 {
+                    return Err(CompileError::at(token.pos, format!("field {} is given twice at {}", name, token.pos.to_string())));
+                }
+            }
+            for field in layout.fields.iter() {
+                if !token.fields.iter().any(|(name, _)| *name == field.name) {
+                    return Err(CompileError::at(token.pos, format!("missing field {} in {} at {}", field.name, struct_name, token.pos.to_string())));
+                }
+            }
+            return Ok(struct_type);
+        }
+        Expr::Field(token) => {
+            match strip_refs(get_expr_type(&token.expr, trans)?) {
+                struct_type @ (ValueType::Struct(..) | ValueType::Generic(..)) => {
+                    let name = struct_type.struct_name().unwrap();
+                    match trans.types.structs[&name].get_field(&token.field) {
+                        Some(field) => {
+                            return Ok(field.value_type.clone());
+                        }
+                        None => {
+                            return Err(CompileError::at(token.pos, format!("struct {} has no field {} at {}", name, token.field, token.pos.to_string())));
+                        }
+                    }
+                }
+                other => {
+                    return Err(CompileError::at(token.pos, format!("a value of type {} has no fields, at {}", other.to_string(), token.pos.to_string())));
+                }
+            }
+        }
+        Expr::Call(token) if is_builtin_function(&token.name) => {
+            let arg_types = check_builtin_args(token, trans)?;
+            if token.name == "alloc" {
+                return Ok(ValueType::Ref(Box::new(arg_types[0].clone()), true));
+            }
+            if let Some((mode, _)) = find_intrinsic(&token.name) {
+                return Ok(if mode == ArithmeticMode::Checked { ValueType::Enum(OPTION_TYPE.to_string()) } else { ValueType::I32 });
+            }
+            return Err(CompileError::at(token.pos, format!("function {} does not return a value, at {}", token.name, token.pos.to_string())));
+        }
+        Expr::Call(token) if trans.is_variable(&token.name) => {
+            let callee = Expr::IdentifierToken(Identifier { text: token.name.clone(), pos: token.pos });
+            return indirect_call_type(&callee, &token.args, token.pos, trans);
+        }
+        Expr::Call(token) => {
+            check_call_args(token, trans)?;
+            match &trans.functions[&resolve_call(token, trans)?].return_type {
+                Some(return_type) => {
+                    return Ok(return_type.clone());
+                }
+                None => {
+                    return Err(CompileError::at(token.pos, format!("function {} does not return a value, at {}", token.name, token.pos.to_string())));
+                }
+            }
+        }
+        Expr::IndirectCall(token) => {
+            return indirect_call_type(&token.callee, &token.args, token.pos, trans);
+        }
+        Expr::AddressOf(token) => {
+            if token.mutable && !is_mutable_place(&token.expr, trans)? {
+                return Err(CompileError::at(token.pos, format!("cannot borrow {} as mutable, it is behind a & reference, at {}", token.expr.to_string(), token.pos.to_string())));
+            }
+            return Ok(ValueType::Ref(Box::new(get_expr_type(&token.expr, trans)?), token.mutable));
+        }
+        Expr::Deref(token) => {
+            match get_expr_type(&token.expr, trans)? {
+                ValueType::Ref(inner_type, _) => {
+                    return Ok(*inner_type);
+                }
+                other => {
+                    return Err(CompileError::at(token.pos, format!("cannot dereference a value of type {} at {}", other.to_string(), token.pos.to_string())));
+                }
+            }
+        }
+        Expr::EnumLiteral(token) => {
+            let layout = match trans.types.enums.get(&token.enum_name) {
+                Some(layout) => layout,
+                None => {
+                    return Err(CompileError::at(token.pos, format!("unknown enum {} at {}", token.enum_name, token.pos.to_string())));
+                }
+            };
+            let variant = match layout.get_variant(&token.variant) {
+                Some((_, variant)) => variant,
+                None => {
+                    return Err(CompileError::at(token.pos, format!("enum {} has no variant {} at {}", token.enum_name, token.variant, token.pos.to_string())));
+                }
+            };
+            if variant.payload.len() != token.args.len() {
+                return Err(CompileError::at(token.pos, format!("variant {}::{} takes {} values but {} were given at {}", token.enum_name, token.variant, variant.payload.len(), token.args.len(), token.pos.to_string())));
+            }
+            for (payload_type, arg) in variant.payload.iter().zip(token.args.iter()) {
+                let arg_type = get_expr_type_as(arg, payload_type, trans)?;
+                if !arg_type.can_be_used_as(payload_type) {
+                    return Err(CompileError::at(token.pos, format!("mismatched types in {}::{} at {}: expected {}, found {}", token.enum_name, token.variant, token.pos.to_string(), payload_type.to_string(), arg_type.to_string())));
+                }
+            }
+            return Ok(ValueType::Enum(token.enum_name.clone()));
+        }
+        Expr::Closure(token) => {
+            let (params, return_type) = closure_signature(token, None, trans)?;
+            return Ok(closure_fn_type(&params, return_type));
+        }
+        Expr::Let(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Function(..) | Expr::Match(..)
+        | Expr::If(..) | Expr::While(..) | Expr::Return(..) | Expr::Tail(..) | Expr::Empty => {
+            return Err(CompileError::new("Could not parse expression".to_string()));
+        }
+    }
+}
+
+// The type of expr where a value of type expected is wanted. That is where closures get the
+// types of the parameters that are not written down from
+fn get_expr_type_as(expr: &Expr, expected: &ValueType, trans: &FunctionTranslator) -> Result<ValueType, CompileError> {
+    if let Expr::Closure(token) = expr {
+        let (params, return_type) = closure_signature(token, Some(expected), trans)?;
+        return Ok(closure_fn_type(&params, return_type));
+    }
+    // [] has no elements to get its type from, it gets it from where it is used. Its length is
+    // still 0, so using it as an array of another length is a mismatch
+    if let (Expr::ArrayLiteral(ArrayLiteral { elements }), ValueType::Array(elem_type, _)) = (expr, expected) {
+        if elements.is_empty() {
+            return Ok(ValueType::Array(elem_type.clone(), 0));
+        }
+    }
+    return get_expr_type(expr, trans);
+}
+
+// The parameters of a closure with their types, and its return type
+type ClosureSignature = (Vec<(String, ValueType)>, Option<ValueType>);
+
+fn closure_fn_type(params: &[(String, ValueType)], return_type: Option<ValueType>) -> ValueType {
+    let params: Vec<ValueType> = params.iter().map(|(_, value_type)| value_type.clone()).collect();
+    return ValueType::Fn(params, return_type.map(Box::new));
+}
+
+// The parameters and return type of a closure. The parameters without a type get theirs from
+// expected, the function type that the closure is used as. |params| value returns the type of
+// its value, which is worked out with the parameters in scope
+fn closure_signature(token: &Closure, expected: Option<&ValueType>, trans: &FunctionTranslator) -> Result<ClosureSignature, CompileError> {
+    let expected_params = match expected {
+        Some(ValueType::Fn(params, _)) if params.len() == token.params.len() => Some(params),
+        Some(expected @ ValueType::Fn(..)) => {
+            return Err(CompileError::at(token.pos, format!("mismatched types at {}: expected {}, found a closure that takes {} parameters", token.pos.to_string(), expected.to_string(), token.params.len())));
+        }
+        _ => None
+    };
+    let mut params: Vec<(String, ValueType)> = vec!();
+    for (index, (name, value_type)) in token.params.iter().enumerate() {
+        let value_type = match (value_type, expected_params) {
+            (ValueType::Infer, Some(expected_params)) => expected_params[index].clone(),
+            (ValueType::Infer, None) => {
+                return Err(CompileError::at(token.pos, format!("cannot infer the type of parameter {} of the closure at {}, give it one like |{}: i32|", name, token.pos.to_string(), name)));
+            }
+            (value_type, _) => value_type.clone()
+        };
+        check_type(&value_type, trans.types).map_err(|err| CompileError::at(token.pos, format!("{} in the closure at {}", err.message, token.pos.to_string())))?;
+        params.push((name.clone(), value_type));
+    }
+    let value = match (&token.return_type, token.body.first()) {
+        (Some(ValueType::Infer), Some(Expr::Return(Return { value: Some(value), .. }))) => value,
+        (return_type, _) => {
+            if let Some(return_type) = return_type {
+                check_type(return_type, trans.types).map_err(|err| CompileError::at(token.pos, format!("{} in the closure at {}", err.message, token.pos.to_string())))?;
+            }
+            return Ok((params, return_type.clone()));
+        }
+    };
+    trans.closure_scopes.borrow_mut().push(params.clone());
+    let return_type = if expr_has_value(value, &|call| call_returns_value(call, trans)) {
+        get_expr_type(value, trans).map(Some)
+    }
+    else {
+        Ok(None)
+    };
+    trans.closure_scopes.borrow_mut().pop();
+    return Ok((params, return_type?));
+}
+
+// The variables from outside of a closure that it uses, in the order they are first used.
+// is_variable tells which names are variables where the closure is created, the other names
+// that the closure does not declare itself are functions
+pub fn closure_captures(token: &Closure, is_variable: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let mut bound: Vec<String> = token.params.iter().map(|(name, _)| name.clone()).collect();
+    let mut free: Vec<String> = vec!();
+    for expr in token.body.iter() {
+        collect_free_variables(expr, &mut bound, &mut free);
+    }
+    free.retain(|name| is_variable(name));
+    return free;
+}
+
+fn use_variable(name: &str, bound: &[String], free: &mut Vec<String>) {
+    if !bound.iter().any(|bound| bound == name) && !free.iter().any(|free| free == name) {
+        free.push(name.to_string());
+    }
+}
+
+// Add the names that expr uses and that are not in bound to free. The variables that expr
+// declares are added to bound, for the code that comes after it
+fn collect_free_variables(expr: &Expr, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    match expr {
+        Expr::IdentifierToken(token) => {
+            use_variable(&token.text, bound, free);
+        }
+        Expr::Call(token) => {
+            use_variable(&token.name, bound, free);
+            for arg in token.args.iter() {
+                collect_free_variables(arg, bound, free);
+            }
+        }
+        Expr::Let(token) => {
+            if let Some(value) = &token.value {
+                collect_free_variables(value, bound, free);
+            }
+            bound.push(token.name.clone());
+        }
+        Expr::Match(token) => {
+            collect_free_variables(&token.value, bound, free);
+            for arm in token.arms.iter() {
+                let scope = bound.len();
+                if let MatchPattern::Variant(_, _, bindings) = &arm.pattern {
+                    bound.extend(bindings.iter().cloned());
+                }
+                for expr in arm.body.iter() {
+                    collect_free_variables(expr, bound, free);
+                }
+                bound.truncate(scope);
+            }
+        }
+        Expr::If(token) => {
+            collect_free_variables(&token.condition, bound, free);
+            for body in std::iter::once(&token.then_body).chain(token.else_body.iter()) {
+                let scope = bound.len();
+                for expr in body.iter() {
+                    collect_free_variables(expr, bound, free);
+                }
+                bound.truncate(scope);
+            }
+        }
+        Expr::While(token) => {
+            collect_free_variables(&token.condition, bound, free);
+            let scope = bound.len();
+            for expr in token.body.iter() {
+                collect_free_variables(expr, bound, free);
+            }
+            bound.truncate(scope);
+        }
+        Expr::Closure(token) => {
+            let scope = bound.len();
+            bound.extend(token.params.iter().map(|(name, _)| name.clone()));
+            for expr in token.body.iter() {
+                collect_free_variables(expr, bound, free);
+            }
+            bound.truncate(scope);
+        }
+        Expr::Operation(token) => {
+            collect_free_variables(&token.expr1, bound, free);
+            collect_free_variables(&token.expr2, bound, free);
+        }
+        Expr::ArrayLiteral(ArrayLiteral { elements: exprs }) | Expr::EnumLiteral(EnumLiteral { args: exprs, .. }) => {
+            for expr in exprs.iter() {
+                collect_free_variables(expr, bound, free);
+            }
+        }
+        Expr::Index(token) => {
+            collect_free_variables(&token.array, bound, free);
+            collect_free_variables(&token.index, bound, free);
+        }
+        Expr::StructLiteral(token) => {
+            for (_, value) in token.fields.iter() {
+                collect_free_variables(value, bound, free);
+            }
+        }
+        Expr::IndirectCall(token) => {
+            collect_free_variables(&token.callee, bound, free);
+            for arg in token.args.iter() {
+                collect_free_variables(arg, bound, free);
+            }
+        }
+        Expr::ArrayRepeat(ArrayRepeat { value: expr, .. }) | Expr::Field(Field { expr, .. }) | Expr::AddressOf(AddressOf { expr, .. })
+        | Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) | Expr::Tail(Tail { value: expr, .. }) => {
+            collect_free_variables(expr, bound, free);
+        }
+        Expr::Return(token) => {
+            if let Some(value) = &token.value {
+                collect_free_variables(value, bound, free);
+            }
+        }
+        Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Function(..) | Expr::Empty => {}
+    }
+}
+
+// Whether an expression gives a value, calls to functions that do not return anything and
+// assignments do not. call_returns_value tells if a call to a function that is not built in
+// returns something
+pub fn expr_has_value(expr: &Expr, call_returns_value: &dyn Fn(&Expr) -> bool) -> bool {
+    match expr {
+        Expr::Call(token) if is_builtin_function(&token.name) => {
+            return token.name == "alloc" || find_intrinsic(&token.name).is_some();
+        }
+        Expr::Call(..) | Expr::IndirectCall(..) => {
+            return call_returns_value(expr);
+        }
+        Expr::Operation(token) => {
+            return !matches!(token.operator.op_type, OperatorType::Assign(..));
+        }
+        Expr::Let(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Function(..) | Expr::Match(..)
+        | Expr::If(..) | Expr::While(..) | Expr::Return(..) | Expr::Tail(..) | Expr::Empty => {
+            return false;
+        }
+        _ => {
+            return true;
+        }
+    }
+}
+
+// Field access and indexing look through references, so r.x works when r is a &point
+fn strip_refs(value_type: ValueType) -> ValueType {
+    match value_type {
+        ValueType::Ref(inner_type, _) => {
+            return strip_refs(*inner_type);
+        }
+        _ => {
+            return value_type;
+        }
+    }
+}
+
+// Whether assigning to the place, or taking a &mut to it, is allowed. It is not when the
+// place is reached through a & reference
+fn is_mutable_place(expr: &Expr, trans: &FunctionTranslator) -> Result<bool, CompileError> {
+    let base = match expr {
+        Expr::Index(token) => &token.array,
+        Expr::Field(token) => &token.expr,
+        Expr::Deref(token) => {
+            if let ValueType::Ref(_, mutable) = get_expr_type(&token.expr, trans)? {
+                return Ok(mutable);
+            }
+            return Ok(false);
+        }
+        _ => {
+            return Ok(true);
+        }
+    };
+    let mut base_type = get_expr_type(base, trans)?;
+    if let ValueType::Ref(..) = base_type {
+        // Every reference on the way has to be a &mut
+        while let ValueType::Ref(inner_type, mutable) = base_type {
+            if !mutable {
+                return Ok(false);
+            }
+            base_type = *inner_type;
+        }
+        return Ok(true);
+    }
+    return is_mutable_place(base, trans);
+}
+
+// Memory reached through a reference might be on the heap and might have been freed, so loads
+// and stores to it are not marked as trusted like the ones to stack slots
+fn heap_mem_flags() -> MemFlags {
+    let mut flags = MemFlags::new();
+    flags.set_aligned();
+    return flags;
+}
+
+fn place_mem_flags(expr: &Expr, trans: &FunctionTranslator) -> Result<MemFlags, CompileError> {
+    let base = match expr {
+        Expr::Index(token) => &token.array,
+        Expr::Field(token) => &token.expr,
+        Expr::Deref(..) => {
+            return Ok(heap_mem_flags());
+        }
+        _ => {
+            return Ok(MemFlags::trusted());
+        }
+    };
+    if let ValueType::Ref(..) = get_expr_type(base, trans)? {
+        return Ok(heap_mem_flags());
+    }
+    return place_mem_flags(base, trans);
+}
+
+// Functions that are part of the language rather than declared in the program
+pub fn is_builtin_function(name: &str) -> bool {
+    return name == "alloc" || name == "free" || name == "print" || find_intrinsic(name).is_some();
+}
+
+fn check_builtin_args(token: &Call, trans: &FunctionTranslator) -> Result<Vec<ValueType>, CompileError> {
+    if find_intrinsic(&token.name).is_some() {
+        if token.args.len() != 2 {
+            return Err(CompileError::at(token.pos, format!("function {} takes 2 arguments but {} were given at {}", token.name, token.args.len(), token.pos.to_string())));
+        }
+        for arg in token.args.iter() {
+            let arg_type = get_expr_type(arg, trans)?;
+            if arg_type != ValueType::I32 {
+                return Err(CompileError::at(token.pos, format!("{} expects i32, found {} at {}", token.name, arg_type.to_string(), token.pos.to_string())));
+            }
+        }
+        return Ok(vec!(ValueType::I32, ValueType::I32));
+    }
+    if token.args.len() != 1 {
+        return Err(CompileError::at(token.pos, format!("function {} takes 1 arguments but {} were given at {}", token.name, token.args.len(), token.pos.to_string())));
+    }
+    let arg_type = get_expr_type(&token.args[0], trans)?;
+    if token.name == "free" && !matches!(arg_type, ValueType::Ref(..)) {
+        return Err(CompileError::at(token.pos, format!("free expects a reference, found {} at {}", arg_type.to_string(), token.pos.to_string())));
+    }
+    if token.name == "print" && arg_type != ValueType::I32 && arg_type != ValueType::F64 {
+        return Err(CompileError::at(token.pos, format!("print expects i32 or f64, found {} at {}", arg_type.to_string(), token.pos.to_string())));
+    }
+    return Ok(vec!(arg_type));
+}
+
+fn check_call_args(token: &Call, trans: &FunctionTranslator) -> Result<(), CompileError> {
+    let name = resolve_call(token, trans)?;
+    let signature = match trans.functions.get(&name) {
+        Some(signature) => signature,
+        None => {
+            return Err(CompileError::at(token.pos, format!("unknown function {} at {}", token.name, token.pos.to_string())));
+        }
+    };
+    return check_args(&name, &signature.params, &token.args, token.pos, trans);
+}
+
+// The name of the function that a call goes to. A call to a generic function goes to the
+// instance for its type arguments, which are either given like max::<i32>(a, b) or worked out
+// from the types of the arguments. An instance that is not in the program yet is added to
+// missing_instances, and the program is built again with it, see JIT::update
+fn resolve_call(token: &Call, trans: &FunctionTranslator) -> Result<String, CompileError> {
+    let generic = match trans.types.generics.functions.get(&token.name) {
+        Some(generic) => generic,
+        None => {
+            return Ok(token.name.clone());
+        }
+    };
+    let type_args = if token.type_args.is_empty() {
+        if generic.params.len() != token.args.len() {
+            return Err(CompileError::at(token.pos, format!("function {} takes {} arguments but {} were given at {}", token.name, generic.params.len(), token.args.len(), token.pos.to_string())));
+        }
+        // Closures get the types of their parameters from the type they are passed as, so they
+        // are looked at after the other arguments
+        let param_types: Vec<ValueType> = generic.params.iter().map(|(_, value_type)| value_type.clone()).collect();
+        let mut arg_types: Vec<Option<ValueType>> = vec!();
+        for arg in token.args.iter() {
+            arg_types.push(if matches!(arg, Expr::Closure(..)) { None } else { Some(get_expr_type(arg, trans)?) });
+        }
+        let mut type_args = infer_type_args(&generic.type_params, &param_types, &arg_types);
+        for (index, arg) in token.args.iter().enumerate() {
+            if !matches!(arg, Expr::Closure(..)) {
+                continue;
+            }
+            let known_args: Vec<ValueType> = type_args.iter().map(|type_arg| type_arg.clone().unwrap_or(ValueType::Infer)).collect();
+            let expected = substitute_type(&param_types[index], &generic.type_params, &known_args);
+            arg_types[index] = Some(get_expr_type_as(arg, &expected, trans)?);
+            type_args = infer_type_args(&generic.type_params, &param_types, &arg_types);
+        }
+        let mut known_args: Vec<ValueType> = vec!();
+        for (type_param, type_arg) in generic.type_params.iter().zip(type_args) {
+            match type_arg {
+                Some(type_arg) => {
+                    known_args.push(type_arg);
+                }
+                None => {
+                    return Err(CompileError::at(token.pos, format!("cannot infer the type parameter {} of {} at {}, give it like {}::<...>(...)", type_param, token.name, token.pos.to_string(), token.name)));
+                }
+            }
+        }
+        known_args
+    }
+    else {
+        token.type_args.clone()
+    };
+    return trans.require_instance(Instance::Function(token.name.clone(), type_args), token.pos);
+}
+
+// The type of a struct literal. The type arguments of a generic struct are worked out from the
+// types of the fields
+fn struct_literal_type(token: &StructLiteral, trans: &FunctionTranslator) -> Result<ValueType, CompileError> {
+    let generic = match trans.types.generics.structs.get(&token.name) {
+        Some(generic) => generic,
+        None => {
+            return Ok(ValueType::Struct(token.name.clone()));
+        }
+    };
+    let mut param_types: Vec<ValueType> = vec!();
+    let mut arg_types: Vec<Option<ValueType>> = vec!();
+    for (name, value) in token.fields.iter() {
+        if let Some((_, value_type)) = generic.fields.iter().find(|(field, _)| field == name) {
+            param_types.push(value_type.clone());
+            arg_types.push(if matches!(value, Expr::Closure(..)) { None } else { Some(get_expr_type(value, trans)?) });
+        }
+    }
+    let mut known_args: Vec<ValueType> = vec!();
+    for (type_param, type_arg) in generic.type_params.iter().zip(infer_type_args(&generic.type_params, &param_types, &arg_types)) {
+        match type_arg {
+            Some(type_arg) => {
+                known_args.push(type_arg);
+            }
+            None => {
+                return Err(CompileError::at(token.pos, format!("cannot infer the type parameter {} of {} at {}", type_param, token.name, token.pos.to_string())));
+            }
+        }
+    }
+    trans.require_instance(Instance::Struct(token.name.clone(), known_args.clone()), token.pos)?;
+    return Ok(ValueType::Generic(token.name.clone(), known_args));
+}
+
+fn check_args(name: &str, params: &[ValueType], args: &[Expr], pos: SourcePos, trans: &FunctionTranslator) -> Result<(), CompileError> {
+    if params.len() != args.len() {
+        return Err(CompileError::at(pos, format!("function {} takes {} arguments but {} were given at {}", name, params.len(), args.len(), pos.to_string())));
+    }
+    for (param_type, arg) in params.iter().zip(args.iter()) {
+        let arg_type = get_expr_type_as(arg, param_type, trans)?;
+        if !arg_type.can_be_used_as(param_type) {
+            return Err(CompileError::at(pos, format!("mismatched types in call to {} at {}: expected {}, found {}", name, pos.to_string(), param_type.to_string(), arg_type.to_string())));
+        }
+    }
+    return Ok(());
+}
+
+// The parameters and return type of the function that callee points to, after checking the
+// arguments of the call against them
+fn fn_pointer_signature(callee: &Expr, args: &[Expr], pos: SourcePos, trans: &FunctionTranslator) -> Result<(Vec<ValueType>, Option<ValueType>), CompileError> {
+    match get_expr_type(callee, trans)? {
+        ValueType::Fn(params, return_type) => {
+            check_args(&callee.to_string(), &params, args, pos, trans)?;
+            return Ok((params, return_type.map(|return_type| *return_type)));
+        }
+        other => {
+            return Err(CompileError::at(pos, format!("cannot call {} at {}, a value of type {} is not a function", callee.to_string(), pos.to_string(), other.to_string())));
+        }
+    }
+}
+
+fn indirect_call_type(callee: &Expr, args: &[Expr], pos: SourcePos, trans: &FunctionTranslator) -> Result<ValueType, CompileError> {
+    match fn_pointer_signature(callee, args, pos, trans)? {
+        (_, Some(return_type)) => {
+            return Ok(return_type);
+        }
+        (_, None) => {
+            return Err(CompileError::at(pos, format!("function {} does not return a value, at {}", callee.to_string(), pos.to_string())));
+        }
+    }
+}
+
+// Whether a call to a function that is not built in gives a value, see expr_has_value
+fn call_returns_value(call: &Expr, trans: &FunctionTranslator) -> bool {
+    let callee_type = match call {
+        Expr::Call(token) if !trans.is_variable(&token.name) => {
+            let name = resolve_call(token, trans).unwrap_or_default();
+            return trans.functions.get(&name).is_some_and(|function| function.return_type.is_some());
+        }
+        Expr::Call(token) => trans.variable_type(&token.name),
+        Expr::IndirectCall(token) => get_expr_type(&token.callee, trans).ok(),
+        _ => None
+    };
+    return matches!(callee_type, Some(ValueType::Fn(_, Some(_))));
+}
+
+// Get the address of array[index], generating a bounds check unless the index is a constant
+// that is known to be in bounds
+fn cranelift_index_address(token: &Index, trans: &mut FunctionTranslator) -> Result<(Value, ValueType), CompileError> {
+    let (elem_type, len) = match strip_refs(get_expr_type(&token.array, trans)?) {
+        ValueType::Array(elem_type, len) => (*elem_type, len),
+        other => {
+            return Err(CompileError::at(token.pos, format!("cannot index into a value of type {} at {}", other.to_string(), token.pos.to_string())));
+        }
+    };
+    let index_type = get_expr_type(&token.index, trans)?;
+    if index_type != ValueType::I32 {
+        return Err(CompileError::at(token.pos, format!("array index must be i32, found {} at {}", index_type.to_string(), token.pos.to_string())));
+    }
+    let array_addr = cranelift_aggregate_address(&token.array, token.pos, trans)?;
+    let index = cranelift_recursive_treverse_tree(&token.index, trans)?;
+
+    let known_in_bounds = match token.index.as_ref() {
+        Expr::NumberToken(num_token) => num_token.num >= 0 && (num_token.num as usize) < len,
+        _ => false
+    };
+    if !known_in_bounds {
+        trans.bounds_check(index, len, token.pos);
+    }
+
+    let pointer_type = trans.module.target_config().pointer_type();
+    let offset = trans.builder.ins().uextend(pointer_type, index);
+    let offset = trans.builder.ins().imul_imm(offset, elem_type.size(trans.types) as i64);
+    let elem_addr = trans.builder.ins().iadd(array_addr, offset);
+    return Ok((elem_addr, elem_type));
+}
+
+// Get the address of expr.field
+fn cranelift_field_address(token: &Field, trans: &mut FunctionTranslator) -> Result<(Value, ValueType), CompileError> {
+    let struct_name = match strip_refs(get_expr_type(&token.expr, trans)?) {
+        struct_type @ (ValueType::Struct(..) | ValueType::Generic(..)) => struct_type.struct_name().unwrap(),
+        other => {
+            return Err(CompileError::at(token.pos, format!("a value of type {} has no fields, at {}", other.to_string(), token.pos.to_string())));
+        }
+    };
+    let (offset, value_type) = match trans.types.structs[&struct_name].get_field(&token.field) {
+        Some(field) => (field.offset, field.value_type.clone()),
+        None => {
+            return Err(CompileError::at(token.pos, format!("struct {} has no field {} at {}", struct_name, token.field, token.pos.to_string())));
+        }
+    };
+    let struct_addr = cranelift_aggregate_address(&token.expr, token.pos, trans)?;
+    let field_addr = trans.builder.ins().iadd_imm(struct_addr, offset as i64);
+    return Ok((field_addr, value_type));
+}
+
+// Get the address of something that can be assigned to in memory, like a[i] or p.x
+fn cranelift_place_address(expr: &Expr, trans: &mut FunctionTranslator) -> Result<(Value, ValueType), CompileError> {
+    match expr {
+        Expr::Index(token) => {
+            return cranelift_index_address(token, trans);
+        }
+        Expr::Field(token) => {
+            return cranelift_field_address(token, trans);
+        }
+        Expr::Deref(token) => {
+            let value_type = get_expr_type(expr, trans)?;
+            let addr = cranelift_recursive_treverse_tree(&token.expr, trans)?;
+            if can_point_to_heap(&token.expr, trans)? {
+                trans.check_not_freed(addr, token.pos);
+            }
+            return Ok((addr, value_type));
+        }
+        _ => {
+            return Err(CompileError::new(format!("cannot assign to {}", expr.to_string())));
+        }
+    }
+}
+
+// Get the address of an array or struct, following any references to it. A reference to an
+// aggregate is the same as the address of the aggregate
+fn cranelift_aggregate_address(expr: &Expr, pos: SourcePos, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let pointer_type = trans.module.target_config().pointer_type();
+    let mut value_type = get_expr_type(expr, trans)?;
+    let mut addr = cranelift_recursive_treverse_tree(expr, trans)?;
+    let mut check = can_point_to_heap(expr, trans)?;
+    while let ValueType::Ref(inner_type, _) = value_type {
+        if check {
+            trans.check_not_freed(addr, pos);
+        }
+        // A reference that a reference points to can point anywhere
+        check = true;
+        if let ValueType::Ref(..) = inner_type.as_ref() {
+            addr = trans.builder.ins().load(pointer_type, heap_mem_flags(), addr, 0);
+        }
+        value_type = *inner_type;
+    }
+    return Ok(addr);
+}
+
+// Get the address of expr, values that are not stored anywhere yet are put in a new stack slot
+fn cranelift_address_of(expr: &Expr, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let value_type = get_expr_type(expr, trans)?;
+    match expr {
+        Expr::IdentifierToken(token) if trans.variables.contains_key(&token.text) => {
+            // Both aggregates and variables that are used with & hold the address of their memory
+            let var = trans.variables[&token.text];
+            return Ok(trans.builder.use_var(var));
+        }
+        Expr::Index(..) | Expr::Field(..) | Expr::Deref(..) => {
+            let (addr, _) = cranelift_place_address(expr, trans)?;
+            return Ok(addr);
+        }
+        _ => {
+            let val = cranelift_recursive_treverse_tree(expr, trans)?;
+            if value_type.is_aggregate() && is_fresh_aggregate(expr) {
+                return Ok(val);
+            }
+            let addr = trans.create_stack_slot(&value_type);
+            trans.store_value(val, addr, 0, &value_type, MemFlags::trusted());
+            return Ok(addr);
+        }
+    }
+}
+
+// Find the variables that are used with &, they have to be kept in memory
+fn collect_addressed_variables(expr: &Expr, names: &mut HashSet<String>) {
+    match expr {
+        Expr::AddressOf(token) => {
+            if let Expr::IdentifierToken(ident) = token.expr.as_ref() {
+                names.insert(ident.text.clone());
+            }
+            collect_addressed_variables(&token.expr, names);
+        }
+        Expr::Operation(token) => {
+            collect_addressed_variables(&token.expr1, names);
+            collect_addressed_variables(&token.expr2, names);
+        }
+        Expr::ArrayLiteral(token) => {
+            for element in token.elements.iter() {
+                collect_addressed_variables(element, names);
+            }
+        }
+        Expr::ArrayRepeat(token) => {
+            collect_addressed_variables(&token.value, names);
+        }
+        Expr::Index(token) => {
+            collect_addressed_variables(&token.array, names);
+            collect_addressed_variables(&token.index, names);
+        }
+        Expr::StructLiteral(token) => {
+            for (_, value) in token.fields.iter() {
+                collect_addressed_variables(value, names);
+            }
+        }
+        Expr::Field(token) => {
+            collect_addressed_variables(&token.expr, names);
+        }
+        Expr::Call(token) => {
+            for arg in token.args.iter() {
+                collect_addressed_variables(arg, names);
+            }
+        }
+        Expr::IndirectCall(token) => {
+            collect_addressed_variables(&token.callee, names);
+            for arg in token.args.iter() {
+                collect_addressed_variables(arg, names);
+            }
+        }
+        Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) => {
+            collect_addressed_variables(expr, names);
+        }
+        Expr::EnumLiteral(token) => {
+            for arg in token.args.iter() {
+                collect_addressed_variables(arg, names);
+            }
+        }
+        Expr::Let(token) => {
+            if let Some(value) = &token.value {
+                collect_addressed_variables(value, names);
+            }
+        }
+        Expr::Match(token) => {
+            collect_addressed_variables(&token.value, names);
+            for arm in token.arms.iter() {
+                for expr in arm.body.iter() {
+                    collect_addressed_variables(expr, names);
+                }
+            }
+        }
+        Expr::If(token) => {
+            collect_addressed_variables(&token.condition, names);
+            for expr in token.then_body.iter().chain(token.else_body.iter().flatten()) {
+                collect_addressed_variables(expr, names);
+            }
+        }
+        Expr::While(token) => {
+            collect_addressed_variables(&token.condition, names);
+            for expr in token.body.iter() {
+                collect_addressed_variables(expr, names);
+            }
+        }
+        Expr::Return(token) => {
+            if let Some(value) = &token.value {
+                collect_addressed_variables(value, names);
+            }
+        }
+        Expr::Tail(token) => {
+            collect_addressed_variables(&token.value, names);
+        }
+        // The body of a closure is built as a function of its own, with its own variables
+        Expr::Closure(..) => {}
+        Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Function(..) | Expr::Empty => {}
+    }
+}
+
+// Fill a new stack slot with count copies of value
+fn cranelift_array_repeat(token: &ArrayRepeat, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let elem_type = get_expr_type(&token.value, trans)?;
+    let elem_size = elem_type.size(trans.types) as i64;
+    let array_addr = trans.create_stack_slot(&ValueType::Array(Box::new(elem_type.clone()), token.count));
+    let value = cranelift_recursive_treverse_tree(&token.value, trans)?;
+
+    // Short arrays are just written out, longer ones get a loop
+    if token.count <= 16 {
+        for i in 0..token.count {
+            trans.store_value(value, array_addr, (i as i64 * elem_size) as i32, &elem_type, MemFlags::trusted());
         }
-        Some(Token::EndExpr) | None => {
-            tokens.curr += 1;
-            return Ok(Expr::Operation(Operation{expr1: Box::new(arg1), expr2: Box::new(arg2), operator: operator}));
+        return Ok(array_addr);
+    }
+    let pointer_type = trans.module.target_config().pointer_type();
+    let header_block = trans.builder.create_block();
+    let body_block = trans.builder.create_block();
+    let exit_block = trans.builder.create_block();
+    trans.builder.append_block_param(header_block, pointer_type);
+
+    let start_offset = trans.builder.ins().iconst(pointer_type, 0);
+    trans.builder.ins().jump(header_block, &[start_offset]);
+
+    trans.builder.switch_to_block(header_block);
+    let offset = trans.builder.block_params(header_block)[0];
+    let done = trans.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, offset, token.count as i64 * elem_size);
+    trans.builder.ins().brnz(done, exit_block, &[]);
+    trans.builder.ins().jump(body_block, &[]);
+
+    trans.builder.switch_to_block(body_block);
+    trans.builder.seal_block(body_block);
+    let elem_addr = trans.builder.ins().iadd(array_addr, offset);
+    trans.store_value(value, elem_addr, 0, &elem_type, MemFlags::trusted());
+    let next_offset = trans.builder.ins().iadd_imm(offset, elem_size);
+    trans.builder.ins().jump(header_block, &[next_offset]);
+    trans.builder.seal_block(header_block);
+
+    trans.builder.switch_to_block(exit_block);
+    trans.builder.seal_block(exit_block);
+    return Ok(array_addr);
+}
+
+// An operator on operands that are already worked out. With overflow checks on, i32 arithmetic
+// that overflows is a runtime error instead of wrapping around
+fn lower_operation(op: &Operator, args: &[Value], value_type: &ValueType, pos: SourcePos, trans: &mut FunctionTranslator) -> Value {
+    match &op.overflow {
+        Some(overflow) if trans.overflow_checks && *value_type == ValueType::I32 => {
+            if overflow.divides {
+                trans.check_divisor(args[1], pos);
+            }
+            let wide = trans.exact_operation(overflow, args);
+            let (result, fits) = trans.narrow(wide);
+            let fits = if overflow.shifts { trans.shift_in_range(args[1]) } else { fits };
+            trans.overflow_check(fits, op, pos);
+            return result;
         }
         _ => {
-            return Err("invalid operator".to_string());
+            return (op.lower)(trans, args, value_type, pos);
         }
     }
-    return Ok(arg1);
 }
 
-pub fn generate_tree(tokens: &mut TokenList) -> Result<Vec<Expr>, String> {
-    let mut return_vec: Vec<Expr> = vec!();
-    while !tokens.is_end() {
-        let expr = recursive_generate_tree(tokens, None);
-        match expr {
-            Err(err) => {
-                return Err(err);
+// wrapping_add(a, b), checked_add(a, b) and saturating_add(a, b), and the same for the other
+// operators that can overflow. Whether overflow checks are on makes no difference to them
+fn cranelift_intrinsic_call(token: &Call, mode: ArithmeticMode, op: &'static Operator, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let overflow = op.overflow.as_ref().unwrap();
+    let a = cranelift_recursive_treverse_tree(&token.args[0], trans)?;
+    let b = cranelift_recursive_treverse_tree(&token.args[1], trans)?;
+    match mode {
+        ArithmeticMode::Wrapping => {
+            return Ok((op.lower)(trans, &[a, b], &ValueType::I32, token.pos));
+        }
+        ArithmeticMode::Checked => {
+            // Divide by 1 instead of 0, the result is thrown away anyway
+            let mut b = b;
+            let mut is_zero = trans.builder.ins().bconst(types::B1, false);
+            if overflow.divides {
+                is_zero = trans.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                let one = trans.builder.ins().iconst(types::I32, 1);
+                b = trans.builder.ins().select(is_zero, one, b);
             }
-            Ok(Expr::Empty) => {
-                continue;
+            let wide = trans.exact_operation(overflow, &[a, b]);
+            let (result, fits) = trans.narrow(wide);
+            let fits = if overflow.shifts { trans.shift_in_range(b) } else { fits };
+            let no = trans.builder.ins().bconst(types::B1, false);
+            let is_some = trans.builder.ins().select(is_zero, no, fits);
+
+            let option_type = ValueType::Enum(OPTION_TYPE.to_string());
+            let layout = &trans.types.enums[OPTION_TYPE];
+            let (some_tag, some) = layout.get_variant("some").unwrap();
+            let (none_tag, _) = layout.get_variant("none").unwrap();
+            let payload_offset = some.offsets[0] as i32;
+            let addr = trans.create_stack_slot(&option_type);
+            let some_tag = trans.builder.ins().iconst(types::I32, some_tag as i64);
+            let none_tag = trans.builder.ins().iconst(types::I32, none_tag as i64);
+            let tag = trans.builder.ins().select(is_some, some_tag, none_tag);
+            trans.store_value(tag, addr, 0, &ValueType::I32, MemFlags::trusted());
+            trans.store_value(result, addr, payload_offset, &ValueType::I32, MemFlags::trusted());
+            return Ok(addr);
+        }
+        ArithmeticMode::Saturating => {
+            if overflow.divides {
+                trans.check_divisor(b, token.pos);
             }
-            _ => {
-                println!("{}", expr.as_ref().unwrap().to_string());
-                return_vec.push(expr.unwrap());
+            // Shifting by more than 31 shifts by 31, and by less than 0 does not shift
+            let mut b = b;
+            if overflow.shifts {
+                let zero = trans.builder.ins().iconst(types::I32, 0);
+                let most = trans.builder.ins().iconst(types::I32, 31);
+                let above = trans.builder.ins().icmp(IntCC::SignedGreaterThan, b, most);
+                let below = trans.builder.ins().icmp(IntCC::SignedLessThan, b, zero);
+                b = trans.builder.ins().select(above, most, b);
+                b = trans.builder.ins().select(below, zero, b);
             }
+            let wide = trans.exact_operation(overflow, &[a, b]);
+            let max = trans.builder.ins().iconst(types::I64, i32::MAX as i64);
+            let min = trans.builder.ins().iconst(types::I64, i32::MIN as i64);
+            let above = trans.builder.ins().icmp(IntCC::SignedGreaterThan, wide, max);
+            let below = trans.builder.ins().icmp(IntCC::SignedLessThan, wide, min);
+            let clamped = trans.builder.ins().select(above, max, wide);
+            let clamped = trans.builder.ins().select(below, min, clamped);
+            return Ok(trans.builder.ins().ireduce(types::I32, clamped));
         }
     }
-    return Ok(return_vec)
 }
 
-pub fn cranelift_recursive_treverse_tree(expr: &Expr, trans: &mut FunctionTranslator) -> Result<Value, String> { // Returns a value
+// alloc(value) moves value to a new heap allocation and returns a &mut to it, free(r) gives the
+// allocation back, print(x) writes a number on its own line
+fn cranelift_builtin_call(token: &Call, trans: &mut FunctionTranslator) -> Result<Option<Value>, CompileError> {
+    let arg_types = check_builtin_args(token, trans)?;
+    if let Some((mode, op)) = find_intrinsic(&token.name) {
+        return Ok(Some(cranelift_intrinsic_call(token, mode, op, trans)?));
+    }
+    if token.name == "print" {
+        let arg = cranelift_recursive_treverse_tree(&token.args[0], trans)?;
+        if arg_types[0] == ValueType::I32 {
+            trans.call_function("vm_print_i32", &[types::I32], &[], &[arg]);
+        }
+        else {
+            trans.call_function("vm_print_f64", &[types::F64], &[], &[arg]);
+        }
+        return Ok(None);
+    }
+    let pointer_type = trans.module.target_config().pointer_type();
+    let value_type = match &arg_types[0] {
+        ValueType::Ref(inner_type, _) if token.name == "free" => inner_type.as_ref().clone(),
+        other => other.clone(),
+    };
+    let size = trans.builder.ins().iconst(types::I64, value_type.size(trans.types) as i64);
+    let align = trans.builder.ins().iconst(types::I64, value_type.align(trans.types) as i64);
+    let arg = cranelift_recursive_treverse_tree(&token.args[0], trans)?;
+    if token.name == "alloc" {
+        let call = trans.call_function("vm_alloc", &[types::I64, types::I64], &[pointer_type], &[size, align]);
+        let addr = trans.builder.inst_results(call)[0];
+        trans.store_value(arg, addr, 0, &value_type, heap_mem_flags());
+        return Ok(Some(addr));
+    }
+    let line = trans.builder.ins().iconst(types::I32, token.pos.line as i64);
+    let col = trans.builder.ins().iconst(types::I32, token.pos.col as i64);
+    let params = [types::I32, types::I32, pointer_type, types::I64, types::I64];
+    trans.call_function("vm_free", &params, &[], &[line, col, arg, size, align]);
+    trans.check_runtime_error();
+    return Ok(None);
+}
+
+// Call a function declared in the program, returns None if the function does not return anything
+fn cranelift_call(token: &Call, trans: &mut FunctionTranslator) -> Result<Option<Value>, CompileError> {
+    if trans.variables.contains_key(&token.name) {
+        let callee = Expr::IdentifierToken(Identifier { text: token.name.clone(), pos: token.pos });
+        return cranelift_indirect_call(&callee, &token.args, token.pos, trans);
+    }
+    check_call_args(token, trans)?;
+    let signature = &trans.functions[&resolve_call(token, trans)?];
+    let params = signature.params.clone();
+    let return_type = signature.return_type.clone();
+    let slot = signature.slot;
+
+    // Calls go through the indirection table, so the called function can be recompiled later
+    let code = trans.function_address(slot);
+    return cranelift_call_address(code, None, &params, return_type, &token.args, trans);
+}
+
+// Call the function or closure that callee is
+fn cranelift_indirect_call(callee: &Expr, args: &[Expr], pos: SourcePos, trans: &mut FunctionTranslator) -> Result<Option<Value>, CompileError> {
+    let (params, return_type) = fn_pointer_signature(callee, args, pos, trans)?;
+    let callee = cranelift_recursive_treverse_tree(callee, trans)?;
+    let pointer_type = trans.module.target_config().pointer_type();
+    let code = trans.builder.ins().load(pointer_type, heap_mem_flags(), callee, 0);
+    let env = trans.builder.ins().load(pointer_type, heap_mem_flags(), callee, pointer_type.bytes() as i32);
+    return cranelift_call_address(code, Some(env), &params, return_type, args, trans);
+}
+
+// Call the machine code at code, the arguments have already been checked against params. A
+// function value comes with an env, which is passed before the other arguments unless it is
+// null, see cranelift_closure
+fn cranelift_call_address(code: Value, env: Option<Value>, params: &[ValueType], return_type: Option<ValueType>, arg_exprs: &[Expr], trans: &mut FunctionTranslator) -> Result<Option<Value>, CompileError> {
+    // Aggregate arguments are passed as their address, the called function makes its own copy
+    let mut args: Vec<Value> = vec!();
+    let mut return_addr: Option<Value> = None;
+    if let Some(return_type) = &return_type {
+        if return_type.is_aggregate() {
+            let addr = trans.create_stack_slot(return_type);
+            return_addr = Some(addr);
+            args.push(addr);
+        }
+    }
+    for (arg, param_type) in arg_exprs.iter().zip(params.iter()) {
+        args.push(cranelift_value_as(arg, param_type, trans)?);
+    }
+
+    let sig = make_cranelift_signature(trans.module, params, return_type.as_ref());
+    let sig_ref = trans.builder.import_signature(sig);
+    let result = match env {
+        None => {
+            let call = trans.builder.ins().call_indirect(sig_ref, code, &args);
+            trans.builder.inst_results(call).first().copied()
+        }
+        Some(env) => {
+            let pointer_type = trans.module.target_config().pointer_type();
+            let closure_sig = make_closure_signature(trans.module, params, return_type.as_ref(), true);
+            let closure_sig_ref = trans.builder.import_signature(closure_sig);
+            let function_block = trans.builder.create_block();
+            let closure_block = trans.builder.create_block();
+            let merge_block = trans.builder.create_block();
+            let result_type = return_type.as_ref().filter(|return_type| !return_type.is_aggregate()).map(|return_type| return_type.cranelift_type(pointer_type));
+            if let Some(result_type) = result_type {
+                trans.builder.append_block_param(merge_block, result_type);
+            }
+            trans.builder.ins().brz(env, function_block, &[]);
+            trans.builder.ins().jump(closure_block, &[]);
+
+            trans.builder.switch_to_block(function_block);
+            trans.builder.seal_block(function_block);
+            let call = trans.builder.ins().call_indirect(sig_ref, code, &args);
+            let results = trans.builder.inst_results(call).to_vec();
+            trans.builder.ins().jump(merge_block, &results);
+
+            trans.builder.switch_to_block(closure_block);
+            trans.builder.seal_block(closure_block);
+            let closure_args: Vec<Value> = std::iter::once(env).chain(args.iter().copied()).collect();
+            let call = trans.builder.ins().call_indirect(closure_sig_ref, code, &closure_args);
+            let results = trans.builder.inst_results(call).to_vec();
+            trans.builder.ins().jump(merge_block, &results);
+
+            trans.builder.switch_to_block(merge_block);
+            trans.builder.seal_block(merge_block);
+            trans.builder.block_params(merge_block).first().copied()
+        }
+    };
+
+    // Stop this function too if the called function hit a runtime error
+    trans.check_runtime_error();
+
+    match return_type {
+        Some(return_type) if return_type.is_aggregate() => {
+            return Ok(return_addr);
+        }
+        Some(_) => {
+            return Ok(result);
+        }
+        None => {
+            return Ok(None);
+        }
+    }
+}
+
+// The value of expr where a value of type expected is wanted, see get_expr_type_as
+fn cranelift_value_as(expr: &Expr, expected: &ValueType, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    if let Expr::Closure(token) = expr {
+        return cranelift_closure(token, Some(expected), trans);
+    }
+    if matches!(expr, Expr::ArrayLiteral(ArrayLiteral { elements }) if elements.is_empty()) {
+        return Ok(trans.create_stack_slot(expected));
+    }
+    return cranelift_recursive_treverse_tree(expr, trans);
+}
+
+// Whether a value of the type can have a function value in it, also behind references
+fn can_hold_fn(value_type: &ValueType, types: &TypeTable, visiting: &mut Vec<String>) -> bool {
+    match value_type {
+        ValueType::I32 | ValueType::F64 => {
+            return false;
+        }
+        ValueType::Fn(..) | ValueType::Infer => {
+            return true;
+        }
+        ValueType::Array(elem_type, _) | ValueType::Ref(elem_type, _) => {
+            return can_hold_fn(elem_type, types, visiting);
+        }
+        ValueType::Struct(..) | ValueType::Generic(..) | ValueType::Enum(..) => {
+            // A type that holds a reference to itself has been looked at further up
+            let name = value_type.struct_name().unwrap_or_else(|| value_type.to_string());
+            if visiting.contains(&name) {
+                return false;
+            }
+            visiting.push(name.clone());
+            let field_types: Vec<&ValueType> = match (types.structs.get(&name), types.enums.get(&name)) {
+                (Some(layout), _) => layout.fields.iter().map(|field| &field.value_type).collect(),
+                (_, Some(layout)) => layout.variants.iter().flat_map(|variant| variant.payload.iter()).collect(),
+                _ => {
+                    return true;
+                }
+            };
+            return field_types.into_iter().any(|field_type| can_hold_fn(field_type, types, visiting));
+        }
+    }
+}
+
+// Whether a closure made in a function with these parameters, return type and captures could
+// be used after the function returns. It can only get out through them, everything else the
+// function has is gone by then
+fn closures_can_escape(params: &[(String, ValueType)], return_type: Option<&ValueType>, captures: &[(String, ValueType)], types: &TypeTable) -> bool {
+    return params.iter().chain(captures.iter()).map(|(_, value_type)| value_type).chain(return_type)
+        .any(|value_type| can_hold_fn(value_type, types, &mut vec!()));
+}
+
+// A closure becomes a function of its own, which is built after the function it is in. The
+// variables it captures are copied into an env when it is created, and the function gets
+// its address as a hidden first parameter. Closures that capture nothing do not take an env
+// and are called just like functions.
+// The env is a stack slot when the closure cannot outlive the function it is made in, see
+// closures_can_escape, and when it is not made in a loop, since every time round would use
+// the same slot. Otherwise it goes on the heap, where it stays until the host's call into the
+// program returns, see release_heap_after
+fn cranelift_closure(token: &Closure, expected: Option<&ValueType>, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let (params, return_type) = closure_signature(token, expected, trans)?;
+    let captures: Vec<(String, ValueType)> = closure_captures(token, &|name| trans.variables.contains_key(name)).into_iter()
+        .map(|name| {
+            let value_type = trans.variable_types[&name].clone();
+            return (name, value_type);
+        })
+        .collect();
+    let pointer_type = trans.module.target_config().pointer_type();
+    let param_types: Vec<ValueType> = params.iter().map(|(_, value_type)| value_type.clone()).collect();
+    let sig = make_closure_signature(trans.module, &param_types, return_type.as_ref(), !captures.is_empty());
+    let name = format!("{}#closure{}", trans.name, trans.closures.len());
+    let func_id = trans.module.declare_function(&name, Linkage::Local, &sig).map_err(|e| CompileError::new(e.to_string()))?;
+    let func_ref = trans.module.declare_func_in_func(func_id, trans.builder.func);
+    let code = trans.builder.ins().func_addr(pointer_type, func_ref);
+
+    let env = if captures.is_empty() {
+        trans.builder.ins().iconst(pointer_type, 0)
+    }
+    else {
+        let capture_types: Vec<&ValueType> = captures.iter().map(|(_, value_type)| value_type).collect();
+        let (offsets, size, align) = layout_fields(&capture_types, 0, trans.types);
+        let env = if !trans.closures_can_escape && trans.loop_depth == 0 {
+            // Rounded up, so that the slot is aligned for the largest capture
+            let slot = trans.builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size.div_ceil(align as u32) * align as u32));
+            trans.builder.ins().stack_addr(pointer_type, slot, 0)
+        }
+        else {
+            let size = trans.builder.ins().iconst(types::I64, size as i64);
+            let align = trans.builder.ins().iconst(types::I64, align as i64);
+            let call = trans.call_function("vm_alloc_env", &[types::I64, types::I64], &[pointer_type], &[size, align]);
+            trans.builder.inst_results(call)[0]
+        };
+        for ((name, value_type), offset) in captures.iter().zip(offsets) {
+            let value = trans.read_variable(name);
+            trans.store_value(value, env, offset as i32, value_type, heap_mem_flags());
+        }
+        env
+    };
+    let fn_type = closure_fn_type(&params, return_type.clone());
+    let value = trans.make_fn_value(&fn_type, code, env);
+    let function = Function { name: "closure".to_string(), type_params: vec!(), is_const: false, params, return_type, body: token.body.clone(), doc: None, pos: token.pos };
+    trans.closures.push(LiftedClosure { func_id, function, captures });
+    return Ok(value);
+}
+
+pub fn cranelift_recursive_treverse_tree(expr: &Expr, trans: &mut FunctionTranslator) -> Result<Value, CompileError> { // Returns a value
     use cranelift::prelude::types::I32;
     match expr {
         Expr::IdentifierToken(token) => {
             // Get the identifier value and return it
-            let var1 = trans.variables.get(token.text.as_str()).unwrap();
-            let val1 = trans.builder.use_var(*var1);
-            return Ok(val1);
+            get_expr_type(expr, trans)?;
+            if !trans.variables.contains_key(&token.text) {
+                // A function value has the address in the slot of the function, so it is the
+                // version of the function that is current when the value is made
+                let signature = &trans.functions[&token.text];
+                let (fn_type, slot) = (signature.fn_type(), signature.slot);
+                let code = trans.function_address(slot);
+                let pointer_type = trans.module.target_config().pointer_type();
+                let env = trans.builder.ins().iconst(pointer_type, 0);
+                return Ok(trans.make_fn_value(&fn_type, code, env));
+            }
+            return Ok(trans.read_variable(&token.text));
         }
         Expr::NumberToken(token) => {
             // Get the number value and return it
@@ -420,55 +3839,596 @@ pub fn cranelift_recursive_treverse_tree(expr: &Expr, trans: &mut FunctionTransl
             return Ok(val1);
 
         }
+        Expr::FloatToken(token) => {
+            let val1 = trans.builder.ins().f64const(token.num);
+            return Ok(val1);
+        }
+        Expr::ArrayLiteral(token) => {
+            // Store every element in a new stack slot, the value of the array is its address
+            let array_type = get_expr_type(expr, trans)?;
+            let array_addr = trans.create_stack_slot(&array_type);
+            let mut offset: i32 = 0;
+            for element in token.elements.iter() {
+                let elem_type = get_expr_type(element, trans)?;
+                let val = cranelift_recursive_treverse_tree(element, trans)?;
+                trans.store_value(val, array_addr, offset, &elem_type, MemFlags::trusted());
+                offset += elem_type.size(trans.types) as i32;
+            }
+            return Ok(array_addr);
+        }
+        Expr::ArrayRepeat(token) => {
+            return cranelift_array_repeat(token, trans);
+        }
+        Expr::Index(..) | Expr::Field(..) | Expr::Deref(..) => {
+            let flags = place_mem_flags(expr, trans)?;
+            let (addr, value_type) = cranelift_place_address(expr, trans)?;
+            return Ok(trans.load_value(addr, 0, &value_type, flags));
+        }
+        Expr::AddressOf(token) => {
+            get_expr_type(expr, trans)?;
+            return cranelift_address_of(&token.expr, trans);
+        }
+        Expr::StructLiteral(token) => {
+            // Store every field in a new stack slot at the offset the layout gives it
+            let struct_type = get_expr_type(expr, trans)?;
+            let struct_addr = trans.create_stack_slot(&struct_type);
+            for (name, value) in token.fields.iter() {
+                let field = trans.types.structs[&struct_type.struct_name().unwrap()].get_field(name).unwrap();
+                let (offset, field_type) = (field.offset as i32, field.value_type.clone());
+                let val = cranelift_value_as(value, &field_type, trans)?;
+                trans.store_value(val, struct_addr, offset, &field_type, MemFlags::trusted());
+            }
+            return Ok(struct_addr);
+        }
+        Expr::EnumLiteral(token) => {
+            // Store the tag and then the payload of the variant in a new stack slot
+            let enum_type = get_expr_type(expr, trans)?;
+            let enum_addr = trans.create_stack_slot(&enum_type);
+            let types = trans.types;
+            let (tag, variant) = types.enums[&token.enum_name].get_variant(&token.variant).unwrap();
+            let tag = trans.builder.ins().iconst(I32, tag as i64);
+            trans.store_value(tag, enum_addr, 0, &ValueType::I32, MemFlags::trusted());
+            for ((arg, payload_type), offset) in token.args.iter().zip(variant.payload.iter()).zip(variant.offsets.iter()) {
+                let val = cranelift_value_as(arg, payload_type, trans)?;
+                trans.store_value(val, enum_addr, *offset as i32, payload_type, MemFlags::trusted());
+            }
+            return Ok(enum_addr);
+        }
+        Expr::Call(token) => {
+            get_expr_type(expr, trans)?;
+            if is_builtin_function(&token.name) {
+                return Ok(cranelift_builtin_call(token, trans)?.unwrap());
+            }
+            return Ok(cranelift_call(token, trans)?.unwrap());
+        }
+        Expr::IndirectCall(token) => {
+            get_expr_type(expr, trans)?;
+            return Ok(cranelift_indirect_call(&token.callee, &token.args, token.pos, trans)?.unwrap());
+        }
         Expr::Operation(token) => {
-            // Get the first value
-            let val1 = cranelift_recursive_treverse_tree(&token.expr1, trans);
+            // Make sure both sides are numbers of the same type
+            let value_type = get_expr_type(expr, trans)?;
+            let op = match token.operator.op_type {
+                OperatorType::Operator(op) => op,
+                OperatorType::Assign(..) => {
+                    return Err(CompileError::new("Cannot parse assign operation inside expression!".to_string()));
+                }
+            };
+            let val1 = cranelift_recursive_treverse_tree(&token.expr1, trans)?;
+            let val2 = cranelift_recursive_treverse_tree(&token.expr2, trans)?;
+            return Ok(lower_operation(op, &[val1, val2], &value_type, token.pos, trans));
+        }
+        Expr::Unary(token) => {
+            let value_type = get_expr_type(expr, trans)?;
+            let val = cranelift_recursive_treverse_tree(&token.expr, trans)?;
+            return Ok((token.operator.lower)(trans, &[val], &value_type, token.pos));
+        }
+        Expr::Closure(token) => {
+            return cranelift_closure(token, None, trans);
+        }
+        _ => {
+            return Err(CompileError::new("Could not parse expression".to_string()));
+        }
+    }
+}
+
+// Values that were just created in a stack slot of their own, that nothing else refers to
+fn is_fresh_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::ArrayLiteral(..) | Expr::ArrayRepeat(..) | Expr::StructLiteral(..) | Expr::EnumLiteral(..) | Expr::Call(..) | Expr::Closure(..) => {
+            return true;
+        }
+        _ => {
+            return false;
+        }
+    }
+}
+
+// Declare a new variable and give it the value of expr, a variable with the same name is shadowed
+fn cranelift_define_variable(name: &str, value_type: ValueType, expr: &Expr, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let val = cranelift_value_as(expr, &value_type, trans)?;
+    let val = if value_type.is_aggregate() && !is_fresh_aggregate(expr) {
+        let dest = trans.create_stack_slot(&value_type);
+        trans.copy_value(dest, val, &value_type);
+        dest
+    }
+    else {
+        val
+    };
+    // A reference that is never changed keeps pointing where it started, memory on the stack is
+    // never freed, so it does not have to be checked when it is used
+    let stack_reference = matches!(value_type, ValueType::Ref(..)) && !trans.reassigned_variables.contains(name)
+        && !trans.addressed_variables.contains(name) && !can_point_to_heap(expr, trans)?;
+    trans.define_variable(name, value_type, val);
+    if stack_reference {
+        let var = trans.variables[name];
+        trans.stack_references.insert(var.index());
+    }
+    return Ok(());
+}
+
+// Whether expr is a place in a variable on the stack, and not somewhere a reference points to
+fn is_stack_place(expr: &Expr, trans: &FunctionTranslator) -> Result<bool, CompileError> {
+    match expr {
+        Expr::IdentifierToken(token) => {
+            return Ok(trans.variable_types.get(&token.text).is_some_and(|value_type| !matches!(value_type, ValueType::Ref(..))));
+        }
+        Expr::Field(Field { expr, .. }) | Expr::Index(Index { array: expr, .. }) => {
+            return Ok(!matches!(get_expr_type(expr, trans)?, ValueType::Ref(..)) && is_stack_place(expr, trans)?);
+        }
+        _ => {
+            return Ok(false);
+        }
+    }
+}
+
+// Whether the reference expr gives might point to memory on the heap, which can be freed
+fn can_point_to_heap(expr: &Expr, trans: &FunctionTranslator) -> Result<bool, CompileError> {
+    match expr {
+        Expr::AddressOf(token) => {
+            return Ok(!is_stack_place(&token.expr, trans)?);
+        }
+        Expr::IdentifierToken(token) => {
+            return Ok(!trans.variables.get(&token.text).is_some_and(|var| trans.stack_references.contains(&var.index())));
+        }
+        _ => {
+            return Ok(true);
+        }
+    }
+}
+
+// A variable declared without a value. The definite assignment check makes sure nothing reads
+// it before it is assigned, so it starts out as zero or as memory that is not written yet
+fn cranelift_declare_unassigned(name: &str, value_type: ValueType, trans: &mut FunctionTranslator) {
+    let pointer_type = trans.module.target_config().pointer_type();
+    let val = match value_type.cranelift_type(pointer_type) {
+        _ if value_type.is_aggregate() => trans.create_stack_slot(&value_type),
+        types::F64 => trans.ins().f64const(0.0),
+        cranelift_type => trans.ins().iconst(cranelift_type, 0)
+    };
+    trans.define_variable(name, value_type, val);
+}
+
+// Apply = or a compound assignment like += to the value at a place in memory
+fn cranelift_assign_to_place(op: Option<&Operator>, place: &Expr, value: &Expr, pos: SourcePos, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let place_type = get_expr_type(place, trans)?;
+    let value_type = get_expr_type_as(value, &place_type, trans)?;
+    if !value_type.can_be_used_as(&place_type) {
+        return Err(CompileError::at(pos, format!("mismatched types when assigning to {}: expected {}, found {}", place.to_string(), place_type.to_string(), value_type.to_string())));
+    }
+    if let Some(op) = op {
+        operation_type(op, &[place_type.clone(), value_type], pos)?;
+    }
+    if !is_mutable_place(place, trans)? {
+        return Err(CompileError::at(pos, format!("cannot assign to {}, it is behind a & reference", place.to_string())));
+    }
+    let flags = place_mem_flags(place, trans)?;
+
+    // Get the value to use
+    let val2 = cranelift_value_as(value, &place_type, trans)?;
+
+    // Get the place to assign to
+    let (addr, place_type) = cranelift_place_address(place, trans)?;
+
+    // Perform the operation
+    match op {
+        None => {
+            trans.store_value(val2, addr, 0, &place_type, flags);
+        }
+        Some(op) => {
+            let val1 = trans.load_value(addr, 0, &place_type, flags);
+            let result = lower_operation(op, &[val1, val2], &place_type, pos, trans);
+            trans.store_value(result, addr, 0, &place_type, flags);
+        }
+    }
+    return Ok(());
+}
+
+// Jump to the arm for the variant using a jump table on the tag of the enum. The semantic pass
+// has already made sure that every variant has an arm
+fn cranelift_match(token: &Match, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let value_type = get_expr_type(&token.value, trans)?;
+    let enum_name = match strip_refs(value_type.clone()) {
+        ValueType::Enum(name) => name,
+        other => {
+            return Err(CompileError::at(token.pos, format!("cannot match on a value of type {} at {}", other.to_string(), token.pos.to_string())));
+        }
+    };
+    for arm in token.arms.iter() {
+        if let MatchPattern::Variant(pattern_enum, ..) = &arm.pattern {
+            if *pattern_enum != enum_name {
+                return Err(CompileError::at(arm.pos, format!("mismatched types in match at {}: expected {}, found {}", arm.pos.to_string(), enum_name, pattern_enum)));
+            }
+        }
+    }
+    let types = trans.types;
+    let layout = &types.enums[&enum_name];
+    let flags = match value_type {
+        ValueType::Ref(..) => heap_mem_flags(),
+        _ => place_mem_flags(&token.value, trans)?
+    };
+    let enum_addr = cranelift_aggregate_address(&token.value, token.pos, trans)?;
+    let tag = trans.load_value(enum_addr, 0, &ValueType::I32, flags);
+
+    // Every variant jumps to the first arm that matches it, arms that nothing jumps to get no block
+    let mut arm_blocks: Vec<Option<Block>> = token.arms.iter().map(|_| None).collect();
+    let mut jump_table = JumpTableData::new();
+    for variant in layout.variants.iter() {
+        let arm_index = token.arms.iter().position(|arm| {
+            match &arm.pattern {
+                MatchPattern::Variant(_, variant_name, _) => *variant_name == variant.name,
+                MatchPattern::Wildcard => true
+            }
+        }).unwrap();
+        let block = *arm_blocks[arm_index].get_or_insert_with(|| trans.builder.create_block());
+        jump_table.push_entry(block);
+    }
+    let jump_table = trans.builder.create_jump_table(jump_table);
+    let default_block = trans.builder.create_block();
+    let merge_block = trans.builder.create_block();
+    trans.builder.ins().br_table(tag, default_block, jump_table);
+
+    // The tag always belongs to a variant, so the default is never taken
+    trans.builder.switch_to_block(default_block);
+    trans.builder.seal_block(default_block);
+    trans.builder.ins().trap(TrapCode::UnreachableCodeReached);
+
+    // The code after the match can be reached if the end of any arm can be reached
+    let reachable = trans.reachable;
+    let mut merge_reachable = false;
+    for (arm, block) in token.arms.iter().zip(arm_blocks) {
+        let block = match block {
+            Some(block) => block,
+            None => {
+                continue;
+            }
+        };
+        trans.builder.switch_to_block(block);
+        trans.builder.seal_block(block);
+        trans.reachable = reachable;
+
+        // Variables declared in the arm, like the bindings, are gone after it
+        let variables = trans.variables.clone();
+        let variable_types = trans.variable_types.clone();
+        if let MatchPattern::Variant(_, variant_name, bindings) = &arm.pattern {
+            let (_, variant) = layout.get_variant(variant_name).unwrap();
+            for ((binding, payload_type), offset) in bindings.iter().zip(variant.payload.iter()).zip(variant.offsets.iter()) {
+                if binding == "_" {
+                    continue;
+                }
+                let val = trans.load_value(enum_addr, *offset as i32, payload_type, flags);
+                let val = if payload_type.is_aggregate() {
+                    let dest = trans.create_stack_slot(payload_type);
+                    trans.copy_value(dest, val, payload_type);
+                    dest
+                }
+                else {
+                    val
+                };
+                record_variable_type(binding, payload_type, arm.pos);
+                trans.define_variable(binding, payload_type.clone(), val);
+            }
+        }
+        for expr in arm.body.iter() {
+            cranelift_treverse_statement(expr, trans)?;
+        }
+        merge_reachable |= trans.reachable;
+        trans.variables = variables;
+        trans.variable_types = variable_types;
+        trans.builder.ins().jump(merge_block, &[]);
+    }
+
+    trans.builder.switch_to_block(merge_block);
+    trans.builder.seal_block(merge_block);
+    trans.reachable = merge_reachable;
+    return Ok(());
+}
+
+// The value of the condition of an if or a while, it has to be an i32
+fn cranelift_condition(condition: &Expr, statement: &str, pos: SourcePos, trans: &mut FunctionTranslator) -> Result<Value, CompileError> {
+    let value_type = get_expr_type(condition, trans)?;
+    if value_type != ValueType::I32 {
+        return Err(CompileError::at(pos, format!("mismatched types in the condition of the {} at {}: expected i32, found {}", statement, pos.to_string(), value_type.to_string())));
+    }
+    return cranelift_recursive_treverse_tree(condition, trans);
+}
+
+// Run the statements of a body, the variables declared in it are gone after it
+fn cranelift_body(body: &[Expr], trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let variables = trans.variables.clone();
+    let variable_types = trans.variable_types.clone();
+    for expr in body.iter() {
+        cranelift_treverse_statement(expr, trans)?;
+    }
+    trans.variables = variables;
+    trans.variable_types = variable_types;
+    return Ok(());
+}
+
+// Branch to the then body or the else body, the code after the if can be reached if the end of
+// either of them can be reached
+fn cranelift_if(token: &If, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let condition = cranelift_condition(&token.condition, "if", token.pos, trans)?;
+    let then_block = trans.builder.create_block();
+    let else_block = trans.builder.create_block();
+    let merge_block = trans.builder.create_block();
+    trans.builder.ins().brnz(condition, then_block, &[]);
+    trans.builder.ins().jump(else_block, &[]);
+
+    let reachable = trans.reachable;
+    let mut merge_reachable = false;
+    for (block, body) in [(then_block, Some(&token.then_body)), (else_block, token.else_body.as_ref())] {
+        trans.builder.switch_to_block(block);
+        trans.builder.seal_block(block);
+        trans.reachable = reachable;
+        cranelift_body(body.map_or(&[], |body| body.as_slice()), trans)?;
+        merge_reachable |= trans.reachable;
+        trans.builder.ins().jump(merge_block, &[]);
+    }
+
+    trans.builder.switch_to_block(merge_block);
+    trans.builder.seal_block(merge_block);
+    trans.reachable = merge_reachable;
+    return Ok(());
+}
+
+// The condition is checked in a block of its own that the end of the body jumps back to. That
+// block can only be sealed once the jump back is there
+fn cranelift_while(token: &While, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let header_block = trans.builder.create_block();
+    let body_block = trans.builder.create_block();
+    let exit_block = trans.builder.create_block();
+    trans.builder.ins().jump(header_block, &[]);
+    trans.loop_depth += 1;
+
+    trans.builder.switch_to_block(header_block);
+    let condition = cranelift_condition(&token.condition, "while", token.pos, trans)?;
+    trans.builder.ins().brnz(condition, body_block, &[]);
+    trans.builder.ins().jump(exit_block, &[]);
 
-            // Get the second value
-            let val2= cranelift_recursive_treverse_tree(&token.expr2, trans);
+    // The code after the loop is reached when the condition is 0, whatever the body does
+    let reachable = trans.reachable;
+    trans.builder.switch_to_block(body_block);
+    trans.builder.seal_block(body_block);
+    cranelift_body(&token.body, trans)?;
+    trans.builder.ins().jump(header_block, &[]);
+    trans.builder.seal_block(header_block);
+    trans.loop_depth -= 1;
+
+    trans.builder.switch_to_block(exit_block);
+    trans.builder.seal_block(exit_block);
+    trans.reachable = reachable;
+    return Ok(());
+}
+
+// Return from the function, the code after a return gets a block of its own that nothing jumps to
+fn cranelift_return(token: &Return, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    let has_value = token.value.as_ref().is_some_and(|value| expr_has_value(value, &|call| call_returns_value(call, trans)));
+    match (&token.value, trans.return_type.clone()) {
+        (Some(value), Some(return_type)) if has_value => {
+            let value_type = get_expr_type_as(value, &return_type, trans)?;
+            if !value_type.can_be_used_as(&return_type) {
+                return Err(CompileError::at(token.pos, format!("mismatched types in return at {}: expected {}, found {}", token.pos.to_string(), return_type.to_string(), value_type.to_string())));
+            }
+            let val = cranelift_value_as(value, &return_type, trans)?;
+            if return_type.is_aggregate() {
+                let return_addr = trans.return_addr.unwrap();
+                trans.copy_value(return_addr, val, &return_type);
+                trans.builder.ins().return_(&[]);
+            }
+            else {
+                trans.builder.ins().return_(&[val]);
+            }
+        }
+        (Some(value), _) if token.is_tail && !has_value => {
+            // The last statement of the function just has no ; after it, the function goes on
+            // to its end like it would with one
+            return cranelift_treverse_statement(value, trans);
+        }
+        (Some(value), Some(return_type)) => {
+            // A call to a function that returns nothing says so itself
+            get_expr_type(value, trans)?;
+            return Err(CompileError::at(token.pos, format!("mismatched types in return at {}: expected {}, found {}, which does not give a value", token.pos.to_string(), return_type.to_string(), value.to_string())));
+        }
+        (Some(value), None) => {
+            if has_value {
+                return Err(CompileError::at(token.pos, format!("cannot return {} at {}, the function does not return anything", value.to_string(), token.pos.to_string())));
+            }
+            cranelift_treverse_statement(value, trans)?;
+            trans.builder.ins().return_(&[]);
+        }
+        (None, Some(return_type)) => {
+            return Err(CompileError::at(token.pos, format!("return without a value at {}, the function returns {}", token.pos.to_string(), return_type.to_string())));
+        }
+        (None, None) => {
+            trans.builder.ins().return_(&[]);
+        }
+    }
+    let block = trans.builder.create_block();
+    trans.builder.switch_to_block(block);
+    trans.builder.seal_block(block);
+    trans.reachable = false;
+    return Ok(());
+}
 
-            // Match operator and perform operation
-            match token.operator.op_type {
-                OperatorType::OpType2(..) => {
-                    // You cannot parse =, +=, -= inside expression
-                    return Err("Cannot parse assign operation inside expression!".to_string());
+pub fn cranelift_treverse_statement(expr: &Expr, trans: &mut FunctionTranslator) -> Result<(), CompileError> {
+    match expr {
+        Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::Empty => {}
+        Expr::Return(token) => {
+            cranelift_return(token, trans)?;
+        }
+        Expr::Tail(token) => {
+            if expr_has_value(&token.value, &|call| call_returns_value(call, trans)) {
+                return Err(CompileError::at(token.pos, format!("the value of {} at {} is not used, only the last expression of a function is returned", token.value.to_string(), token.pos.to_string())));
+            }
+            cranelift_treverse_statement(&token.value, trans)?;
+        }
+        Expr::StructDecl(token) => {
+            return Err(CompileError::at(token.pos, format!("structs can only be declared at the top level, at {}", token.pos.to_string())));
+        }
+        Expr::EnumDecl(token) => {
+            return Err(CompileError::at(token.pos, format!("enums can only be declared at the top level, at {}", token.pos.to_string())));
+        }
+        Expr::ConstDecl(token) => {
+            return Err(CompileError::at(token.pos, format!("consts can only be declared at the top level, at {}", token.pos.to_string())));
+        }
+        Expr::Function(token) => {
+            return Err(CompileError::at(token.pos, format!("functions can only be declared at the top level, at {}", token.pos.to_string())));
+        }
+        Expr::Match(token) => {
+            cranelift_match(token, trans)?;
+        }
+        Expr::If(token) => {
+            cranelift_if(token, trans)?;
+        }
+        Expr::While(token) => {
+            cranelift_while(token, trans)?;
+        }
+        Expr::Let(token) => {
+            let value_type = match (&token.value_type, &token.value) {
+                (Some(declared_type), Some(value)) => {
+                    check_type(declared_type, trans.types).map_err(|err| err.or_at(token.pos))?;
+                    let value_type = get_expr_type_as(value, declared_type, trans)?;
+                    if !value_type.can_be_used_as(declared_type) {
+                        return Err(CompileError::at(token.pos, format!("mismatched types for {}: expected {}, found {}", token.name, declared_type.to_string(), value_type.to_string())));
+                    }
+                    declared_type.clone()
+                }
+                (Some(declared_type), None) => {
+                    check_type(declared_type, trans.types).map_err(|err| err.or_at(token.pos))?;
+                    declared_type.clone()
                 }
-                OperatorType::OpType0(OpType0::Mul) => {
-                    // You cannot parse =, +=, -= inside expression
-                    let result = trans.builder.ins().imul(val1.unwrap(), val2.unwrap());
-                    return Ok(result)
+                (None, Some(value)) => get_expr_type(value, trans)?,
+                (None, None) => {
+                    return Err(CompileError::at(token.pos, format!("let {} at {} needs a type or a value", token.name, token.pos.to_string())));
                 }
-                OperatorType::OpType1(OpType1::Add) => {
-                    // Add values
-                    let result = trans.builder.ins().iadd(val1.unwrap(), val2.unwrap());
-                    return Ok(result)
+            };
+            record_variable_type(&token.name, &value_type, token.pos);
+            match &token.value {
+                Some(value) => {
+                    cranelift_define_variable(token.name.as_str(), value_type, value, trans)?;
                 }
-                OperatorType::OpType1(OpType1::Sub) => {
-                    // Subtract values
-                    let result = trans.builder.ins().isub(val1.unwrap(), val2.unwrap());
-                    return Ok(result)
+                None => {
+                    cranelift_declare_unassigned(token.name.as_str(), value_type, trans);
+                }
+            }
+        }
+        Expr::Call(token) if is_builtin_function(&token.name) => {
+            cranelift_builtin_call(token, trans)?;
+        }
+        Expr::Call(token) => {
+            // The function might not return anything, so there is no value to type check
+            cranelift_call(token, trans)?;
+        }
+        Expr::IndirectCall(token) => {
+            cranelift_indirect_call(&token.callee, &token.args, token.pos, trans)?;
+        }
+        Expr::ArrayLiteral(..) | Expr::ArrayRepeat(..) | Expr::Index(..) | Expr::StructLiteral(..) | Expr::Field(..) | Expr::AddressOf(..) | Expr::Deref(..) | Expr::Unary(..) | Expr::EnumLiteral(..) | Expr::Closure(..) => {
+            // Nothing is assigned, but indexing can still fail its bounds check
+            cranelift_recursive_treverse_tree(expr, trans)?;
+        }
+        Expr::Operation(op_token) => {
+            match &op_token.operator.op_type {
+                OperatorType::Assign(op) => {
+                    // Operation is an assign operation, =, +=, -= etc.
+                    match op_token.expr1.as_ref() {
+                        Expr::IdentifierToken(token) => {
+                            let name = token.text.as_str();
+
+                            // Assigning to a variable that does not exist yet declares it
+                            if !trans.variables.contains_key(name) {
+                                if op.is_none() {
+                                    let value_type = get_expr_type(&op_token.expr2, trans)?;
+                                    record_variable_type(name, &value_type, op_token.pos);
+                                    return cranelift_define_variable(name, value_type, &op_token.expr2, trans);
+                                }
+                                return Err(CompileError::at(token.pos, format!("unknown variable {} at {}", name, token.pos.to_string())));
+                            }
+                            let var_type = trans.variable_types.get(name).unwrap().clone();
+                            let value_type = get_expr_type_as(&op_token.expr2, &var_type, trans)?;
+                            if !value_type.can_be_used_as(&var_type) {
+                                return Err(CompileError::at(op_token.pos, format!("mismatched types for {}: expected {}, found {}", name, var_type.to_string(), value_type.to_string())));
+                            }
+
+                            // Get the value to use
+                            let val2 = cranelift_value_as(&op_token.expr2, &var_type, trans);
+
+                            // Get the variable to assign to
+                            let var1 = *trans.variables.get(name).unwrap();
+
+                            // Perform the operation
+                            match op {
+                                None if var_type.is_aggregate() => {
+                                    // The variable holds the address of its memory, copy the new value into it
+                                    let dest = trans.builder.use_var(var1);
+                                    trans.copy_value(dest, val2?, &var_type);
+                                }
+                                None => {
+                                    trans.write_variable(name, val2?);
+                                }
+                                Some(op) => {
+                                    operation_type(op, &[var_type.clone(), value_type], op_token.pos)?;
+                                    let val1 = trans.read_variable(name);
+                                    let result = lower_operation(op, &[val1, val2?], &var_type, op_token.pos, trans);
+                                    trans.write_variable(name, result);
+                                }
+                            }
+                        }
+                        Expr::Index(..) | Expr::Field(..) | Expr::Deref(..) => {
+                            cranelift_assign_to_place(*op, &op_token.expr1, &op_token.expr2, op_token.pos, trans)?;
+                        }
+                        _ => {
+                            return Err(CompileError::at(op_token.pos, format!("cannot assign to {} at {}", op_token.expr1.to_string(), op_token.pos.to_string())));
+                        }
+                    }
                 }
                 _ => {
-                    return Err("Operator implemented yet!".to_string());
+                    // We dont care about the output, since the expression is for example x + y, and
+                    // it does not assign to anything
+                    let _ = cranelift_recursive_treverse_tree(expr, trans);
                 }
             }
         }
-        _ => {
-            return Err("Could not parse expression".to_string());
-        }
     }
+    return Ok(());
 }
 
-pub fn cranelift_treverse_tree(expr_tree: &Vec<Expr>) -> Result<(JIT, FuncId), String> {
-    // Create the jit
-    let mut jit = JIT::default();
+// Build a cranelift function out of a list of statements. A function that returns something
+// has to return on every path through it. Closures pass the variables they capture, which
+// are read from the env they get. The closures inside the function are returned to be built next
+fn cranelift_build_function(jit: &mut JIT, func_id: FuncId, token: &Function, body: &[&Expr], captures: &[(String, ValueType)], types: &TypeTable, functions: &FunctionTable) -> Result<Vec<LiftedClosure>, CompileError> {
+    let params = &token.params;
+    let return_type = token.return_type.as_ref();
 
     // Declare a variable type to use inside it
     let int = jit.module.target_config().pointer_type();
-    use cranelift::prelude::types::I32;
 
     // Add new function to cranelift(i think?)
-    jit.ctx.func.signature.returns.push(AbiParam::new(I32));
+    let param_types: Vec<ValueType> = params.iter().map(|(_, value_type)| value_type.clone()).collect();
+    jit.ctx.func.signature = make_closure_signature(&jit.module, &param_types, return_type, !captures.is_empty());
+
+    let overflow_checks = jit.overflow_checks;
+    let name = jit.module.declarations().get_function_decl(func_id).name.clone();
 
     // Create the builder to build function for cranelift.
     let mut builder = FunctionBuilder::new(&mut jit.ctx.func, &mut jit.builder_context);
@@ -488,169 +4448,607 @@ pub fn cranelift_treverse_tree(expr_tree: &Vec<Expr>) -> Result<(JIT, FuncId), S
     // predecessors.
     builder.seal_block(entry_block);
 
-    // Declare variables variable
-    let mut variables: HashMap<String, Variable> = HashMap::new();
-
-    // Declare a variable for returning stuff
-    let var = Variable::new(0);
-    let name = "return_var";
-
-    // Declare var in variables for function
-    if !variables.contains_key(name) {
-        variables.insert(name.into(), var);
-        builder.declare_var(var, I32);
-    }
+    let mut block_params: Vec<Value> = builder.block_params(entry_block).to_vec();
 
     // Create a struct to keep track of variables used to create the function
     // (translate your own code of your language into cranelift)
     let mut trans: FunctionTranslator = FunctionTranslator {
         _int: int,
         builder,
-        variables,
+        variables: HashMap::new(),
+        variable_types: HashMap::new(),
+        variable_count: 0,
+        addressed_variables: HashSet::new(),
+        reassigned_variables: HashSet::new(),
+        stack_references: HashSet::new(),
+        return_type: return_type.cloned(),
+        return_addr: None,
+        reachable: true,
+        loop_depth: 0,
+        closures_can_escape: closures_can_escape(params, return_type, captures, types),
+        overflow_checks,
+        types,
+        functions,
         module: &mut jit.module,
+        name,
+        closures: vec!(),
+        closure_scopes: RefCell::new(vec!()),
+        missing_instances: &jit.missing_instances,
+        used_instances: &jit.used_instances,
     };
 
-    // Insert the type of input and output of the function, its signature
-    let mut sig = trans.module.make_signature();
-    sig.params.push(AbiParam::new(int));
-    sig.returns.push(AbiParam::new(I32));
-
-    let mut var_iter = 1;
-
-    for expr in expr_tree {
-        match expr {
-            Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::Empty => {
-                continue;
-            }
-            Expr::Operation(op_token) => {
-                match &op_token.operator.op_type {
-                    OperatorType::OpType2(op_type) => {
-                        // Operation is an assign operation, =, +=, -= etc.
-                        match op_token.expr1.as_ref() {
-                            Expr::IdentifierToken(token) => {
-                                // Get the value to use
-                                let val2 = cranelift_recursive_treverse_tree(&op_token.expr2, &mut trans);
-
-                                // Get the variable to assign to
-                                let name = token.text.as_str();
-                                if !trans.variables.contains_key(name) {
-                                    let var = Variable::new(var_iter);
-                                    var_iter += 1;
-                                    trans.variables.insert(name.into(), var);
-                                    trans.builder.declare_var(var, I32);
-                                }
-                                let var1 = trans.variables.get(name).unwrap();
-
-                                // Perform the operation
-                                match op_type {
-                                    OpType2::Eq => {
-                                        trans.builder.def_var(*var1, val2.unwrap());
-                                    }
-                                    OpType2::AddEq => {
-                                        let val1 = trans.builder.use_var(*var1);
-                                        let result = trans.builder.ins().iadd(val1, val2.unwrap());
-                                        trans.builder.def_var(*var1, result);
-                                    }
-                                    OpType2::SubEq => {
-                                        let val1 = trans.builder.use_var(*var1);
-                                        let result = trans.builder.ins().isub(val1, val2.unwrap());
-                                        trans.builder.def_var(*var1, result);
-                                    }
-                                }
-                            }
-                            _ => {
-                                println!("Expected identifier!");
-                                panic!();
-                            }
-                        }
-                    }
-                    _ => {
-                        // We dont care about the output, since the expression is for example x + y, and
-                        // it does not assign to anything
-                        let _ = cranelift_recursive_treverse_tree(&expr, &mut trans);
-                    }
+    for expr in body {
+        collect_addressed_variables(expr, &mut trans.addressed_variables);
+        walk_expr(expr, &mut |expr| {
+            if let Expr::Operation(token) = expr {
+                if let (OperatorType::Assign(..), Expr::IdentifierToken(ident)) = (&token.operator.op_type, token.expr1.as_ref()) {
+                    trans.reassigned_variables.insert(ident.text.clone());
                 }
             }
+        });
+    }
+
+    // The captured variables are copied out of the env, so that each call starts with them as
+    // they were when the closure was created
+    if !captures.is_empty() {
+        let env = block_params.remove(0);
+        let capture_types: Vec<&ValueType> = captures.iter().map(|(_, value_type)| value_type).collect();
+        let (offsets, _, _) = layout_fields(&capture_types, 0, types);
+        for ((name, value_type), offset) in captures.iter().zip(offsets) {
+            let val = trans.load_value(env, offset as i32, value_type, heap_mem_flags());
+            let val = if value_type.is_aggregate() {
+                let dest = trans.create_stack_slot(value_type);
+                trans.copy_value(dest, val, value_type);
+                dest
+            }
+            else {
+                val
+            };
+            trans.define_variable(name, value_type.clone(), val);
+        }
+    }
+
+    // Aggregates are returned by writing them to the address the caller passes first
+    if let Some(return_type) = return_type {
+        check_type(return_type, types).map_err(|err| err.or_at(token.pos))?;
+        if return_type.is_aggregate() {
+            trans.return_addr = Some(block_params.remove(0));
+        }
+    }
+
+    // Make the parameters into variables, aggregates get copied so that changing them does not
+    // change the value the caller passed in
+    for ((name, value_type), param) in params.iter().zip(block_params) {
+        check_type(value_type, types).map_err(|err| err.or_at(token.pos))?;
+        let val = if value_type.is_aggregate() {
+            let dest = trans.create_stack_slot(value_type);
+            trans.copy_value(dest, param, value_type);
+            dest
         }
+        else {
+            param
+        };
+        trans.define_variable(name, value_type.clone(), val);
     }
 
-    // Get a variable to use as return value, what the function will return
-    let return_value = trans.builder.use_var(var);
+    for expr in body {
+        cranelift_treverse_statement(expr, &mut trans)?;
+    }
 
-    // Insert the return instruction.
-    trans.builder.ins().return_(&[return_value]);
+    // A function that does not return anything returns at the end of its body
+    if return_type.is_none() {
+        trans.builder.ins().return_(&[]);
+    }
+    else if trans.reachable {
+        return Err(CompileError::at(token.pos, format!("function {} can reach the end of its body without returning a value, at {}", token.name, token.pos.to_string())));
+    }
+    else {
+        trans.builder.ins().trap(TrapCode::UnreachableCodeReached);
+    }
 
     // Tell the builder we're done with this function.
     trans.builder.finalize();
-
-    // Declare the function using the context, so that it can be called
-    let name = String::from("some_other_func");
-    let id = jit
-        .module
-        .declare_function(&name, Linkage::Export, &jit.ctx.func.signature)
-        .map_err(|e| e.to_string()).unwrap();
+    let closures = mem::take(&mut trans.closures);
+    if jit.check_only {
+        jit.module.clear_context(&mut jit.ctx);
+        return Ok(closures);
+    }
 
     // Define the function, not sure what it does
     let result = jit.module
-    .define_function(id, &mut jit.ctx, &mut codegen::binemit::NullTrapSink {});
+    .define_function(func_id, &mut jit.ctx, &mut codegen::binemit::NullTrapSink {});
 
     // Make sure it does not give any errors
-    if result.is_err() {
-        let err = result.err().unwrap();
-        if let ModuleError::Compilation(CodegenError::Verifier(VerifierErrors{0: verifier_errors} )) = err {
+    if let Err(err) = result {
+        if let ModuleError::Compilation(CodegenError::Verifier(VerifierErrors(verifier_errors))) = &err {
             for i in verifier_errors.iter() {
-                println!("(VerifierError");
-                println!("message: {}", i.message);
-                println!("context: {}", i.context.as_ref().unwrap_or(&"".to_string()));
-                println!("location: {})", i.location);
+                debug_print("(VerifierError");
+                debug_print(&format!("message: {}", i.message));
+                debug_print(&format!("context: {}", i.context.as_ref().unwrap_or(&"".to_string())));
+                debug_print(&format!("location: {})", i.location));
             }
         }
-        else {
-            println!("{}", err.to_string() );
-        }
-        //println!("{}", result.err().unwrap().to_string() );
-        panic!();
+        jit.module.clear_context(&mut jit.ctx);
+        return Err(CompileError::at(token.pos, format!("could not compile function {}: {}", token.name, err)));
     }
 
     // Now that compilation is finished, we can clear out the context state.
     jit.module.clear_context(&mut  jit.ctx);
+    return Ok(closures);
+}
+
+// Call f on expr and every expression inside it
+pub fn walk_expr(expr: &Expr, f: &mut dyn FnMut(&Expr)) {
+    f(expr);
+    match expr {
+        Expr::Operation(token) => {
+            walk_expr(&token.expr1, f);
+            walk_expr(&token.expr2, f);
+        }
+        Expr::ArrayLiteral(token) => {
+            for element in token.elements.iter() {
+                walk_expr(element, f);
+            }
+        }
+        Expr::ArrayRepeat(token) => {
+            walk_expr(&token.value, f);
+        }
+        Expr::Index(token) => {
+            walk_expr(&token.array, f);
+            walk_expr(&token.index, f);
+        }
+        Expr::StructLiteral(token) => {
+            for (_, value) in token.fields.iter() {
+                walk_expr(value, f);
+            }
+        }
+        Expr::Field(token) => {
+            walk_expr(&token.expr, f);
+        }
+        Expr::Call(token) => {
+            for arg in token.args.iter() {
+                walk_expr(arg, f);
+            }
+        }
+        Expr::IndirectCall(token) => {
+            walk_expr(&token.callee, f);
+            for arg in token.args.iter() {
+                walk_expr(arg, f);
+            }
+        }
+        Expr::AddressOf(token) => {
+            walk_expr(&token.expr, f);
+        }
+        Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) => {
+            walk_expr(expr, f);
+        }
+        Expr::EnumLiteral(token) => {
+            for arg in token.args.iter() {
+                walk_expr(arg, f);
+            }
+        }
+        Expr::Let(token) => {
+            if let Some(value) = &token.value {
+                walk_expr(value, f);
+            }
+        }
+        Expr::Match(token) => {
+            walk_expr(&token.value, f);
+            for arm in token.arms.iter() {
+                for expr in arm.body.iter() {
+                    walk_expr(expr, f);
+                }
+            }
+        }
+        Expr::If(token) => {
+            walk_expr(&token.condition, f);
+            for expr in token.then_body.iter().chain(token.else_body.iter().flatten()) {
+                walk_expr(expr, f);
+            }
+        }
+        Expr::While(token) => {
+            walk_expr(&token.condition, f);
+            for expr in token.body.iter() {
+                walk_expr(expr, f);
+            }
+        }
+        Expr::Return(token) => {
+            if let Some(value) = &token.value {
+                walk_expr(value, f);
+            }
+        }
+        Expr::Tail(token) => {
+            walk_expr(&token.value, f);
+        }
+        Expr::Function(Function { body, .. }) | Expr::Closure(Closure { body, .. }) => {
+            for expr in body.iter() {
+                walk_expr(expr, f);
+            }
+        }
+        Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Empty => {}
+    }
+}
 
-    // Finalize the functions which we just defined, which resolves any
-    // outstanding relocations (patching in addresses, now that they're
-    // available).
-    jit.module.finalize_definitions();
+// Call f on expr and every expression inside it, f sees an expression before the ones inside it
+pub fn walk_expr_mut(expr: &mut Expr, f: &mut dyn FnMut(&mut Expr)) {
+    f(expr);
+    match expr {
+        Expr::Operation(token) => {
+            walk_expr_mut(&mut token.expr1, f);
+            walk_expr_mut(&mut token.expr2, f);
+        }
+        Expr::ArrayLiteral(ArrayLiteral { elements: exprs }) | Expr::Call(Call { args: exprs, .. }) | Expr::EnumLiteral(EnumLiteral { args: exprs, .. })
+        | Expr::Function(Function { body: exprs, .. }) | Expr::Closure(Closure { body: exprs, .. }) => {
+            for expr in exprs.iter_mut() {
+                walk_expr_mut(expr, f);
+            }
+        }
+        Expr::Index(token) => {
+            walk_expr_mut(&mut token.array, f);
+            walk_expr_mut(&mut token.index, f);
+        }
+        Expr::StructLiteral(token) => {
+            for (_, value) in token.fields.iter_mut() {
+                walk_expr_mut(value, f);
+            }
+        }
+        Expr::IndirectCall(token) => {
+            walk_expr_mut(&mut token.callee, f);
+            for arg in token.args.iter_mut() {
+                walk_expr_mut(arg, f);
+            }
+        }
+        Expr::ArrayRepeat(ArrayRepeat { value: expr, .. }) | Expr::Field(Field { expr, .. }) | Expr::AddressOf(AddressOf { expr, .. })
+        | Expr::Deref(Deref { expr, .. }) | Expr::Unary(Unary { expr, .. }) | Expr::Tail(Tail { value: expr, .. }) => {
+            walk_expr_mut(expr, f);
+        }
+        Expr::Let(token) => {
+            if let Some(value) = &mut token.value {
+                walk_expr_mut(value, f);
+            }
+        }
+        Expr::Match(token) => {
+            walk_expr_mut(&mut token.value, f);
+            for arm in token.arms.iter_mut() {
+                for expr in arm.body.iter_mut() {
+                    walk_expr_mut(expr, f);
+                }
+            }
+        }
+        Expr::If(token) => {
+            walk_expr_mut(&mut token.condition, f);
+            for expr in token.then_body.iter_mut().chain(token.else_body.iter_mut().flatten()) {
+                walk_expr_mut(expr, f);
+            }
+        }
+        Expr::While(token) => {
+            walk_expr_mut(&mut token.condition, f);
+            for expr in token.body.iter_mut() {
+                walk_expr_mut(expr, f);
+            }
+        }
+        Expr::Return(token) => {
+            if let Some(value) = &mut token.value {
+                walk_expr_mut(value, f);
+            }
+        }
+        Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::StructDecl(..) | Expr::EnumDecl(..) | Expr::ConstDecl(..) | Expr::Empty => {}
+    }
+}
 
-    return Ok((jit, id));
+// A hash of everything the machine code of a function depends on. That is its own code, the
+// types, the signatures of the functions it calls and the positions that end up in runtime
+// errors, so moving a function around recompiles it while its error messages would change
+fn function_fingerprint(code: &str, body: &[&Expr], types_code: &str, functions: &FunctionTable) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    types_code.hash(&mut hasher);
+    for expr in body {
+        walk_expr(expr, &mut |expr| {
+            match expr {
+                // Everything that can fail at runtime: indexing, arithmetic, following a
+                // reference that might have been freed, and calls
+                Expr::Index(Index { pos, .. }) | Expr::Operation(Operation { pos, .. }) | Expr::Unary(Unary { pos, .. })
+                | Expr::Deref(Deref { pos, .. }) | Expr::Field(Field { pos, .. }) | Expr::IndirectCall(IndirectCall { pos, .. }) => {
+                    (pos.line, pos.col).hash(&mut hasher);
+                }
+                Expr::Call(token) => {
+                    (token.pos.line, token.pos.col).hash(&mut hasher);
+                    if let Some(signature) = functions.get(&token.name) {
+                        signature.fn_type().to_string().hash(&mut hasher);
+                    }
+                }
+                Expr::IdentifierToken(token) => {
+                    // The name of a function can be used as a pointer to it
+                    if let Some(signature) = functions.get(&token.text) {
+                        signature.fn_type().to_string().hash(&mut hasher);
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
+    return hasher.finish();
 }
 
-pub fn tokenize(my_str: String) -> TokenList {
-    let mut token_list = TokenList::new();
-    let split_str = my_str.split_ascii_whitespace();
-    for i in split_str.into_iter() {
-        if let Some(token) = is_operator(i.to_string()) {
-            println!("operator: {}", i);
-            token_list.tokens.push(Token::OperatorToken(token));
+impl JIT {
+    // Compile a program into the jit, and return the function that runs it. Functions that
+    // were compiled by an earlier update are kept when their fingerprint did not change, the
+    // others are compiled under a new name and swapped in through the indirection table. The
+    // old machine code is only freed with the jit, it could still be running further up the stack.
+    // If the program has errors, the jit keeps running the program it had before
+    pub fn update(&mut self, expr_tree: &[Expr]) -> Result<FuncId, CompileError> {
+        // Which instances of generic functions the program needs is only known once the types
+        // of the arguments are, like max<i32> for max(a, b). Those that turn up while building
+        // are added to the program, which is then built again. The functions that did build
+        // are kept for the next try
+        let mut instances: Vec<Instance> = vec!();
+        let mut built: HashMap<String, CompiledFunction> = HashMap::new();
+        loop {
+            let (expr_tree, generics) = instantiate_generics(expr_tree, &instances)?;
+            let result = self.build_program(&expr_tree, generics, &mut built);
+            let missing = self.missing_instances.take();
+            match result {
+                Err(err) => {
+                    let count = instances.len();
+                    for instance in missing {
+                        if !instances.contains(&instance) {
+                            instances.push(instance);
+                        }
+                    }
+                    if instances.len() == count {
+                        return Err(err);
+                    }
+                }
+                Ok(program_id) => {
+                    return Ok(program_id);
+                }
+            }
+        }
+    }
+
+    // Compile a program that has no generics left in it, see update. Functions that are in
+    // built were compiled by an earlier try of the same update
+    fn build_program(&mut self, expr_tree: &[Expr], generics: Generics, built: &mut HashMap<String, CompiledFunction>) -> Result<FuncId, CompileError> {
+        let mut types = compute_type_layouts(expr_tree)?;
+        types.generics = generics;
+        semantic_pass(expr_tree, &types)?;
+
+        // Run const fns and work out consts, what is left only has their results in it
+        let expr_tree = &fold_constants(expr_tree, &types, self.overflow_checks)?;
+
+        for generic in types.generics.functions.values() {
+            if self.host_functions.contains_key(&generic.name) {
+                return Err(CompileError::at(generic.pos, format!("function {} is provided by the host and cannot be declared again, at {}", generic.name, generic.pos.to_string())));
+            }
+        }
+
+        // Every function gets a slot first, so that functions can call functions declared after them
+        let mut functions = self.host_functions.clone();
+        for expr in expr_tree {
+            if let Expr::Function(token) = expr {
+                if self.host_functions.contains_key(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("function {} is provided by the host and cannot be declared again, at {}", token.name, token.pos.to_string())));
+                }
+                if functions.contains_key(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("function {} is declared twice at {}", token.name, token.pos.to_string())));
+                }
+                if is_builtin_function(&token.name) {
+                    return Err(CompileError::at(token.pos, format!("function {} is built in and cannot be declared again, at {}", token.name, token.pos.to_string())));
+                }
+                // Callers need the layout of what they pass and get back before the function is built
+                for value_type in token.params.iter().map(|(_, value_type)| value_type).chain(token.return_type.iter()) {
+                    check_type(value_type, &types).map_err(|err| CompileError::at(token.pos, format!("{} in the signature of {} at {}", err.message, token.name, token.pos.to_string())))?;
+                }
+                let params: Vec<ValueType> = token.params.iter().map(|(_, value_type)| value_type.clone()).collect();
+                let slot = self.slots.entry(token.name.clone()).or_insert_with(|| Box::new(0));
+                functions.insert(token.name.clone(), FunctionSignature { params, return_type: token.return_type.clone(), slot: slot.as_ref() });
+            }
+        }
+
+        // The layout of every type goes into every fingerprint, and so do the signatures of the
+        // generic functions since the instance a call goes to depends on them
+        let mut types_code: Vec<String> = expr_tree.iter()
+            .filter(|expr| matches!(expr, Expr::StructDecl(..) | Expr::EnumDecl(..)))
+            .map(|expr| expr.to_string())
+            .collect();
+        let mut generics_code: Vec<String> = types.generics.functions.values()
+            .map(|function| Expr::Function(Function { body: vec!(), ..function.clone() }).to_string())
+            .chain(types.generics.structs.values().map(|decl| Expr::StructDecl(decl.clone()).to_string()))
+            .collect();
+        generics_code.sort();
+        types_code.extend(generics_code);
+        let types_code = types_code.join("\n");
+
+        // Everything that is not a declaration makes up the program itself
+        let program_body: Vec<&Expr> = expr_tree.iter()
+            .filter(|expr| !matches!(expr, Expr::Function(..) | Expr::StructDecl(..) | Expr::EnumDecl(..)))
+            .collect();
+        let program_code: Vec<String> = program_body.iter().map(|expr| expr.to_string()).collect();
+        let program = Function {
+            name: PROGRAM_NAME.to_string(),
+            type_params: vec!(),
+            is_const: false,
+            params: vec!(),
+            return_type: Some(ValueType::I32),
+            body: vec!(),
+            doc: None,
+            pos: SourcePos { line: 1, col: 1 }
+        };
+        // The program returns 0 if it gets to its end
+        let program_end = Expr::Return(Return { value: Some(Box::new(Expr::NumberToken(NumberToken { num: 0, text: None }))), is_tail: false, pos: SourcePos { line: 1, col: 1 } });
+        let mut program_body = program_body;
+        program_body.push(&program_end);
+        let mut to_compile: Vec<(&Function, Vec<&Expr>, String)> = vec!((&program, program_body, program_code.join("\n")));
+        for expr in expr_tree {
+            if let Expr::Function(token) = expr {
+                to_compile.push((token, token.body.iter().collect(), expr.to_string()));
+            }
         }
-        else if let Some(token) = is_number(i.to_string()) {
-            println!("number: {}", i);
-            token_list.tokens.push(Token::NumberToken(token));
+
+        self.generation += 1;
+        let mut compiled: HashMap<String, CompiledFunction> = HashMap::new();
+        let mut recompiled: Vec<String> = vec!();
+        for (token, body, code) in to_compile {
+            let fingerprint = function_fingerprint(&code, &body, &types_code, &functions);
+            // A function that is kept is not looked at again, so the instances it uses have to
+            // be added to the program from what it used when it was compiled
+            let kept = match (self.compiled.get(&token.name), built.get(&token.name)) {
+                (Some(old), _) if old.fingerprint == fingerprint => Some((old.clone(), false)),
+                (_, Some(old)) if old.fingerprint == fingerprint => Some((old.clone(), true)),
+                _ => None
+            };
+            if let Some((old, is_new)) = kept {
+                for instance in old.instances.iter() {
+                    let exists = match instance {
+                        Instance::Function(..) => functions.contains_key(&instance.to_string()),
+                        Instance::Struct(..) => types.structs.contains_key(&instance.to_string())
+                    };
+                    if !exists {
+                        self.missing_instances.borrow_mut().push(instance.clone());
+                    }
+                }
+                if is_new {
+                    recompiled.push(token.name.clone());
+                }
+                compiled.insert(token.name.clone(), old);
+                continue;
+            }
+            let linkage = if token.name == PROGRAM_NAME { Linkage::Export } else { Linkage::Local };
+            let params: Vec<ValueType> = token.params.iter().map(|(_, value_type)| value_type.clone()).collect();
+            let sig = make_cranelift_signature(&self.module, &params, token.return_type.as_ref());
+            let func_id = self
+                .module
+                .declare_function(&format!("{}#{}", token.name, self.generation), linkage, &sig)
+                .map_err(|e| CompileError::new(e.to_string()))?;
+            // The closures in a function are built after it, and can have closures in them too
+            self.used_instances.borrow_mut().clear();
+            let mut pending = vec!(LiftedClosure { func_id, function: token.clone(), captures: vec!() });
+            while let Some(closure) = pending.pop() {
+                let closure_body: Vec<&Expr> = if closure.func_id == func_id { body.clone() } else { closure.function.body.iter().collect() };
+                match cranelift_build_function(self, closure.func_id, &closure.function, &closure_body, &closure.captures, &types, &functions) {
+                    Ok(closures) => {
+                        pending.extend(closures);
+                    }
+                    Err(err) => {
+                        // Throw away the half built function, so that the next update starts out clean
+                        self.module.clear_context(&mut self.ctx);
+                        self.builder_context = FunctionBuilderContext::new();
+                        return Err(err);
+                    }
+                }
+            }
+            let function = CompiledFunction { fingerprint, func_id, params, return_type: token.return_type.clone(), instances: self.used_instances.take() };
+            built.insert(token.name.clone(), function.clone());
+            compiled.insert(token.name.clone(), function);
+            recompiled.push(token.name.clone());
         }
-        else if let Some(token) = is_identifier(i.to_string()) {
-            println!("identifier: {}", i);
-            token_list.tokens.push(Token::IdentifierToken(token));
+        if !self.missing_instances.borrow().is_empty() {
+            return Err(CompileError::new("the program is missing instances of generics".to_string()));
         }
-        else if let Some(token) = is_end_of_expression(i.to_string()) {
-            println!("EndOfExpression: {}", i);
-            token_list.tokens.push(token);
+        if self.check_only {
+            return Ok(compiled[PROGRAM_NAME].func_id);
         }
-        else {
-            println!("undefined: {}", i);
+
+        // Finalize the functions which we just defined, which resolves any
+        // outstanding relocations (patching in addresses, now that they're
+        // available).
+        self.module.finalize_definitions();
+
+        // Swap the new functions in
+        for name in recompiled.iter() {
+            if let Some(slot) = self.slots.get_mut(name) {
+                **slot = self.module.get_finalized_function(compiled[name].func_id) as usize;
+            }
+        }
+        debug_print(&format!("recompiled: {}", recompiled.join(", ")));
+        let program_id = compiled[PROGRAM_NAME].func_id;
+        self.compiled = compiled;
+        self.recompiled = recompiled;
+        return Ok(program_id);
+    }
+
+    // Let scripts call a function of the host program. It is called with the C calling
+    // convention, with the arguments and return value as the cranelift types of the value types
+    pub fn register_host_function(&mut self, name: &str, params: Vec<ValueType>, return_type: Option<ValueType>, addr: *const u8) {
+        let slot = self.slots.entry(name.to_string()).or_insert_with(|| Box::new(0));
+        **slot = addr as usize;
+        let slot: *const usize = slot.as_ref();
+        self.host_functions.insert(name.to_string(), FunctionSignature { params, return_type, slot });
+    }
+
+    // With overflow checks on, +, -, *, / and % on i32s report a runtime error when the result
+    // does not fit instead of wrapping around, like a debug build of Rust. The wrapping_add style
+    // intrinsics still wrap. Every function is compiled again at the next update
+    pub fn set_overflow_checks(&mut self, overflow_checks: bool) {
+        if self.overflow_checks != overflow_checks {
+            self.overflow_checks = overflow_checks;
+            self.compiled.clear();
         }
     }
-    return token_list;
+
+    // Build the functions of later updates only as far as finding their errors, see check_program
+    fn set_check_only(&mut self) {
+        self.check_only = true;
+    }
+
+    // The machine code and signature of a function of the program, as of the last update
+    pub fn get_function(&self, name: &str) -> Option<(*const u8, &[ValueType], Option<&ValueType>)> {
+        let function = self.compiled.get(name).filter(|_| name != PROGRAM_NAME)?;
+        let code_ptr = self.module.get_finalized_function(function.func_id);
+        return Some((code_ptr, &function.params, function.return_type.as_ref()));
+    }
+}
+
+pub fn cranelift_treverse_tree(expr_tree: &[Expr]) -> Result<(JIT, FuncId), CompileError> {
+    // Create the jit
+    let mut jit = JIT::default();
+    let id = jit.update(expr_tree)?;
+    return Ok((jit, id));
+}
+
+// Find the errors in a program the same way compiling it does, with the types checked and the
+// consts worked out, but without making any machine code for it
+pub fn check_program(expr_tree: &[Expr]) -> Result<(), CompileError> {
+    let mut jit = JIT::default();
+    jit.set_check_only();
+    jit.update(expr_tree)?;
+    return Ok(());
+}
+
+// Turn one word of the source into a token, None if it is not part of the language
+pub fn classify_word(word: &str) -> Option<Token> {
+    if let Some(text) = is_doc_comment(word) {
+        return Some(Token::DocComment(text));
+    }
+    if let Some(token) = is_operator(word.to_string()) {
+        return Some(Token::OperatorToken(token));
+    }
+    if let Some(token) = is_number(word.to_string()) {
+        return Some(Token::NumberToken(token));
+    }
+    if let Some(token) = is_float(word.to_string()) {
+        return Some(Token::FloatToken(token));
+    }
+    if let Some(keyword) = find_keyword(word) {
+        return Some(Token::Keyword(keyword));
+    }
+    if let Some(token) = is_identifier(word.to_string()) {
+        return Some(Token::IdentifierToken(token));
+    }
+    if let Some(token) = is_end_of_expression(word.to_string()) {
+        return Some(token);
+    }
+    return is_delimiter(word.to_string());
 }
 
-pub fn run_code(id: FuncId, jit: JIT) {
+// The tokens are taken from the concrete syntax tree, see cst.rs
+pub fn tokenize(my_str: String) -> TokenList {
+    return Cst::parse(&my_str).to_token_list();
+}
+
+// Call the compiled program, returning what it returns or the runtime error that stopped it
+pub fn execute_program(id: FuncId, jit: &JIT) -> Result<i32, RuntimeError> {
     // Retrieve a pointer to the machine code.
     let code_ptr = jit.module.get_finalized_function(id);
 
@@ -661,35 +5059,323 @@ pub fn run_code(id: FuncId, jit: JIT) {
         type I = ();
         let input: I = ();
         type O = i32;
-        //let result: O = ();
         let code_fn = mem::transmute::<_, fn(I) -> O>(code_ptr);
 
         // And now we can call it!
-        println!("Calling Program!:");
-        let result = code_fn(input);
-        println!("return: {}", result);
-        println!(":Program ended!");
+        let result = release_heap_after(|| code_fn(input));
+        match take_runtime_error() {
+            Some(err) => {
+                return Err(err);
+            }
+            None => {
+                return Ok(result);
+            }
+        }
     }
 }
 
-pub fn compile_code(my_str: String) {
+// Compile and run a program, returns what it returned and printed, or the compile error
+pub fn compile_code(my_str: String) -> Result<ProgramOutcome, CompileError> {
+    return recompile_code(&mut JIT::default(), my_str);
+}
+
+// Same as compile_code, but only the functions that changed since the last time the jit
+// compiled something are compiled again, see JIT::update
+pub fn recompile_code(jit: &mut JIT, my_str: String) -> Result<ProgramOutcome, CompileError> {
     // Use the lexer to split up string into tokens
     let mut token_list = tokenize(my_str);
-    println!("tokens: {}", token_list.to_string());
+    debug_print(&format!("tokens: {}", token_list.to_string()));
 
     // Generate expression tree
-    let tree = generate_tree(&mut token_list);
+    let tree = generate_tree(&mut token_list)?;
 
     // Treverse tree with cranelift to generate executable function
-    let (jit, id) = cranelift_treverse_tree(&tree.unwrap()).unwrap();
+    let id = jit.update(&tree)?;
 
     // Run the function
-    run_code(id, jit);
+    let (result, output) = capture_output(|| execute_program(id, jit));
+    return Ok(ProgramOutcome { result: result.map_err(|err| err.to_string()), output });
 }
 
 pub struct FunctionTranslator<'a> {
     _int: types::Type,
     builder: FunctionBuilder<'a>,
     variables: HashMap<String, Variable>,
+    variable_types: HashMap<String, ValueType>,
+    variable_count: usize,
+    addressed_variables: HashSet<String>, // Variables that are used with &, see is_memory_variable
+    reassigned_variables: HashSet<String>, // Variables that are assigned to after they are declared
+    stack_references: HashSet<usize>, // The indexes of the variables of references that can only point to the stack, see can_point_to_heap
+    return_type: Option<ValueType>,
+    return_addr: Option<Value>, // Where an aggregate return value is written to, the caller passes it
+    reachable: bool, // Whether the code being generated can run, it cannot after a return
+    loop_depth: usize, // How many loops the code being generated is in
+    closures_can_escape: bool, // See cranelift_closure
+    overflow_checks: bool, // See JIT::set_overflow_checks
+    types: &'a TypeTable,
+    functions: &'a FunctionTable,
     module: &'a mut JITModule,
+    name: String, // The name the function is declared with in the module, closures are named after it
+    closures: Vec<LiftedClosure>, // The closures in the function, they are built after it
+    closure_scopes: RefCell<Vec<Vec<(String, ValueType)>>>, // Parameters of closures whose types are being worked out
+    missing_instances: &'a RefCell<Vec<Instance>>, // See resolve_call
+    used_instances: &'a RefCell<Vec<Instance>>,
+}
+
+// A closure that has been taken out of the function it is in, to be built as a function of its own
+pub struct LiftedClosure {
+    func_id: FuncId,
+    function: Function,
+    captures: Vec<(String, ValueType)>,
+}
+
+impl<'a> FunctionTranslator<'a> {
+    // The name of an instance of a generic, an error if the program does not have it yet
+    pub fn require_instance(&self, instance: Instance, pos: SourcePos) -> Result<String, CompileError> {
+        let name = instance.to_string();
+        let exists = match &instance {
+            Instance::Function(..) => self.functions.contains_key(&name),
+            Instance::Struct(..) => self.types.structs.contains_key(&name)
+        };
+        if !exists {
+            self.missing_instances.borrow_mut().push(instance);
+            return Err(CompileError::at(pos, format!("{} is not instantiated at {}", name, pos.to_string())));
+        }
+        if !self.used_instances.borrow().contains(&instance) {
+            self.used_instances.borrow_mut().push(instance);
+        }
+        return Ok(name);
+    }
+    pub fn is_variable(&self, name: &str) -> bool {
+        return self.closure_scopes.borrow().iter().any(|scope| scope.iter().any(|(param, _)| param == name)) || self.variables.contains_key(name);
+    }
+    pub fn variable_type(&self, name: &str) -> Option<ValueType> {
+        for scope in self.closure_scopes.borrow().iter().rev() {
+            if let Some((_, value_type)) = scope.iter().find(|(param, _)| param == name) {
+                return Some(value_type.clone());
+            }
+        }
+        return self.variable_types.get(name).cloned();
+    }
+    pub fn declare_variable(&mut self, name: &str, value_type: ValueType) -> Variable {
+        let pointer_type = self.module.target_config().pointer_type();
+        let var = Variable::new(self.variable_count);
+        self.variable_count += 1;
+        self.variables.insert(name.into(), var);
+        self.variable_types.insert(name.into(), value_type.clone());
+        if self.is_memory_variable(name) {
+            self.builder.declare_var(var, pointer_type);
+        }
+        else {
+            self.builder.declare_var(var, value_type.cranelift_type(pointer_type));
+        }
+        return var;
+    }
+    // Create a stack slot big enough to hold value_type and return its address
+    pub fn create_stack_slot(&mut self, value_type: &ValueType) -> Value {
+        let pointer_type = self.module.target_config().pointer_type();
+        let slot = self.builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, value_type.size(self.types)));
+        return self.builder.ins().stack_addr(pointer_type, slot, 0);
+    }
+    pub fn copy_value(&mut self, dest: Value, src: Value, value_type: &ValueType) {
+        let config = self.module.target_config();
+        let align = value_type.align(self.types);
+        self.builder.emit_small_memory_copy(config, dest, src, value_type.size(self.types) as u64, align, align, false);
+    }
+    // Write value to memory, aggregates are copied from the address in value
+    pub fn store_value(&mut self, value: Value, addr: Value, offset: i32, value_type: &ValueType, flags: MemFlags) {
+        if value_type.is_aggregate() {
+            let dest = self.builder.ins().iadd_imm(addr, offset as i64);
+            self.copy_value(dest, value, value_type);
+        }
+        else {
+            self.builder.ins().store(flags, value, addr, offset);
+        }
+    }
+    // Read a value from memory, for aggregates this is just their address
+    pub fn load_value(&mut self, addr: Value, offset: i32, value_type: &ValueType, flags: MemFlags) -> Value {
+        let pointer_type = self.module.target_config().pointer_type();
+        if value_type.is_aggregate() {
+            return self.builder.ins().iadd_imm(addr, offset as i64);
+        }
+        return self.builder.ins().load(value_type.cranelift_type(pointer_type), flags, addr, offset);
+    }
+    // Scalar variables that have their address taken live in a stack slot, the cranelift
+    // variable then holds the address of the slot
+    fn is_memory_variable(&self, name: &str) -> bool {
+        return self.addressed_variables.contains(name) && !self.variable_types[name].is_aggregate();
+    }
+    pub fn define_variable(&mut self, name: &str, value_type: ValueType, value: Value) {
+        let var = self.declare_variable(name, value_type.clone());
+        if self.is_memory_variable(name) {
+            let addr = self.create_stack_slot(&value_type);
+            self.store_value(value, addr, 0, &value_type, MemFlags::trusted());
+            self.builder.def_var(var, addr);
+        }
+        else {
+            self.builder.def_var(var, value);
+        }
+    }
+    pub fn read_variable(&mut self, name: &str) -> Value {
+        let var = self.variables[name];
+        let value = self.builder.use_var(var);
+        if self.is_memory_variable(name) {
+            let value_type = self.variable_types[name].clone();
+            return self.load_value(value, 0, &value_type, MemFlags::trusted());
+        }
+        return value;
+    }
+    pub fn write_variable(&mut self, name: &str, value: Value) {
+        let var = self.variables[name];
+        if self.is_memory_variable(name) {
+            let addr = self.builder.use_var(var);
+            let value_type = self.variable_types[name].clone();
+            self.store_value(value, addr, 0, &value_type, MemFlags::trusted());
+        }
+        else {
+            self.builder.def_var(var, value);
+        }
+    }
+    // Call a function that the jit was given a symbol for
+    pub fn call_function(&mut self, name: &str, params: &[types::Type], returns: &[types::Type], args: &[Value]) -> codegen::ir::Inst {
+        let mut sig = self.module.make_signature();
+        for param in params {
+            sig.params.push(AbiParam::new(*param));
+        }
+        for return_type in returns {
+            sig.returns.push(AbiParam::new(*return_type));
+        }
+        let callee = self
+            .module
+            .declare_function(name, Linkage::Import, &sig)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let local_callee = self
+            .module
+            .declare_func_in_func(callee, self.builder.func);
+
+        return self.builder.ins().call(local_callee, args);
+    }
+    // A function value is the address of the code to call followed by the address of its env
+    pub fn make_fn_value(&mut self, fn_type: &ValueType, code: Value, env: Value) -> Value {
+        let pointer_size = self.module.target_config().pointer_type().bytes() as i32;
+        let addr = self.create_stack_slot(fn_type);
+        self.builder.ins().store(MemFlags::trusted(), code, addr, 0);
+        self.builder.ins().store(MemFlags::trusted(), env, addr, pointer_size);
+        return addr;
+    }
+    // The address a function has right now, from its slot in the indirection table
+    pub fn function_address(&mut self, slot: *const usize) -> Value {
+        let pointer_type = self.module.target_config().pointer_type();
+        let slot_addr = self.builder.ins().iconst(pointer_type, slot as i64);
+        return self.builder.ins().load(pointer_type, MemFlags::trusted(), slot_addr, 0);
+    }
+    // Stop the function after a runtime error has been reported, the caller checks for the error
+    pub fn return_after_runtime_error(&mut self) {
+        match &self.return_type {
+            Some(ValueType::Ref(..)) => {
+                let pointer_type = self.module.target_config().pointer_type();
+                let null = self.builder.ins().iconst(pointer_type, 0);
+                self.builder.ins().return_(&[null]);
+            }
+            Some(ValueType::I32) => {
+                let zero = self.builder.ins().iconst(types::I32, 0);
+                self.builder.ins().return_(&[zero]);
+            }
+            Some(ValueType::F64) => {
+                let zero = self.builder.ins().f64const(0.0);
+                self.builder.ins().return_(&[zero]);
+            }
+            _ => {
+                self.builder.ins().return_(&[]);
+            }
+        }
+    }
+    // Return straight away if a runtime error has been reported
+    pub fn check_runtime_error(&mut self) {
+        let call = self.call_function("has_runtime_error", &[], &[types::I32], &[]);
+        let has_error = self.builder.inst_results(call)[0];
+        let error_block = self.builder.create_block();
+        let ok_block = self.builder.create_block();
+        self.builder.ins().brnz(has_error, error_block, &[]);
+        self.builder.ins().jump(ok_block, &[]);
+
+        self.builder.switch_to_block(error_block);
+        self.builder.seal_block(error_block);
+        self.return_after_runtime_error();
+
+        self.builder.switch_to_block(ok_block);
+        self.builder.seal_block(ok_block);
+    }
+    // Report a runtime error when divisor is 0
+    pub fn check_divisor(&mut self, divisor: Value, pos: SourcePos) {
+        self.runtime_error_unless(divisor, "divide_by_zero", pos, &[]);
+    }
+    // Report a runtime error when divisor is 0. The divisor that is returned is never -1 either,
+    // since i32::MIN / -1 traps, the caller works out what dividing by -1 gives by itself
+    pub fn checked_divisor(&mut self, divisor: Value, pos: SourcePos) -> Value {
+        self.check_divisor(divisor, pos);
+        let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, divisor, -1);
+        let one = self.builder.ins().iconst(types::I32, 1);
+        return self.builder.ins().select(is_minus_one, one, divisor);
+    }
+    // The exact result of an operator on i32 operands, as an i64
+    pub fn exact_operation(&mut self, overflow: &Overflow, args: &[Value]) -> Value {
+        let wide: Vec<Value> = args.iter().map(|arg| self.builder.ins().sextend(types::I64, *arg)).collect();
+        return (overflow.lower_i64)(self, &wide);
+    }
+    // An i64 cut down to an i32, and whether it fits in one
+    pub fn narrow(&mut self, wide: Value) -> (Value, Value) {
+        let narrow = self.builder.ins().ireduce(types::I32, wide);
+        let extended = self.builder.ins().sextend(types::I64, narrow);
+        let fits = self.builder.ins().icmp(IntCC::Equal, extended, wide);
+        return (narrow, fits);
+    }
+    // Whether a shift amount is in 0..32
+    pub fn shift_in_range(&mut self, amount: Value) -> Value {
+        return self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, amount, 32);
+    }
+    // Report that op overflowed unless fits is true
+    pub fn overflow_check(&mut self, fits: Value, op: &Operator, pos: SourcePos) {
+        let op = self.builder.ins().iconst(types::I32, operator_index(op) as i64);
+        self.runtime_error_unless(fits, "arithmetic_overflow", pos, &[op]);
+    }
+    // Add an instruction to the block being built
+    pub fn ins(&mut self) -> impl InstBuilder<'_> + use<'_, 'a> {
+        return self.builder.ins();
+    }
+    // Report a runtime error unless 0 <= index < len
+    pub fn bounds_check(&mut self, index: Value, len: usize, pos: SourcePos) {
+        let in_bounds = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, index, len as i64);
+        let len = self.builder.ins().iconst(types::I32, len as i64);
+        self.runtime_error_unless(in_bounds, "index_out_of_bounds", pos, &[index, len]);
+    }
+    // Stop with a runtime error if addr, which a reference points to, is in memory that was freed
+    pub fn check_not_freed(&mut self, addr: Value, pos: SourcePos) {
+        let pointer_type = self.module.target_config().pointer_type();
+        let call = self.call_function("vm_is_live", &[pointer_type], &[types::I32], &[addr]);
+        let live = self.builder.inst_results(call)[0];
+        self.runtime_error_unless(live, "use_after_free", pos, &[]);
+    }
+    // Unless ok is true, call the runtime error function with the position and args, which are
+    // all i32s, and stop the function
+    fn runtime_error_unless(&mut self, ok: Value, function: &str, pos: SourcePos, args: &[Value]) {
+        let error_block = self.builder.create_block();
+        let ok_block = self.builder.create_block();
+        self.builder.ins().brz(ok, error_block, &[]);
+        self.builder.ins().jump(ok_block, &[]);
+
+        self.builder.switch_to_block(error_block);
+        self.builder.seal_block(error_block);
+        let line = self.builder.ins().iconst(types::I32, pos.line as i64);
+        let col = self.builder.ins().iconst(types::I32, pos.col as i64);
+        let mut error_args = vec!(line, col);
+        error_args.extend_from_slice(args);
+        let params = vec!(types::I32; error_args.len());
+        self.call_function(function, &params, &[], &error_args);
+        self.return_after_runtime_error();
+
+        self.builder.switch_to_block(ok_block);
+        self.builder.seal_block(ok_block);
+    }
 }
\ No newline at end of file
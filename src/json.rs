@@ -0,0 +1,269 @@
+// Just enough JSON for the language server protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>) // The fields in the order they were written
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut parser = JsonParser { chars, index: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.index < parser.chars.len() {
+            return Err(format!("unexpected '{}' after the JSON value at {}", parser.chars[parser.index], parser.index));
+        }
+        return Ok(value);
+    }
+    pub fn to_string(&self) -> String {
+        match self {
+            Json::Null => {
+                return "null".to_string();
+            }
+            Json::Bool(value) => {
+                return value.to_string();
+            }
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => {
+                return (*value as i64).to_string();
+            }
+            Json::Number(value) => {
+                return value.to_string();
+            }
+            Json::String(text) => {
+                return quote(text);
+            }
+            Json::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(|element| element.to_string()).collect();
+                return format!("[{}]", elements.join(","));
+            }
+            Json::Object(fields) => {
+                let fields: Vec<String> = fields.iter().map(|(key, value)| format!("{}:{}", quote(key), value.to_string())).collect();
+                return format!("{{{}}}", fields.join(","));
+            }
+        }
+    }
+    // The value of a field, None if this is not an object or it has no such field
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => {
+                return fields.iter().find(|(field, _)| field == key).map(|(_, value)| value);
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None
+        }
+    }
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Json::Number(value) => Some(*value),
+            _ => None
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None
+        }
+    }
+}
+
+// Build an object from fields, so that messages can be written close to how they look
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    return Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect());
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            c if (c as u32) < 0x20 => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    index: usize
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while self.index < self.chars.len() && self.chars[self.index].is_whitespace() {
+            self.index += 1;
+        }
+    }
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.chars.get(self.index) != Some(&expected) {
+            return Err(format!("expected '{}' at {}", expected, self.index));
+        }
+        self.index += 1;
+        return Ok(());
+    }
+    // Consume word if the input continues with it
+    fn keyword(&mut self, word: &str) -> bool {
+        let end = self.index + word.chars().count();
+        if end <= self.chars.len() && self.chars[self.index..end].iter().copied().eq(word.chars()) {
+            self.index = end;
+            return true;
+        }
+        return false;
+    }
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.index).copied() {
+            Some('{') => {
+                self.index += 1;
+                let mut fields: Vec<(String, Json)> = vec!();
+                self.skip_whitespace();
+                if self.chars.get(self.index) == Some(&'}') {
+                    self.index += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.get(self.index) {
+                        Some(',') => {
+                            self.index += 1;
+                        }
+                        Some('}') => {
+                            self.index += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => {
+                            return Err(format!("expected ',' or '}}' at {}", self.index));
+                        }
+                    }
+                }
+            }
+            Some('[') => {
+                self.index += 1;
+                let mut elements: Vec<Json> = vec!();
+                self.skip_whitespace();
+                if self.chars.get(self.index) == Some(&']') {
+                    self.index += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.get(self.index) {
+                        Some(',') => {
+                            self.index += 1;
+                        }
+                        Some(']') => {
+                            self.index += 1;
+                            return Ok(Json::Array(elements));
+                        }
+                        _ => {
+                            return Err(format!("expected ',' or ']' at {}", self.index));
+                        }
+                    }
+                }
+            }
+            Some('"') => {
+                return Ok(Json::String(self.string()?));
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.index;
+                while self.index < self.chars.len() && (self.chars[self.index].is_ascii_digit() || "+-.eE".contains(self.chars[self.index])) {
+                    self.index += 1;
+                }
+                let text: String = self.chars[start..self.index].iter().collect();
+                return text.parse::<f64>().map(Json::Number).map_err(|_| format!("invalid number {} at {}", text, start));
+            }
+            _ if self.keyword("true") => {
+                return Ok(Json::Bool(true));
+            }
+            _ if self.keyword("false") => {
+                return Ok(Json::Bool(false));
+            }
+            _ if self.keyword("null") => {
+                return Ok(Json::Null);
+            }
+            _ => {
+                return Err(format!("expected a JSON value at {}", self.index));
+            }
+        }
+    }
+    fn string(&mut self) -> Result<String, String> {
+        if self.chars.get(self.index) != Some(&'"') {
+            return Err(format!("expected a string at {}", self.index));
+        }
+        self.index += 1;
+        let mut text = String::new();
+        loop {
+            let c = match self.chars.get(self.index) {
+                Some(c) => *c,
+                None => {
+                    return Err("unterminated string".to_string());
+                }
+            };
+            self.index += 1;
+            match c {
+                '"' => {
+                    return Ok(text);
+                }
+                '\\' => {
+                    let escaped = self.chars.get(self.index).copied();
+                    self.index += 1;
+                    match escaped {
+                        Some('n') => text.push('\n'),
+                        Some('r') => text.push('\r'),
+                        Some('t') => text.push('\t'),
+                        Some('b') => text.push('\u{8}'),
+                        Some('f') => text.push('\u{c}'),
+                        Some('u') => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the basic plane are written as two surrogates
+                            if (0xd800..0xdc00).contains(&code) && self.keyword("\\u") {
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            text.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        Some(c) => text.push(c),
+                        None => {
+                            return Err("unterminated string".to_string());
+                        }
+                    }
+                }
+                c => {
+                    text.push(c);
+                }
+            }
+        }
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let end = self.index + 4;
+        if end > self.chars.len() {
+            return Err("unterminated string".to_string());
+        }
+        let digits: String = self.chars[self.index..end].iter().collect();
+        self.index = end;
+        return u32::from_str_radix(&digits, 16).map_err(|_| format!("invalid escape \\u{}", digits));
+    }
+}
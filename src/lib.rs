@@ -0,0 +1,30 @@
+// The code base writes out returns and types explicitly, so those lints are turned off
+#![allow(clippy::needless_return)]
+#![allow(clippy::unused_unit)]
+#![allow(clippy::needless_late_init)]
+#![allow(clippy::redundant_field_names)]
+#![allow(clippy::len_zero)]
+#![allow(clippy::new_without_default)]
+#![allow(clippy::inherent_to_string)]
+#![allow(clippy::unnecessary_unwrap)]
+#![allow(clippy::explicit_counter_loop)]
+#![allow(clippy::missing_transmute_annotations)]
+pub mod jit;
+pub mod interpreter;
+pub mod fuzz;
+pub mod printer;
+pub mod cst;
+pub mod grammar;
+pub mod operators;
+pub mod json;
+pub mod symbols;
+pub mod lsp;
+pub mod engine;
+pub mod generics;
+#[cfg(test)]
+mod test;
+pub use jit::compile_code;
+pub use engine::{Engine, Program, Diagnostics, Callable};
+pub mod util;
+pub use util::*;
+
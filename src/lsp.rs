@@ -0,0 +1,359 @@
+use crate::jit::*;
+use crate::cst::Cst;
+use crate::json::{Json, object};
+use crate::symbols::{Reference, SymbolKind, SymbolTable};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+// A language server, so that editors can show errors, types and where things are declared. It
+// talks the language server protocol over stdin and stdout, and checks programs with the same
+// tokenizer, parser and compiler that run them
+
+// An open file and what the compiler found out about it
+struct Document {
+    errors: Vec<(String, SourcePos, usize)>, // The message, where it is and how many characters it covers
+    unplaced_errors: Vec<String>, // Errors the compiler could not say where they are
+    symbols: SymbolTable
+}
+
+fn analyze(source: &str) -> Document {
+    let mut errors: Vec<CompileError> = vec!();
+    // A crash in the compiler should not take the server down with it
+    let result = std::panic::catch_unwind(|| {
+        let mut token_list = tokenize(source.to_string());
+        match generate_tree(&mut token_list) {
+            Ok(tree) => {
+                // Only checked, the file is compiled again on every change and never run
+                let (result, variable_types) = capture_variable_types(|| check_program(&tree));
+                return (Some(tree), variable_types, result.err());
+            }
+            Err(err) => {
+                return (None, vec!(), Some(err));
+            }
+        }
+    });
+    let (tree, variable_types) = match result {
+        Ok((tree, variable_types, err)) => {
+            errors.extend(err);
+            (tree, variable_types)
+        }
+        Err(_) => {
+            errors.push(CompileError::new("the compiler crashed while checking this file".to_string()));
+            (None, vec!())
+        }
+    };
+
+    // An error covers the token it points at
+    let tokens = Cst::parse(source).tokens().into_iter().map(|token| (token.pos, token.text.chars().count())).collect::<Vec<(SourcePos, usize)>>();
+    let mut placed: Vec<(String, SourcePos, usize)> = vec!();
+    let mut unplaced_errors: Vec<String> = vec!();
+    for err in errors {
+        match err.pos {
+            Some(pos) => {
+                let len = tokens.iter().find(|(token_pos, _)| *token_pos == pos).map_or(1, |(_, len)| *len);
+                placed.push((err.message, pos, len));
+            }
+            None => {
+                unplaced_errors.push(err.message);
+            }
+        }
+    }
+    return Document { errors: placed, unplaced_errors, symbols: SymbolTable::build(source, tree, &variable_types) };
+}
+
+// Positions in the protocol count from 0, the compiler counts from 1
+fn lsp_position(pos: SourcePos) -> Json {
+    return object(vec!(
+        ("line", Json::Number(pos.line as f64 - 1.0)),
+        ("character", Json::Number(pos.col as f64 - 1.0))
+    ));
+}
+
+fn lsp_range(start: SourcePos, end: SourcePos) -> Json {
+    return object(vec!(("start", lsp_position(start)), ("end", lsp_position(end))));
+}
+
+fn reference_range(reference: &Reference) -> Json {
+    let end = SourcePos { line: reference.pos.line, col: reference.pos.col + reference.len as u32 };
+    return lsp_range(reference.pos, end);
+}
+
+fn source_pos(params: &Json) -> Option<SourcePos> {
+    let position = params.get("position")?;
+    let line = position.get("line")?.as_number()? as u32;
+    let character = position.get("character")?.as_number()? as u32;
+    return Some(SourcePos { line: line + 1, col: character + 1 });
+}
+
+fn symbol_kind(kind: SymbolKind) -> f64 {
+    // The numbers the protocol uses for each kind of symbol
+    match kind {
+        SymbolKind::Function => 12.0,
+        SymbolKind::Struct => 23.0,
+        SymbolKind::Enum => 10.0,
+        SymbolKind::Variant => 22.0,
+        SymbolKind::Field => 8.0,
+        SymbolKind::Const => 14.0,
+        SymbolKind::Variable | SymbolKind::Parameter => 13.0
+    }
+}
+
+fn error_response(id: Json, code: f64, message: String) -> Json {
+    return object(vec!(
+        ("jsonrpc", Json::String("2.0".to_string())),
+        ("id", id),
+        ("error", object(vec!(("code", Json::Number(code)), ("message", Json::String(message)))))
+    ));
+}
+
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutting_down: bool,
+    pub exited: bool
+}
+
+impl Server {
+    pub fn new() -> Server {
+        return Server { documents: HashMap::new(), shutting_down: false, exited: false };
+    }
+
+    // Handle one message from the editor, returns the messages to send back
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(|method| method.as_str()).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                return self.notification(method, &params);
+            }
+        };
+        let result = if self.shutting_down {
+            Err((-32600.0, "the server is shutting down".to_string()))
+        }
+        else {
+            self.request(method, &params)
+        };
+        let response = match result {
+            Ok(result) => object(vec!(("jsonrpc", Json::String("2.0".to_string())), ("id", id), ("result", result))),
+            Err((code, message)) => error_response(id, code, message)
+        };
+        return vec!(response);
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(|uri| uri.as_str()).unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|document| document.get("text")).and_then(|text| text.as_str()).unwrap_or("");
+                self.documents.insert(uri.clone(), analyze(text));
+            }
+            "textDocument/didChange" => {
+                // The whole text is sent on every change, see the capabilities in initialize
+                let changes = match params.get("contentChanges") {
+                    Some(Json::Array(changes)) => changes.clone(),
+                    _ => vec!()
+                };
+                match changes.last().and_then(|change| change.get("text")).and_then(|text| text.as_str()) {
+                    Some(text) => {
+                        self.documents.insert(uri.clone(), analyze(text));
+                    }
+                    None => {
+                        return vec!();
+                    }
+                }
+            }
+            "textDocument/didSave" => {
+                if let Some(text) = params.get("text").and_then(|text| text.as_str()) {
+                    self.documents.insert(uri.clone(), analyze(text));
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            }
+            "exit" => {
+                self.exited = true;
+                return vec!();
+            }
+            _ => {
+                return vec!();
+            }
+        }
+        // An error without a position is not put anywhere in the file, the editor shows it on its own
+        let mut messages = vec!(self.diagnostics(&uri));
+        for message in self.documents.get(&uri).map_or(&vec!(), |document| &document.unplaced_errors) {
+            messages.push(object(vec!(
+                ("jsonrpc", Json::String("2.0".to_string())),
+                ("method", Json::String("window/showMessage".to_string())),
+                ("params", object(vec!(("type", Json::Number(1.0)), ("message", Json::String(message.clone())))))
+            )));
+        }
+        return messages;
+    }
+
+    fn diagnostics(&self, uri: &str) -> Json {
+        let diagnostics = match self.documents.get(uri) {
+            Some(document) => document.errors.iter().map(|(message, pos, len)| {
+                let end = SourcePos { line: pos.line, col: pos.col + *len as u32 };
+                object(vec!(
+                    ("range", lsp_range(*pos, end)),
+                    ("severity", Json::Number(1.0)),
+                    ("source", Json::String("vicmil".to_string())),
+                    ("message", Json::String(message.clone()))
+                ))
+            }).collect(),
+            None => vec!()
+        };
+        return object(vec!(
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("method", Json::String("textDocument/publishDiagnostics".to_string())),
+            ("params", object(vec!(("uri", Json::String(uri.to_string())), ("diagnostics", Json::Array(diagnostics)))))
+        ));
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (f64, String)> {
+        let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(|uri| uri.as_str()).unwrap_or("").to_string();
+        let location = |reference: &Reference| object(vec!(("uri", Json::String(uri.clone())), ("range", reference_range(reference))));
+        match method {
+            "initialize" => {
+                let capabilities = object(vec!(
+                    ("textDocumentSync", object(vec!(
+                        ("openClose", Json::Bool(true)),
+                        ("change", Json::Number(1.0)), // The whole document is sent on every change
+                        ("save", object(vec!(("includeText", Json::Bool(true)))))
+                    ))),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true))
+                ));
+                let server_info = object(vec!(("name", Json::String("vicmil_compiler".to_string()))));
+                return Ok(object(vec!(("capabilities", capabilities), ("serverInfo", server_info))));
+            }
+            "shutdown" => {
+                self.shutting_down = true;
+                return Ok(Json::Null);
+            }
+            "textDocument/hover" | "textDocument/definition" | "textDocument/references" => {
+                let document = match self.documents.get(&uri) {
+                    Some(document) => document,
+                    None => {
+                        return Ok(Json::Null);
+                    }
+                };
+                let symbols = &document.symbols;
+                let reference = match source_pos(params).and_then(|pos| symbols.reference_at(pos)) {
+                    Some(reference) => reference,
+                    None => {
+                        return Ok(Json::Null);
+                    }
+                };
+                let symbol = &symbols.symbols[reference.symbol];
+                if method == "textDocument/hover" {
+                    let mut text = format!("```vm\n{}\n```", symbols.describe(reference.symbol));
+                    if let Some(doc) = &symbol.doc {
+                        text += &format!("\n\n{}", doc);
+                    }
+                    let contents = object(vec!(("kind", Json::String("markdown".to_string())), ("value", Json::String(text))));
+                    return Ok(object(vec!(("contents", contents), ("range", reference_range(reference)))));
+                }
+                let references = symbols.references_to(reference.symbol);
+                if method == "textDocument/definition" {
+                    return Ok(references.into_iter().find(|reference| reference.is_declaration).map_or(Json::Null, location));
+                }
+                let include_declaration = params.get("context").and_then(|context| context.get("includeDeclaration")).and_then(|include| include.as_bool()).unwrap_or(true);
+                let locations = references.into_iter()
+                    .filter(|reference| include_declaration || !reference.is_declaration)
+                    .map(location)
+                    .collect();
+                return Ok(Json::Array(locations));
+            }
+            "textDocument/documentSymbol" => {
+                let symbols = match self.documents.get(&uri) {
+                    Some(document) => &document.symbols,
+                    None => {
+                        return Ok(Json::Null);
+                    }
+                };
+                let document_symbol = |index: usize, children: Vec<Json>| {
+                    let symbol = &symbols.symbols[index];
+                    let end = SourcePos { line: symbol.pos.line, col: symbol.pos.col + symbol.name.chars().count() as u32 };
+                    let range_end = if symbol.end.line > end.line || symbol.end.col > end.col { symbol.end } else { end };
+                    object(vec!(
+                        ("name", Json::String(symbol.name.clone())),
+                        ("detail", Json::String(symbols.describe(index))),
+                        ("kind", Json::Number(symbol_kind(symbol.kind))),
+                        ("range", lsp_range(symbol.pos, range_end)),
+                        ("selectionRange", lsp_range(symbol.pos, end)),
+                        ("children", Json::Array(children))
+                    ))
+                };
+                let mut document_symbols: Vec<Json> = vec!();
+                for (index, symbol) in symbols.symbols.iter().enumerate() {
+                    if !matches!(symbol.kind, SymbolKind::Function | SymbolKind::Struct | SymbolKind::Enum | SymbolKind::Const) {
+                        continue;
+                    }
+                    let children: Vec<Json> = (0..symbols.symbols.len())
+                        .filter(|member| matches!(symbols.symbols[*member].kind, SymbolKind::Field | SymbolKind::Variant))
+                        .filter(|member| symbols.symbols[*member].container.as_deref() == Some(symbol.name.as_str()))
+                        .map(|member| document_symbol(member, vec!()))
+                        .collect();
+                    document_symbols.push(document_symbol(index, children));
+                }
+                return Ok(Json::Array(document_symbols));
+            }
+            _ => {
+                return Err((-32601.0, format!("unknown method {}", method)));
+            }
+        }
+    }
+}
+
+// Read the body of one message, None at the end of the input. Every message has a
+// Content-Length header and then the JSON
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| format!("invalid Content-Length {}", value.trim()))?);
+        }
+    }
+    let length = length.ok_or("message without a Content-Length header")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|err| err.to_string())?;
+    return Ok(Some(String::from_utf8_lossy(&body).into_owned()));
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).map_err(|err| err.to_string())?;
+    return output.flush().map_err(|err| err.to_string());
+}
+
+// Serve until the editor sends exit or closes the input
+pub fn run_server(mut input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    // stdout is where the messages go, so the compiler must not print to it
+    set_debug_output(false);
+    let mut server = Server::new();
+    while let Some(body) = read_message(&mut input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) => server.handle(&message),
+            // The body was read in full, so the messages after it can still be handled
+            Err(err) => vec!(error_response(Json::Null, -32700.0, format!("parse error: {}", err)))
+        };
+        for reply in replies {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    return Ok(());
+}
@@ -1,22 +1,103 @@
-pub mod jit;
-pub use jit::*;
-pub mod util;
-pub use util::*;
-
-use cranelift::codegen::CodegenError;
-use cranelift::codegen::verifier::{VerifierErrors};
+// The code base writes out returns and types explicitly, so those lints are turned off
+#![allow(clippy::needless_return)]
+#![allow(clippy::unused_unit)]
+#![allow(clippy::needless_late_init)]
+#![allow(clippy::redundant_field_names)]
+#![allow(clippy::len_zero)]
+#![allow(clippy::new_without_default)]
+#![allow(clippy::inherent_to_string)]
+#![allow(clippy::unnecessary_unwrap)]
+#![allow(clippy::explicit_counter_loop)]
+#![allow(clippy::missing_transmute_annotations)]
+use vicmil_compiler::*;
 use cranelift::prelude::*;
-use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{DataContext, FuncId, Linkage, Module, ModuleError};
-use std::collections::HashMap;
-use core::mem;
+use cranelift_module::Module;
 
 
 pub fn print_hello_world() -> () {
-    println!("{}", "hello world!");
+    println!("hello world!");
+}
+
+// Run a source file with the JIT, the interpreter, or both while checking that they agree
+fn run_file(command: &str, path: &str) -> Result<(), String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    match command {
+        "run" => {
+            let outcome = compile_code(source).map_err(|err| err.to_string())?;
+            println!("{}", outcome.to_string());
+        }
+        "interpret" => {
+            jit::set_debug_output(false);
+            let mut token_list = jit::tokenize(source);
+            let tree = jit::generate_tree(&mut token_list).map_err(|err| err.to_string())?;
+            let outcome = interpreter::interpret_program(&tree).map_err(|err| err.to_string())?;
+            println!("{}", outcome.to_string());
+        }
+        "fmt" => {
+            jit::set_debug_output(false);
+            let formatted = printer::format_source(&source)?;
+            if formatted != source {
+                std::fs::write(path, formatted).map_err(|err| format!("could not write {}: {}", path, err))?;
+            }
+        }
+        "diff" => {
+            jit::set_debug_output(false);
+            let outcome = interpreter::run_differential(source)?;
+            println!("{}", outcome.to_string());
+        }
+        _ => {
+            return Err(format!("unknown command {}, expected run, interpret, diff or fmt", command));
+        }
+    }
+    return Ok(());
+}
+
+// Fuzz the compiler, the number of iterations and the first seed can be given
+fn run_fuzzer(args: &[String]) -> Result<(), String> {
+    let iterations = match args.first() {
+        Some(arg) => arg.parse::<u64>().map_err(|_| format!("expected a number of iterations, found {}", arg))?,
+        None => 1000
+    };
+    let seed = match args.get(1) {
+        Some(arg) => arg.parse::<u64>().map_err(|_| format!("expected a seed, found {}", arg))?,
+        None => 0
+    };
+    let failures = fuzz::fuzz(iterations, seed);
+    for failure in failures.iter() {
+        println!("{}\n", failure.to_string());
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} fuzz cases failed", failures.len()));
+    }
+    println!("{} fuzz iterations passed", iterations);
+    return Ok(());
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 2 && args[1] == "fuzz" {
+        if let Err(err) = run_fuzzer(&args[2..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() == 2 && args[1] == "lsp" {
+        let stdin = std::io::stdin();
+        if let Err(err) = lsp::run_server(stdin.lock(), std::io::stdout()) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() == 3 {
+        if let Err(err) = run_file(&args[1], &args[2]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut included_functions = IncludedFunctions::new();
     included_functions.add_print_int_function();
     included_functions.add_function("print_hello_world", print_hello_world as *const u8);
@@ -70,6 +151,6 @@ fn main() {
     let result = compiler_util.run_code(id);
     println!("{}", result);
 
-    //compile_code("x = 7 ; x += 8 + 10 * 5 + 3 ; return_var = x ; x = 3".to_string());
+    //compile_code("x = 7 ; x += 8 + 10 * 5 + 3 ; return x".to_string());
     //println!("Program finished!");
 }
\ No newline at end of file
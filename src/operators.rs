@@ -0,0 +1,308 @@
+use crate::jit::*;
+use cranelift::prelude::*;
+
+// Every operator of the language is one entry in OPERATORS. The lexer finds operators by their
+// symbol, the parser groups them by precedence and associativity, and the type checker, the JIT
+// and the interpreter use the rest of the entry, so adding an operator only takes a new entry.
+// Every infix operator also gets a compound assignment, a += b is a = a + b
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Associativity {
+    Left,
+    Right
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Prefix, // ~x, it applies to the operand right after it
+    Infix // x + y
+}
+
+// The types an operator works on, every operand has the same type and so does the result
+#[derive(Debug, Clone, Copy)]
+pub enum Operands {
+    Integers,
+    // Integers and floats, with how the interpreter works the operator out on floats
+    Numbers(fn(&[f64]) -> f64)
+}
+
+// The instructions for an operator, the operands have already been worked out and all have
+// the type that is passed in
+pub type Lowering = fn(&mut FunctionTranslator, &[Value], &ValueType, SourcePos) -> Value;
+
+#[derive(Debug)]
+pub struct Operator {
+    pub symbol: &'static str,
+    pub precedence: usize, // The operators with the lowest precedence are applied first
+    pub associativity: Associativity,
+    pub arity: Arity,
+    pub operands: Operands,
+    pub lower: Lowering,
+    // How the interpreter works the operator out on integers, the error is a runtime error.
+    // Integers wrap around on overflow, like they do in the code the JIT generates
+    pub eval_i32: fn(&[i32]) -> Result<i32, &'static str>,
+    // For the operators that can overflow, what the checked, wrapping and saturating forms need
+    pub overflow: Option<Overflow>
+}
+
+// The result of an operator that can overflow is worked out exactly on operands widened to i64,
+// it overflowed when it does not fit back in an i32
+#[derive(Debug)]
+pub struct Overflow {
+    pub name: &'static str, // The intrinsics are wrapping_name, checked_name and saturating_name
+    pub message: &'static str, // The runtime error when overflow checks are on
+    pub divides: bool, // Whether the right operand is a divisor, dividing by zero is never allowed
+    pub shifts: bool, // Whether the right operand is a shift amount, it overflows when it is outside 0..32
+    pub lower_i64: fn(&mut FunctionTranslator, &[Value]) -> Value,
+    pub eval_i64: fn(&[i64]) -> i64
+}
+
+// The three ways to handle overflow that the intrinsics give
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticMode {
+    Wrapping, // The result wraps around, like the operators do when overflow checks are off
+    Checked, // option::some(result), or option::none when it overflows or divides by zero
+    Saturating // The result is clamped to i32::MIN and i32::MAX
+}
+
+// = and the compound assignments bind looser than every operator, and group from the right
+pub const ASSIGNMENT_PRECEDENCE: usize = 6;
+
+pub static OPERATORS: [Operator; 11] = [
+    Operator {
+        symbol: "~",
+        precedence: 0,
+        associativity: Associativity::Right,
+        arity: Arity::Prefix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, _| trans.ins().bnot(args[0]),
+        eval_i32: |args| Ok(!args[0]),
+        overflow: None
+    },
+    Operator {
+        symbol: "*",
+        precedence: 0,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Numbers(|args| args[0] * args[1]),
+        lower: |trans, args, value_type, _| if *value_type == ValueType::F64 { trans.ins().fmul(args[0], args[1]) } else { trans.ins().imul(args[0], args[1]) },
+        eval_i32: |args| Ok(args[0].wrapping_mul(args[1])),
+        overflow: Some(Overflow {
+            name: "mul",
+            message: "attempt to multiply with overflow",
+            divides: false,
+            shifts: false,
+            lower_i64: |trans, args| trans.ins().imul(args[0], args[1]),
+            eval_i64: |args| args[0] * args[1]
+        })
+    },
+    Operator {
+        symbol: "/",
+        precedence: 0,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Numbers(|args| args[0] / args[1]),
+        lower: lower_div,
+        eval_i32: |args| if args[1] == 0 { Err(DIVIDE_BY_ZERO) } else { Ok(args[0].wrapping_div(args[1])) },
+        overflow: Some(Overflow {
+            name: "div",
+            message: "attempt to divide with overflow",
+            divides: true,
+            shifts: false,
+            lower_i64: |trans, args| trans.ins().sdiv(args[0], args[1]),
+            eval_i64: |args| args[0] / args[1]
+        })
+    },
+    Operator {
+        symbol: "%",
+        precedence: 0,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, pos| {
+            // The divisor is never -1, and the remainder of dividing by -1 is 0 anyway
+            let divisor = trans.checked_divisor(args[1], pos);
+            return trans.ins().srem(args[0], divisor);
+        },
+        eval_i32: |args| if args[1] == 0 { Err(DIVIDE_BY_ZERO) } else { Ok(args[0].wrapping_rem(args[1])) },
+        // The remainder always fits, but dividing by zero still makes checked_rem give none
+        overflow: Some(Overflow {
+            name: "rem",
+            message: "attempt to calculate the remainder with overflow",
+            divides: true,
+            shifts: false,
+            lower_i64: |trans, args| trans.ins().srem(args[0], args[1]),
+            eval_i64: |args| args[0] % args[1]
+        })
+    },
+    Operator {
+        symbol: "+",
+        precedence: 1,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Numbers(|args| args[0] + args[1]),
+        lower: |trans, args, value_type, _| if *value_type == ValueType::F64 { trans.ins().fadd(args[0], args[1]) } else { trans.ins().iadd(args[0], args[1]) },
+        eval_i32: |args| Ok(args[0].wrapping_add(args[1])),
+        overflow: Some(Overflow {
+            name: "add",
+            message: "attempt to add with overflow",
+            divides: false,
+            shifts: false,
+            lower_i64: |trans, args| trans.ins().iadd(args[0], args[1]),
+            eval_i64: |args| args[0] + args[1]
+        })
+    },
+    Operator {
+        symbol: "-",
+        precedence: 1,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Numbers(|args| args[0] - args[1]),
+        lower: |trans, args, value_type, _| if *value_type == ValueType::F64 { trans.ins().fsub(args[0], args[1]) } else { trans.ins().isub(args[0], args[1]) },
+        eval_i32: |args| Ok(args[0].wrapping_sub(args[1])),
+        overflow: Some(Overflow {
+            name: "sub",
+            message: "attempt to subtract with overflow",
+            divides: false,
+            shifts: false,
+            lower_i64: |trans, args| trans.ins().isub(args[0], args[1]),
+            eval_i64: |args| args[0] - args[1]
+        })
+    },
+    // Shifts only look at the low 5 bits of the amount. Bits that are shifted out are not an
+    // overflow, only an amount outside 0..32 is, so the exact result is the shifted i32
+    Operator {
+        symbol: "<<",
+        precedence: 2,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, _| trans.ins().ishl(args[0], args[1]),
+        eval_i32: |args| Ok(args[0].wrapping_shl(args[1] as u32)),
+        overflow: Some(Overflow {
+            name: "shl",
+            message: "attempt to shift left with overflow",
+            divides: false,
+            shifts: true,
+            lower_i64: |trans, args| {
+                let shifted = trans.ins().ishl(args[0], args[1]);
+                let narrow = trans.ins().ireduce(types::I32, shifted);
+                return trans.ins().sextend(types::I64, narrow);
+            },
+            eval_i64: |args| (args[0] as i32).wrapping_shl(args[1] as u32) as i64
+        })
+    },
+    Operator {
+        symbol: ">>",
+        precedence: 2,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, value_type, _| if value_type.is_signed() { trans.ins().sshr(args[0], args[1]) } else { trans.ins().ushr(args[0], args[1]) },
+        eval_i32: |args| Ok(args[0].wrapping_shr(args[1] as u32)),
+        // The operand is sign extended, so shifting it right as an i64 gives the same bits
+        overflow: Some(Overflow {
+            name: "shr",
+            message: "attempt to shift right with overflow",
+            divides: false,
+            shifts: true,
+            lower_i64: |trans, args| trans.ins().sshr(args[0], args[1]),
+            eval_i64: |args| (args[0] as i32).wrapping_shr(args[1] as u32) as i64
+        })
+    },
+    Operator {
+        symbol: "&",
+        precedence: 3,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, _| trans.ins().band(args[0], args[1]),
+        eval_i32: |args| Ok(args[0] & args[1]),
+        overflow: None
+    },
+    Operator {
+        symbol: "^",
+        precedence: 4,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, _| trans.ins().bxor(args[0], args[1]),
+        eval_i32: |args| Ok(args[0] ^ args[1]),
+        overflow: None
+    },
+    Operator {
+        symbol: "|",
+        precedence: 5,
+        associativity: Associativity::Left,
+        arity: Arity::Infix,
+        operands: Operands::Integers,
+        lower: |trans, args, _, _| trans.ins().bor(args[0], args[1]),
+        eval_i32: |args| Ok(args[0] | args[1]),
+        overflow: None
+    }
+];
+
+const DIVIDE_BY_ZERO: &str = "attempt to divide by zero";
+
+fn lower_div(trans: &mut FunctionTranslator, args: &[Value], value_type: &ValueType, pos: SourcePos) -> Value {
+    if *value_type == ValueType::F64 {
+        return trans.ins().fdiv(args[0], args[1]);
+    }
+    // i32::MIN / -1 does not fit in an i32, it wraps around to i32::MIN like a - b does
+    let divisor = trans.checked_divisor(args[1], pos);
+    let quotient = trans.ins().sdiv(args[0], divisor);
+    let negated = trans.ins().ineg(args[0]);
+    let is_minus_one = trans.ins().icmp_imm(IntCC::Equal, args[1], -1);
+    return trans.ins().select(is_minus_one, negated, quotient);
+}
+
+pub fn find_operator(symbol: &str) -> Option<&'static Operator> {
+    return OPERATORS.iter().find(|op| op.symbol == symbol);
+}
+
+// The type of what an operator gives for operands of these types
+pub fn operation_type(op: &Operator, operand_types: &[ValueType], pos: SourcePos) -> Result<ValueType, CompileError> {
+    let value_type = &operand_types[0];
+    let is_number = matches!(value_type, ValueType::I32 | ValueType::F64);
+    if operand_types.iter().any(|other| other != value_type) || !is_number {
+        if let [operand_type] = operand_types {
+            return Err(CompileError::at(pos, format!("cannot use {} on {} at {}", op.symbol, operand_type.to_string(), pos.to_string())));
+        }
+        let names: Vec<String> = operand_types.iter().map(|operand_type| operand_type.to_string()).collect();
+        return Err(CompileError::at(pos, format!("cannot do arithmetic on {}", names.join(" and "))));
+    }
+    if *value_type == ValueType::F64 && matches!(op.operands, Operands::Integers) {
+        return Err(CompileError::at(pos, format!("cannot use {} on f64 at {}", op.symbol, pos.to_string())));
+    }
+    return Ok(value_type.clone());
+}
+
+// wrapping_add, checked_div and so on, every operator that can overflow has all three
+pub fn find_intrinsic(name: &str) -> Option<(ArithmeticMode, &'static Operator)> {
+    let modes = [("wrapping_", ArithmeticMode::Wrapping), ("checked_", ArithmeticMode::Checked), ("saturating_", ArithmeticMode::Saturating)];
+    for (prefix, mode) in modes {
+        if let Some(op_name) = name.strip_prefix(prefix) {
+            let op = OPERATORS.iter().find(|op| op.overflow.as_ref().is_some_and(|overflow| overflow.name == op_name));
+            return op.map(|op| (mode, op));
+        }
+    }
+    return None;
+}
+
+// The index of an operator in OPERATORS, generated code passes it to the runtime error functions
+pub fn operator_index(op: &Operator) -> usize {
+    return OPERATORS.iter().position(|other| std::ptr::eq(other, op)).unwrap();
+}
+
+// The exact result of an operator that can overflow, the error is dividing by zero or shifting
+// by an amount outside 0..32, which has no exact result
+pub fn eval_exact(overflow: &Overflow, args: &[i32]) -> Result<i64, &'static str> {
+    if overflow.divides && args[1] == 0 {
+        return Err(DIVIDE_BY_ZERO);
+    }
+    if overflow.shifts && !(0..32).contains(&args[1]) {
+        return Err(overflow.message);
+    }
+    let args: Vec<i64> = args.iter().map(|arg| *arg as i64).collect();
+    return Ok((overflow.eval_i64)(&args));
+}
@@ -0,0 +1,258 @@
+use crate::jit::*;
+use crate::cst::Cst;
+use std::collections::VecDeque;
+
+const INDENT: &str = "    ";
+
+// A comment, trailing if there is code before it on its line. A /* */ comment can take up
+// more than one line
+struct Comment {
+    line: u32,
+    text: String,
+    trailing: bool
+}
+
+// The doc comment of a declaration, as /// lines
+fn doc_lines(expr: &Expr) -> Vec<String> {
+    let doc = match expr {
+        Expr::Function(Function { doc, .. }) | Expr::StructDecl(StructDecl { doc, .. })
+        | Expr::EnumDecl(EnumDecl { doc, .. }) | Expr::ConstDecl(ConstDecl { doc, .. }) => doc,
+        _ => &None
+    };
+    return doc.iter().flat_map(|doc| doc.lines()).map(|line| format!("/// {}", line).trim_end().to_string()).collect();
+}
+
+// The first line that any part of expr was found on, if its tokens kept their position
+fn first_line(expr: &Expr) -> Option<u32> {
+    let mut lines: Vec<Option<u32>> = vec!();
+    match expr {
+        Expr::Operation(token) => {
+            lines.push(first_line(&token.expr1));
+            lines.push(Some(token.pos.line));
+        }
+        Expr::ArrayLiteral(token) => {
+            lines.extend(token.elements.iter().map(first_line));
+        }
+        Expr::ArrayRepeat(token) => {
+            lines.push(first_line(&token.value));
+        }
+        Expr::Index(token) => {
+            lines.push(first_line(&token.array));
+            lines.push(Some(token.pos.line));
+        }
+        Expr::Field(token) => {
+            lines.push(first_line(&token.expr));
+            lines.push(Some(token.pos.line));
+        }
+        Expr::IndirectCall(token) => {
+            lines.push(first_line(&token.callee));
+            lines.push(Some(token.pos.line));
+        }
+        Expr::StructLiteral(StructLiteral { pos, .. }) | Expr::Call(Call { pos, .. }) | Expr::AddressOf(AddressOf { pos, .. })
+        | Expr::Deref(Deref { pos, .. }) | Expr::Unary(Unary { pos, .. }) | Expr::EnumLiteral(EnumLiteral { pos, .. }) | Expr::Let(Let { pos, .. })
+        | Expr::StructDecl(StructDecl { pos, .. }) | Expr::EnumDecl(EnumDecl { pos, .. }) | Expr::ConstDecl(ConstDecl { pos, .. })
+        | Expr::Function(Function { pos, .. }) | Expr::Match(Match { pos, .. }) | Expr::If(If { pos, .. }) | Expr::While(While { pos, .. }) | Expr::Return(Return { pos, .. })
+        | Expr::Tail(Tail { pos, .. }) | Expr::Closure(Closure { pos, .. }) => {
+            lines.push(Some(pos.line));
+        }
+        Expr::IdentifierToken(..) | Expr::NumberToken(..) | Expr::FloatToken(..) | Expr::Empty => {}
+    }
+    return lines.into_iter().flatten().min();
+}
+
+// A statement as it is written in a block, the value at the end of a block has no ; after it
+fn statement_text(statement: &Expr) -> String {
+    match statement {
+        Expr::Return(Return { is_tail: true, .. }) | Expr::Tail(..) => {
+            return statement.to_string();
+        }
+        _ => {
+            return format!("{};", statement.to_string());
+        }
+    }
+}
+
+// Prints an expression tree back as source, with the comments of the source it came from put
+// back in front of the statements that followed them
+pub struct Printer<'a> {
+    comments: VecDeque<Comment>,
+    source_lines: Vec<&'a str>,
+    out: String,
+    indent: usize,
+    last_line: Option<u32> // The line of the statement that was printed last in the current block
+}
+
+impl<'a> Printer<'a> {
+    fn new(source: &'a str, comments: VecDeque<Comment>) -> Printer<'a> {
+        return Printer { comments, source_lines: source.lines().collect(), out: String::new(), indent: 0, last_line: None };
+    }
+    fn push_line(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.out += &INDENT.repeat(self.indent);
+        }
+        self.out += text;
+        self.out += "\n";
+    }
+    // Keep one empty line where the source had at least one between two statements
+    fn empty_line_before(&mut self, line: u32) {
+        if let Some(last_line) = self.last_line {
+            let has_empty_line = (last_line + 1..line).any(|line| {
+                self.source_lines.get(line as usize - 1).is_some_and(|text| text.trim().is_empty())
+            });
+            if has_empty_line {
+                self.push_line("");
+            }
+        }
+    }
+    // Print the comments that come before line
+    fn leading_comments(&mut self, line: u32) {
+        while self.comments.front().is_some_and(|comment| comment.line < line) {
+            let comment = self.comments.pop_front().unwrap();
+            self.empty_line_before(comment.line);
+            self.push_line(&comment.text);
+            self.last_line = Some(comment.line);
+        }
+    }
+    // The comments at the end of line, if there are any
+    fn trailing_comment(&mut self, line: Option<u32>) -> String {
+        let mut text = String::new();
+        while let Some(line) = line {
+            if !self.comments.front().is_some_and(|comment| comment.line == line && comment.trailing) {
+                break;
+            }
+            text += &format!(" {}", self.comments.pop_front().unwrap().text);
+        }
+        return text;
+    }
+    fn statements(&mut self, statements: &[Expr]) {
+        self.last_line = None;
+        for statement in statements.iter() {
+            let line = first_line(statement);
+            if let Some(line) = line {
+                self.leading_comments(line);
+                self.empty_line_before(line);
+            }
+            self.statement(statement, line);
+            // A block in the statement leaves the line of its last statement behind
+            self.last_line = self.last_line.max(line);
+        }
+    }
+    fn block(&mut self, header: &str, line: Option<u32>, body: &[Expr]) {
+        let comment = self.trailing_comment(line);
+        if body.is_empty() {
+            self.push_line(&format!("{} {{}}{}", header, comment));
+            return;
+        }
+        self.push_line(&format!("{} {{{}", header, comment));
+        self.indent += 1;
+        self.statements(body);
+        self.indent -= 1;
+        self.push_line("}");
+    }
+    fn statement(&mut self, statement: &Expr, line: Option<u32>) {
+        for doc_line in doc_lines(statement) {
+            self.push_line(&doc_line);
+        }
+        match statement {
+            Expr::Function(token) => {
+                let params: Vec<String> = token.params.iter()
+                    .map(|(name, value_type)| format!("{}: {}", name, value_type.to_string()))
+                    .collect();
+                let mut header = format!("fn {}{}({})", token.name, type_params_to_string(&token.type_params), params.join(", "));
+                if token.is_const {
+                    header = format!("const {}", header);
+                }
+                if let Some(return_type) = &token.return_type {
+                    header += &format!(" -> {}", return_type.to_string());
+                }
+                self.block(&header, line, &token.body);
+            }
+            Expr::Match(token) => {
+                let comment = self.trailing_comment(line);
+                self.push_line(&format!("match {} {{{}", token.value.to_string(), comment));
+                self.indent += 1;
+                for arm in token.arms.iter() {
+                    self.leading_comments(arm.pos.line);
+                    self.match_arm(arm);
+                }
+                self.indent -= 1;
+                self.push_line("}");
+            }
+            Expr::If(token) => {
+                self.if_statement(token, "if", line);
+            }
+            Expr::While(token) => {
+                self.block(&format!("while {}", token.condition.to_string()), line, &token.body);
+            }
+            Expr::StructDecl(..) | Expr::EnumDecl(..) => {
+                let comment = self.trailing_comment(line);
+                self.push_line(&format!("{}{}", statement.to_string(), comment));
+            }
+            _ => {
+                let comment = self.trailing_comment(line);
+                self.push_line(&format!("{}{}", statement_text(statement), comment));
+            }
+        }
+    }
+    // if condition { ... } else if condition { ... } else { ... }, each else goes on the line of
+    // the } before it
+    fn if_statement(&mut self, token: &If, keyword: &str, line: Option<u32>) {
+        self.block(&format!("{} {}", keyword, token.condition.to_string()), line, &token.then_body);
+        let else_body = match &token.else_body {
+            Some(else_body) => else_body,
+            None => {
+                return;
+            }
+        };
+        let start = self.out[..self.out.len() - 1].rfind('\n').map_or(0, |end| end + 1);
+        let last_line = self.out.split_off(start);
+        if last_line.trim() != "}" {
+            // An empty then body is printed as {} on the line of the if, the else closes it instead
+            let open = last_line.rfind("{}").unwrap();
+            self.out += &last_line[..open + 1];
+            self.out += &last_line[open + 2..];
+        }
+        match else_body.as_slice() {
+            [Expr::If(else_if)] => {
+                self.if_statement(else_if, "} else if", first_line(&else_if.condition));
+            }
+            _ => {
+                self.block("} else", None, else_body);
+            }
+        }
+    }
+    fn match_arm(&mut self, arm: &MatchArm) {
+        let header = format!("{} =>", arm.pattern.to_string());
+        // An arm with one simple statement and no comments in it fits on one line
+        if let [statement] = arm.body.as_slice() {
+            let line = first_line(statement).unwrap_or(arm.pos.line);
+            let has_comment = self.comments.front().is_some_and(|comment| comment.line < line);
+            if !matches!(statement, Expr::Match(..) | Expr::If(..) | Expr::While(..)) && !has_comment {
+                let comment = self.trailing_comment(Some(arm.pos.line).filter(|arm_line| *arm_line == line));
+                self.push_line(&format!("{} {{ {} }}{}", header, statement_text(statement), comment));
+                return;
+            }
+        }
+        self.block(&header, Some(arm.pos.line), &arm.body);
+    }
+}
+
+// Print a program as source, with the same layout format_source gives
+pub fn print_program(expr_tree: &[Expr]) -> String {
+    let mut printer = Printer::new("", VecDeque::new());
+    printer.statements(expr_tree);
+    return printer.out;
+}
+
+// Rewrite source in the canonical layout, keeping its comments
+pub fn format_source(source: &str) -> Result<String, String> {
+    let cst = Cst::parse(source);
+    let tree = generate_tree(&mut cst.to_token_list()).map_err(|err| err.to_string())?;
+    let comments: VecDeque<Comment> = cst.comments().into_iter()
+        .map(|(trivia, trailing)| Comment { line: trivia.pos.line, text: trivia.text.trim_end().to_string(), trailing })
+        .collect();
+    let mut printer = Printer::new(source, comments);
+    printer.statements(&tree);
+    printer.leading_comments(u32::MAX);
+    return Ok(printer.out);
+}
@@ -0,0 +1,633 @@
+use crate::jit::*;
+use crate::cst::Cst;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+// The symbols of a program and every place they are named, for tools like the language server.
+// The names are found in the tokens, so that every use of a name has a position. The expression
+// tree, when the program parses, and the types the compiler worked out add the details
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Struct,
+    Enum,
+    Variant,
+    Field,
+    Const,
+    Variable,
+    Parameter
+}
+
+#[derive(Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub pos: SourcePos, // Where the name is in the declaration
+    pub end: SourcePos, // Where the declaration ends, just after its last token
+    pub container: Option<String>, // The struct of a field or the enum of a variant
+    pub value_type: Option<ValueType>, // The type of a variable, parameter, field or const, if it is known
+    pub doc: Option<String>
+}
+
+// A place where a symbol is named, the declaration is one of them
+#[derive(Clone)]
+pub struct Reference {
+    pub symbol: usize,
+    pub pos: SourcePos,
+    pub len: usize, // In characters
+    pub is_declaration: bool
+}
+
+pub struct SymbolTable {
+    pub symbols: Vec<Symbol>,
+    pub references: Vec<Reference>,
+    tree: Vec<Expr>
+}
+
+// A token with the text it was made from
+struct Word {
+    text: String,
+    token: Token,
+    pos: SourcePos
+}
+
+impl Word {
+    fn end(&self) -> SourcePos {
+        return SourcePos { line: self.pos.line, col: self.pos.col + self.text.chars().count() as u32 };
+    }
+}
+
+#[derive(PartialEq)]
+enum ContextKind {
+    Block,
+    FunctionBody, // Variables from outside of it cannot be used in it
+    Paren,
+    Bracket,
+    StructDecl,
+    EnumDecl,
+    StructLiteral(String),
+    ArmExpr // The body of a match arm that is one expression, it ends at the next , or }
+}
+
+// An open bracket and the variables that were declared in it
+struct Context {
+    kind: ContextKind,
+    variables: Vec<usize>
+}
+
+// Names that mean something to the language without being declared, they are not symbols
+const BUILTIN_NAMES: [&str; 3] = ["i32", "f64", "_"];
+
+struct Resolver<'a> {
+    words: Vec<Word>,
+    tree: &'a [Expr],
+    variable_types: &'a [VariableType],
+    symbols: Vec<Symbol>,
+    references: Vec<Reference>,
+    declarations: HashMap<usize, usize>, // Word index of a name to the symbol it declares
+    resolved: HashMap<usize, usize>, // Word index of a name to the symbol it names
+    contexts: Vec<Context>,
+    pending: Vec<usize>, // Variables for the next block, like the parameters of a function
+    pending_lets: Vec<(usize, usize)>, // Variables that can be used after the next ;, and the context they are in
+    bindings: HashSet<usize>, // Word indices of match bindings that are already declared
+    function: Option<String>, // The function whose parameters or body are being looked at
+    next_block_is_function: bool
+}
+
+impl<'a> Resolver<'a> {
+    fn is(&self, index: Option<usize>, token: Token) -> bool {
+        return index.and_then(|index| self.words.get(index)).is_some_and(|word| mem::discriminant(&word.token) == mem::discriminant(&token));
+    }
+    fn identifier(&self, index: Option<usize>) -> Option<&str> {
+        match index.and_then(|index| self.words.get(index)) {
+            Some(Word { token: Token::IdentifierToken(token), .. }) => Some(token.text.as_str()),
+            _ => None
+        }
+    }
+    fn keyword(&self, index: Option<usize>) -> Option<&'static str> {
+        match index.and_then(|index| self.words.get(index)) {
+            Some(Word { token: Token::Keyword(keyword), .. }) => Some(*keyword),
+            _ => None
+        }
+    }
+    fn is_assign(&self, index: usize) -> bool {
+        return matches!(self.words.get(index), Some(Word { token: Token::OperatorToken(OperatorToken { op_type: OperatorType::Assign(None) }), .. }));
+    }
+    fn is_open(&self, index: usize) -> bool {
+        return matches!(self.words[index].token, Token::LParen | Token::LBracket | Token::LCurly);
+    }
+    fn is_close(&self, index: usize) -> bool {
+        return matches!(self.words[index].token, Token::RParen | Token::RBracket | Token::RCurly);
+    }
+    // The index of the bracket that closes the one at open, or the last word if it is never closed
+    fn matching_close(&self, open: usize) -> usize {
+        let mut depth = 0;
+        for index in open..self.words.len() {
+            if self.is_open(index) {
+                depth += 1;
+            }
+            else if self.is_close(index) {
+                depth -= 1;
+                if depth == 0 {
+                    return index;
+                }
+            }
+        }
+        return self.words.len() - 1;
+    }
+    fn matching_open(&self, close: usize) -> Option<usize> {
+        let mut depth = 0;
+        for index in (0..=close).rev() {
+            if self.is_close(index) {
+                depth += 1;
+            }
+            else if self.is_open(index) {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+        }
+        return None;
+    }
+    fn find(&self, kind: SymbolKind, name: &str, container: Option<&str>) -> Option<usize> {
+        return self.symbols.iter().position(|symbol| symbol.kind == kind && symbol.name == name && symbol.container.as_deref() == container.or(symbol.container.as_deref()));
+    }
+    fn declare(&mut self, index: usize, kind: SymbolKind, container: Option<String>, end: SourcePos) -> usize {
+        let word = &self.words[index];
+        self.symbols.push(Symbol { name: word.text.clone(), kind, pos: word.pos, end, container, value_type: None, doc: None });
+        let symbol = self.symbols.len() - 1;
+        self.declarations.insert(index, symbol);
+        return symbol;
+    }
+    fn refer(&mut self, index: usize, symbol: usize) {
+        let word = &self.words[index];
+        let is_declaration = self.declarations.get(&index) == Some(&symbol);
+        self.references.push(Reference { symbol, pos: word.pos, len: word.text.chars().count(), is_declaration });
+        self.resolved.insert(index, symbol);
+    }
+
+    // Find the functions, types and consts first, they can be used before they are declared
+    fn declare_globals(&mut self) {
+        for index in 0..self.words.len() {
+            let keyword = match self.keyword(Some(index)) {
+                Some(keyword) if self.identifier(Some(index + 1)).is_some() => keyword,
+                _ => {
+                    continue;
+                }
+            };
+            let name = index + 1;
+            match keyword {
+                "fn" => {
+                    let open = (name..self.words.len()).find(|index| self.is(Some(*index), Token::LCurly));
+                    let end = open.map_or(self.words[name].end(), |open| self.words[self.matching_close(open)].end());
+                    self.declare(name, SymbolKind::Function, None, end);
+                }
+                "const" => {
+                    let semicolon = (name..self.words.len()).find(|index| self.is(Some(*index), Token::EndExpr));
+                    let end = self.words[semicolon.unwrap_or(name)].end();
+                    self.declare(name, SymbolKind::Const, None, end);
+                }
+                "struct" | "enum" if self.is(Some(name + 1), Token::LCurly) => {
+                    let close = self.matching_close(name + 1);
+                    let type_name = self.words[name].text.clone();
+                    let is_struct = keyword == "struct";
+                    let kind = if is_struct { SymbolKind::Struct } else { SymbolKind::Enum };
+                    self.declare(name, kind, None, self.words[close].end());
+                    // The fields and variants are the names right after the { and each , in it
+                    let mut depth = 0;
+                    for member in name + 2..close {
+                        if self.is_open(member) {
+                            depth += 1;
+                        }
+                        else if self.is_close(member) {
+                            depth -= 1;
+                        }
+                        let starts_member = self.is(Some(member - 1), Token::LCurly) || self.is(Some(member - 1), Token::Comma);
+                        if depth != 0 || !starts_member || self.identifier(Some(member)).is_none() {
+                            continue;
+                        }
+                        if is_struct {
+                            self.declare(member, SymbolKind::Field, Some(type_name.clone()), self.words[member].end());
+                        }
+                        else {
+                            self.declare(member, SymbolKind::Variant, Some(type_name.clone()), self.words[member].end());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.add_declaration_details();
+    }
+
+    // Give the declarations their types and doc comments from the expression tree
+    fn add_declaration_details(&mut self) {
+        for expr in self.tree.iter() {
+            match expr {
+                Expr::Function(token) => {
+                    if let Some(symbol) = self.find(SymbolKind::Function, &token.name, None) {
+                        self.symbols[symbol].doc = token.doc.clone();
+                    }
+                }
+                Expr::ConstDecl(token) => {
+                    if let Some(symbol) = self.find(SymbolKind::Const, &token.name, None) {
+                        self.symbols[symbol].doc = token.doc.clone();
+                        self.symbols[symbol].value_type = Some(token.value_type.clone());
+                    }
+                }
+                Expr::StructDecl(token) => {
+                    if let Some(symbol) = self.find(SymbolKind::Struct, &token.name, None) {
+                        self.symbols[symbol].doc = token.doc.clone();
+                    }
+                    for (field, value_type) in token.fields.iter() {
+                        if let Some(symbol) = self.find(SymbolKind::Field, field, Some(&token.name)) {
+                            self.symbols[symbol].value_type = Some(value_type.clone());
+                        }
+                    }
+                }
+                Expr::EnumDecl(token) => {
+                    if let Some(symbol) = self.find(SymbolKind::Enum, &token.name, None) {
+                        self.symbols[symbol].doc = token.doc.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn variable_type(&self, name: &str, pos: SourcePos) -> Option<ValueType> {
+        return self.variable_types.iter().find(|variable| variable.name == name && variable.pos == pos).map(|variable| variable.value_type.clone());
+    }
+    fn parameter_type(&self, name: &str) -> Option<ValueType> {
+        let function = self.function.as_deref()?;
+        return self.tree.iter().find_map(|expr| match expr {
+            Expr::Function(token) if token.name == function => {
+                token.params.iter().find(|(param, _)| param == name).map(|(_, value_type)| value_type.clone())
+            }
+            _ => None
+        });
+    }
+    fn declare_variable(&mut self, index: usize, kind: SymbolKind, value_type: Option<ValueType>) -> usize {
+        let end = self.words[index].end();
+        let symbol = self.declare(index, kind, None, end);
+        self.symbols[symbol].value_type = value_type;
+        self.refer(index, symbol);
+        return symbol;
+    }
+    fn lookup_variable(&self, name: &str) -> Option<usize> {
+        for context in self.contexts.iter().rev() {
+            if let Some(symbol) = context.variables.iter().rev().find(|symbol| self.symbols[**symbol].name == name) {
+                return Some(*symbol);
+            }
+            if context.kind == ContextKind::FunctionBody {
+                return None;
+            }
+        }
+        return None;
+    }
+    fn lookup_global(&self, name: &str) -> Option<usize> {
+        return [SymbolKind::Const, SymbolKind::Struct, SymbolKind::Enum, SymbolKind::Function].iter().find_map(|kind| self.find(*kind, name, None));
+    }
+
+    // The type of the expression that ends just before the word at index, as far as it can be
+    // worked out from the names in it. References are looked through, like field access does
+    fn type_before(&self, index: usize) -> Option<ValueType> {
+        let last = index.checked_sub(1)?;
+        let value_type = match &self.words[last].token {
+            Token::IdentifierToken(..) if self.is(last.checked_sub(1), Token::Dot) => {
+                let struct_name = match self.type_before(last - 1)? {
+                    ValueType::Struct(name) => name,
+                    _ => {
+                        return None;
+                    }
+                };
+                let field = self.find(SymbolKind::Field, &self.words[last].text, Some(&struct_name))?;
+                self.symbols[field].value_type.clone()?
+            }
+            Token::IdentifierToken(..) => {
+                self.symbols[*self.resolved.get(&last)?].value_type.clone()?
+            }
+            Token::RBracket => {
+                match self.type_before(self.matching_open(last)?)? {
+                    ValueType::Array(elem_type, _) => *elem_type,
+                    _ => {
+                        return None;
+                    }
+                }
+            }
+            _ => {
+                return None;
+            }
+        };
+        let mut value_type = value_type;
+        while let ValueType::Ref(inner_type, _) = value_type {
+            value_type = *inner_type;
+        }
+        return Some(value_type);
+    }
+
+    // Declare the bindings of the match pattern whose ( is at open, they can be used in its arm
+    fn declare_bindings(&mut self, open: usize, pattern_pos: SourcePos) {
+        let close = self.matching_close(open);
+        for index in open + 1..close {
+            if let Some(name) = self.identifier(Some(index)) {
+                if name != "_" {
+                    let value_type = self.variable_type(name, pattern_pos);
+                    let symbol = self.declare_variable(index, SymbolKind::Variable, value_type);
+                    self.pending.push(symbol);
+                }
+                self.bindings.insert(index);
+            }
+        }
+    }
+
+    fn resolve_identifier(&mut self, index: usize) {
+        let name = self.words[index].text.clone();
+        let prev = index.checked_sub(1);
+        let next = Some(index + 1);
+        if self.bindings.contains(&index) {
+            return;
+        }
+        if let Some(symbol) = self.declarations.get(&index).copied() {
+            self.refer(index, symbol);
+            if self.symbols[symbol].kind == SymbolKind::Function {
+                self.function = Some(name);
+                self.next_block_is_function = true;
+            }
+            return;
+        }
+        let in_context = |kind: &ContextKind, resolver: &Resolver| resolver.contexts.last().is_some_and(|context| context.kind == *kind);
+        let starts_member = self.is(prev, Token::LCurly) || self.is(prev, Token::Comma);
+
+        if self.keyword(prev) == Some("let") {
+            let value_type = self.variable_type(&name, self.words[index - 1].pos);
+            let symbol = self.declare_variable(index, SymbolKind::Variable, value_type);
+            self.pending_lets.push((symbol, self.contexts.len()));
+            return;
+        }
+        if in_context(&ContextKind::Paren, self) && self.is(next, Token::Colon) && (self.is(prev, Token::LParen) || self.is(prev, Token::Comma)) && self.function.is_some() && self.next_block_is_function {
+            // A parameter of the function that is being declared
+            let value_type = self.parameter_type(&name);
+            let symbol = self.declare_variable(index, SymbolKind::Parameter, value_type);
+            self.pending.push(symbol);
+            return;
+        }
+        if BUILTIN_NAMES.contains(&name.as_str()) {
+            return;
+        }
+        if self.is(prev, Token::PathSep) {
+            let enum_name = self.identifier(index.checked_sub(2)).unwrap_or("").to_string();
+            if let Some(symbol) = self.find(SymbolKind::Variant, &name, Some(&enum_name)) {
+                self.refer(index, symbol);
+            }
+            // The names in the parentheses after a variant in a pattern are bindings
+            if self.is(next, Token::LParen) {
+                let close = self.matching_close(index + 1);
+                if self.is(Some(close + 1), Token::FatArrow) {
+                    let pattern_pos = self.words[index - 2].pos;
+                    self.declare_bindings(index + 1, pattern_pos);
+                }
+            }
+            return;
+        }
+        if self.is(next, Token::PathSep) {
+            if let Some(symbol) = self.find(SymbolKind::Enum, &name, None) {
+                self.refer(index, symbol);
+            }
+            return;
+        }
+        if self.is(prev, Token::Dot) {
+            let field = match self.type_before(index - 1) {
+                Some(ValueType::Struct(struct_name)) => self.find(SymbolKind::Field, &name, Some(&struct_name)),
+                _ => {
+                    // Without a type, a field name that only one struct has is still known
+                    let fields: Vec<usize> = (0..self.symbols.len()).filter(|symbol| self.symbols[*symbol].kind == SymbolKind::Field && self.symbols[*symbol].name == name).collect();
+                    if fields.len() == 1 { Some(fields[0]) } else { None }
+                }
+            };
+            if let Some(symbol) = field {
+                self.refer(index, symbol);
+            }
+            return;
+        }
+        if let Some(Context { kind: ContextKind::StructLiteral(struct_name), .. }) = self.contexts.last() {
+            if starts_member && self.is(next, Token::Colon) {
+                if let Some(symbol) = self.find(SymbolKind::Field, &name, Some(struct_name)) {
+                    self.refer(index, symbol);
+                }
+                return;
+            }
+        }
+        // A variable that holds a function pointer is called like a function
+        if self.is(next, Token::LParen) && self.lookup_variable(&name).is_none() {
+            if let Some(symbol) = self.find(SymbolKind::Function, &name, None) {
+                self.refer(index, symbol);
+            }
+            return;
+        }
+        if let Some(symbol) = self.lookup_variable(&name) {
+            self.refer(index, symbol);
+            return;
+        }
+        if let Some(symbol) = self.lookup_global(&name) {
+            self.refer(index, symbol);
+            return;
+        }
+        // Assigning to a variable that does not exist yet declares it
+        let starts_statement = prev.is_none() || [Token::EndExpr, Token::LCurly, Token::RCurly, Token::FatArrow].iter().any(|token| self.is(prev, token.clone()));
+        if starts_statement && self.is_assign(index + 1) {
+            let value_type = self.variable_type(&name, self.words[index + 1].pos);
+            let symbol = self.declare_variable(index, SymbolKind::Variable, value_type);
+            self.contexts.last_mut().unwrap().variables.push(symbol);
+        }
+    }
+
+    fn open_context(&mut self, index: usize) {
+        let kind = match self.words[index].token {
+            Token::LParen => ContextKind::Paren,
+            Token::LBracket => ContextKind::Bracket,
+            _ if mem::take(&mut self.next_block_is_function) => ContextKind::FunctionBody,
+            _ => {
+                let keyword = self.keyword(index.checked_sub(2));
+                let name = self.identifier(index.checked_sub(1));
+                let names_struct = name.is_some_and(|name| self.find(SymbolKind::Struct, name, None).is_some());
+                if keyword == Some("struct") {
+                    ContextKind::StructDecl
+                }
+                else if keyword == Some("enum") {
+                    ContextKind::EnumDecl
+                }
+                else if names_struct && !matches!(keyword, Some("match" | "if" | "while")) {
+                    ContextKind::StructLiteral(name.unwrap().to_string())
+                }
+                else {
+                    ContextKind::Block
+                }
+            }
+        };
+        let variables = if kind == ContextKind::Paren || kind == ContextKind::Bracket { vec!() } else { mem::take(&mut self.pending) };
+        self.contexts.push(Context { kind, variables });
+    }
+
+    fn close_context(&mut self) {
+        if self.contexts.len() > 1 {
+            self.contexts.pop();
+        }
+        let depth = self.contexts.len();
+        self.pending_lets.retain(|(_, context)| *context <= depth);
+    }
+
+    fn resolve(&mut self) {
+        self.contexts.push(Context { kind: ContextKind::Block, variables: vec!() });
+        for index in 0..self.words.len() {
+            match self.words[index].token {
+                Token::IdentifierToken(..) => {
+                    self.resolve_identifier(index);
+                }
+                Token::LParen | Token::LBracket | Token::LCurly => {
+                    self.open_context(index);
+                }
+                Token::RCurly if self.contexts.last().is_some_and(|context| context.kind == ContextKind::ArmExpr) => {
+                    self.close_context();
+                    self.close_context();
+                }
+                Token::RParen | Token::RBracket | Token::RCurly => {
+                    self.close_context();
+                }
+                Token::Comma if self.contexts.last().is_some_and(|context| context.kind == ContextKind::ArmExpr) => {
+                    self.close_context();
+                }
+                Token::FatArrow if !self.is(Some(index + 1), Token::LCurly) => {
+                    let variables = mem::take(&mut self.pending);
+                    self.contexts.push(Context { kind: ContextKind::ArmExpr, variables });
+                }
+                Token::EndExpr => {
+                    // A variable made by let can be used from the statement after it
+                    let depth = self.contexts.len();
+                    for (symbol, context) in mem::take(&mut self.pending_lets) {
+                        if context == depth {
+                            self.contexts.last_mut().unwrap().variables.push(symbol);
+                        }
+                        else {
+                            self.pending_lets.push((symbol, context));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl SymbolTable {
+    // tree is the expression tree of source if it parses, variable_types are the types the
+    // compiler gave its variables
+    pub fn build(source: &str, tree: Option<Vec<Expr>>, variable_types: &[VariableType]) -> SymbolTable {
+        let cst = Cst::parse(source);
+        let words: Vec<Word> = cst.tokens().into_iter()
+            .filter_map(|token| match &token.token {
+                Some(Token::DocComment(..)) | None => None,
+                Some(kind) => Some(Word { text: token.text.clone(), token: kind.clone(), pos: token.pos })
+            })
+            .collect();
+        let tree = tree.unwrap_or_default();
+        let mut resolver = Resolver {
+            words,
+            tree: &tree,
+            variable_types,
+            symbols: vec!(),
+            references: vec!(),
+            declarations: HashMap::new(),
+            resolved: HashMap::new(),
+            contexts: vec!(),
+            pending: vec!(),
+            pending_lets: vec!(),
+            bindings: HashSet::new(),
+            function: None,
+            next_block_is_function: false
+        };
+        resolver.declare_globals();
+        resolver.resolve();
+        let (symbols, references) = (resolver.symbols, resolver.references);
+        return SymbolTable { symbols, references, tree };
+    }
+    // The name at pos, the position just after a name also counts
+    pub fn reference_at(&self, pos: SourcePos) -> Option<&Reference> {
+        return self.references.iter().find(|reference| {
+            reference.pos.line == pos.line && reference.pos.col <= pos.col && pos.col <= reference.pos.col + reference.len as u32
+        });
+    }
+    pub fn references_to(&self, symbol: usize) -> Vec<&Reference> {
+        return self.references.iter().filter(|reference| reference.symbol == symbol).collect();
+    }
+    // How a symbol is declared, like fn add(a: i32, b: i32) -> i32
+    pub fn describe(&self, symbol: usize) -> String {
+        let symbol = &self.symbols[symbol];
+        let typed = |name: &str| match &symbol.value_type {
+            Some(value_type) => format!("{}: {}", name, value_type.to_string()),
+            None => name.to_string()
+        };
+        match symbol.kind {
+            SymbolKind::Function => {
+                for expr in self.tree.iter() {
+                    if let Expr::Function(token) = expr {
+                        if token.name == symbol.name {
+                            let params: Vec<String> = token.params.iter().map(|(name, value_type)| format!("{}: {}", name, value_type.to_string())).collect();
+                            let mut text = format!("fn {}({})", token.name, params.join(", "));
+                            if token.is_const {
+                                text = format!("const {}", text);
+                            }
+                            if let Some(return_type) = &token.return_type {
+                                text += &format!(" -> {}", return_type.to_string());
+                            }
+                            return text;
+                        }
+                    }
+                }
+                return format!("fn {}", symbol.name);
+            }
+            SymbolKind::Struct | SymbolKind::Enum => {
+                for expr in self.tree.iter() {
+                    match expr {
+                        Expr::StructDecl(StructDecl { name, .. }) | Expr::EnumDecl(EnumDecl { name, .. }) if *name == symbol.name => {
+                            return expr.to_string();
+                        }
+                        _ => {}
+                    }
+                }
+                let keyword = if symbol.kind == SymbolKind::Struct { "struct" } else { "enum" };
+                return format!("{} {}", keyword, symbol.name);
+            }
+            SymbolKind::Variant => {
+                let enum_name = symbol.container.clone().unwrap_or_default();
+                for expr in self.tree.iter() {
+                    if let Expr::EnumDecl(token) = expr {
+                        let payload = token.variants.iter().find(|(variant, _)| *variant == symbol.name).map(|(_, payload)| payload);
+                        match payload {
+                            Some(payload) if token.name == enum_name && !payload.is_empty() => {
+                                let payload: Vec<String> = payload.iter().map(|value_type| value_type.to_string()).collect();
+                                return format!("{}::{}({})", enum_name, symbol.name, payload.join(", "));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                return format!("{}::{}", enum_name, symbol.name);
+            }
+            SymbolKind::Field => {
+                return typed(&format!("{}.{}", symbol.container.clone().unwrap_or_default(), symbol.name));
+            }
+            SymbolKind::Const => {
+                return format!("const {}", typed(&symbol.name));
+            }
+            SymbolKind::Variable => {
+                return format!("let {}", typed(&symbol.name));
+            }
+            SymbolKind::Parameter => {
+                return typed(&symbol.name);
+            }
+        }
+    }
+}
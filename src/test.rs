@@ -0,0 +1,645 @@
+// Runs every .vm program under tests/ and checks it against the annotations in the file:
+//     // output: 30      a line the program prints, in order
+//     // expect: 42      the value the program returns
+//     // error: ...      a compile or runtime error the program stops with
+// Run with BLESS=1 to write what the programs do now into their annotations instead
+use crate::jit::*;
+use crate::interpreter::{ProgramOutcome, CALL_DEPTH_LIMIT, run_differential};
+use crate::printer::format_source;
+use crate::cst::Cst;
+use crate::json::{Json, object};
+use crate::lsp::{Server, run_server};
+use crate::engine::{Engine, Callable};
+use crate::fuzz::{FuzzTarget, fuzz, generate_input, run_target};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const OUTPUT: &str = "// output:";
+const EXPECT: &str = "// expect:";
+const ERROR: &str = "// error:";
+
+fn is_annotation(line: &str) -> bool {
+    let line = line.trim_start();
+    return line.starts_with(OUTPUT) || line.starts_with(EXPECT) || line.starts_with(ERROR);
+}
+
+// Find every .vm file in dir and the directories in it
+fn find_programs(dir: &Path, programs: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_programs(&path, programs);
+        }
+        else if path.extension().is_some_and(|extension| extension == "vm") {
+            programs.push(path);
+        }
+    }
+}
+
+// The annotations that a program should have, given what it did
+fn annotations(outcome: &Result<ProgramOutcome, String>) -> Vec<String> {
+    let mut lines: Vec<String> = vec!();
+    match outcome {
+        Ok(outcome) => {
+            for line in outcome.output.lines() {
+                lines.push(format!("{} {}", OUTPUT, line));
+            }
+            match &outcome.result {
+                Ok(value) => lines.push(format!("{} {}", EXPECT, value)),
+                Err(err) => lines.push(format!("{} {}", ERROR, err))
+            }
+        }
+        Err(err) => {
+            lines.push(format!("{} {}", ERROR, err));
+        }
+    }
+    return lines;
+}
+
+// The annotations are comments, so the program can be run as it is
+fn run_program(source: &str) -> Result<ProgramOutcome, String> {
+    return compile_code(source.to_string()).map_err(|err| err.to_string());
+}
+
+// Check a program against its annotations, returns what is wrong if it does not match them
+fn check_program(source: &str) -> Result<(), String> {
+    let expected: Vec<String> = source.lines().filter(|line| is_annotation(line)).map(|line| line.trim().to_string()).collect();
+    if expected.iter().filter(|line| !line.starts_with(OUTPUT)).count() != 1 {
+        return Err("expected exactly one // expect: or // error: annotation".to_string());
+    }
+    let actual = annotations(&run_program(source));
+    let matches = expected.len() == actual.len() && expected.iter().zip(actual.iter()).all(|(expected, actual)| {
+        // An error only has to contain the expected text, so tests can leave out the details
+        match expected.strip_prefix(ERROR) {
+            Some(message) => actual.starts_with(ERROR) && actual.contains(message.trim()),
+            None => expected == actual
+        }
+    });
+    if !matches {
+        return Err(format!("expected:\n{}\nfound:\n{}", expected.join("\n"), actual.join("\n")));
+    }
+    return Ok(());
+}
+
+// Replace the annotations of a program with what it does now, they are put at the top
+fn bless_program(source: &str) -> String {
+    let code: Vec<&str> = source.lines().filter(|line| !is_annotation(line)).collect();
+    let code = code.join("\n");
+
+    // Errors have positions in them, so run the program again once the annotations it gets are in place
+    let count = annotations(&run_program(&code)).len();
+    let placeholder = format!("{}\n", OUTPUT).repeat(count);
+    let lines = annotations(&run_program(&format!("{}{}", placeholder, code)));
+    return format!("{}\n{}\n", lines.join("\n"), code);
+}
+
+#[test]
+fn golden_files() {
+    set_debug_output(false);
+    let bless = std::env::var("BLESS").is_ok();
+    let mut programs: Vec<PathBuf> = vec!();
+    find_programs(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"), &mut programs);
+    programs.sort();
+    assert!(!programs.is_empty(), "no .vm files found under tests/");
+
+    let mut failures: Vec<String> = vec!();
+    for path in programs.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        if bless {
+            fs::write(path, bless_program(&source)).unwrap();
+        }
+        else if let Err(err) = check_program(&source) {
+            failures.push(format!("{}: {}", path.display(), err));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("{} of {} programs failed, run with BLESS=1 to update them\n\n{}", failures.len(), programs.len(), failures.join("\n\n"));
+    }
+}
+
+// Formatting a program must not change what it does, and formatting it again must not change it
+#[test]
+fn formatted_golden_files() {
+    set_debug_output(false);
+    let mut programs: Vec<PathBuf> = vec!();
+    find_programs(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"), &mut programs);
+    programs.sort();
+
+    let mut failures: Vec<String> = vec!();
+    for path in programs.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        // Formatting moves code around, so the positions in expected errors would no longer match
+        if !source.lines().any(|line| line.trim_start().starts_with(EXPECT)) {
+            continue;
+        }
+        let formatted = format_source(&source).unwrap();
+        if format_source(&formatted).unwrap() != formatted {
+            failures.push(format!("{}: formatting the formatted program changed it:\n{}", path.display(), formatted));
+        }
+        else if let Err(err) = check_program(&formatted) {
+            failures.push(format!("{}: {}\n-- formatted:\n{}", path.display(), err, formatted));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("{} programs changed when formatted\n\n{}", failures.len(), failures.join("\n\n"));
+    }
+}
+
+// The interpreter has to agree with the JIT on every program that runs to its end
+#[test]
+fn differential_golden_files() {
+    set_debug_output(false);
+    let mut programs: Vec<PathBuf> = vec!();
+    find_programs(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"), &mut programs);
+    programs.sort();
+
+    let mut failures: Vec<String> = vec!();
+    for path in programs.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        if !source.lines().any(|line| line.trim_start().starts_with(EXPECT)) {
+            continue;
+        }
+        if let Err(err) = run_differential(source) {
+            failures.push(format!("{}: {}", path.display(), err));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("{} programs ran differently in the interpreter\n\n{}", failures.len(), failures.join("\n\n"));
+    }
+}
+
+// The concrete syntax tree has to give back the source it was made from, exactly
+#[test]
+fn cst_round_trip() {
+    let mut programs: Vec<PathBuf> = vec!();
+    find_programs(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"), &mut programs);
+    for path in programs.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        assert!(Cst::parse(&source).to_string() == source, "{} changed when printed from its concrete syntax tree", path.display());
+    }
+}
+
+// Drive the language server through a session like an editor would
+#[test]
+fn language_server() {
+    set_debug_output(false);
+    let source = "struct point { x: i32, y: f64 }\nfn get_x(p: point) -> i32 {\n    p.x\n}\nlet p = point { x: 4, y: 2.5 };\nlet x = get_x(p);\nreturn x + p.x;\n";
+    let mut server = Server::new();
+    let request = |id: f64, method: &str, params: Json| object(vec!(("id", Json::Number(id)), ("method", Json::String(method.to_string())), ("params", params)));
+    let document = |uri: &str| object(vec!(("uri", Json::String(uri.to_string()))));
+    let at = |line: f64, character: f64| object(vec!(
+        ("textDocument", document("file:///a.vm")),
+        ("position", object(vec!(("line", Json::Number(line)), ("character", Json::Number(character)))))
+    ));
+    let open = |text: &str| object(vec!(
+        ("method", Json::String("textDocument/didOpen".to_string())),
+        ("params", object(vec!(("textDocument", object(vec!(("uri", Json::String("file:///a.vm".to_string())), ("text", Json::String(text.to_string()))))))))
+    ));
+
+    let diagnostics = server.handle(&open(source)).remove(0).to_string();
+    assert!(diagnostics.contains("\"diagnostics\":[]"), "{}", diagnostics);
+
+    // The type of x is worked out by the compiler
+    let hover = server.handle(&request(1.0, "textDocument/hover", at(5.0, 4.0))).remove(0).to_string();
+    assert!(hover.contains("let x: i32"), "{}", hover);
+    let hover = server.handle(&request(2.0, "textDocument/hover", at(1.0, 3.0))).remove(0).to_string();
+    assert!(hover.contains("fn get_x(p: point) -> i32"), "{}", hover);
+
+    // p.x in get_x is the field x of point
+    let definition = server.handle(&request(3.0, "textDocument/definition", at(2.0, 6.0))).remove(0).to_string();
+    assert!(definition.contains("\"start\":{\"line\":0,\"character\":15}"), "{}", definition);
+
+    // The p in get_x is its parameter, not the p of the program
+    let references = server.handle(&request(4.0, "textDocument/references", at(4.0, 4.0))).remove(0);
+    let locations = match references.get("result") {
+        Some(Json::Array(locations)) => locations.len(),
+        _ => 0
+    };
+    assert_eq!(locations, 3, "{}", references.to_string());
+
+    let symbols = server.handle(&request(5.0, "textDocument/documentSymbol", object(vec!(("textDocument", document("file:///a.vm")))))).remove(0).to_string();
+    assert!(symbols.contains("\"name\":\"point\"") && symbols.contains("\"name\":\"get_x\""), "{}", symbols);
+
+    let diagnostics = server.handle(&open("let a = [1, 2;\n")).remove(0).to_string();
+    assert!(diagnostics.contains("expected ',' or ']' at line 1, col 14") && diagnostics.contains("\"character\":13"), "{}", diagnostics);
+
+    // The position comes with the error, the message can say "line" anywhere in it
+    let diagnostics = server.handle(&open("struct p { x: i32 }\nlet v = p { x: 1 };\nreturn v.line;\n")).remove(0).to_string();
+    assert!(diagnostics.contains("\"start\":{\"line\":2,\"character\":7}"), "{}", diagnostics);
+
+    // An error without a position is shown on its own, not at the start of the file
+    let messages: Vec<String> = server.handle(&open("let a = [];\n")).iter().map(|message| message.to_string()).collect();
+    assert!(messages[0].contains("\"diagnostics\":[]") && messages[1].contains("cannot infer the type of an empty array"), "{:?}", messages);
+}
+
+// A message that is not JSON gets a parse error back, and the messages after it are still served
+#[test]
+fn language_server_parse_error() {
+    let message = |body: &str| format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let input = message("{\"id\": 1, \"method\":") + &message("{\"id\": 2, \"method\": \"shutdown\"}") + &message("{\"method\": \"exit\"}");
+    let mut output: Vec<u8> = vec!();
+    run_server(input.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("\"code\":-32700") && output.contains("\"id\":2,\"result\":null"), "{}", output);
+}
+
+// Only the functions that changed are compiled again, the rest keep their machine code
+#[test]
+fn incremental_recompilation() {
+    set_debug_output(false);
+    let mut jit = JIT::default();
+    let program = |double: &str, triple: &str| format!(
+        "fn double(x: i32) -> i32 {{\n    {}\n}}\nfn triple(x: i32) -> i32 {{\n    {}\n}}\nreturn double(5) + triple(1);\n",
+        double, triple
+    );
+    let run = |jit: &mut JIT, source: String| recompile_code(jit, source).unwrap().result.unwrap();
+
+    assert_eq!(run(&mut jit, program("x * 2", "x * 3")), 13);
+    assert_eq!(jit.recompiled.len(), 3);
+
+    // The program calls double through the indirection table, so it sees the new version
+    assert_eq!(run(&mut jit, program("x + x + 1", "x * 3")), 14);
+    assert_eq!(jit.recompiled, vec!("double".to_string()));
+
+    assert_eq!(run(&mut jit, program("x + x + 1", "x * 3")), 14);
+    assert!(jit.recompiled.is_empty());
+
+    // A program with errors leaves the jit running the program it had
+    assert!(recompile_code(&mut jit, program("x + x + 1", "x * y")).is_err());
+    assert_eq!(run(&mut jit, program("x + x + 1", "x * 3")), 14);
+    assert!(jit.recompiled.is_empty());
+
+    // The callers of a function have to be compiled again when its signature changes
+    let source = program("x + x + 1", "x * 3").replace("fn double(x: i32) -> i32", "fn double(x: i32, y: i32) -> i32").replace("double(5)", "double(5, 0)");
+    assert_eq!(run(&mut jit, source), 14);
+    let mut recompiled = jit.recompiled.clone();
+    recompiled.sort();
+    assert_eq!(recompiled, vec!("<program>".to_string(), "double".to_string()));
+
+    // Moving a function moves the positions in its runtime errors, so it is compiled again
+    let source = "fn d(x: i32) -> i32 {\n    100 / x\n}\nreturn d(0);\n";
+    let error = recompile_code(&mut jit, source.to_string()).unwrap().result.unwrap_err();
+    assert!(error.contains("at line 2, col 9"), "{}", error);
+    let error = recompile_code(&mut jit, format!("\n\n{}", source)).unwrap().result.unwrap_err();
+    assert!(error.contains("at line 4, col 9"), "{}", error);
+    assert!(jit.recompiled.contains(&"d".to_string()));
+}
+
+// A function pointer comes from the indirection table, so it points to the newest version of
+// the function even when the code that takes it is not compiled again
+#[test]
+fn function_pointers_after_recompilation() {
+    set_debug_output(false);
+    let mut jit = JIT::default();
+    let program = |double: &str| format!(
+        "fn double(x: i32) -> i32 {{\n    {}\n}}\nfn get() -> fn(i32) -> i32 {{\n    double\n}}\nlet f = get();\nreturn f(5);\n",
+        double
+    );
+    assert_eq!(recompile_code(&mut jit, program("x * 2")).unwrap().result, Ok(10));
+    assert_eq!(recompile_code(&mut jit, program("x * 3")).unwrap().result, Ok(15));
+    assert_eq!(jit.recompiled, vec!("double".to_string()));
+}
+
+// Instances of generic functions are named after their type arguments, so they are only
+// compiled again when the generic they come from changes
+#[test]
+fn generic_recompilation() {
+    set_debug_output(false);
+    let mut jit = JIT::default();
+    let program = |twice: &str| format!(
+        "fn twice<T>(x: T) -> T {{\n    {}\n}}\nfn scale(x: f64) -> f64 {{\n    twice(x)\n}}\nprint(scale(1.5));\nreturn twice(5);\n",
+        twice
+    );
+    let outcome = recompile_code(&mut jit, program("x + x")).unwrap();
+    assert_eq!((outcome.result, outcome.output), (Ok(10), "3\n".to_string()));
+    let mut recompiled = jit.recompiled.clone();
+    recompiled.sort();
+    assert_eq!(recompiled, vec!("<program>".to_string(), "scale".to_string(), "twice<f64>".to_string(), "twice<i32>".to_string()));
+    assert!(jit.get_function("twice<i32>").is_some());
+
+    assert_eq!(recompile_code(&mut jit, program("x + x + x")).unwrap().result, Ok(15));
+    let mut recompiled = jit.recompiled.clone();
+    recompiled.sort();
+    assert_eq!(recompiled, vec!("twice<f64>".to_string(), "twice<i32>".to_string()));
+
+    assert_eq!(recompile_code(&mut jit, program("x + x + x")).unwrap().result, Ok(15));
+    assert!(jit.recompiled.is_empty());
+}
+
+extern "C" fn square(x: f64) -> f64 {
+    return x * x;
+}
+
+// Compile a script and call into it from Rust, without the compiler printing anything
+#[test]
+fn engine() {
+    let mut engine = Engine::new();
+    engine.register_fn("square", square as extern "C" fn(f64) -> f64).unwrap();
+    assert_eq!(engine.register_fn("print", square as extern "C" fn(f64) -> f64), Err("function print is built in and cannot be registered".to_string()));
+    assert_eq!(engine.register_fn("wrapping_add", square as extern "C" fn(f64) -> f64), Err("function wrapping_add is built in and cannot be registered".to_string()));
+    let source = "fn area(r: f64) -> f64 {\n    3.0 * square(r)\n}\nfn add(a: i32, b: i32) -> i32 {\n    print(a);\n    a + b\n}\nfn at(i: i32) -> i32 {\n    let a = [1, 2, 3];\n    a[i]\n}\nreturn add(1, 2);\n";
+    let mut program = engine.compile(source).unwrap();
+    assert_eq!(program.call::<_, f64>("area", (2.0,)), Ok(12.0));
+    assert_eq!(program.call::<_, i32>("add", (4, 5)), Ok(9));
+    assert_eq!(program.run(), Ok(3));
+    assert_eq!(program.take_output(), "4\n1\n");
+
+    assert!(program.call::<_, i32>("add", (4.0, 5)).is_err());
+    assert!(program.call::<_, i32>("missing", ()).is_err());
+    assert_eq!(program.call::<_, i32>("at", (5,)), Err("runtime error: index out of bounds: the len is 3 but the index is 5 at line 10, col 5".to_string()));
+
+    let diagnostics = engine.compile("let a = [1, 2;\n").err().unwrap();
+    assert_eq!(diagnostics.errors[0].pos, Some(SourcePos { line: 1, col: 14 }));
+    assert!(Engine::new().compile(source).is_err());
+}
+
+extern "C" fn apply_twice(f: Callable<(i32,), i32>, x: i32) -> i32 {
+    return unsafe { f.call((f.call((x,)),)) };
+}
+
+// Host functions can be given functions and closures, a closure brings the variables it captured
+#[test]
+fn engine_callables() {
+    let mut engine = Engine::new();
+    engine.register_fn("apply_twice", apply_twice as extern "C" fn(Callable<(i32,), i32>, i32) -> i32).unwrap();
+    let source = "fn double(x: i32) -> i32 {\n    x * 2\n}\nfn with_double(x: i32) -> i32 {\n    apply_twice(double, x)\n}\nfn with_offset(x: i32, offset: i32) -> i32 {\n    apply_twice(|y| y + offset, x)\n}\nfn with_error(x: i32) -> i32 {\n    apply_twice(|y| 10 / y, x)\n}\nreturn 0;\n";
+    let mut program = engine.compile(source).unwrap();
+    assert_eq!(program.call::<_, i32>("with_double", (3,)), Ok(12));
+    assert_eq!(program.call::<_, i32>("with_offset", (3, 5)), Ok(13));
+    assert_eq!(program.call::<_, i32>("with_error", (20,)), Err("runtime error: attempt to divide by zero at line 11, col 24".to_string()));
+}
+
+// With overflow checks on, arithmetic that does not fit is a runtime error in generated code and
+// a compile error in const evaluation, the intrinsics still do what their name says
+#[test]
+fn overflow_checks() {
+    let mut engine = Engine::new();
+    engine.set_overflow_checks(true);
+    let source = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\nfn scale(a: i32) -> i32 {\n    let b = a;\n    b *= 65536;\n    b\n}\nfn wrap(a: i32) -> i32 {\n    wrapping_add(a, 1)\n}\nfn shift(a: i32, b: i32) -> i32 {\n    a << b\n}\nreturn 0;\n";
+    let mut program = engine.compile(source).unwrap();
+    assert_eq!(program.call::<_, i32>("add", (2, 3)), Ok(5));
+    assert_eq!(program.call::<_, i32>("add", (i32::MAX, 1)), Err("runtime error: attempt to add with overflow at line 2, col 7".to_string()));
+    assert_eq!(program.call::<_, i32>("scale", (32768,)), Err("runtime error: attempt to multiply with overflow at line 6, col 7".to_string()));
+    assert_eq!(program.call::<_, i32>("wrap", (i32::MAX,)), Ok(i32::MIN));
+    // Shifting bits out is fine, shifting by an amount outside 0..32 is not
+    assert_eq!(program.call::<_, i32>("shift", (3, 31)), Ok(i32::MIN));
+    assert_eq!(program.call::<_, i32>("shift", (1, 32)), Err("runtime error: attempt to shift left with overflow at line 13, col 7".to_string()));
+    assert_eq!(program.call::<_, i32>("shift", (1, -1)), Err("runtime error: attempt to shift left with overflow at line 13, col 7".to_string()));
+
+    let diagnostics = engine.compile("const big: i32 = 0 - 2147483647 - 2;\n").err().unwrap();
+    assert_eq!(diagnostics.to_string(), "runtime error: attempt to subtract with overflow at line 1, col 33");
+
+    // Without them the same program wraps around, and turning them on compiles everything again
+    set_debug_output(false);
+    let mut jit = JIT::default();
+    assert_eq!(recompile_code(&mut jit, format!("{}add(2147483647, 1);", source.replace("return 0;", "return "))).unwrap().result, Ok(i32::MIN));
+    jit.set_overflow_checks(true);
+    let outcome = recompile_code(&mut jit, format!("{}add(2147483647, 1);", source.replace("return 0;", "return "))).unwrap();
+    assert!(outcome.result.is_err());
+    assert_eq!(jit.recompiled.len(), 5);
+}
+
+// The differential fuzzer has to reach recursion and arrays that do not fit in a page, and the
+// JIT and the interpreter have to agree on what it makes
+#[test]
+fn differential_fuzz() {
+    let programs: Vec<String> = (0..50).map(|seed| generate_input(FuzzTarget::Differential, seed)).collect();
+    assert!(programs.iter().any(|program| program.contains(" - 1, ") || program.contains(" - 1)")), "no generated program recurses");
+    let array_lengths = |program: &String| -> Vec<usize> {
+        return program.split("; ").skip(1).filter_map(|rest| rest.split(']').next().unwrap().parse().ok()).collect();
+    };
+    assert!(programs.iter().any(|program| array_lengths(program).iter().any(|len| *len >= 1000)), "no generated program has a large array");
+}
+
+// What a program returned, or the error that stopped it
+fn run(source: &str) -> Result<i32, String> {
+    return run_program(source)?.result;
+}
+
+#[test]
+fn arrays() {
+    assert_eq!(run("let a: [i32; 3] = [1, 2, 3]; a[1] = a[0] + a[2]; return a[1] * 10 + a[2];"), Ok(43));
+    assert_eq!(run("let grid = [[0; 4]; 3]; grid[2][3] = 7; let row = grid[2]; return row[3] + grid[0][3];"), Ok(7));
+    assert_eq!(run("let big = [1; 100]; let i = 99; return big[i] + big[0];"), Ok(2));
+}
+
+#[test]
+fn array_bounds() {
+    let err = run("let a = [1, 2, 3]; let i = 3; return a[i];").unwrap_err();
+    assert!(err.contains("index out of bounds"), "{}", err);
+    let err = run("let a = [1, 2, 3]; let i = 0 - 1; a[i] = 5;").unwrap_err();
+    assert!(err.contains("index out of bounds"), "{}", err);
+    let err = run("let a = [1, 2]; let x = 1; return x[0];").unwrap_err();
+    assert!(err.contains("cannot index"), "{}", err);
+}
+
+#[test]
+fn structs() {
+    let source = "
+        struct point { x: i32, y: f64 }
+        struct line { a: point, b: point, tag: i32 }
+        fn make_point(x: i32, y: f64) -> point { return point { x: x, y: y }; }
+        fn swap(l: line) -> line { let tmp = l.a; l.a = l.b; l.b = tmp; return l; }
+        let l = line { a: make_point(1, 0.5), b: make_point(20, 2.5), tag: 7 };
+        let s = swap(l);
+        l.a.x += 100;
+        let pts = [make_point(3, 1.0), make_point(4, 2.0)];
+        pts[1].x = 40;
+        return l.a.x * 1000 + s.a.x * 10 + pts[1].x + s.tag;
+    ";
+    // swap changes its own copy of l, not the one it was given
+    assert_eq!(run(source), Ok(101247));
+}
+
+#[test]
+fn struct_errors() {
+    let err = run("struct p { x: i32 } let q = p { x: 1 }; return q.z;").unwrap_err();
+    assert!(err.contains("struct p has no field z"), "{}", err);
+    let err = run("struct p { x: i32, y: i32 } let q = p { x: 1 };").unwrap_err();
+    assert!(err.contains("missing field y"), "{}", err);
+}
+
+#[test]
+fn references() {
+    let source = "
+        struct point { x: i32, y: i32 }
+        fn bump(p: &mut i32) { *p += 5; }
+        fn move_point(p: &mut point) { p.x = p.x + 1; p.y += 2; }
+        fn sum(p: &point) -> i32 { return p.x + p.y; }
+        let a = 10;
+        bump(&mut a);
+        let pt = point { x: 1, y: 2 };
+        move_point(&mut pt);
+        let h = alloc(point { x: 100, y: 200 });
+        h.x += 1;
+        let arr = alloc([1, 2, 3]);
+        arr[1] = 40;
+        let total = arr[0] + arr[1] + arr[2];
+        free(arr);
+        free(h);
+        return a + sum(&pt) + total;
+    ";
+    assert_eq!(run(source), Ok(65));
+}
+
+#[test]
+fn reference_errors() {
+    let err = run("let h = alloc(5); free(h); free(h);").unwrap_err();
+    assert!(err.contains("already freed"), "{}", err);
+    let err = run("let x = 1; let r = &x; *r = 2;").unwrap_err();
+    assert!(err.contains("cannot assign to *r, it is behind a & reference"), "{}", err);
+}
+
+#[test]
+fn enums() {
+    let source = "
+        enum op { add(i32), mul(i32), neg, set(i32), keep }
+        fn apply(o: op, acc: i32) -> i32 {
+            match o {
+                op::keep => { return acc; }
+                op::set(v) => { return v; }
+                op::neg => { return 0 - acc; }
+                op::mul(v) => { return acc * v; }
+                op::add(v) => { return acc + v; }
+            }
+        }
+        let program = [op::set(3), op::add(4), op::mul(5), op::neg, op::keep, op::add(100)];
+        let acc = 0;
+        acc = apply(program[0], acc);
+        acc = apply(program[1], acc);
+        acc = apply(program[2], acc);
+        acc = apply(program[3], acc);
+        acc = apply(program[4], acc);
+        acc = apply(program[5], acc);
+        return acc;
+    ";
+    // The arms are in a different order from the variants, the jump table goes by the tag
+    assert_eq!(run(source), Ok(65));
+}
+
+#[test]
+fn enum_errors() {
+    let err = run("enum shape { circle(f64), rect(i32, i32), empty } let s = shape::empty; match s { shape::circle(r) => { return 1; } }").unwrap_err();
+    assert!(err.contains("non-exhaustive match at line 1, col 73: shape::rect, shape::empty not covered"), "{}", err);
+    let err = run("enum shape { circle(f64), empty } let s = shape::empty; match s { shape::circle(a, b) => { return 1; } _ => { return 2; } }").unwrap_err();
+    assert!(err.contains("has 2 values but the variant has 1"), "{}", err);
+}
+
+#[test]
+fn consts() {
+    let source = "
+        struct point { x: i32, y: i32 }
+        const fn square(x: i32) -> i32 { return x * x; }
+        const fn make(x: i32) -> point { return point { x: x, y: square(x) }; }
+        const base: i32 = 10;
+        const table: [i32; 4] = [1, 2, base, square(3)];
+        const origin: point = make(base);
+        fn scale(v: i32) -> i32 { return v * base; }
+        let runtime = 7;
+        return table[3] + origin.y + scale(2) + square(runtime);
+    ";
+    assert_eq!(run(source), Ok(178));
+}
+
+#[test]
+fn const_errors() {
+    let err = run("fn runtime_only(x: i32) -> i32 { return x; } const x: i32 = runtime_only(1);").unwrap_err();
+    assert!(err.contains("cannot call runtime_only in const evaluation, it is not a const fn"), "{}", err);
+    let err = run("const fn forever(x: i32) -> i32 { return forever(x + 1); } const big: i32 = forever(1);").unwrap_err();
+    assert!(err.contains("the recursion might never end"), "{}", err);
+    let err = run("const x: i32 = 1 / 0;").unwrap_err();
+    assert!(err.contains("divide by zero"), "{}", err);
+}
+
+#[test]
+fn interpreter_agrees_with_jit() {
+    let source = "
+        struct point { x: i32, y: f64 }
+        enum shape { circle(f64), rect(i32, i32) }
+        fn area(s: &shape) -> i32 {
+            match s {
+                shape::circle(r) => { return 3; }
+                shape::rect(w, h) => { return w * h; }
+            }
+        }
+        let p = alloc(point { x: 2, y: 0.5 });
+        p.x += 40;
+        print(p.y * 3.0);
+        let shapes = [shape::rect(2, 3), shape::circle(1.0)];
+        print(area(&shapes[0]) + area(&shapes[1]));
+        return p.x;
+        free(p);
+    ";
+    let outcome = run_differential(source.to_string()).unwrap();
+    assert_eq!(outcome.output, "1.5\n9\n");
+    assert_eq!(outcome.result, Ok(42));
+
+    // Runtime errors are part of the outcome, and they have to match too
+    let outcome = run_differential("let a = [1, 2]; let i = 2; print(a[0]); return a[i];".to_string()).unwrap();
+    assert_eq!(outcome.output, "1\n");
+    assert!(outcome.result.unwrap_err().contains("index out of bounds"));
+
+    // The interpreter gives up on recursion deeper than the JIT can go, so that is not a disagreement
+    let source = format!("fn down(n: i32) -> i32 {{\n    if n {{\n        return down(n - 1) + 1;\n    }}\n    0\n}}\nprint(1);\nreturn down({});", CALL_DEPTH_LIMIT + 10);
+    let outcome = run_differential(source).unwrap();
+    assert_eq!(outcome.output, "1\n");
+    assert_eq!(outcome.result, Ok(CALL_DEPTH_LIMIT as i32 + 10));
+}
+
+// Generated programs have to compile and give the same outcome with the JIT and the interpreter,
+// and no input, however broken, may make the compiler panic
+#[test]
+fn fuzz_targets() {
+    let program = generate_input(FuzzTarget::Differential, 1);
+    assert!(run_target(FuzzTarget::Differential, &program).is_ok(), "{}", program);
+    let failures: Vec<String> = fuzz(50, 0).iter().map(|failure| failure.to_string()).collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+static MOST_ENVS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+extern "C" fn note_envs() {
+    MOST_ENVS.fetch_max(closure_env_count(), std::sync::atomic::Ordering::Relaxed);
+}
+
+// The envs of closures that outlive the function that made them are freed when the call from
+// Rust returns, the others are never on the heap
+#[test]
+fn closure_envs() {
+    let source = "fn adder(n: i32) -> fn(i32) -> i32 {\n    |x| x + n\n}\nfn sum(n: i32) -> i32 {\n    let total = 0;\n    while n {\n        total += adder(n)(1);\n        n -= 1;\n    }\n    total\n}\nfn local(n: i32) -> i32 {\n    let f = |x: i32| x + n;\n    f(1)\n}\nreturn sum(10);\n";
+    let mut program = Engine::new().compile(source).unwrap();
+    assert_eq!(program.call::<_, i32>("sum", (1000,)), Ok(501500));
+    assert_eq!(closure_env_count(), 0);
+    assert_eq!(program.run(), Ok(65));
+    assert_eq!(closure_env_count(), 0);
+    assert_eq!(program.call::<_, i32>("local", (1,)), Ok(2));
+
+    // They pile up while a long loop runs, and are all freed once it returns
+    let source = source.replace("total += adder(n)(1);", "total += adder(n)(1);\n        note_envs();");
+    let mut engine = Engine::new();
+    engine.register_fn("note_envs", note_envs as extern "C" fn()).unwrap();
+    let mut program = engine.compile(&source).unwrap();
+    assert_eq!(program.call::<_, i32>("sum", (100000,)), Ok(705182704));
+    assert_eq!(MOST_ENVS.load(std::sync::atomic::Ordering::Relaxed), 100000);
+    assert_eq!(closure_env_count(), 0);
+}
+
+static MOST_FREED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+extern "C" fn note_freed() {
+    MOST_FREED.fetch_max(freed_bytes(), std::sync::atomic::Ordering::Relaxed);
+}
+
+// Freed memory is kept back so that using it can be caught, but only so much of it, a long
+// loop that allocates and frees does not keep all of it until the call returns
+#[test]
+fn freed_memory_is_bounded() {
+    let source = "let n = 10000;\nwhile n {\n    let h = alloc([0; 256]);\n    h[0] = n;\n    free(h);\n    note_freed();\n    n -= 1;\n}\nlet h = alloc(5);\nfree(h);\nprint(*h);\nreturn 0;\n";
+    let mut engine = Engine::new();
+    engine.register_fn("note_freed", note_freed as extern "C" fn()).unwrap();
+    let error = engine.compile(source).unwrap().run().unwrap_err();
+    assert!(error.contains("use of memory after it was freed at line 11"), "{}", error);
+    assert_eq!(freed_bytes(), 0);
+    let most = MOST_FREED.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(most > 1000 * 1024 && most <= (1 << 20) + 1024, "{}", most);
+}
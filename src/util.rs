@@ -1,7 +1,7 @@
 use cranelift::codegen::CodegenError;
 use cranelift::codegen::verifier::{VerifierErrors};
 use cranelift::prelude::*;
-use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_jit::JITModule;
 use cranelift_module::{DataContext, FuncId, Linkage, Module, ModuleError};
 use std::collections::HashMap;
 use core::mem;
@@ -122,7 +122,7 @@ impl<'a> FunctionCreator<'a> {
 
         let local_callee = self
             .module
-            .declare_func_in_func(callee, &mut self.func_builder.func);
+            .declare_func_in_func(callee, self.func_builder.func);
 
         let call = self.func_builder.ins().call(local_callee, args);
         return call;
@@ -165,7 +165,7 @@ impl<'a> FunctionCreator<'a> {
 impl CompilerUtil {
     // Just input None if you have no included functions
     pub fn new(included_functions: Option<IncludedFunctions>) -> Self {
-        let mut builder = JITBuilder::new(cranelift_module::default_libcall_names());
+        let mut builder = crate::jit::new_jit_builder();
 
         if included_functions.is_some() {
             for i in 0..included_functions.as_ref().unwrap().func.len() {
@@ -224,7 +224,7 @@ impl CompilerUtil {
                 }
             }
             else {
-                println!("{}", err.to_string() );
+                println!("{}", err);
             }
             //println!("{}", result.err().unwrap().to_string() );
             panic!();